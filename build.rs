@@ -0,0 +1,4 @@
+fn main() {
+    cxx_build::bridge("src/lib.rs").flag_if_supported("-std=c++14").compile("rfs-cxx");
+    println!("cargo:rerun-if-changed=src/lib.rs");
+}
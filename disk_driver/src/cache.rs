@@ -221,7 +221,12 @@ impl<T: DiskDriver> DiskDriver for CacheDiskDriver<T> {
     }
 
     fn ddriver_ioctl(&mut self, cmd: u32, arg: &mut [u8]) -> Result<()> {
-        self.inner.ddriver_ioctl(cmd, arg)
+        self.inner.ddriver_ioctl(cmd, arg)?;
+        // inner disk may be enlarged
+        if cmd == IOC_REQ_DEVICE_SIZE {
+            self.info.size = u32::from_le_bytes(arg[0..4].try_into().unwrap());
+        }
+        Ok(())
     }
 
     fn ddriver_reset(&mut self) -> Result<()> {
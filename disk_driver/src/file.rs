@@ -105,6 +105,10 @@ impl DiskDriver for FileDiskDriver {
     fn ddriver_ioctl(&mut self, cmd: u32, arg: &mut [u8]) -> Result<()> {
         match cmd {
             IOC_REQ_DEVICE_SIZE => {
+                // file may be enlarged after opened
+                if let Some(len) = self.file.as_ref().and_then(|f| f.metadata().ok()).map(|m| m.len()) {
+                    self.info.consts.layout_size = self.info.consts.layout_size.max(len.min(u32::MAX as u64) as u32);
+                }
                 arg[0..4].copy_from_slice(&self.info.consts.layout_size.to_le_bytes());
                 Ok(())
             }
@@ -85,16 +85,21 @@ impl DiskDriver for MemoryDiskDriver {
 
 impl MemoryDiskDriver {
     pub fn new() -> Self {
+        Self::with_size(MEM_DISK_SIZE)
+    }
+
+    /// Create memory disk of `size` bytes
+    pub fn with_size(size: usize) -> Self {
         Self {
             info: DiskInfo {
                 stats: Default::default(),
                 consts: DiskConst {
-                    layout_size: MEM_DISK_SIZE as u32,
+                    layout_size: size as u32,
                     iounit_size: MEM_DISK_UNIT as u32,
                     ..Default::default()
                 },
             },
-            mem: vec![0 as u8; MEM_DISK_SIZE],
+            mem: vec![0 as u8; size],
             pointer: 0,
         }
     }
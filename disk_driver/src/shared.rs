@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use anyhow::{anyhow, Result};
+use crate::{DiskDriver, SeekType};
+
+struct SharedInner<T: DiskDriver> {
+    driver: T,
+    /// Cursor of `driver`, to skip seeking when a handle continues where it stopped
+    offset: u64,
+}
+
+/// Driver shared by threads: handles made by `clone` keep their own cursors,
+/// every request locks the inner driver and seeks to the cursor of its handle
+pub struct SharedDiskDriver<T: DiskDriver> {
+    inner: Arc<Mutex<SharedInner<T>>>,
+    offset: u64,
+}
+
+impl<T: DiskDriver> SharedDiskDriver<T> {
+    pub fn new(driver: T) -> Self {
+        Self { inner: Arc::new(Mutex::new(SharedInner { driver, offset: 0 })), offset: 0 }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, SharedInner<T>>> {
+        Self::lock_at(&self.inner, None)
+    }
+
+    /// Lock inner driver, moving its cursor to `offset` if given
+    fn lock_at(inner: &Mutex<SharedInner<T>>, offset: Option<u64>) -> Result<MutexGuard<'_, SharedInner<T>>> {
+        let mut inner = inner.lock().map_err(|_| anyhow!("shared driver poisoned"))?;
+        if let Some(offset) = offset.filter(|x| *x != inner.offset) {
+            inner.driver.ddriver_seek(offset as i64, SeekType::Set)?;
+            inner.offset = offset;
+        }
+        Ok(inner)
+    }
+
+    /// Run `f` on inner driver
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R> {
+        let mut inner = self.lock()?;
+        let r = f(&mut inner.driver);
+        // cursor of inner driver is unknown after `f`
+        inner.offset = u64::MAX;
+        Ok(r)
+    }
+}
+
+impl<T: DiskDriver> Clone for SharedDiskDriver<T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), offset: self.offset }
+    }
+}
+
+impl<T: DiskDriver> DiskDriver for SharedDiskDriver<T> {
+    fn ddriver_open(&mut self, path: &str) -> Result<()> {
+        let mut inner = self.lock()?;
+        inner.driver.ddriver_open(path)?;
+        inner.offset = u64::MAX;
+        Ok(())
+    }
+
+    fn ddriver_close(&mut self) -> Result<()> {
+        self.lock()?.driver.ddriver_close()
+    }
+
+    fn ddriver_seek(&mut self, offset: i64, whence: SeekType) -> Result<u64> {
+        if whence == SeekType::Set {
+            self.offset = offset as u64;
+            return Ok(self.offset);
+        }
+        let mut inner = Self::lock_at(&self.inner, Some(self.offset))?;
+        self.offset = inner.driver.ddriver_seek(offset, whence)?;
+        inner.offset = self.offset;
+        Ok(self.offset)
+    }
+
+    fn ddriver_write(&mut self, buf: &[u8], size: usize) -> Result<usize> {
+        let mut inner = Self::lock_at(&self.inner, Some(self.offset))?;
+        let n = inner.driver.ddriver_write(buf, size)?;
+        self.offset += n as u64;
+        inner.offset = self.offset;
+        Ok(n)
+    }
+
+    fn ddriver_read(&mut self, buf: &mut [u8], size: usize) -> Result<usize> {
+        let mut inner = Self::lock_at(&self.inner, Some(self.offset))?;
+        let n = inner.driver.ddriver_read(buf, size)?;
+        self.offset += n as u64;
+        inner.offset = self.offset;
+        Ok(n)
+    }
+
+    fn ddriver_ioctl(&mut self, cmd: u32, arg: &mut [u8]) -> Result<()> {
+        self.with(|driver| driver.ddriver_ioctl(cmd, arg))?
+    }
+
+    fn ddriver_reset(&mut self) -> Result<()> {
+        self.with(|driver| driver.ddriver_reset())?
+    }
+
+    fn ddriver_flush(&mut self) -> Result<()> {
+        self.lock()?.driver.ddriver_flush()
+    }
+
+    fn ddriver_flush_range(&mut self, left: u64, right: u64) -> Result<()> {
+        self.lock()?.driver.ddriver_flush_range(left, right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryDiskDriver;
+    use crate::driver_tester;
+
+    #[test]
+    fn simple_test() -> Result<()> {
+        let mut driver = SharedDiskDriver::new(MemoryDiskDriver::new());
+        driver_tester(&mut driver)?;
+        // handles keep their own cursors
+        let mut a = driver.clone();
+        let mut b = driver.clone();
+        a.ddriver_seek(512, SeekType::Set)?;
+        b.ddriver_seek(2048, SeekType::Set)?;
+        a.ddriver_write(&[1; 512], 512)?;
+        b.ddriver_write(&[2; 512], 512)?;
+        a.ddriver_write(&[3; 512], 512)?;
+        let mut buf = [0; 512];
+        driver.ddriver_seek(1024, SeekType::Set)?;
+        driver.ddriver_read(&mut buf, 512)?;
+        assert_eq!(buf, [3; 512]);
+        driver.ddriver_seek(2048, SeekType::Set)?;
+        driver.ddriver_read(&mut buf, 512)?;
+        assert_eq!(buf, [2; 512]);
+        Ok(())
+    }
+}
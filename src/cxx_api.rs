@@ -0,0 +1,171 @@
+/// Implementation of the C++ API in `lib.rs` on `api::Rfs` over an image file.
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Error, Result};
+use disk_driver::file::FileDiskDriver;
+use libc::{EBADF, EEXIST, EINVAL, EIO};
+use log::*;
+use crate::ffi::{DirEntry, Stat};
+use crate::rfs_lib::api::{Rfs, RfsOptions};
+use crate::rfs_lib::mkfs::MkfsOptions;
+use crate::rfs_lib::utils::*;
+
+/// Unit of disk io for images
+const CXX_DISK_UNIT: u32 = 512;
+
+pub struct Filesystem {
+    rfs: Option<Rfs<FileDiskDriver>>,
+    errno: i32,
+}
+
+fn message(e: &Error) -> String {
+    format!("{:#}", e)
+}
+
+fn seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn open_image(path: &str, size: u64, options: RfsOptions) -> Result<Box<Filesystem>> {
+    if size > u32::MAX as u64 {
+        return Err(Error::new(Errno(EINVAL)).context(format!("image size {} too large", size)));
+    }
+    let driver = FileDiskDriver::new("", size as u32, CXX_DISK_UNIT, false);
+    let rfs = Rfs::open(driver, RfsOptions { path: path.to_string(), ..options })?;
+    Ok(Box::new(Filesystem { rfs: Some(rfs), errno: 0 }))
+}
+
+pub fn format(path: &str, size: u64, block_size: u32) -> Result<Box<Filesystem>, String> {
+    let mkfs = MkfsOptions { block_size: block_size as usize, ..Default::default() };
+    open_image(path, size, RfsOptions { mkfs: Some(mkfs), format: true, ..Default::default() })
+        .map_err(|e| message(&e))
+}
+
+pub fn open(path: &str, read_only: bool) -> Result<Box<Filesystem>, String> {
+    let size = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+    open_image(path, size, RfsOptions { read_only, ..Default::default() }).map_err(|e| message(&e))
+}
+
+impl Filesystem {
+    /// Run `f` on the opened filesystem, keeping errno of failure
+    fn with<R>(&mut self, f: impl FnOnce(&mut Rfs<FileDiskDriver>) -> Result<R>) -> Result<R, String> {
+        let result = match self.rfs.as_mut() {
+            Some(rfs) => f(rfs),
+            None => Err(Error::new(Errno(EBADF)).context("filesystem closed")),
+        };
+        self.check(result)
+    }
+
+    fn check<R>(&mut self, result: Result<R>) -> Result<R, String> {
+        result.map_err(|e| {
+            self.errno = get_errno(&e, EIO);
+            message(&e)
+        })
+    }
+
+    pub fn close(&mut self) -> Result<(), String> {
+        let result = self.rfs.take().map_or(Ok(()), |rfs| rfs.close());
+        self.check(result)
+    }
+
+    pub fn error(&self) -> i32 {
+        self.errno
+    }
+
+    pub fn lookup(&mut self, path: &str) -> Result<u64, String> {
+        self.with(|rfs| Ok(rfs.metadata(path)?.ino as u64))
+    }
+
+    pub fn stat(&mut self, path: &str) -> Result<Stat, String> {
+        self.with(|rfs| {
+            let meta = rfs.metadata(path)?;
+            Ok(Stat {
+                ino: meta.ino as u64,
+                file_type: usize::from(meta.file_type.clone()) as u8,
+                mode: meta.mode,
+                size: meta.len,
+                nlink: meta.nlink as u32,
+                uid: meta.uid,
+                gid: meta.gid,
+                atime: seconds(meta.atime),
+                mtime: seconds(meta.mtime),
+                ctime: seconds(meta.ctime),
+            })
+        })
+    }
+
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, String> {
+        self.with(|rfs| rfs.read_file(path))
+    }
+
+    pub fn write(&mut self, path: &str, data: &[u8]) -> Result<(), String> {
+        self.with(|rfs| rfs.write_file(path, data))
+    }
+
+    pub fn readdir(&mut self, path: &str) -> Result<Vec<DirEntry>, String> {
+        self.with(|rfs| Ok(rfs.read_dir(path)?.into_iter().map(|e| DirEntry {
+            name: e.name,
+            ino: e.ino as u64,
+            file_type: usize::from(e.file_type) as u8,
+        }).collect()))
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<(), String> {
+        self.with(|rfs| {
+            if rfs.exists(path) { return Err(Error::new(Errno(EEXIST)).context(path.to_string())); }
+            rfs.create_dir_all(path)
+        })
+    }
+
+    pub fn unlink(&mut self, path: &str) -> Result<(), String> {
+        self.with(|rfs| rfs.remove(path))
+    }
+}
+
+impl Drop for Filesystem {
+    fn drop(&mut self) {
+        if self.rfs.is_some() {
+            if let Err(e) = self.close() { error!("close filesystem: {}", e); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{ENOENT, ENOTEMPTY};
+
+    #[test]
+    fn test_cxx_api() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("rfs-cxx-{}.img", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut fs = format(path, 4 << 20, 1024).map_err(Error::msg)?;
+        fs.mkdir("/dir").map_err(Error::msg)?;
+        fs.write("/dir/file", b"hello").map_err(Error::msg)?;
+        assert!(fs.mkdir("/dir").is_err());
+        assert_eq!(fs.error(), EEXIST);
+        fs.close().map_err(Error::msg)?;
+        assert!(fs.lookup("/dir").is_err());
+        assert_eq!(fs.error(), EBADF);
+
+        let mut fs = open(path, false).map_err(Error::msg)?;
+        let entries = fs.readdir("/dir").map_err(Error::msg)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "file");
+        assert_eq!(entries[0].file_type, libc::DT_REG);
+        let stat = fs.stat("/dir/file").map_err(Error::msg)?;
+        assert_eq!((stat.size, stat.ino), (5, fs.lookup("/dir/file").map_err(Error::msg)?));
+        assert_eq!(fs.read("/dir/file").map_err(Error::msg)?, b"hello");
+        let e = fs.unlink("/dir").unwrap_err();
+        assert_eq!(fs.error(), ENOTEMPTY);
+        assert!(e.starts_with("/dir"));
+        fs.unlink("/dir/file").map_err(Error::msg)?;
+        assert!(fs.read("/dir/file").is_err());
+        assert_eq!(fs.error(), ENOENT);
+        drop(fs);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
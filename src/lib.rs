@@ -1,39 +1,60 @@
 extern crate core;
 
 mod rfs_lib;
+mod cxx_api;
 
-use lazy_static::lazy_static;
-use mut_static::MutStatic;
 pub use rfs_lib::*;
+use cxx_api::{format, open, Filesystem};
 
-lazy_static! {
-    // Store static mount point argument for signal call use
-    pub static ref MOUNT_POINT: MutStatic<String> = MutStatic::new();
-    pub static ref DEVICE_FILE: MutStatic<String> = MutStatic::new();
-    pub static ref FORCE_FORMAT: MutStatic<bool> = MutStatic::new();
-    pub static ref MKFS_FORMAT: MutStatic<bool> = MutStatic::new();
-    pub static ref LAYOUT_FILE: MutStatic<String> = MutStatic::new();
-    pub static ref ENABLE_CACHING: MutStatic<bool> = MutStatic::new();
-}
-
-#[cxx::bridge]
+/// C++ API, the header is generated to `target/cxxbridge/rfs/src/lib.rs.h`.
+/// Failed calls throw `rust::Error` with the message, `Filesystem::error` keeps the errno.
+#[cxx::bridge(namespace = "rfs")]
 mod ffi {
-    extern "Rust" {
-        fn add(left: usize, right: usize) -> usize;
+    /// Attributes of a file, times are seconds since epoch
+    struct Stat {
+        ino: u64,
+        /// `DT_*` from `<dirent.h>`
+        file_type: u8,
+        /// Permission bits
+        mode: u16,
+        size: u64,
+        nlink: u32,
+        uid: u32,
+        gid: u32,
+        atime: i64,
+        mtime: i64,
+        ctime: i64,
     }
-}
 
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+    struct DirEntry {
+        name: String,
+        ino: u64,
+        /// `DT_*` from `<dirent.h>`
+        file_type: u8,
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    extern "Rust" {
+        /// Opened filesystem, written back on `close` or when dropped
+        type Filesystem;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+        /// Create or overwrite image `path` of `size` bytes with a new filesystem
+        fn format(path: &str, size: u64, block_size: u32) -> Result<Box<Filesystem>>;
+        /// Open filesystem in image `path`
+        fn open(path: &str, read_only: bool) -> Result<Box<Filesystem>>;
+        fn close(self: &mut Filesystem) -> Result<()>;
+        /// Errno of last failed call
+        fn error(self: &Filesystem) -> i32;
+        /// Inode number of `path`
+        fn lookup(self: &mut Filesystem, path: &str) -> Result<u64>;
+        fn stat(self: &mut Filesystem, path: &str) -> Result<Stat>;
+        /// Whole content of a regular file
+        fn read(self: &mut Filesystem, path: &str) -> Result<Vec<u8>>;
+        /// Create or truncate a regular file and write `data` to it
+        fn write(self: &mut Filesystem, path: &str, data: &[u8]) -> Result<()>;
+        /// Entries of a directory without `.` and `..`
+        fn readdir(self: &mut Filesystem, path: &str) -> Result<Vec<DirEntry>>;
+        fn mkdir(self: &mut Filesystem, path: &str) -> Result<()>;
+        /// Remove a file or an empty directory
+        fn unlink(self: &mut Filesystem, path: &str) -> Result<()>;
     }
 }
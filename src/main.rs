@@ -1,10 +1,14 @@
 use std::env::set_var;
 use std::fs;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::process::Stdio;
-use clap::{arg, ArgAction, command};
+use clap::{arg, ArgAction, ArgMatches, command, Command};
 // use crate::hello::HelloFS;
 use anyhow::{anyhow, Result};
 use disk_driver::cache::CacheDiskDriver;
+use disk_driver::DiskDriver;
 use disk_driver::file::FileDiskDriver;
 use execute::Execute;
 use fork::{Fork, fork};
@@ -13,7 +17,19 @@ use nix::sys::signal;
 use retry::delay::Fixed;
 use retry::{OperationResult, retry_with_index};
 use log::*;
-use rfs::{DEVICE_FILE, ENABLE_CACHING, FORCE_FORMAT, LAYOUT_FILE, MKFS_FORMAT, MOUNT_POINT, RFS};
+use rfs::RFS;
+use rfs::config::RfsConfig;
+use rfs::server::RfsServer;
+use rfs::quota::QuotaType;
+use rfs::journal::RFS_JOURNAL_DEFAULT_BLOCKS;
+use rfs::reflink::RFS_IOC_CLONE;
+use rfs::resize::RFS_IOC_GROW;
+use rfs::image::image_restore;
+use rfs::debug::DebugShell;
+use rfs::crypt::{crypt_key_identifier, RfsKeyArg, RFS_IOC_ADD_KEY, RFS_IOC_REMOVE_KEY, RFS_IOC_SET_POLICY};
+use rfs::mkfs::MkfsOptions;
+use rfs::layout::parse_layout;
+use rfs::state::parse_errors_behavior;
 use crate::rfs_lib::utils::init_logs;
 
 mod rfs_lib;
@@ -28,7 +44,7 @@ fn main() -> Result<()> {
             .required(false))
         .arg(arg!(--format "Format disk").action(ArgAction::SetTrue)
             .required(false))
-        .arg(arg!(--mkfs "Use mkfs.ext2 to format disk").action(ArgAction::SetTrue)
+        .arg(arg!(--mkfs "Format disk as ext2 revision 1 instead of layout file").action(ArgAction::SetTrue)
             .required(false))
         .arg(arg!(-c --cache "Enable caching").action(ArgAction::SetTrue)
             .required(false))
@@ -41,14 +57,21 @@ fn main() -> Result<()> {
         .arg(arg!(-r --read_only "Mount as read only filesystem").action(ArgAction::SetTrue)
             .required(false))
         .arg(arg!(-v --verbose "Print more debug information, or set `RUST_LOG=debug`").action(ArgAction::SetTrue)
-            .required(false))
+            .required(false).global(true))
         .arg(arg!(-q --quiet "Do not print logs").action(ArgAction::SetTrue)
-            .required(false))
+            .required(false).global(true))
         .arg(arg!(--latency "Enable disk latency").action(ArgAction::SetTrue)
             .required(false))
+        .arg(
+            arg!(-j --threads <THREADS> "Serve requests with THREADS worker threads")
+                .required(false)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1"),
+        )
         .arg(
             arg!(-d --device <FILE> "Device path (filesystem storage file)")
                 .required(false)
+                .global(true)
                 .default_value("ddriver"),
         )
         .arg(
@@ -60,14 +83,157 @@ fn main() -> Result<()> {
         .arg(
             arg!(--unit <UNIT> "IO unit of disk in bytes")
                 .required(false)
+                .global(true)
                 .value_parser(clap::value_parser!(u32).range(1..))
                 .default_value("512"),
         )
+        .arg(
+            arg!(--superblock <BLOCK> "Open filesystem from backup super block at BLOCK, and restore primary one")
+                .required(false)
+                .global(true)
+                .value_parser(clap::value_parser!(u32))
+                .default_value("0"),
+        )
         .arg(
             arg!(-l --layout <FILE> "Select layout file for formatting disk")
                 .required(false)
                 .default_value("none"),
         )
+        .arg(arg!(--snapshot <NAME> "Mount snapshot NAME read only instead of the live filesystem")
+            .required(false))
+        .arg(arg!(--compress <METHOD> "Compress new regular files with METHOD, lz4 or zstd")
+            .required(false)
+            .value_parser(["lz4", "zstd"]))
+        .arg(arg!(--"key-file" <FILE> "Add master key from FILE to unlock encrypted directories, may be repeated")
+            .required(false)
+            .action(ArgAction::Append))
+        .subcommand(
+            Command::new("quota")
+                .about("Report or edit disk quotas of an unmounted device")
+                .arg(arg!(-g --group "Operate on group quota instead of user quota").action(ArgAction::SetTrue))
+                .arg(arg!(--on "Enable quota and count current usage").action(ArgAction::SetTrue))
+                .arg(arg!(--check "Recount usage by scanning all inodes").action(ArgAction::SetTrue))
+                .arg(arg!(--id <ID> "Uid or gid to set limits for")
+                    .value_parser(clap::value_parser!(u32)))
+                .arg(arg!(--block_soft <BLOCKS> "Soft limit of blocks, 0 for no limit")
+                    .value_parser(clap::value_parser!(u32)).requires("id"))
+                .arg(arg!(--block_hard <BLOCKS> "Hard limit of blocks, 0 for no limit")
+                    .value_parser(clap::value_parser!(u32)).requires("id"))
+                .arg(arg!(--inode_soft <INODES> "Soft limit of inodes, 0 for no limit")
+                    .value_parser(clap::value_parser!(u32)).requires("id"))
+                .arg(arg!(--inode_hard <INODES> "Hard limit of inodes, 0 for no limit")
+                    .value_parser(clap::value_parser!(u32)).requires("id"))
+                .arg(arg!(--block_grace <SECONDS> "Grace period of block soft limit")
+                    .value_parser(clap::value_parser!(u32)))
+                .arg(arg!(--inode_grace <SECONDS> "Grace period of inode soft limit")
+                    .value_parser(clap::value_parser!(u32)))
+        )
+        .subcommand(
+            Command::new("journal")
+                .about("Show or create metadata journal of an unmounted device")
+                .arg(arg!(--create "Create journal file and enable journaling").action(ArgAction::SetTrue))
+                .arg(arg!(--blocks <BLOCKS> "Journal size in blocks, 1024 by default")
+                    .value_parser(clap::value_parser!(u32).range(16..)))
+        )
+        .subcommand(
+            Command::new("mkfs")
+                .about("Create an ext2 revision 1 filesystem on device")
+                .arg(arg!(-b --block_size <BYTES> "Block size, 1024, 2048 or 4096")
+                    .value_parser(clap::value_parser!(u32)).default_value("1024"))
+                .arg(arg!(-N --inodes <COUNT> "Inodes count, overrides bytes per inode")
+                    .value_parser(clap::value_parser!(u32).range(1..)))
+                .arg(arg!(-i --bytes_per_inode <BYTES> "Bytes per inode")
+                    .value_parser(clap::value_parser!(u32).range(1024..)).default_value("4096"))
+                .arg(arg!(-I --inode_size <BYTES> "Inode size")
+                    .value_parser(clap::value_parser!(u32)).default_value("128"))
+                .arg(arg!(-L --label <LABEL> "Volume label"))
+                .arg(arg!(-U --uuid <UUID> "Volume uuid, random by default"))
+                .arg(arg!(-m --reserved <PERCENT> "Percentage of blocks reserved for super user")
+                    .value_parser(clap::value_parser!(u32).range(0..=50)).default_value("5"))
+                .arg(arg!(-O --features <FEATURES> "Comma separated features, `^` prefix to disable")
+                    .action(ArgAction::Append))
+                .arg(arg!(-e --errors <BEHAVIOR> "Behavior when detecting errors: continue, remount-ro or panic")
+                    .default_value("continue"))
+                .arg(arg!(--size <DISK_SIZE> "Size in MiB when creating device file")
+                    .value_parser(clap::value_parser!(u32).range(1..)).default_value("4"))
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Check and repair consistency of an unmounted device")
+                .arg(arg!(-y --repair "Repair found problems").action(ArgAction::SetTrue))
+        )
+        .subcommand(
+            Command::new("resize")
+                .about("Grow or shrink an unmounted device image, keeping its content")
+                .arg(arg!(<size> "New size in MiB").value_parser(clap::value_parser!(u32).range(1..)))
+        )
+        .subcommand(
+            Command::new("defrag")
+                .about("Report fragmentation of an unmounted device and move fragmented files into contiguous blocks")
+                .arg(arg!(-n --check "Only report fragmentation").action(ArgAction::SetTrue))
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Inspect and change an unmounted device with debugfs-style commands")
+                .arg(arg!(-R --request <REQUEST> "Run one command and exit").required(false))
+        )
+        .subcommand(
+            Command::new("image")
+                .about("Save metadata of an unmounted device without file data, or restore it")
+                .subcommand_required(true)
+                .subcommand(Command::new("dump").about("Write metadata image of device")
+                    .arg(arg!(<file> "Image file"))
+                    .arg(arg!(-s --scramble "Replace file names by generated names").action(ArgAction::SetTrue)))
+                .subcommand(Command::new("restore").about("Rebuild a sparse device from metadata image")
+                    .arg(arg!(<file> "Image file")))
+        )
+        .subcommand(
+            Command::new("reflink")
+                .about("Clone file on a mounted rfs, sharing data blocks until written")
+                .arg(arg!(<src> "Source file"))
+                .arg(arg!(<dst> "Destination file, created or replaced"))
+        )
+        .subcommand(
+            Command::new("grow")
+                .about("Grow a mounted rfs into space added to its device")
+                .arg(arg!(<path> "Any file on the mounted rfs"))
+        )
+        .subcommand(
+            Command::new("encrypt")
+                .about("Manage encryption keys and policies on a mounted rfs")
+                .subcommand_required(true)
+                .subcommand(Command::new("add-key").about("Unlock directories encrypted with a key")
+                    .arg(arg!(<path> "Any file on the mounted rfs"))
+                    .arg(arg!(<key> "Key file of 16 to 64 raw bytes")))
+                .subcommand(Command::new("remove-key").about("Lock directories encrypted with a key again")
+                    .arg(arg!(<path> "Any file on the mounted rfs"))
+                    .arg(arg!(<key> "Key file of 16 to 64 raw bytes")))
+                .subcommand(Command::new("set-policy").about("Encrypt an empty directory with an added key")
+                    .arg(arg!(<path> "Empty directory"))
+                    .arg(arg!(<key> "Key file of 16 to 64 raw bytes")))
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .about("Manage snapshots of an unmounted device")
+                .subcommand_required(true)
+                .subcommand(Command::new("create").about("Take a snapshot of current state").arg(arg!(<name> "Snapshot name")))
+                .subcommand(Command::new("list").about("List snapshots from the oldest"))
+                .subcommand(Command::new("delete").about("Remove a snapshot").arg(arg!(<name> "Snapshot name")))
+                .subcommand(Command::new("rollback").about("Bring filesystem back to a snapshot, newer snapshots are dropped")
+                    .arg(arg!(<name> "Snapshot name")))
+        )
+        .subcommand(
+            Command::new("layout")
+                .about("Inspect layout files")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Validate layout file and print resolved layout")
+                        .arg(arg!([file] "Layout file, the global --layout by default"))
+                        .arg(arg!(--size <DISK_SIZE> "Size in MiB if device file not exists")
+                            .value_parser(clap::value_parser!(u32).range(1..)).default_value("4"))
+                )
+        )
         .get_matches();
 
     if matches.get_flag("verbose") {
@@ -76,63 +242,54 @@ fn main() -> Result<()> {
     if !matches.get_flag("quiet") {
         init_logs();
     }
-    let mountpoint = matches.get_one::<String>("mountpoint").unwrap();
     let device = matches.get_one::<String>("device").unwrap();
+    let disk_unit = matches.get_one::<u32>("unit").unwrap().clone();
+    // only the device and backup super block are used by subcommands
+    let config = RfsConfig {
+        device: device.clone(),
+        super_block: *matches.get_one::<u32>("superblock").unwrap() as usize,
+        ..Default::default()
+    };
+    match matches.subcommand() {
+        Some(("quota", sub)) => return quota(&config, disk_unit, sub),
+        Some(("journal", sub)) => return journal(&config, disk_unit, sub),
+        Some(("fsck", sub)) => return fsck(&config, disk_unit, sub),
+        Some(("defrag", sub)) => return defrag(&config, disk_unit, sub),
+        Some(("resize", sub)) => return resize(&config, disk_unit, sub),
+        Some(("mkfs", sub)) => return mkfs(device, disk_unit, sub),
+        Some(("image", sub)) => return image(&config, disk_unit, sub),
+        Some(("debug", sub)) => return debug(&config, disk_unit, sub),
+        Some(("reflink", sub)) => return reflink(sub),
+        Some(("encrypt", sub)) => return encrypt(sub),
+        Some(("grow", sub)) => return grow(sub),
+        Some(("snapshot", sub)) => return snapshot(&config, disk_unit, sub),
+        Some(("layout", sub)) => return layout(device, matches.get_one::<String>("layout").unwrap(), sub),
+        _ => {}
+    }
+    let mountpoint = matches.get_one::<String>("mountpoint").unwrap();
     let layout = matches.get_one::<String>("layout").unwrap();
     let path_mountpoint = fs::canonicalize(mountpoint)?;
     // let path_device = fs::canonicalize(device)?;
     let abspath_mountpoint = path_mountpoint.to_str().unwrap();
     // let abspath_device = path_device.to_str().unwrap();
     info!("Device: {}", device);
-    DEVICE_FILE.set(device.clone()).unwrap();
-    LAYOUT_FILE.set(layout.clone()).unwrap();
-
-    MOUNT_POINT.set(abspath_mountpoint.clone().to_string()).unwrap();
-    FORCE_FORMAT.set(matches.get_flag("format")).unwrap();
-    MKFS_FORMAT.set(matches.get_flag("mkfs")).unwrap();
-    // MKFS_FORMAT.set(true).unwrap();
-    ENABLE_CACHING.set(matches.get_flag("cache")).unwrap();
+    let config = RfsConfig {
+        format: matches.get_flag("format"),
+        mkfs: matches.get_flag("mkfs"),
+        layout: layout.clone(),
+        snapshot: matches.get_one::<String>("snapshot").cloned().unwrap_or_default(),
+        compress: matches.get_one::<String>("compress").cloned().unwrap_or_default(),
+        key_files: matches.get_many::<String>("key-file").map_or(vec![], |x| x.cloned().collect()),
+        threads: *matches.get_one::<usize>("threads").unwrap(),
+        ..config
+    };
+    let caching = matches.get_flag("cache");
 
     let disk_size = matches.get_one::<u32>("size").unwrap().clone() * 0x400 * 0x400;
-    let disk_unit = matches.get_one::<u32>("unit").unwrap().clone();
     let cache_size = matches.get_one::<u32>("cache_size").unwrap().clone();
     let latency = matches.get_flag("latency").clone();
 
-    macro_rules! umount {
-        () => {
-            {
-                use log::*;
-                info!("Unmounting {}", MOUNT_POINT.read().unwrap().clone());
-                let mut command = execute::command_args!("fusermount", "-u", MOUNT_POINT.read().unwrap().clone());
-                command.stdout(Stdio::piped());
-                let output = command.execute_output().unwrap();
-                info!("fusermount output: {}", String::from_utf8(output.stdout).unwrap());
-            }
-        };
-    }
-
-    pub extern "C" fn signal_handler(_: i32) {
-        unsafe { println!("[{}] Received signal and will umount.", libc::getpid()); }
-        umount!();
-        unsafe { println!("[{}] All Done.", libc::getpid()); }
-        std::process::exit(0);
-    }
-
-    let sig_action = signal::SigAction::new(
-        signal::SigHandler::Handler(signal_handler),
-        signal::SaFlags::SA_NODEFER,
-        signal::SigSet::empty(),
-    );
-    unsafe {
-        match signal::sigaction(signal::SIGINT, &sig_action) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("SIGINT signal set failed, {:?}", e);
-            }
-        }
-    }
-
-    let read_only = matches.get_flag("read_only");
+    let read_only = matches.get_flag("read_only") || matches.contains_id("snapshot");
     let options = vec![
         if read_only { MountOption::RO } else { MountOption::RW },
         MountOption::FSName("rfs".parse()?)];
@@ -143,15 +300,16 @@ fn main() -> Result<()> {
             Ok(())
         }
         Ok(Fork::Child) => {
+            umount_on_sigint(abspath_mountpoint.to_string());
             match retry_with_index(Fixed::from_millis(100), |current_try| {
                 info!("[try {}/{}] Mount to {}", current_try, retry_times, abspath_mountpoint);
-                let res = if ENABLE_CACHING.read().unwrap().clone() {
-                    mount2(RFS::new(CacheDiskDriver::new(
-                        FileDiskDriver::new("", disk_size, disk_unit, latency), cache_size as usize)
-                    ), abspath_mountpoint, &options)
+                let res = if caching {
+                    mount2(RfsServer::new(CacheDiskDriver::new(
+                        FileDiskDriver::new("", disk_size, disk_unit, latency), cache_size as usize),
+                        config.clone()), abspath_mountpoint, &options)
                 } else {
-                    mount2(RFS::new(
-                        FileDiskDriver::new("", disk_size, disk_unit, latency)),
+                    mount2(RfsServer::new(
+                        FileDiskDriver::new("", disk_size, disk_unit, latency), config.clone()),
                            abspath_mountpoint, &options)
                 };
                 match res {
@@ -163,7 +321,7 @@ fn main() -> Result<()> {
                         if current_try > retry_times {
                             OperationResult::Err(format!("Failed to mount after {} retries! Err: {}", retry_times, e))
                         } else {
-                            umount!();
+                            umount(abspath_mountpoint);
                             info!("Umount Done.");
                             OperationResult::Retry(format!("Failed to mount, trying to umount..."))
                         }
@@ -178,6 +336,277 @@ fn main() -> Result<()> {
     }
 }
 
+fn umount(mountpoint: &str) {
+    info!("Unmounting {}", mountpoint);
+    let mut command = execute::command_args!("fusermount", "-u", mountpoint);
+    command.stdout(Stdio::piped());
+    let output = command.execute_output().unwrap();
+    info!("fusermount output: {}", String::from_utf8(output.stdout).unwrap());
+}
+
+/// Block SIGINT and wait for it on a separate thread, which unmounts `mountpoint` and exits.
+/// Must run before any other thread is spawned so that every thread inherits the mask.
+fn umount_on_sigint(mountpoint: String) {
+    let mut set = signal::SigSet::empty();
+    set.add(signal::SIGINT);
+    if let Err(e) = set.thread_block() {
+        println!("SIGINT signal set failed, {:?}", e);
+        return;
+    }
+    std::thread::spawn(move || {
+        if set.wait().is_ok() {
+            println!("[{}] Received signal and will umount.", std::process::id());
+            umount(&mountpoint);
+            println!("[{}] All Done.", std::process::id());
+            std::process::exit(0);
+        }
+    });
+}
+
+/// Open an existing device image without mounting, disk size is taken from image file
+fn open_device(config: &RfsConfig, disk_unit: u32) -> Result<RFS<FileDiskDriver>> {
+    let device = config.device.as_str();
+    let disk_size = fs::metadata(device)
+        .map_err(|e| anyhow!("Cannot open device {}: {}", device, e))?.len() as u32;
+    let mut fs = RFS::new(FileDiskDriver::new("", disk_size, disk_unit, false), config.clone());
+    fs.rfs_init(device)?;
+    Ok(fs)
+}
+
+fn quota(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let qtype = if matches.get_flag("group") { QuotaType::Group } else { QuotaType::User };
+    let mut fs = open_device(config, disk_unit)?;
+    if matches.get_flag("on") {
+        fs.quota_enable(qtype)?;
+    }
+    if matches.get_flag("check") {
+        fs.quota_check()?;
+    }
+    let block_grace = matches.get_one::<u32>("block_grace").copied();
+    let inode_grace = matches.get_one::<u32>("inode_grace").copied();
+    if block_grace.is_some() || inode_grace.is_some() {
+        fs.quota_set_grace(qtype, block_grace, inode_grace)?;
+    }
+    if let Some(id) = matches.get_one::<u32>("id") {
+        fs.quota_set(qtype, *id,
+                     matches.get_one::<u32>("block_soft").copied(),
+                     matches.get_one::<u32>("block_hard").copied(),
+                     matches.get_one::<u32>("inode_soft").copied(),
+                     matches.get_one::<u32>("inode_hard").copied())?;
+    }
+    print!("{}", fs.quota_report(qtype));
+    fs.rfs_destroy()
+}
+
+fn journal(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let mut fs = open_device(config, disk_unit)?;
+    if matches.get_flag("create") {
+        let blocks = matches.get_one::<u32>("blocks").map_or(RFS_JOURNAL_DEFAULT_BLOCKS, |x| *x as usize);
+        fs.journal_create(blocks)?;
+    }
+    print!("{}", fs.journal_report());
+    fs.rfs_destroy()
+}
+
+fn mkfs(device: &str, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let disk_size = match fs::metadata(device) {
+        Ok(m) if m.len() > 0 => m.len() as u32,
+        _ => *matches.get_one::<u32>("size").unwrap() * 0x400 * 0x400,
+    };
+    let options = MkfsOptions {
+        block_size: *matches.get_one::<u32>("block_size").unwrap() as usize,
+        inodes_count: matches.get_one::<u32>("inodes").map(|x| *x as usize),
+        bytes_per_inode: *matches.get_one::<u32>("bytes_per_inode").unwrap() as usize,
+        inode_size: *matches.get_one::<u32>("inode_size").unwrap() as usize,
+        label: matches.get_one::<String>("label").cloned().unwrap_or_default(),
+        uuid: matches.get_one::<String>("uuid").map(|x| MkfsOptions::parse_uuid(x)).transpose()?,
+        reserved_percent: *matches.get_one::<u32>("reserved").unwrap(),
+        features: matches.get_many::<String>("features").map_or(vec![], |x| x.cloned().collect()),
+        errors: parse_errors_behavior(matches.get_one::<String>("errors").unwrap())?,
+    };
+    let mut fs = RFS::new(FileDiskDriver::new("", disk_size, disk_unit, false), RfsConfig::default());
+    fs.driver_open(device)?;
+    fs.rfs_mkfs(&options)?;
+    println!("{}", fs.super_block.to_string());
+    let backups = fs.super_block_backups();
+    if !backups.is_empty() {
+        println!("Superblock backups stored on blocks: {}", backups.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "));
+    }
+    fs.get_driver().ddriver_close()
+}
+
+fn layout(device: &str, layout: &str, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("check", sub)) => {
+            let file = sub.get_one::<String>("file").map_or(layout, |x| x.as_str());
+            let disk_size = match fs::metadata(device) {
+                Ok(m) if m.len() > 0 => m.len() as usize,
+                _ => *sub.get_one::<u32>("size").unwrap() as usize * 0x400 * 0x400,
+            };
+            let text = fs::read_to_string(file).map_err(|e| anyhow!("Cannot open layout {}: {}", file, e))?;
+            let args = parse_layout(&text, disk_size).map_err(|e| anyhow!("{}:{}", file, e))?;
+            println!("{:#?}", args);
+            Ok(())
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Clone by `RFS_IOC_CLONE` on the opened destination, source is given by inode number
+fn reflink(matches: &ArgMatches) -> Result<()> {
+    let src = matches.get_one::<String>("src").unwrap();
+    let dst = matches.get_one::<String>("dst").unwrap();
+    let ino = fs::metadata(src).map_err(|e| anyhow!("Cannot open {}: {}", src, e))?.ino();
+    let file = fs::OpenOptions::new().write(true).create(true).open(dst)
+        .map_err(|e| anyhow!("Cannot open {}: {}", dst, e))?;
+    if unsafe { libc::ioctl(file.as_raw_fd(), RFS_IOC_CLONE as _, &ino) } < 0 {
+        return Err(anyhow!("Cannot clone {} to {}: {}", src, dst, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn grow(matches: &ArgMatches) -> Result<()> {
+    let path = matches.get_one::<String>("path").unwrap();
+    let file = fs::File::open(path).map_err(|e| anyhow!("Cannot open {}: {}", path, e))?;
+    let mut blocks = 0u64;
+    if unsafe { libc::ioctl(file.as_raw_fd(), RFS_IOC_GROW as _, &mut blocks) } < 0 {
+        return Err(anyhow!("Cannot grow rfs of {}: {}", path, std::io::Error::last_os_error()));
+    }
+    println!("{} blocks", blocks);
+    Ok(())
+}
+
+fn encrypt(matches: &ArgMatches) -> Result<()> {
+    let (name, sub) = matches.subcommand().unwrap();
+    let path = sub.get_one::<String>("path").unwrap();
+    let key_file = sub.get_one::<String>("key").unwrap();
+    let raw = fs::read(key_file).map_err(|e| anyhow!("Cannot read key file {}: {}", key_file, e))?;
+    if raw.len() > 64 { return Err(anyhow!("Key file {} is larger than 64 bytes", key_file)); }
+    let id = crypt_key_identifier(&raw);
+    let file = fs::File::open(path).map_err(|e| anyhow!("Cannot open {}: {}", path, e))?;
+    let r = match name {
+        "add-key" => {
+            let mut arg = RfsKeyArg { size: raw.len() as u32, raw: [0; 64] };
+            arg.raw[..raw.len()].copy_from_slice(&raw);
+            unsafe { libc::ioctl(file.as_raw_fd(), RFS_IOC_ADD_KEY as _, &arg) }
+        }
+        "remove-key" => unsafe { libc::ioctl(file.as_raw_fd(), RFS_IOC_REMOVE_KEY as _, &id) },
+        _ => unsafe { libc::ioctl(file.as_raw_fd(), RFS_IOC_SET_POLICY as _, &id) },
+    };
+    if r < 0 {
+        return Err(anyhow!("Cannot {} on {}: {}", name, path, std::io::Error::last_os_error()));
+    }
+    println!("{}", id.iter().map(|x| format!("{:02x}", x)).collect::<String>());
+    Ok(())
+}
+
+fn snapshot(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let mut fs = open_device(config, disk_unit)?;
+    match matches.subcommand() {
+        Some(("create", sub)) => { fs.rfs_snapshot_create(sub.get_one::<String>("name").unwrap())?; }
+        Some(("list", _)) => {
+            let active = fs.super_block.s_snapshot_inum as usize;
+            println!("{:>4} {:<20} {:>8} {:>10} {:>10}", "ID", "NAME", "INODE", "BLOCKS", "TIME");
+            for s in fs.snapshot_list()? {
+                println!("{:>4} {:<20} {:>8} {:>10} {:>10}{}", s.id, s.name, s.ino, s.blocks, s.time,
+                         if s.ino == active { " active" } else { "" });
+            }
+        }
+        Some(("delete", sub)) => fs.rfs_snapshot_delete(sub.get_one::<String>("name").unwrap())?,
+        Some(("rollback", sub)) => fs.rfs_snapshot_rollback(sub.get_one::<String>("name").unwrap())?,
+        _ => unreachable!(),
+    }
+    fs.rfs_destroy()
+}
+
+/// Commands are read from stdin until `quit` or end of input, errors do not end the session
+fn debug(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let mut fs = open_device(config, disk_unit)?;
+    let mut shell = DebugShell::default();
+    let mut run = |line: &str| match shell.run(&mut fs, line) {
+        Ok(out) => print!("{}", out),
+        Err(e) => println!("{}: {}", line.split_whitespace().next().unwrap_or_default(), e),
+    };
+    if let Some(request) = matches.get_one::<String>("request") {
+        run(request);
+    } else {
+        let stdin = std::io::stdin();
+        loop {
+            print!("rfs: ");
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.read_line(&mut line)? == 0 { break; }
+            if ["quit", "q"].contains(&line.trim()) { break; }
+            run(&line);
+        }
+    }
+    fs.rfs_destroy()
+}
+
+/// Restored device must be new or empty, blocks not in image are left as holes
+fn image(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let device = config.device.as_str();
+    let (name, sub) = matches.subcommand().unwrap();
+    let path = sub.get_one::<String>("file").unwrap();
+    if name == "dump" {
+        let mut fs = open_device(config, disk_unit)?;
+        let file = fs::File::create(path).map_err(|e| anyhow!("Cannot create image {}: {}", path, e))?;
+        let mut out = std::io::BufWriter::new(file);
+        let count = fs.rfs_image(&mut out, sub.get_flag("scramble"))?;
+        out.flush()?;
+        println!("{} blocks of {} B saved to {}", count, fs.block_size(), path);
+        return fs.rfs_destroy();
+    }
+    if fs::metadata(device).map_or(false, |m| m.len() > 0) {
+        return Err(anyhow!("Device {} is not empty", device));
+    }
+    let file = fs::File::open(path).map_err(|e| anyhow!("Cannot open image {}: {}", path, e))?;
+    let mut output = fs::OpenOptions::new().write(true).create(true).open(device)
+        .map_err(|e| anyhow!("Cannot open device {}: {}", device, e))?;
+    let header = image_restore(&mut std::io::BufReader::new(file), &mut output)?;
+    output.set_len(header.disk_size)?;
+    println!("{} blocks of {} B restored to {}", header.count, header.block_size, device);
+    Ok(())
+}
+
+/// Exit code follows e2fsck: 0 clean, 1 errors corrected, 4 errors left uncorrected
+fn fsck(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let mut fs = open_device(config, disk_unit)?;
+    let report = fs.rfs_fsck(matches.get_flag("repair"))?;
+    print!("{}", report);
+    fs.rfs_destroy()?;
+    if !report.is_clean() {
+        std::process::exit(if report.repaired { 1 } else { 4 });
+    }
+    Ok(())
+}
+
+/// Image file is extended before growing and truncated after shrinking
+fn resize(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let device = config.device.as_str();
+    let size = *matches.get_one::<u32>("size").unwrap() as u64 * 0x400 * 0x400;
+    let file = fs::OpenOptions::new().write(true).open(device)
+        .map_err(|e| anyhow!("Cannot open device {}: {}", device, e))?;
+    let old_size = file.metadata()?.len();
+    if size > old_size { file.set_len(size)?; }
+    let mut fs = open_device(config, disk_unit)?;
+    if let Err(e) = fs.rfs_resize((size / fs.block_size() as u64) as usize) {
+        fs.rfs_destroy()?;
+        file.set_len(old_size)?;
+        return Err(e);
+    }
+    println!("{} blocks of {} B, {} groups", fs.super_block.s_blocks_count, fs.block_size(), fs.groups_count());
+    fs.rfs_destroy()?;
+    if size < old_size { file.set_len(size)?; }
+    Ok(())
+}
+
+fn defrag(config: &RfsConfig, disk_unit: u32, matches: &ArgMatches) -> Result<()> {
+    let mut fs = open_device(config, disk_unit)?;
+    print!("{}", fs.rfs_defrag(!matches.get_flag("check"))?);
+    fs.rfs_destroy()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,290 @@
+/// Path-based API for using images in-process without FUSE, similar to `std::fs`.
+///
+/// `Rfs::open` loads the filesystem on a driver, formatting it first if asked.
+/// Paths are absolute or relative to root, errors carry `Errno` like FUSE
+/// replies. Changes are written to the driver by `close`.
+use std::time::SystemTime;
+use anyhow::{Error, Result};
+use disk_driver::DiskDriver;
+use libc::{EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::config::RfsConfig;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::mkfs::MkfsOptions;
+use crate::rfs_lib::utils::*;
+
+/// Bytes read or written in one call, a multiple of all block sizes
+const RFS_IO_CHUNK: usize = 0x20000;
+
+#[derive(Debug, Clone, Default)]
+pub struct RfsOptions {
+    /// Passed to `DiskDriver::ddriver_open`
+    pub path: String,
+    /// Format with these options when no filesystem is found
+    pub mkfs: Option<MkfsOptions>,
+    /// Format even if a filesystem is found, needs `mkfs`
+    pub format: bool,
+    pub read_only: bool,
+    /// Owner of created files and directories
+    pub uid: u32,
+    pub gid: u32,
+    /// Compression, snapshot and keys of the opened filesystem, `device` is taken from `path`
+    pub config: RfsConfig,
+}
+
+/// Attributes of one file, like `std::fs::Metadata`
+#[derive(Debug, Clone)]
+pub struct RfsMetadata {
+    pub ino: usize,
+    pub file_type: Ext2FileType,
+    /// Permission bits
+    pub mode: u16,
+    pub len: u64,
+    pub nlink: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+}
+
+impl RfsMetadata {
+    fn new(ino: usize, inode: &Ext2INode) -> Self {
+        Self {
+            ino,
+            file_type: Ext2FileType::try_from((inode.i_mode >> 12) as usize).unwrap_or(Ext2FileType::Unknown),
+            mode: inode.i_mode & 0xFFF,
+            len: inode.i_size as u64 | (inode.i_size_high as u64) << 32,
+            nlink: inode.i_links_count,
+            uid: inode.uid(),
+            gid: inode.gid(),
+            atime: utc_time(inode.i_atime),
+            mtime: utc_time(inode.i_mtime),
+            ctime: utc_time(inode.i_ctime),
+        }
+    }
+
+    pub fn is_dir(&self) -> bool { self.file_type == Ext2FileType::Directory }
+
+    pub fn is_file(&self) -> bool { self.file_type == Ext2FileType::RegularFile }
+
+    pub fn is_symlink(&self) -> bool { self.file_type == Ext2FileType::Symlink }
+}
+
+/// Entry returned by `read_dir`, `.` and `..` are not listed
+#[derive(Debug, Clone, PartialEq)]
+pub struct RfsDirEntry {
+    pub name: String,
+    pub ino: usize,
+    pub file_type: Ext2FileType,
+}
+
+pub struct Rfs<T: DiskDriver> {
+    pub fs: RFS<T>,
+    uid: u32,
+    gid: u32,
+}
+
+fn errno(code: i32, path: &str) -> Error {
+    Error::new(Errno(code)).context(path.to_string())
+}
+
+impl<T: DiskDriver> Rfs<T> {
+    /// Open filesystem on `driver`
+    pub fn open(driver: T, options: RfsOptions) -> Result<Self> {
+        let mut fs = RFS::new(driver, RfsConfig { device: options.path.clone(), ..options.config });
+        fs.driver_open(&options.path)?;
+        let mut super_block = fs.read_super_block()?;
+        if let Some(mkfs) = options.mkfs.as_ref().filter(|_| options.format || !super_block.magic_matched()) {
+            fs.rfs_mkfs(mkfs)?;
+            super_block = fs.read_super_block()?;
+        }
+        if !super_block.magic_matched() {
+            return Err(errno(EINVAL, &format!("no filesystem found on {}", options.path)));
+        }
+        fs.read_only = options.read_only;
+        fs.rfs_load(&super_block)?;
+        Ok(Self { fs, uid: options.uid, gid: options.gid })
+    }
+
+    /// Write all changes and close driver
+    pub fn close(mut self) -> Result<()> {
+        self.fs.rfs_destroy()
+    }
+
+    /// Inode of path
+    fn lookup(&mut self, path: &str) -> Result<usize> {
+        let mut ino = EXT2_ROOT_INO;
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            if !self.metadata_ino(ino)?.is_dir() { return Err(errno(ENOTDIR, path)); }
+            ino = self.fs.rfs_lookup(ino, name).map_err(|_| errno(ENOENT, path))?.0;
+        }
+        Ok(ino)
+    }
+
+    /// Parent directory inode and last name of path
+    fn lookup_parent<'a>(&mut self, path: &'a str) -> Result<(usize, &'a str)> {
+        let path_trimmed = path.trim_end_matches('/');
+        let (parent, name) = path_trimmed.rsplit_once('/').unwrap_or(("", path_trimmed));
+        if name.is_empty() || name == "." || name == ".." { return Err(errno(EINVAL, path)); }
+        let parent = self.lookup(parent)?;
+        if !self.metadata_ino(parent)?.is_dir() { return Err(errno(ENOTDIR, path)); }
+        Ok((parent, name))
+    }
+
+    fn metadata_ino(&mut self, ino: usize) -> Result<RfsMetadata> {
+        Ok(RfsMetadata::new(ino, &self.fs.get_inode(ino)?))
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<RfsMetadata> {
+        let ino = self.lookup(path)?;
+        self.metadata_ino(ino)
+    }
+
+    pub fn exists(&mut self, path: &str) -> bool {
+        self.lookup(path).is_ok()
+    }
+
+    /// Create directory and all missing parents
+    pub fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        let mut ino = EXT2_ROOT_INO;
+        let (uid, gid) = (self.uid, self.gid);
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            ino = match self.fs.rfs_lookup(ino, name) {
+                Ok((child, inode)) => {
+                    if !RfsMetadata::new(child, &inode).is_dir() { return Err(errno(ENOTDIR, path)); }
+                    child
+                }
+                Err(_) => self.fs.transaction(|fs| fs.make_node(ino, name, 0o755, Ext2FileType::Directory, uid, gid))?.0,
+            };
+        }
+        Ok(())
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<RfsDirEntry>> {
+        let ino = self.lookup(path)?;
+        if !self.metadata_ino(ino)?.is_dir() { return Err(errno(ENOTDIR, path)); }
+        let mut entries = vec![];
+        for e in self.fs.rfs_readdir(ino as u64, 0)? {
+            let name = e.get_name();
+            if name == "." || name == ".." { continue; }
+            let file_type = self.metadata_ino(e.inode as usize)?.file_type;
+            entries.push(RfsDirEntry { name, ino: e.inode as usize, file_type });
+        }
+        Ok(entries)
+    }
+
+    /// Whole content of a regular file
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let meta = self.metadata(path)?;
+        if meta.is_dir() { return Err(errno(EISDIR, path)); }
+        let mut data = Vec::with_capacity(meta.len as usize);
+        while (data.len() as u64) < meta.len {
+            // whole blocks are read, the tail is cut after
+            let bs = self.fs.block_size();
+            let size = RFS_IO_CHUNK.min((meta.len - data.len() as u64) as usize).div_ceil(bs) * bs;
+            let chunk = self.fs.rfs_read(meta.ino as u64, data.len() as i64, size as u32)?;
+            if chunk.is_empty() { break; }
+            data.extend(chunk);
+        }
+        data.truncate(meta.len as usize);
+        Ok(data)
+    }
+
+    /// Create or truncate a regular file and write `data` to it
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let (uid, gid) = (self.uid, self.gid);
+        let ino = match self.fs.rfs_lookup(parent, name) {
+            Ok((ino, inode)) => {
+                if RfsMetadata::new(ino, &inode).is_dir() { return Err(errno(EISDIR, path)); }
+                self.fs.transaction(|fs| fs.rfs_setattr(ino as u64, None, None, None, Some(0), None, None, None, None, None))?;
+                ino
+            }
+            Err(_) => self.fs.transaction(|fs| fs.make_node(parent, name, 0o644, Ext2FileType::RegularFile, uid, gid))?.0,
+        };
+        for (i, chunk) in data.chunks(RFS_IO_CHUNK).enumerate() {
+            self.fs.transaction(|fs| fs.rfs_write(ino as u64, (i * RFS_IO_CHUNK) as i64, chunk))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a file or an empty directory
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let ino = self.fs.rfs_lookup(parent, name).map_err(|_| errno(ENOENT, path))?.0;
+        if self.metadata_ino(ino)?.is_dir() && !self.read_dir(path)?.is_empty() {
+            return Err(errno(ENOTEMPTY, path));
+        }
+        self.fs.transaction(|fs| fs.rfs_unlink(parent, name))
+    }
+
+    /// Move `from` to `to`, replacing a file or an empty directory at `to`
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let (parent, name) = self.lookup_parent(from)?;
+        let ino = self.fs.rfs_lookup(parent, name).map_err(|_| errno(ENOENT, from))?.0;
+        let (newparent, newname) = self.lookup_parent(to)?;
+        // a directory can not move into its own subtree
+        let mut dir = newparent;
+        while dir != EXT2_ROOT_INO {
+            if dir == ino { return Err(errno(EINVAL, to)); }
+            dir = self.fs.rfs_lookup(dir, "..")?.0;
+        }
+        self.fs.transaction(|fs| fs.rfs_rename(parent, name, newparent, newname))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_api() -> Result<()> {
+        let options = RfsOptions { path: "mem".to_string(), mkfs: Some(MkfsOptions::default()), uid: 1000, ..Default::default() };
+        let mut fs = Rfs::open(MemoryDiskDriver::with_size(4 * 0x400 * 0x400), options)?;
+        fs.create_dir_all("/a/b/c")?;
+        fs.create_dir_all("a/b")?;
+        assert!(fs.metadata("/a/b/c")?.is_dir());
+        let data = (0..300000).map(|x| x as u8).collect::<Vec<_>>();
+        fs.write_file("/a/b/file", &data)?;
+        assert_eq!(fs.read_file("/a/b/file")?, data);
+        fs.write_file("/a/b/file", b"short")?;
+        assert_eq!(fs.read_file("a/b/file")?, b"short");
+        let meta = fs.metadata("/a/b/file")?;
+        assert!(meta.is_file());
+        assert_eq!((meta.len, meta.uid, meta.mode), (5, 1000, 0o644));
+        let mut names = fs.read_dir("/a/b")?.into_iter().map(|x| x.name).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["c", "file"]);
+
+        assert_eq!(get_errno(&fs.read_file("/a/missing").unwrap_err(), 0), ENOENT);
+        assert_eq!(get_errno(&fs.write_file("/a/b/file/x", b"").unwrap_err(), 0), ENOTDIR);
+        assert_eq!(get_errno(&fs.remove("/a/b").unwrap_err(), 0), ENOTEMPTY);
+        fs.rename("/a/b/file", "/a/moved")?;
+        assert!(!fs.exists("/a/b/file"));
+        assert_eq!(get_errno(&fs.rename("/a", "/a/b/c/x").unwrap_err(), 0), EINVAL);
+        assert_eq!(get_errno(&fs.rename("/a/b", "/a/b/x").unwrap_err(), 0), EINVAL);
+        assert_eq!(get_errno(&fs.rename("/a/moved", "/a/b").unwrap_err(), 0), EISDIR);
+        // empty directories are replaced, others are not
+        fs.create_dir_all("/d/e")?;
+        assert_eq!(get_errno(&fs.rename("/a/b", "/d").unwrap_err(), 0), ENOTEMPTY);
+        fs.rename("/d", "/a/b/c")?;
+        assert!(fs.exists("/a/b/c/e"));
+        fs.remove("/a/b/c/e")?;
+        fs.remove("/a/b/c")?;
+        fs.remove("/a/b")?;
+        assert_eq!(fs.read_dir("/a")?.into_iter().map(|x| x.name).collect::<Vec<_>>(), ["moved"]);
+        let driver = {
+            fs.fs.rfs_destroy()?;
+            fs.fs.driver
+        };
+
+        let options = RfsOptions { path: "mem".to_string(), read_only: true, ..Default::default() };
+        let mut fs = Rfs::open(driver, options)?;
+        assert_eq!(fs.read_file("/a/moved")?, b"short");
+        assert!(fs.write_file("/a/new", b"").is_err());
+        assert!(fs.fs.rfs_fsck(false)?.is_clean());
+        fs.close()
+    }
+}
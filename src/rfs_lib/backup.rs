@@ -0,0 +1,89 @@
+/// Recovery from backup super blocks.
+///
+/// Backups are written by `write_fs_meta` to the first block of groups with
+/// `group_has_super`, followed by a copy of group desc table.
+use anyhow::{anyhow, Result};
+use disk_driver::DiskDriver;
+use log::*;
+use std::mem::size_of;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+impl<T: DiskDriver> RFS<T> {
+    /// Find backup super block at `block`, trying all block sizes
+    fn find_backup_super_block(&mut self, block: usize) -> Result<Ext2SuperBlock> {
+        let unit = self.disk_block_size();
+        let count = size_of::<Ext2SuperBlock>() / unit;
+        let mut buf = vec![0; count * unit];
+        for bs in [1024, 2048, 4096] {
+            if (block + 1) * bs > self.disk_size() { continue; }
+            self.seek_disk_block(block * bs / unit)?;
+            self.read_disk_blocks(&mut buf, count)?;
+            let sb: Ext2SuperBlock = unsafe { deserialize_row(&buf) };
+            if !sb.magic_matched() || 0x400 << sb.s_log_block_size != bs || sb.s_blocks_per_group == 0
+                || !sb.checksum_valid() { continue; }
+            let group = sb.s_block_group_nr as usize;
+            if group > 0 && sb.s_first_data_block as usize + group * sb.s_blocks_per_group as usize == block {
+                return Ok(sb);
+            }
+        }
+        Err(anyhow!("no backup super block found at block {}", block))
+    }
+
+    /// Copy backup super block at `block` and the group desc table after it to primary place
+    pub fn restore_super_block(&mut self, block: usize) -> Result<()> {
+        let mut sb = self.find_backup_super_block(block)?;
+        warn!("restore super block from backup at block {} of group {}", block, sb.s_block_group_nr);
+        sb.s_block_group_nr = 0;
+        sb.update_checksum();
+        self.super_block.apply_from(&sb);
+        self.alloc_load_counts();
+        self.filesystem_first_block = 1;
+        let bs = self.block_size();
+        let offset = EXT2_SUPER_BLOCK_OFFSET;
+        let mut data = self.get_data_block(offset / bs)?;
+        data[offset % bs..][..size_of::<Ext2SuperBlock>()].copy_from_slice(unsafe { serialize_row(&sb) });
+        self.write_data_block(offset / bs, &data)?;
+        for i in 0..self.group_desc_blocks() {
+            let data = self.get_data_block(block + 1 + i)?;
+            self.write_data_block(self.group_desc_block() + i, &data)?;
+        }
+        self.get_driver().ddriver_flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_backup() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(20 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions::default())?;
+        assert_eq!(fs.groups_count(), 3);
+        assert_eq!(fs.super_block_backups(), vec![8193]);
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(ino as u64, 0, &vec![1; 10000 * 1024])?;
+        fs.rfs_dump()?;
+        let free = fs.alloc().free_blocks_count;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        // break primary super block and group desc table
+        let zero = fs.create_block_vec();
+        fs.write_data_block(1, &zero)?;
+        fs.write_data_block(2, &zero)?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.driver_open("mem")?;
+        assert!(fs.restore_super_block(8194).is_err());
+        fs.restore_super_block(8193)?;
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.alloc().free_blocks_count, free);
+        assert_eq!(fs.rfs_read(ino as u64, 9999 * 1024, 1024)?, vec![1; 1024]);
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+/// Mapping from logical blocks of one inode to disk blocks.
+///
+/// Read, write and truncate go through `BlockMap`, implemented by classic ext2
+/// indirect blocks and by ext4 extent trees (`extent` module), selected by
+/// `EXT4_EXTENTS_FL` of each inode.
+use anyhow::{anyhow, Result};
+use disk_driver::DiskDriver;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::extent::ExtentMap;
+
+pub trait BlockMap<T: DiskDriver> {
+    /// Visit blocks from `block_index` in order, `f(block, index)` returns (continue, allocate),
+    /// block is 0 for holes, allocate maps a new block to this index and visits it again
+    fn visit(&self, fs: &mut RFS<T>, ino: usize, block_index: usize,
+             f: &mut dyn FnMut(usize, usize) -> Result<(bool, bool)>) -> Result<()>;
+
+    /// Logical index after the last block which can be mapped now
+    fn end(&self, fs: &mut RFS<T>, ino: usize, inode: &Ext2INode) -> Result<usize>;
+
+    /// Blocks holding the mapping itself, not including data blocks
+    fn meta_blocks(&self, fs: &mut RFS<T>, ino: usize, inode: &Ext2INode) -> Result<Vec<usize>>;
+
+    /// Map each logical index to block, or unmap it if block is 0, old blocks are not released.
+    /// Index blocks are allocated when needed.
+    fn map(&self, fs: &mut RFS<T>, ino: usize, mappings: &[(usize, usize)]) -> Result<()>;
+
+    /// Release all blocks from logical index `keep`
+    fn truncate(&self, fs: &mut RFS<T>, ino: usize, keep: usize) -> Result<()>;
+
+    /// Move each data and index block `b` to `f(fs, b)`, which copies its content
+    /// or returns `b` to keep it
+    fn relocate(&self, fs: &mut RFS<T>, ino: usize,
+                f: &mut dyn FnMut(&mut RFS<T>, usize) -> Result<usize>) -> Result<()>;
+}
+
+/// 12 direct blocks, then single, double and triple indirect blocks
+pub struct IndirectMap;
+
+impl IndirectMap {
+    /// Allocate an empty index block for inode
+    fn new_table<T: DiskDriver>(fs: &mut RFS<T>, ino: usize, inode: &mut Ext2INode) -> Result<usize> {
+        let table = fs.allocate_block_for(ino, inode)?;
+        let data = fs.create_block_vec();
+        fs.write_meta_block(table, &data)?;
+        Ok(table)
+    }
+
+    /// Relocate block, and blocks it points to if it is an index block of `depth` levels
+    fn relocate_table<T: DiskDriver>(fs: &mut RFS<T>, block: usize, depth: usize,
+                                     f: &mut dyn FnMut(&mut RFS<T>, usize) -> Result<usize>) -> Result<usize> {
+        let block = f(fs, block)?;
+        if depth == 0 { return Ok(block); }
+        let mut data = fs.get_data_block(block)?;
+        for p in data.chunks_mut(4) {
+            let sub = u32::from_le_bytes(p.try_into().unwrap()) as usize;
+            if sub == 0 { continue; }
+            p.copy_from_slice(&(Self::relocate_table(fs, sub, depth - 1, f)? as u32).to_le_bytes());
+        }
+        fs.write_meta_block(block, &data)?;
+        Ok(block)
+    }
+}
+
+impl<T: DiskDriver> BlockMap<T> for IndirectMap {
+    fn visit(&self, fs: &mut RFS<T>, ino: usize, block_index: usize,
+             f: &mut dyn FnMut(usize, usize) -> Result<(bool, bool)>) -> Result<()> {
+        fs.visit_blocks_indirect(ino, block_index, f)
+    }
+
+    fn end(&self, fs: &mut RFS<T>, _ino: usize, inode: &Ext2INode) -> Result<usize> {
+        Ok(if inode.i_block[EXT2_DIND_BLOCK] != 0 { fs.threshold(2) } else if inode.i_block[EXT2_IND_BLOCK] != 0 {
+            fs.threshold(1)
+        } else { fs.threshold(0) })
+    }
+
+    fn meta_blocks(&self, fs: &mut RFS<T>, _ino: usize, inode: &Ext2INode) -> Result<Vec<usize>> {
+        let mut blocks = vec![];
+        if inode.i_block[EXT2_IND_BLOCK] != 0 {
+            blocks.push(inode.i_block[EXT2_IND_BLOCK] as usize);
+        }
+        if inode.i_block[EXT2_DIND_BLOCK] != 0 {
+            blocks.push(inode.i_block[EXT2_DIND_BLOCK] as usize);
+            let data = fs.get_data_block(inode.i_block[EXT2_DIND_BLOCK] as usize)?;
+            blocks.extend(data.chunks(4)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize)
+                .filter(|x| *x != 0));
+        }
+        if inode.i_block[EXT2_TIND_BLOCK] != 0 {
+            warn!("L3 index blocks are not supported");
+            blocks.push(inode.i_block[EXT2_TIND_BLOCK] as usize);
+        }
+        Ok(blocks)
+    }
+
+    fn map(&self, fs: &mut RFS<T>, ino: usize, mappings: &[(usize, usize)]) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        let layer = fs.block_size() / 4;
+        for &(index, block) in mappings {
+            let (table, offset) = if index < fs.threshold(0) {
+                inode.i_block[index] = block as u32;
+                continue;
+            } else if index < fs.threshold(1) {
+                if inode.i_block[EXT2_IND_BLOCK] == 0 && block != 0 {
+                    inode.i_block[EXT2_IND_BLOCK] = Self::new_table(fs, ino, &mut inode)? as u32;
+                }
+                (inode.i_block[EXT2_IND_BLOCK] as usize, (index - fs.threshold(0)) << 2)
+            } else if index < fs.threshold(2) {
+                if inode.i_block[EXT2_DIND_BLOCK] == 0 && block != 0 {
+                    inode.i_block[EXT2_DIND_BLOCK] = Self::new_table(fs, ino, &mut inode)? as u32;
+                }
+                let dind = inode.i_block[EXT2_DIND_BLOCK] as usize;
+                if dind == 0 { continue; }
+                let mut data = fs.get_data_block(dind)?;
+                let p = ((index - fs.threshold(1)) / layer) << 2;
+                let mut sub = u32::from_le_bytes(data[p..p + 4].try_into().unwrap()) as usize;
+                if sub == 0 && block != 0 {
+                    sub = Self::new_table(fs, ino, &mut inode)?;
+                    data[p..p + 4].copy_from_slice(&(sub as u32).to_le_bytes());
+                    fs.write_meta_block(dind, &data)?;
+                }
+                (sub, ((index - fs.threshold(1)) % layer) << 2)
+            } else {
+                return Err(anyhow!("L3 index blocks are not supported"));
+            };
+            // nothing to unmap without index block
+            if table == 0 { continue; }
+            let mut data = fs.get_data_block(table)?;
+            data[offset..offset + 4].copy_from_slice(&(block as u32).to_le_bytes());
+            fs.write_meta_block(table, &data)?;
+        }
+        fs.set_inode(ino, &inode)
+    }
+
+    fn truncate(&self, fs: &mut RFS<T>, ino: usize, keep: usize) -> Result<()> {
+        let inode = fs.get_inode(ino)?;
+        let end = self.end(fs, ino, &inode)?;
+        let mut freed = vec![];
+        if keep < end {
+            fs.visit_blocks_indirect(ino, keep, &mut |block, index| {
+                if block != 0 { freed.push(block); }
+                Ok((index + 1 < end, false))
+            })?;
+        }
+        let mut inode = fs.get_inode(ino)?;
+        for i in keep..fs.threshold(0) {
+            inode.i_block[i] = 0;
+        }
+        let layer = fs.block_size() / 4;
+        let table = inode.i_block[EXT2_IND_BLOCK] as usize;
+        if table != 0 {
+            if keep <= fs.threshold(0) {
+                freed.push(table);
+                inode.i_block[EXT2_IND_BLOCK] = 0;
+            } else if keep < fs.threshold(1) {
+                let mut data = fs.get_data_block(table)?;
+                data[(keep - fs.threshold(0)) << 2..].fill(0);
+                fs.write_meta_block(table, &data)?;
+            }
+        }
+        let table = inode.i_block[EXT2_DIND_BLOCK] as usize;
+        if table != 0 {
+            // first index to drop, relative to double indirect blocks
+            let first = keep.saturating_sub(fs.threshold(1));
+            let mut data = fs.get_data_block(table)?;
+            for (i, p) in data.chunks_mut(4).enumerate() {
+                let sub = u32::from_le_bytes(p.try_into().unwrap()) as usize;
+                if sub == 0 || (i + 1) * layer <= first { continue; }
+                if i * layer >= first {
+                    freed.push(sub);
+                    p.fill(0);
+                } else {
+                    let mut sub_data = fs.get_data_block(sub)?;
+                    sub_data[(first - i * layer) << 2..].fill(0);
+                    fs.write_meta_block(sub, &sub_data)?;
+                }
+            }
+            if first == 0 {
+                freed.push(table);
+                inode.i_block[EXT2_DIND_BLOCK] = 0;
+            } else {
+                fs.write_meta_block(table, &data)?;
+            }
+        }
+        if inode.i_block[EXT2_TIND_BLOCK] != 0 {
+            warn!("L3 index blocks are not supported");
+        }
+        for block in freed {
+            fs.free_block_for(ino, &mut inode, block)?;
+        }
+        fs.set_inode(ino, &inode)
+    }
+
+    fn relocate(&self, fs: &mut RFS<T>, ino: usize,
+                f: &mut dyn FnMut(&mut RFS<T>, usize) -> Result<usize>) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        for i in 0..EXT2_N_BLOCKS {
+            let block = inode.i_block[i] as usize;
+            if block == 0 { continue; }
+            let depth = if i < EXT2_IND_BLOCK { 0 } else { i - EXT2_IND_BLOCK + 1 };
+            inode.i_block[i] = Self::relocate_table(fs, block, depth, f)? as u32;
+        }
+        fs.set_inode(ino, &inode)
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Block map used by inode, not for inline inodes
+    pub fn block_map(inode: &Ext2INode) -> Box<dyn BlockMap<T>> {
+        if inode.i_flags & EXT4_EXTENTS_FL as u32 != 0 { Box::new(ExtentMap) } else { Box::new(IndirectMap) }
+    }
+
+    /// Logical index after the last block of inode which can be mapped
+    pub fn blocks_end(&mut self, ino: usize, inode: &Ext2INode) -> Result<usize> {
+        if Self::is_inline(inode) { return Ok(0); }
+        Self::block_map(inode).end(self, ino, inode)
+    }
+
+    /// Release blocks after `size` bytes of file
+    pub fn truncate_blocks(&mut self, ino: usize, size: usize) -> Result<()> {
+        let inode = self.get_inode(ino)?;
+        if Self::is_inline(&inode) { return Ok(()); }
+        Self::block_map(&inode).truncate(self, ino, size.div_ceil(self.block_size()))
+    }
+}
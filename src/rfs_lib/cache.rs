@@ -0,0 +1,248 @@
+/// Inode cache and directory entry cache.
+///
+/// Inodes read by `get_inode` are kept in memory and `set_inode` only changes
+/// the cached copy, marking it dirty. Dirty inodes are written to inode tables
+/// with other metadata by `write_fs_meta`, when the cache is full, and before
+/// a block of inode table holding them is read. Cached inodes differing from a
+/// written inode table block are dropped unless dirty. Lookups keep the inode
+/// of each name in a directory, or that the name is not found, until entries
+/// of the directory are rewritten.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem::size_of;
+use anyhow::Result;
+use disk_driver::DiskDriver;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// Inodes kept in memory, dirty ones are written and clean ones dropped when reached
+const RFS_INODE_CACHE_SIZE: usize = 4096;
+/// Names kept in memory, all dropped when reached
+const RFS_DENTRY_CACHE_SIZE: usize = 16384;
+
+#[derive(Debug, Clone, Default)]
+pub struct InodeCache {
+    inodes: HashMap<usize, Ext2INode>,
+    dirty: BTreeSet<usize>,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl InodeCache {
+    pub fn clear(&mut self) {
+        self.inodes.clear();
+        self.dirty.clear();
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.len()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DentryCache {
+    /// Inode of names in each directory, `None` if not found
+    dirs: HashMap<usize, HashMap<String, Option<usize>>>,
+    count: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl DentryCache {
+    pub fn clear(&mut self) {
+        self.dirs.clear();
+        self.count = 0;
+    }
+
+    /// Cached lookup result, `Some(None)` for names not found
+    pub fn get(&mut self, parent: usize, name: &str) -> Option<Option<usize>> {
+        let r = self.dirs.get(&parent).and_then(|x| x.get(name)).copied();
+        if r.is_some() { self.hits += 1; } else { self.misses += 1; }
+        r
+    }
+
+    pub fn insert(&mut self, parent: usize, name: &str, ino: Option<usize>) {
+        if self.count >= RFS_DENTRY_CACHE_SIZE { self.clear(); }
+        if self.dirs.entry(parent).or_default().insert(name.to_string(), ino).is_none() {
+            self.count += 1;
+        }
+    }
+
+    /// Forget names in directory `parent`
+    pub fn invalidate(&mut self, parent: usize) {
+        if let Some(names) = self.dirs.remove(&parent) {
+            self.count -= names.len();
+        }
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Cached copy of inode
+    pub(crate) fn inode_cache_get(&mut self, ino: usize) -> Option<Ext2INode> {
+        let r = self.inode_cache.inodes.get(&ino).cloned();
+        if r.is_some() { self.inode_cache.hits += 1; } else { self.inode_cache.misses += 1; }
+        r
+    }
+
+    /// Keep inode in cache, to be written if `dirty`
+    pub(crate) fn inode_cache_put(&mut self, ino: usize, inode: &Ext2INode, dirty: bool) -> Result<()> {
+        if self.inode_cache.inodes.len() >= RFS_INODE_CACHE_SIZE && !self.inode_cache.inodes.contains_key(&ino) {
+            debug!("inode cache full, flush {} dirty inodes", self.inode_cache.dirty.len());
+            self.inode_cache_flush(None)?;
+            // inodes may get dirty again while flushing
+            let dirty = &self.inode_cache.dirty;
+            self.inode_cache.inodes.retain(|ino, _| dirty.contains(ino));
+        }
+        self.inode_cache.inodes.insert(ino, inode.clone());
+        if dirty { self.inode_cache.dirty.insert(ino); }
+        Ok(())
+    }
+
+    /// Write dirty inodes in inode table block `block`, or all of them if `None`
+    pub(crate) fn inode_cache_flush(&mut self, block: Option<usize>) -> Result<()> {
+        if self.inode_cache.dirty.is_empty() { return Ok(()); }
+        let inodes_count = self.super_block.s_inodes_count as usize;
+        let mut blocks: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+        for ino in self.inode_cache.dirty.iter().copied().collect::<Vec<_>>() {
+            // inodes removed by shrinking
+            if ino > inodes_count {
+                self.inode_cache.dirty.remove(&ino);
+                self.inode_cache.inodes.remove(&ino);
+                continue;
+            }
+            let (b, offset) = self.fetch_inode_block_offset(ino)?;
+            if block.is_some_and(|x| x != b) { continue; }
+            blocks.entry(b).or_default().push((ino, offset));
+        }
+        for (b, mut inodes) in blocks {
+            // clean before reading the block, others stay dirty while writing this one,
+            // and may be written already by reading their blocks
+            inodes.retain(|(ino, _)| self.inode_cache.dirty.remove(ino));
+            if inodes.is_empty() { continue; }
+            let mut buf = self.get_data_block(b)?;
+            for (ino, offset) in inodes {
+                let inode = &self.inode_cache.inodes[&ino];
+                buf[offset..offset + size_of::<Ext2INode>()].copy_from_slice(unsafe { serialize_row(inode) });
+                self.inode_csum_set(ino, &mut buf[offset..]);
+                // cached copy gets the checksum as written
+                self.inode_cache.inodes.insert(ino, unsafe { deserialize_row(&buf[offset..]) });
+            }
+            self.write_meta_block(b, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Inode numbers in inode table block `block`, empty for other blocks
+    fn inode_cache_block_inodes(&self, block: usize) -> std::ops::Range<usize> {
+        let per_block = self.block_size() / self.inode_size();
+        for (group, desc) in self.alloc().group_desc_table.iter().enumerate() {
+            let start = desc.bg_inode_table as usize;
+            if block >= start && block < start + self.inode_table_blocks() {
+                let first = group * self.inodes_per_group() + (block - start) * per_block + 1;
+                return first..first + per_block;
+            }
+        }
+        0..0
+    }
+
+    /// Before inode table block `block` is read by other code, write its dirty inodes
+    pub(crate) fn inode_cache_before_read(&mut self, block: usize) -> Result<()> {
+        if self.inode_cache.dirty.is_empty() { return Ok(()); }
+        if self.inode_cache_block_inodes(block).any(|x| self.inode_cache.dirty.contains(&x)) {
+            self.inode_cache_flush(Some(block))?;
+        }
+        Ok(())
+    }
+
+    /// Drop clean cached inodes changed by writing `buf` to block `block`
+    pub(crate) fn inode_cache_written(&mut self, block: usize, buf: &[u8]) {
+        if self.inode_cache.inodes.is_empty() || buf.len() != self.block_size() { return; }
+        let inode_size = self.inode_size();
+        let size = size_of::<Ext2INode>().min(inode_size);
+        for (i, ino) in self.inode_cache_block_inodes(block).enumerate() {
+            if self.inode_cache.dirty.contains(&ino) { continue; }
+            let changed = self.inode_cache.inodes.get(&ino)
+                .is_some_and(|inode| unsafe { &serialize_row(inode)[..size] } != &buf[i * inode_size..][..size]);
+            if changed { self.inode_cache.inodes.remove(&ino); }
+        }
+    }
+
+    /// Drop all cached inodes and names, changes not written are lost.
+    /// Callers flush first unless the inode tables on disk replace them
+    pub fn cache_clear(&mut self) {
+        self.inode_cache.clear();
+        self.dentry_cache.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    /// Walk like `ls -lR`
+    fn walk<T: DiskDriver>(fs: &mut RFS<T>, dir: usize) -> Result<usize> {
+        let mut count = 0;
+        for e in fs.rfs_readdir(dir as u64, 0)? {
+            let name = e.get_name();
+            if name == "." || name == ".." { continue; }
+            let (ino, inode) = fs.rfs_lookup(dir, &name)?;
+            count += 1;
+            if inode.i_mode as usize >> 12 == Ext2FileType::Directory.into() {
+                count += walk(fs, ino)?;
+            }
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_cache() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(8 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions { features: vec!["metadata_csum".to_string()], ..Default::default() })?;
+        for i in 0..4 {
+            let (dir, _) = fs.make_node(EXT2_ROOT_INO, &format!("dir-{}", i), 0o755, Ext2FileType::Directory, 0, 0)?;
+            for j in 0..20 {
+                let (ino, _) = fs.make_node(dir, &format!("file-{}", j), 0o644, Ext2FileType::RegularFile, 0, 0)?;
+                fs.rfs_write(ino as u64, 0, &[j as u8; 100])?;
+            }
+        }
+        assert!(fs.inode_cache.dirty_count() > 0);
+        fs.rfs_dump()?;
+        assert_eq!(fs.inode_cache.dirty_count(), 0);
+
+        // a second walk reads no inodes and directories
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(walk(&mut fs, EXT2_ROOT_INO)?, 85);
+        let misses = (fs.inode_cache.misses, fs.dentry_cache.misses);
+        assert_eq!(walk(&mut fs, EXT2_ROOT_INO)?, 85);
+        assert_eq!((fs.inode_cache.misses, fs.dentry_cache.misses), misses);
+
+        // names not found are cached until created, unlinked and renamed names are gone
+        let (dir, _) = fs.rfs_lookup(EXT2_ROOT_INO, "dir-0")?;
+        assert!(fs.rfs_lookup(dir, "new").is_err());
+        assert!(fs.rfs_lookup(dir, "new").is_err());
+        assert_eq!(fs.dentry_cache.get(dir, "new"), Some(None));
+        let (new, _) = fs.make_node(dir, "new", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        assert_eq!(fs.rfs_lookup(dir, "new")?.0, new);
+        fs.rfs_unlink(dir, "file-0")?;
+        assert!(fs.rfs_lookup(dir, "file-0").is_err());
+        fs.rfs_rename(dir, "new", EXT2_ROOT_INO, "moved")?;
+        assert!(fs.rfs_lookup(dir, "new").is_err());
+        assert_eq!(fs.rfs_lookup(EXT2_ROOT_INO, "moved")?.0, new);
+
+        // inode changes reach disk
+        let mut inode = fs.get_inode(new)?;
+        inode.i_uid = 1000;
+        fs.set_inode(new, &inode)?;
+        fs.rfs_dump()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.get_inode(new)?.i_uid, 1000);
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+/// crc32c metadata checksums, enabled by `metadata_csum` ro_compat feature.
+///
+/// Checksums are computed the same way as ext4, seeded by crc32c of super block
+/// uuid, so e2fsck can verify them: super block, group descriptors, bitmaps,
+/// inodes, extent tree blocks and directory blocks, which end with a 12 bytes
+/// tail entry holding the checksum. Mismatches found when reading are reported by `fs_error`.
+use std::cmp::min;
+use std::mem::size_of;
+use anyhow::Result;
+use disk_driver::DiskDriver;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::extent::EXT4_EXT_ENTRY_SIZE;
+use crate::rfs_lib::utils::*;
+
+/// Size of checksum tail entry at the end of directory blocks
+pub const EXT2_DIR_TAIL_SIZE: usize = 12;
+/// Fake name_len and file_type of tail entry
+const EXT2_DIR_TAIL_FT: u8 = 0xde;
+
+const EXT2_INODE_CSUM_LO: usize = 0x7c;
+const EXT2_INODE_EXTRA_ISIZE: usize = 0x80;
+const EXT2_INODE_CSUM_HI: usize = 0x82;
+const EXT2_INODE_GENERATION: usize = 0x64;
+const EXT2_GROUP_DESC_CSUM: usize = 0x1e;
+
+/// crc32c without pre and post inversion, same as `ext4_chksum`
+pub fn ext2_crc32c(seed: u32, data: &[u8]) -> u32 {
+    !crc32c::crc32c_append(!seed, data)
+}
+
+impl Ext2SuperBlock {
+    pub fn has_metadata_csum(&self) -> bool {
+        self.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_METADATA_CSUM as u32 != 0
+    }
+
+    fn checksum(&self) -> u32 {
+        let data = unsafe { serialize_row(self) };
+        ext2_crc32c(!0, &data[..size_of::<Ext2SuperBlock>() - 4])
+    }
+
+    /// Update `s_checksum` if enabled, call after all fields are set
+    pub fn update_checksum(&mut self) {
+        if self.has_metadata_csum() {
+            self.s_checksum = self.checksum();
+        }
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        !self.has_metadata_csum() ||
+            (self.s_checksum_type as usize == EXT2_CRC32C_CHKSUM && self.s_checksum == self.checksum())
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn metadata_csum(&self) -> bool {
+        self.super_block.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_METADATA_CSUM as u32 != 0
+    }
+
+    fn csum_seed(&self) -> u32 {
+        ext2_crc32c(!0, &self.super_block.s_uuid)
+    }
+
+    /// Seed of inode and its directory blocks
+    fn inode_csum_seed(&self, ino: usize, generation: u32) -> u32 {
+        // same slot as `fetch_inode_block_offset`
+        let ino = if ino <= 1 { ino + 1 } else { ino } as u32;
+        let seed = ext2_crc32c(self.csum_seed(), &ino.to_le_bytes());
+        ext2_crc32c(seed, &generation.to_le_bytes())
+    }
+
+    /// Checksum of raw inode with `inode_size` bytes, returns (checksum, has high 16 bits)
+    fn inode_csum(&self, ino: usize, raw: &[u8]) -> (u32, bool) {
+        let mut data = raw[..self.inode_size()].to_vec();
+        let generation = u32::from_le_bytes(data[EXT2_INODE_GENERATION..][..4].try_into().unwrap());
+        data[EXT2_INODE_CSUM_LO..][..2].fill(0);
+        let has_hi = data.len() > EXT2_INODE_EXTRA_ISIZE &&
+            u16::from_le_bytes([data[EXT2_INODE_EXTRA_ISIZE], data[EXT2_INODE_EXTRA_ISIZE + 1]]) >= 4;
+        if has_hi { data[EXT2_INODE_CSUM_HI..][..2].fill(0); }
+        (ext2_crc32c(self.inode_csum_seed(ino, generation), &data), has_hi)
+    }
+
+    pub fn inode_csum_set(&self, ino: usize, raw: &mut [u8]) {
+        if !self.metadata_csum() { return; }
+        let (csum, has_hi) = self.inode_csum(ino, raw);
+        raw[EXT2_INODE_CSUM_LO..][..2].copy_from_slice(&(csum as u16).to_le_bytes());
+        if has_hi { raw[EXT2_INODE_CSUM_HI..][..2].copy_from_slice(&((csum >> 16) as u16).to_le_bytes()); }
+    }
+
+    pub fn inode_csum_verify(&mut self, ino: usize, raw: &[u8]) -> Result<()> {
+        if !self.metadata_csum() { return Ok(()); }
+        let raw = &raw[..self.inode_size()];
+        let (csum, has_hi) = self.inode_csum(ino, raw);
+        let mut found = u16::from_le_bytes([raw[EXT2_INODE_CSUM_LO], raw[EXT2_INODE_CSUM_LO + 1]]) as u32;
+        if has_hi { found |= (u16::from_le_bytes([raw[EXT2_INODE_CSUM_HI], raw[EXT2_INODE_CSUM_HI + 1]]) as u32) << 16; }
+        let expected = if has_hi { csum } else { csum & 0xffff };
+        // never written inodes are all zeros
+        if found == expected || raw.iter().all(|x| *x == 0) { return Ok(()); }
+        Err(self.fs_error(format!("inode {} checksum mismatch: found {:x}, expected {:x}", ino, found, expected)))
+    }
+
+    /// Space for directory entries in one block
+    pub fn dir_block_space(&self) -> usize {
+        self.block_size() - if self.metadata_csum() { EXT2_DIR_TAIL_SIZE } else { 0 }
+    }
+
+    fn dir_block_csum(&mut self, ino: usize, data: &[u8]) -> Result<u32> {
+        let generation = self.get_inode(ino)?.i_generation;
+        Ok(ext2_crc32c(self.inode_csum_seed(ino, generation), &data[..self.block_size() - EXT2_DIR_TAIL_SIZE]))
+    }
+
+    /// Fill tail entry of one directory block of `ino`
+    pub fn dir_tail_set(&mut self, ino: usize, data: &mut [u8]) -> Result<()> {
+        if !self.metadata_csum() { return Ok(()); }
+        let csum = self.dir_block_csum(ino, data)?;
+        let tail = &mut data[self.block_size() - EXT2_DIR_TAIL_SIZE..];
+        tail.fill(0);
+        tail[4..6].copy_from_slice(&(EXT2_DIR_TAIL_SIZE as u16).to_le_bytes());
+        tail[7] = EXT2_DIR_TAIL_FT;
+        tail[8..].copy_from_slice(&csum.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn dir_block_verify(&mut self, ino: usize, block: usize, data: &[u8]) -> Result<()> {
+        if !self.metadata_csum() { return Ok(()); }
+        let tail = &data[self.block_size() - EXT2_DIR_TAIL_SIZE..];
+        if u16::from_le_bytes([tail[4], tail[5]]) as usize != EXT2_DIR_TAIL_SIZE || tail[7] != EXT2_DIR_TAIL_FT {
+            return Err(self.fs_error(format!("directory {} block {} has no checksum tail", ino, block)));
+        }
+        let found = u32::from_le_bytes(tail[8..].try_into().unwrap());
+        let expected = self.dir_block_csum(ino, data)?;
+        if found == expected { return Ok(()); }
+        Err(self.fs_error(format!("directory {} block {} checksum mismatch: found {:x}, expected {:x}",
+                                  ino, block, found, expected)))
+    }
+
+    /// Offset of checksum tail in extent node block, after `eh_max` entries
+    fn extent_tail_offset(&self, data: &[u8]) -> usize {
+        let max_entries = u16::from_le_bytes([data[4], data[5]]) as usize;
+        min(EXT4_EXT_ENTRY_SIZE * (max_entries + 1), self.block_size() - 4)
+    }
+
+    fn extent_block_csum(&mut self, ino: usize, data: &[u8]) -> Result<u32> {
+        let generation = self.get_inode(ino)?.i_generation;
+        Ok(ext2_crc32c(self.inode_csum_seed(ino, generation), &data[..self.extent_tail_offset(data)]))
+    }
+
+    /// Fill checksum tail of one extent node block of `ino`
+    pub fn extent_tail_set(&mut self, ino: usize, data: &mut [u8]) -> Result<()> {
+        if !self.metadata_csum() { return Ok(()); }
+        let csum = self.extent_block_csum(ino, data)?;
+        let offset = self.extent_tail_offset(data);
+        data[offset..offset + 4].copy_from_slice(&csum.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn extent_block_verify(&mut self, ino: usize, block: usize, data: &[u8]) -> Result<()> {
+        if !self.metadata_csum() { return Ok(()); }
+        let offset = self.extent_tail_offset(data);
+        let found = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let expected = self.extent_block_csum(ino, data)?;
+        if found == expected { return Ok(()); }
+        Err(self.fs_error(format!("inode {} extent block {} checksum mismatch: found {:x}, expected {:x}",
+                                  ino, block, found, expected)))
+    }
+
+    /// Checksums of bitmaps of one group in memory, returns (block bitmap, inode bitmap)
+    fn group_bitmap_csum(&self, group: usize) -> (u16, u16) {
+        let seed = self.csum_seed();
+        let data_bytes = self.group_bitmap_bytes(true);
+        let inode_bytes = self.group_bitmap_bytes(false);
+        let alloc = self.alloc();
+        (ext2_crc32c(seed, &alloc.bitmap_data[group * data_bytes..][..data_bytes]) as u16,
+         ext2_crc32c(seed, &alloc.bitmap_inode[group * inode_bytes..][..inode_bytes]) as u16)
+    }
+
+    fn group_desc_csum(&self, group: usize) -> u16 {
+        let mut data = unsafe { serialize_row(&self.alloc().group_desc_table[group]) }.to_vec();
+        data[EXT2_GROUP_DESC_CSUM..][..2].fill(0);
+        ext2_crc32c(ext2_crc32c(self.csum_seed(), &(group as u32).to_le_bytes()), &data) as u16
+    }
+
+    /// Update checksums in group desc table from bitmaps in memory
+    pub fn group_csum_set(&mut self) {
+        if !self.metadata_csum() { return; }
+        let groups = self.alloc().group_desc_table.len();
+        for group in 0..groups {
+            let (block_bitmap, inode_bitmap) = self.group_bitmap_csum(group);
+            {
+                let mut alloc = self.alloc();
+                let gd = &mut alloc.group_desc_table[group];
+                gd.bg_block_bitmap_csum_lo = block_bitmap;
+                gd.bg_inode_bitmap_csum_lo = inode_bitmap;
+                // bitmaps of all groups are written with group desc table
+                gd.bg_flags &= !((EXT2_BG_BLOCK_UNINIT | EXT2_BG_INODE_UNINIT) as u16);
+            }
+            let csum = self.group_desc_csum(group);
+            self.alloc().group_desc_table[group].bg_checksum = csum;
+        }
+    }
+
+    /// Verify group desc table and bitmaps just loaded, uninitialized bitmaps are skipped
+    pub fn group_csum_verify(&mut self) -> Result<()> {
+        if !self.metadata_csum() { return Ok(()); }
+        let groups = self.alloc().group_desc_table.len();
+        for group in 0..groups {
+            let gd = self.alloc().group_desc_table[group];
+            let (block_bitmap, inode_bitmap) = self.group_bitmap_csum(group);
+            let flags = gd.bg_flags as usize;
+            let what = if gd.bg_checksum != self.group_desc_csum(group) {
+                "group desc"
+            } else if flags & EXT2_BG_BLOCK_UNINIT == 0 && gd.bg_block_bitmap_csum_lo != block_bitmap {
+                "block bitmap"
+            } else if flags & EXT2_BG_INODE_UNINIT == 0 && gd.bg_inode_bitmap_csum_lo != inode_bitmap {
+                "inode bitmap"
+            } else { continue; };
+            return Err(self.fs_error(format!("{} checksum mismatch of group {}", what, group)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use crate::rfs_lib::utils::get_errno;
+    use disk_driver::memory::MemoryDiskDriver;
+    use libc::EIO;
+
+    #[test]
+    fn test_checksum() -> Result<()> {
+        assert_eq!(crc32c::crc32c(b"123456789"), 0xe3069283);
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(4 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions { features: vec!["metadata_csum".to_string()], ..Default::default() })?;
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_dump()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert!(fs.metadata_csum());
+        assert_eq!(fs.rfs_lookup(EXT2_ROOT_INO, "file")?.0, ino);
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        // flip one bit of the inode
+        let (block, offset) = fs.fetch_inode_block_offset(ino)?;
+        let mut data = fs.get_data_block(block)?;
+        data[offset + 8] ^= 1;
+        fs.write_data_block(block, &data)?;
+        assert_eq!(get_errno(&fs.get_inode(ino).unwrap_err(), 0), EIO);
+        data[offset + 8] ^= 1;
+        fs.write_data_block(block, &data)?;
+
+        // and one bit of root directory
+        let block = fs.get_inode(EXT2_ROOT_INO)?.i_block[0] as usize;
+        let mut data = fs.get_data_block(block)?;
+        data[0] ^= 1;
+        fs.write_data_block(block, &data)?;
+        assert_eq!(get_errno(&fs.get_dir_entries(EXT2_ROOT_INO).unwrap_err(), 0), EIO);
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+/// Transparent compression of regular files marked with `EXT2_COMPR_FL`.
+///
+/// File content is split in clusters of `RFS_COMPRESS_CLUSTER` blocks. A cluster which
+/// saves at least one block is stored compressed in its first blocks behind a small
+/// header and the rest of the cluster is left as holes, so `i_blocks` counts only
+/// blocks used on disk. Other clusters are stored as is, zero clusters as holes.
+use std::cmp::{max, min};
+use std::mem::size_of;
+use anyhow::{anyhow, Result};
+use disk_driver::DiskDriver;
+use libc::c_long;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+
+/// Blocks in one compression cluster
+pub const RFS_COMPRESS_CLUSTER: usize = 16;
+/// "RFSC", first bytes of compressed cluster
+const RFS_COMPRESS_MAGIC: u32 = 0x43534652;
+/// Magic, method, payload size and crc32c of payload, all u32
+const RFS_COMPRESS_HEADER_SIZE: usize = 16;
+/// zstd level used for new clusters
+const RFS_ZSTD_LEVEL: i32 = 3;
+/// `FS_IOC_GETFLAGS`, `_IOR('f', 1, long)`, sent by FUSE for `lsattr`
+pub const RFS_IOC_GETFLAGS: u32 = (2 << 30) | ((size_of::<c_long>() as u32) << 16) | ((b'f' as u32) << 8) | 1;
+/// `FS_IOC_SETFLAGS`, `_IOW('f', 2, long)`, sent by FUSE for `chattr`
+pub const RFS_IOC_SETFLAGS: u32 = (1 << 30) | ((size_of::<c_long>() as u32) << 16) | ((b'f' as u32) << 8) | 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressMethod {
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl CompressMethod {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(anyhow!("unknown compression method {}, expected lz4 or zstd", name)),
+        }
+    }
+
+    fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Lz4 => lz4_flex::block::compress(data),
+            Self::Zstd => zstd::bulk::compress(data, RFS_ZSTD_LEVEL)?,
+        })
+    }
+
+    fn decompress(self, data: &[u8], size: usize) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Lz4 => lz4_flex::block::decompress(data, size)?,
+            Self::Zstd => zstd::bulk::decompress(data, size)?,
+        })
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// File content is kept in compression clusters
+    pub fn is_compressed(inode: &Ext2INode) -> bool {
+        inode.i_flags & EXT2_COMPR_FL as u32 != 0 && !Self::is_inline(inode) && !Self::is_encrypted(inode)
+    }
+
+    /// Method from `RfsConfig::compress` for new clusters, lz4 if not given
+    pub fn compress_method(&self) -> CompressMethod {
+        CompressMethod::parse(&self.config.compress).unwrap_or(CompressMethod::Lz4)
+    }
+
+    /// New regular files are compressed by default when `RfsConfig::compress` is set
+    pub fn compress_default(&self) -> bool {
+        !self.config.compress.is_empty()
+    }
+
+    pub(crate) fn compress_cluster_size(&self) -> usize {
+        RFS_COMPRESS_CLUSTER * self.block_size()
+    }
+
+    /// Disk blocks of one cluster, 0 for holes
+    pub(crate) fn compress_cluster_blocks(&mut self, ino: usize, cluster: usize) -> Result<Vec<usize>> {
+        let first = cluster * RFS_COMPRESS_CLUSTER;
+        let mut blocks = vec![0; RFS_COMPRESS_CLUSTER];
+        self.visit_blocks_inode(ino, first, &mut |block, index| {
+            if index >= first + RFS_COMPRESS_CLUSTER { return Ok((false, false)); }
+            blocks[index - first] = block;
+            Ok((index + 1 < first + RFS_COMPRESS_CLUSTER, false))
+        })?;
+        Ok(blocks)
+    }
+
+    /// Whole content of one cluster, decompressed if stored compressed
+    fn compress_cluster_read(&mut self, ino: usize, cluster: usize) -> Result<Vec<u8>> {
+        let sz = self.block_size();
+        let blocks = self.compress_cluster_blocks(ino, cluster)?;
+        let mut data = vec![0; self.compress_cluster_size()];
+        for (i, block) in blocks.iter().enumerate().filter(|(_, x)| **x != 0) {
+            self.read_data_block(*block, &mut data[i * sz..(i + 1) * sz])?;
+        }
+        Ok(Self::compress_cluster_decode(ino, cluster, &blocks, data, sz))
+    }
+
+    /// Content of cluster from raw `data` of its `blocks`, decompressed if stored compressed
+    pub(crate) fn compress_cluster_decode(ino: usize, cluster: usize, blocks: &[usize], data: Vec<u8>, sz: usize) -> Vec<u8> {
+        let field = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        if field(0) != RFS_COMPRESS_MAGIC { return data; }
+        let size = field(2) as usize;
+        let used = (RFS_COMPRESS_HEADER_SIZE + size).div_ceil(sz);
+        // raw clusters may start with the magic by chance
+        let method = match CompressMethod::from_id(field(1)) {
+            Some(method) if used < RFS_COMPRESS_CLUSTER
+                && blocks[..used].iter().all(|x| *x != 0) && blocks[used..].iter().all(|x| *x == 0) => method,
+            _ => return data,
+        };
+        let payload = &data[RFS_COMPRESS_HEADER_SIZE..RFS_COMPRESS_HEADER_SIZE + size];
+        if crc32c::crc32c(payload) != field(3) { return data; }
+        match method.decompress(payload, data.len()) {
+            Ok(v) if v.len() == data.len() => v,
+            _ => {
+                warn!("bad compressed cluster {} of inode {}", cluster, ino);
+                data
+            }
+        }
+    }
+
+    /// Store whole content of one cluster, `valid` bytes of it are in file
+    fn compress_cluster_write(&mut self, ino: usize, cluster: usize, data: &[u8], valid: usize) -> Result<()> {
+        let sz = self.block_size();
+        let needed = valid.div_ceil(sz);
+        let stored = if data[..valid].iter().all(|x| *x == 0) { vec![] } else {
+            let method = self.compress_method();
+            let payload = method.compress(data)?;
+            let mut packed = [RFS_COMPRESS_MAGIC, method as u32, payload.len() as u32, crc32c::crc32c(&payload)]
+                .iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+            packed.extend(payload);
+            if packed.len().div_ceil(sz) < needed { packed } else { data[..valid].to_vec() }
+        };
+        let used = stored.len().div_ceil(sz);
+        let first = cluster * RFS_COMPRESS_CLUSTER;
+        if used > 0 {
+            // whole blocks, nothing left from old content
+            let mut stored = stored;
+            stored.resize(used * sz, 0);
+            self.rfs_write_blocks(ino as u64, (first * sz) as i64, &stored)?;
+        }
+        self.compress_release(ino, first + used, first + RFS_COMPRESS_CLUSTER)
+    }
+
+    /// Unmap and release blocks in logical range `from..to`
+    fn compress_release(&mut self, ino: usize, from: usize, to: usize) -> Result<()> {
+        let mut mapped = vec![];
+        self.visit_blocks_inode(ino, from, &mut |block, index| {
+            if index >= to { return Ok((false, false)); }
+            if block != 0 { mapped.push((index, block)); }
+            Ok((index + 1 < to, false))
+        })?;
+        if mapped.is_empty() { return Ok(()); }
+        let inode = self.get_inode(ino)?;
+        Self::block_map(&inode).map(self, ino, &mapped.iter().map(|x| (x.0, 0)).collect::<Vec<_>>())?;
+        let mut inode = self.get_inode(ino)?;
+        for (_, block) in mapped {
+            self.free_block_for(ino, &mut inode, block)?;
+        }
+        self.set_inode(ino, &inode)
+    }
+
+    /// Read `size` bytes from compressed file, zeros after end of file
+    pub fn compress_read(&mut self, ino: usize, offset: usize, size: usize) -> Result<Vec<u8>> {
+        let cs = self.compress_cluster_size();
+        let inode = self.get_inode(ino)?;
+        let end = min(offset + size, inode.i_size as usize | (inode.i_size_high as usize) << 32);
+        let mut data = vec![0; size];
+        if end <= offset { return Ok(data); }
+        for cluster in offset / cs..end.div_ceil(cs) {
+            let base = cluster * cs;
+            let (start, stop) = (max(offset, base), min(end, base + cs));
+            let content = self.compress_cluster_read(ino, cluster)?;
+            data[start - offset..stop - offset].copy_from_slice(&content[start - base..stop - base]);
+        }
+        Ok(data)
+    }
+
+    /// Write to compressed file, every touched cluster is compressed again
+    pub fn compress_write(&mut self, ino: usize, offset: usize, data: &[u8]) -> Result<u32> {
+        let cs = self.compress_cluster_size();
+        let inode = self.get_inode(ino)?;
+        let size = inode.i_size as usize | (inode.i_size_high as usize) << 32;
+        let end = offset + data.len();
+        let new_size = max(size, end);
+        debug!("compressed write inode {} offset {:x} len {:x}", ino, offset, data.len());
+        for cluster in offset / cs..end.div_ceil(cs) {
+            let base = cluster * cs;
+            let (start, stop) = (max(offset, base), min(end, base + cs));
+            let mut content = if start == base && stop == base + cs { vec![0; cs] } else {
+                let mut content = self.compress_cluster_read(ino, cluster)?;
+                // bytes after old end of file may be stale
+                if size < base + cs { content[size.saturating_sub(base)..].fill(0); }
+                content
+            };
+            content[start - base..stop - base].copy_from_slice(&data[start - offset..stop - offset]);
+            self.compress_cluster_write(ino, cluster, &content, min(cs, new_size - base))?;
+        }
+        let mut inode = self.get_inode(ino)?;
+        inode.i_size = new_size as u32;
+        inode.i_size_high = (new_size >> 32) as u32;
+        self.set_inode(ino, &inode)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Cut the last cluster kept when compressed file shrinks to `size`,
+    /// blocks after it are left for `truncate_blocks`
+    pub fn compress_truncate(&mut self, ino: usize, size: usize) -> Result<()> {
+        let cs = self.compress_cluster_size();
+        if size.is_multiple_of(cs) { return Ok(()); }
+        let mut content = self.compress_cluster_read(ino, size / cs)?;
+        content[size % cs..].fill(0);
+        self.compress_cluster_write(ino, size / cs, &content, size % cs)
+    }
+
+    /// Flags shown by `FS_IOC_GETFLAGS`
+    pub fn rfs_get_flags(&mut self, ino: u64) -> Result<u32> {
+        let inode = self.get_inode(RFS::<T>::shift_ino(ino as usize))?;
+        Ok(inode.i_flags & EXT2_FL_USER_VISIBLE as u32)
+    }
+
+    /// Set user modifiable flags like `FS_IOC_SETFLAGS`,
+    /// content is converted when `EXT2_COMPR_FL` of a regular file changes
+    pub fn rfs_set_flags(&mut self, ino: u64, flags: u32) -> Result<Ext2INode> {
+        let ino = RFS::<T>::shift_ino(ino as usize);
+        self.snapshot_check_protected(ino)?;
+        let mut inode = self.get_inode(ino)?;
+        // block map format is not changed by flags
+        let modifiable = (EXT2_FL_USER_MODIFIABLE & !EXT4_EXTENTS_FL) as u32;
+        let flags = inode.i_flags & !modifiable | flags & modifiable;
+        let toggled = (inode.i_flags ^ flags) & EXT2_COMPR_FL as u32 != 0;
+        let regular = inode.i_mode as usize >> 12 == Ext2FileType::RegularFile.into();
+        if !toggled || !regular || Self::is_inline(&inode) || Self::is_encrypted(&inode) {
+            inode.i_flags = flags;
+            self.set_inode(ino, &inode)?;
+            return Ok(inode);
+        }
+        let size = inode.i_size as usize;
+        debug!("convert inode {} of {} bytes, compress={}", ino, size, flags & EXT2_COMPR_FL as u32 != 0);
+        let content = self.rfs_read(ino as u64, 0, size as u32)?;
+        self.truncate_blocks(ino, 0)?;
+        let mut inode = self.get_inode(ino)?;
+        inode.i_flags = flags;
+        inode.i_size = 0;
+        self.set_inode(ino, &inode)?;
+        if Self::is_compressed(&inode) {
+            self.compress_write(ino, 0, &content)?;
+        } else if !content.is_empty() {
+            self.rfs_write_blocks(ino as u64, 0, &content)?;
+        }
+        self.get_inode(ino)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let bs = fs.block_size();
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let text = (0..bs * 40).map(|x| b"compressible "[x % 13]).collect::<Vec<_>>();
+        fs.rfs_write(ino as u64, 0, &text)?;
+        let free = fs.alloc().free_blocks_count;
+        let raw = fs.get_inode(ino)?.i_blocks;
+
+        // chattr +c packs existing content
+        fs.rfs_set_flags(ino as u64, EXT2_COMPR_FL as u32)?;
+        assert_eq!(fs.rfs_get_flags(ino as u64)?, EXT2_COMPR_FL as u32);
+        let packed = fs.get_inode(ino)?.i_blocks;
+        assert!(packed < raw / 4);
+        assert!(fs.alloc().free_blocks_count > free);
+        assert_eq!(fs.rfs_read(ino as u64, 0, text.len() as u32)?, text);
+
+        // unaligned write across clusters, then shrink into a cluster
+        let mut expected = text.clone();
+        expected[bs * 15 + 7..bs * 17].fill(0xa5);
+        fs.rfs_write(ino as u64, (bs * 15 + 7) as i64, &expected[bs * 15 + 7..bs * 17])?;
+        assert_eq!(fs.rfs_read(ino as u64, 0, text.len() as u32)?, expected);
+        fs.rfs_setattr(ino as u64, None, None, None, Some((bs * 20 + 3) as u64), None, None, None, None, None)?;
+        expected.truncate(bs * 20 + 3);
+        assert_eq!(fs.rfs_read(ino as u64, 0, expected.len() as u32)?, expected);
+
+        // raw content read back after clearing the flag
+        fs.rfs_write(ino as u64, (bs * 32) as i64, &text[..bs * 8])?;
+        expected.resize(bs * 32, 0);
+        expected.extend(&text[..bs * 8]);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.rfs_read(ino as u64, 0, expected.len() as u32)?, expected);
+        fs.rfs_set_flags(ino as u64, 0)?;
+        assert_eq!(fs.rfs_read(ino as u64, 0, expected.len() as u32)?, expected);
+        assert!(fs.get_inode(ino)?.i_blocks > packed);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        let method = CompressMethod::parse("zstd")?;
+        let packed = method.compress(&text)?;
+        assert!(packed.len() < text.len() / 4);
+        assert_eq!(method.decompress(&packed, text.len())?, text);
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+/// Options of one filesystem instance, given to `RFS::new` so that several
+/// instances can live in one process.
+#[derive(Debug, Clone, Default)]
+pub struct RfsConfig {
+    /// Device file opened by `rfs_init` when mounted
+    pub device: String,
+    /// Format disk even if a filesystem is found
+    pub format: bool,
+    /// Format as ext2 revision 1 by mkfs instead of layout file
+    pub mkfs: bool,
+    /// Layout file for formatting disk, the default layout if not found
+    pub layout: String,
+    /// Backup super block to open filesystem from, 0 for primary
+    pub super_block: usize,
+    /// Snapshot to mount read only, empty for the live filesystem
+    pub snapshot: String,
+    /// Compression method of new regular files, empty to compress only files marked by chattr
+    pub compress: String,
+    /// Master key files of encrypted directories added on mount
+    pub key_files: Vec<String>,
+    /// Worker threads serving FUSE requests, requests are served in order if not above 1
+    pub threads: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use disk_driver::memory::MemoryDiskDriver;
+    use crate::rfs_lib::RFS;
+    use crate::rfs_lib::desc::*;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        // two instances with their own options in one process
+        let mut plain = RFS::new(MemoryDiskDriver::new(), RfsConfig::default());
+        plain.rfs_init("mem")?;
+        let mut compressed = RFS::new(MemoryDiskDriver::new(), RfsConfig { compress: "zstd".to_string(), ..Default::default() });
+        compressed.rfs_init("mem")?;
+        let (a, _) = plain.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let (b, _) = compressed.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        assert!(!RFS::<MemoryDiskDriver>::is_compressed(&plain.get_inode(a)?));
+        assert!(RFS::<MemoryDiskDriver>::is_compressed(&compressed.get_inode(b)?));
+        assert!(compressed.rfs_lookup(EXT2_ROOT_INO, "a").is_err());
+        Ok(())
+    }
+}
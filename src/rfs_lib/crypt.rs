@@ -0,0 +1,519 @@
+/// Per-directory encryption like fscrypt, enabled by `encrypt` feature.
+///
+/// A policy set on an empty directory is kept as a v2 encryption context in the `c`
+/// xattr of every inode created under it, each with its own nonce. Per-inode keys are
+/// derived from a master key by HKDF-SHA512 as fscrypt does. Contents are encrypted
+/// with AES-256-XTS per block and names with AES-256-CTS, stored base64url encoded
+/// in directory entries. Master keys are only held in memory; without one, directories
+/// list the encoded names and contents can not be read or written.
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::mem::size_of;
+use aes::Aes256;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use anyhow::{anyhow, Error, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use disk_driver::DiskDriver;
+use hkdf::Hkdf;
+use libc::{EEXIST, EINVAL, ENAMETOOLONG, ENOKEY, ENOTDIR, ENOTEMPTY, EOPNOTSUPP, EXDEV};
+use log::*;
+use sha2::Sha512;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// Xattr index of encryption context, named `c`
+const EXT4_XATTR_INDEX_ENCRYPTION: u8 = 9;
+const EXT4_XATTR_CONTEXT_NAME: &[u8] = b"c";
+const FSCRYPT_CONTEXT_V2: u8 = 2;
+/// Names padded to 16 bytes
+const FSCRYPT_POLICY_FLAGS_PAD_16: u8 = 0x02;
+const FSCRYPT_KEY_IDENTIFIER_SIZE: usize = 16;
+const FSCRYPT_FILE_NONCE_SIZE: usize = 16;
+const FSCRYPT_MIN_KEY_SIZE: usize = 16;
+const FSCRYPT_MAX_KEY_SIZE: usize = 64;
+/// HKDF info contexts of fscrypt
+const HKDF_CONTEXT_KEY_IDENTIFIER: u8 = 1;
+const HKDF_CONTEXT_PER_FILE_ENC_KEY: u8 = 2;
+const AES_BLOCK_SIZE: usize = 16;
+/// Longest name which still fits in a directory entry once encoded
+pub const RFS_CRYPT_NAME_MAX: usize = 176;
+
+/// Argument of `RFS_IOC_ADD_KEY`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RfsKeyArg {
+    pub size: u32,
+    pub raw: [u8; FSCRYPT_MAX_KEY_SIZE],
+}
+
+/// `_IOW('r', 2, RfsKeyArg)`, add a master key
+pub const RFS_IOC_ADD_KEY: u32 = (1 << 30) | ((size_of::<RfsKeyArg>() as u32) << 16) | ((b'r' as u32) << 8) | 2;
+/// `_IOW('r', 3, [u8; 16])`, remove master key by identifier
+pub const RFS_IOC_REMOVE_KEY: u32 = (1 << 30) | ((FSCRYPT_KEY_IDENTIFIER_SIZE as u32) << 16) | ((b'r' as u32) << 8) | 3;
+/// `_IOW('r', 4, [u8; 16])`, set policy with key identifier on an empty directory
+pub const RFS_IOC_SET_POLICY: u32 = (1 << 30) | ((FSCRYPT_KEY_IDENTIFIER_SIZE as u32) << 16) | ((b'r' as u32) << 8) | 4;
+
+/// Master keys added to this mount, by identifier
+#[derive(Clone, Default)]
+pub struct CryptKeys {
+    keys: BTreeMap<[u8; FSCRYPT_KEY_IDENTIFIER_SIZE], Vec<u8>>,
+}
+
+impl Debug for CryptKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.keys.keys().map(hex_string)).finish()
+    }
+}
+
+/// Encryption context v2 stored in each encrypted inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CryptContext {
+    pub contents_mode: u8,
+    pub filenames_mode: u8,
+    pub flags: u8,
+    pub key_identifier: [u8; FSCRYPT_KEY_IDENTIFIER_SIZE],
+    pub nonce: [u8; FSCRYPT_FILE_NONCE_SIZE],
+}
+
+impl CryptContext {
+    const SIZE: usize = 8 + FSCRYPT_KEY_IDENTIFIER_SIZE + FSCRYPT_FILE_NONCE_SIZE;
+
+    fn new(key_identifier: [u8; FSCRYPT_KEY_IDENTIFIER_SIZE]) -> Self {
+        Self {
+            contents_mode: EXT4_ENCRYPTION_MODE_AES_256_XTS as u8,
+            filenames_mode: EXT4_ENCRYPTION_MODE_AES_256_CTS as u8,
+            flags: FSCRYPT_POLICY_FLAGS_PAD_16,
+            key_identifier,
+            nonce: rand::random(),
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut data = vec![FSCRYPT_CONTEXT_V2, self.contents_mode, self.filenames_mode, self.flags, 0, 0, 0, 0];
+        data.extend(self.key_identifier);
+        data.extend(self.nonce);
+        data
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != Self::SIZE || data[0] != FSCRYPT_CONTEXT_V2 { return None; }
+        Some(Self {
+            contents_mode: data[1],
+            filenames_mode: data[2],
+            flags: data[3],
+            key_identifier: data[8..24].try_into().unwrap(),
+            nonce: data[24..40].try_into().unwrap(),
+        })
+    }
+}
+
+/// Key state of one inode
+enum CryptKey {
+    Plain,
+    Locked,
+    /// Per-inode key, XTS uses all of it and CTS the first half
+    Unlocked([u8; 64]),
+}
+
+fn hex_string(data: &[u8; FSCRYPT_KEY_IDENTIFIER_SIZE]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// HKDF-SHA512 expand of master key with fscrypt info prefix
+fn crypt_hkdf(master: &[u8], context: u8, info: &[u8], okm: &mut [u8]) {
+    let hkdf = Hkdf::<Sha512>::new(None, master);
+    let info = [b"fscrypt\0".as_slice(), &[context], info].concat();
+    hkdf.expand(&info, okm).unwrap();
+}
+
+/// Identifier of master key, used in policies and to remove it
+pub fn crypt_key_identifier(master: &[u8]) -> [u8; FSCRYPT_KEY_IDENTIFIER_SIZE] {
+    let mut id = [0; FSCRYPT_KEY_IDENTIFIER_SIZE];
+    crypt_hkdf(master, HKDF_CONTEXT_KEY_IDENTIFIER, &[], &mut id);
+    id
+}
+
+/// Multiply XTS tweak by x in GF(2^128)
+fn xts_next(tweak: &mut [u8; AES_BLOCK_SIZE]) {
+    let carry = tweak[AES_BLOCK_SIZE - 1] >> 7;
+    for i in (1..AES_BLOCK_SIZE).rev() {
+        tweak[i] = tweak[i] << 1 | tweak[i - 1] >> 7;
+    }
+    tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+}
+
+/// AES-256-XTS of one data unit, length is a multiple of 16
+pub(crate) fn xts_crypt(key: &[u8; 64], index: u64, data: &mut [u8], encrypt: bool) {
+    let cipher = Aes256::new_from_slice(&key[..32]).unwrap();
+    let mut tweak = [0; AES_BLOCK_SIZE];
+    tweak[..8].copy_from_slice(&index.to_le_bytes());
+    Aes256::new_from_slice(&key[32..]).unwrap().encrypt_block((&mut tweak).into());
+    for chunk in data.chunks_mut(AES_BLOCK_SIZE) {
+        chunk.iter_mut().zip(tweak).for_each(|(x, t)| *x ^= t);
+        if encrypt { cipher.encrypt_block(chunk.into()); } else { cipher.decrypt_block(chunk.into()); }
+        chunk.iter_mut().zip(tweak).for_each(|(x, t)| *x ^= t);
+        xts_next(&mut tweak);
+    }
+}
+
+/// AES-256-CTS-CBC with zero IV of name padded to 16 bytes
+fn cts_encrypt(key: &[u8; 64], name: &[u8]) -> Vec<u8> {
+    let cipher = Aes256::new_from_slice(&key[..32]).unwrap();
+    let mut data = name.to_vec();
+    data.resize(max(name.len().div_ceil(AES_BLOCK_SIZE), 1) * AES_BLOCK_SIZE, 0);
+    let mut last = [0; AES_BLOCK_SIZE];
+    for chunk in data.chunks_mut(AES_BLOCK_SIZE) {
+        chunk.iter_mut().zip(last).for_each(|(x, c)| *x ^= c);
+        cipher.encrypt_block(chunk.into());
+        last.copy_from_slice(chunk);
+    }
+    // ciphertext stealing of whole blocks swaps the last two
+    let n = data.len();
+    if n > AES_BLOCK_SIZE {
+        let (head, tail) = data.split_at_mut(n - AES_BLOCK_SIZE);
+        head[n - 2 * AES_BLOCK_SIZE..].swap_with_slice(tail);
+    }
+    data
+}
+
+fn cts_decrypt(key: &[u8; 64], data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() || !data.len().is_multiple_of(AES_BLOCK_SIZE) { return None; }
+    let cipher = Aes256::new_from_slice(&key[..32]).unwrap();
+    let mut data = data.to_vec();
+    let n = data.len();
+    if n > AES_BLOCK_SIZE {
+        let (head, tail) = data.split_at_mut(n - AES_BLOCK_SIZE);
+        head[n - 2 * AES_BLOCK_SIZE..].swap_with_slice(tail);
+    }
+    let mut last = [0; AES_BLOCK_SIZE];
+    for chunk in data.chunks_mut(AES_BLOCK_SIZE) {
+        let saved: [u8; AES_BLOCK_SIZE] = (&*chunk).try_into().unwrap();
+        cipher.decrypt_block(chunk.into());
+        chunk.iter_mut().zip(last).for_each(|(x, c)| *x ^= c);
+        last = saved;
+    }
+    while data.last() == Some(&0) { data.pop(); }
+    Some(data)
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn crypt_enabled(&self) -> bool {
+        self.super_block.s_feature_incompat & EXT4_FEATURE_INCOMPAT_ENCRYPT as u32 != 0
+    }
+
+    pub fn is_encrypted(inode: &Ext2INode) -> bool {
+        inode.i_flags & EXT4_ENCRYPT_FL as u32 != 0
+    }
+
+    /// Add keys from files in `RfsConfig::key_files`
+    pub fn crypt_load(&mut self) -> Result<()> {
+        for file in self.config.key_files.clone() {
+            let raw = std::fs::read(&file).map_err(|e| anyhow!("Cannot read key file {}: {}", file, e))?;
+            let id = self.rfs_add_key(&raw)?;
+            info!("added key {} from {}", hex_string(&id), file);
+        }
+        Ok(())
+    }
+
+    /// Add master key, returns its identifier
+    pub fn rfs_add_key(&mut self, raw: &[u8]) -> Result<[u8; FSCRYPT_KEY_IDENTIFIER_SIZE]> {
+        if raw.len() < FSCRYPT_MIN_KEY_SIZE || raw.len() > FSCRYPT_MAX_KEY_SIZE {
+            return Err(Error::new(Errno(EINVAL)).context(format!("key of {} bytes, expected {} to {}",
+                                                                 raw.len(), FSCRYPT_MIN_KEY_SIZE, FSCRYPT_MAX_KEY_SIZE)));
+        }
+        let id = crypt_key_identifier(raw);
+        self.crypt.keys.insert(id, raw.to_vec());
+        Ok(id)
+    }
+
+    /// Remove master key, files under it are locked again
+    pub fn rfs_remove_key(&mut self, id: &[u8; FSCRYPT_KEY_IDENTIFIER_SIZE]) -> Result<()> {
+        match self.crypt.keys.remove(id) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(Errno(ENOKEY)).context(format!("key {} not added", hex_string(id)))),
+        }
+    }
+
+    /// Encryption context of inode, None if not encrypted
+    pub fn crypt_context(&mut self, ino: usize) -> Result<Option<CryptContext>> {
+        if !Self::is_encrypted(&self.get_inode(ino)?) { return Ok(None); }
+        let value = self.ibody_xattr_get(ino, EXT4_XATTR_INDEX_ENCRYPTION, EXT4_XATTR_CONTEXT_NAME)?;
+        match value.as_deref().and_then(CryptContext::from_bytes) {
+            Some(ctx) => Ok(Some(ctx)),
+            None => Err(anyhow!("bad encryption context of inode {}", ino)),
+        }
+    }
+
+    fn crypt_key(&mut self, ino: usize) -> Result<CryptKey> {
+        let ctx = match self.crypt_context(ino)? {
+            Some(ctx) => ctx,
+            None => return Ok(CryptKey::Plain),
+        };
+        Ok(match self.crypt.keys.get(&ctx.key_identifier) {
+            Some(master) => {
+                let mut key = [0; 64];
+                crypt_hkdf(master, HKDF_CONTEXT_PER_FILE_ENC_KEY, &ctx.nonce, &mut key);
+                CryptKey::Unlocked(key)
+            }
+            None => CryptKey::Locked,
+        })
+    }
+
+    /// Key of encrypted inode, None if not encrypted, fails without master key
+    pub(crate) fn crypt_require(&mut self, ino: usize) -> Result<Option<[u8; 64]>> {
+        match self.crypt_key(ino)? {
+            CryptKey::Plain => Ok(None),
+            CryptKey::Locked => Err(Error::new(Errno(ENOKEY)).context(format!("inode {} is locked", ino))),
+            CryptKey::Unlocked(key) => Ok(Some(key)),
+        }
+    }
+
+    /// Name stored in directory for `name`, names in locked directories are already encoded
+    pub fn crypt_name(&mut self, dir: usize, name: &str) -> Result<String> {
+        if dir < EXT2_ROOT_INO || name == "." || name == ".." { return Ok(name.to_string()); }
+        match self.crypt_key(dir)? {
+            CryptKey::Unlocked(key) => {
+                if name.len() > RFS_CRYPT_NAME_MAX {
+                    return Err(Error::new(Errno(ENAMETOOLONG)).context(format!("name {} too long to encrypt", name)));
+                }
+                Ok(URL_SAFE_NO_PAD.encode(cts_encrypt(&key, name.as_bytes())))
+            }
+            _ => Ok(name.to_string()),
+        }
+    }
+
+    /// Show plain names of entries if directory is unlocked
+    pub fn crypt_entries(&mut self, dir: usize, entries: &mut [Ext2DirEntry]) -> Result<()> {
+        let key = match self.crypt_key(dir)? {
+            CryptKey::Unlocked(key) => key,
+            _ => return Ok(()),
+        };
+        for e in entries.iter_mut().filter(|x| x.get_name() != "." && x.get_name() != "..") {
+            let plain = URL_SAFE_NO_PAD.decode(e.get_name()).ok()
+                .and_then(|x| cts_decrypt(&key, &x))
+                .and_then(|x| String::from_utf8(x).ok());
+            match plain {
+                Some(name) => e.update_name(&name),
+                None => warn!("cannot decrypt name {} in inode {}", e.get_name(), dir),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a new entry can be made in `dir`, which needs the key if encrypted
+    pub fn crypt_check_create(&mut self, dir: usize) -> Result<()> {
+        if dir >= EXT2_ROOT_INO { self.crypt_require(dir)?; }
+        Ok(())
+    }
+
+    /// Entries in encrypted directory must use the same master key
+    pub fn crypt_check_link(&mut self, dir: usize, ino: usize) -> Result<()> {
+        let dir_ctx = match self.crypt_context(dir)? {
+            Some(ctx) => ctx,
+            None => return Ok(()),
+        };
+        self.crypt_require(dir)?;
+        match self.crypt_context(ino)? {
+            Some(ctx) if ctx.key_identifier == dir_ctx.key_identifier => Ok(()),
+            _ => Err(Error::new(Errno(EXDEV)).context(format!("inode {} has another encryption policy", ino))),
+        }
+    }
+
+    /// Give new inode the policy of its parent directory
+    pub fn crypt_inherit(&mut self, parent: usize, ino: usize) -> Result<()> {
+        if parent < EXT2_ROOT_INO { return Ok(()); }
+        if let Some(ctx) = self.crypt_context(parent)? {
+            self.crypt_set_context(ino, CryptContext { nonce: rand::random(), ..ctx })?;
+        }
+        Ok(())
+    }
+
+    fn crypt_set_context(&mut self, ino: usize, ctx: CryptContext) -> Result<()> {
+        let mut inode = self.get_inode(ino)?;
+        inode.i_flags |= EXT4_ENCRYPT_FL as u32;
+        self.set_inode(ino, &inode)?;
+        self.ibody_xattr_set(ino, EXT4_XATTR_INDEX_ENCRYPTION, EXT4_XATTR_CONTEXT_NAME, Some(&ctx.to_bytes()))
+    }
+
+    /// Encrypt empty directory with master key `id`, like `FS_IOC_SET_ENCRYPTION_POLICY`
+    pub fn rfs_set_policy(&mut self, ino: u64, id: &[u8; FSCRYPT_KEY_IDENTIFIER_SIZE]) -> Result<()> {
+        let ino = RFS::<T>::shift_ino(ino as usize);
+        if !self.crypt_enabled() || self.inode_size() < EXT2_GOOD_OLD_INODE_SIZE * 2 {
+            return Err(Error::new(Errno(EOPNOTSUPP)).context("encryption needs encrypt feature and 256 bytes inodes"));
+        }
+        let inode = self.get_inode(ino)?;
+        if inode.i_mode as usize >> 12 != Ext2FileType::Directory.into() {
+            return Err(Error::new(Errno(ENOTDIR)).context(format!("inode {} is not a directory", ino)));
+        }
+        if let Some(ctx) = self.crypt_context(ino)? {
+            if &ctx.key_identifier == id { return Ok(()); }
+            return Err(Error::new(Errno(EEXIST)).context(format!("inode {} already has a policy", ino)));
+        }
+        if !self.crypt.keys.contains_key(id) {
+            return Err(Error::new(Errno(ENOKEY)).context(format!("key {} not added", hex_string(id))));
+        }
+        if ino == EXT2_ROOT_INO || self.get_dir_entries(ino)?.iter().any(|x| x.get_name() != "." && x.get_name() != "..") {
+            return Err(Error::new(Errno(ENOTEMPTY)).context(format!("inode {} is not an empty directory", ino)));
+        }
+        debug!("set policy {} on inode {}", hex_string(id), ino);
+        self.crypt_set_context(ino, CryptContext::new(*id))
+    }
+
+    /// Logical blocks `start..end` of inode, 0 for holes
+    pub(crate) fn crypt_blocks(&mut self, ino: usize, start: usize, end: usize) -> Result<Vec<usize>> {
+        let mut blocks = vec![0; end - start];
+        if end > start {
+            self.visit_blocks_inode(ino, start, &mut |block, index| {
+                if index >= end { return Ok((false, false)); }
+                blocks[index - start] = block;
+                Ok((index + 1 < end, false))
+            })?;
+        }
+        Ok(blocks)
+    }
+
+    /// Read `size` bytes of encrypted file, zeros after end of file
+    pub fn crypt_read(&mut self, ino: usize, offset: usize, size: usize) -> Result<Vec<u8>> {
+        let key = self.crypt_require(ino)?.unwrap();
+        let sz = self.block_size();
+        let inode = self.get_inode(ino)?;
+        let end = min(offset + size, inode.i_size as usize | (inode.i_size_high as usize) << 32);
+        let mut data = vec![0; size];
+        if end <= offset { return Ok(data); }
+        let first = offset / sz;
+        let mut buf = self.create_block_vec();
+        for (i, block) in self.crypt_blocks(ino, first, end.div_ceil(sz))?.into_iter().enumerate() {
+            if block == 0 { continue; }
+            let index = first + i;
+            self.read_data_block(block, &mut buf)?;
+            xts_crypt(&key, index as u64, &mut buf, false);
+            let (start, stop) = (max(offset, index * sz), min(end, (index + 1) * sz));
+            data[start - offset..stop - offset].copy_from_slice(&buf[start - index * sz..stop - index * sz]);
+        }
+        Ok(data)
+    }
+
+    /// Encrypt whole blocks of plain `data` and write them from logical block `first`
+    fn crypt_write_blocks(&mut self, ino: usize, key: &[u8; 64], first: usize, mut data: Vec<u8>) -> Result<()> {
+        let sz = self.block_size();
+        for (i, chunk) in data.chunks_mut(sz).enumerate() {
+            xts_crypt(key, (first + i) as u64, chunk, true);
+        }
+        self.rfs_write_blocks(ino as u64, (first * sz) as i64, &data)?;
+        Ok(())
+    }
+
+    /// Write to encrypted file, partial blocks are read and written whole
+    pub fn crypt_write(&mut self, ino: usize, offset: usize, data: &[u8]) -> Result<u32> {
+        let key = self.crypt_require(ino)?.unwrap();
+        let sz = self.block_size();
+        let inode = self.get_inode(ino)?;
+        let size = inode.i_size as usize | (inode.i_size_high as usize) << 32;
+        let end = offset + data.len();
+        let (first, last) = (offset / sz, end.div_ceil(sz));
+        let mut buf = vec![0; (last - first) * sz];
+        for index in [first, last - 1] {
+            if index * sz < offset || (index + 1) * sz > end {
+                let part = self.crypt_read(ino, index * sz, sz)?;
+                buf[(index - first) * sz..][..sz].copy_from_slice(&part);
+            }
+        }
+        buf[offset - first * sz..][..data.len()].copy_from_slice(data);
+        self.crypt_write_blocks(ino, &key, first, buf)?;
+        let mut inode = self.get_inode(ino)?;
+        let size = max(size, end);
+        inode.i_size = size as u32;
+        inode.i_size_high = (size >> 32) as u32;
+        self.set_inode(ino, &inode)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Clear the rest of the last block kept when encrypted file shrinks to `size`
+    pub fn crypt_truncate(&mut self, ino: usize, size: usize) -> Result<()> {
+        let key = self.crypt_require(ino)?.unwrap();
+        let sz = self.block_size();
+        if size.is_multiple_of(sz) { return Ok(()); }
+        let mut buf = self.crypt_read(ino, size / sz * sz, sz)?;
+        buf[size % sz..].fill(0);
+        self.crypt_write_blocks(ino, &key, size / sz, buf)
+    }
+
+    /// Store symlink target of encrypted inode like fscrypt, length then ciphertext
+    pub fn crypt_symlink(&mut self, ino: usize, link: &str) -> Result<Option<Vec<u8>>> {
+        let key = match self.crypt_require(ino)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let data = cts_encrypt(&key, link.as_bytes());
+        if data.len() + 2 > EXT2_N_BLOCKS * 4 {
+            return Err(Error::new(Errno(ENAMETOOLONG)).context(format!("link {} too long to encrypt", link)));
+        }
+        Ok(Some([(data.len() as u16).to_le_bytes().as_slice(), &data].concat()))
+    }
+
+    /// Target of encrypted symlink, encoded ciphertext if locked
+    pub fn crypt_readlink(&mut self, ino: usize) -> Result<Vec<u8>> {
+        let inode = self.get_inode(ino)?;
+        let raw = unsafe { serialize_row(&inode.i_block) }.to_vec();
+        let len = min(u16::from_le_bytes([raw[0], raw[1]]) as usize, raw.len() - 2);
+        let data = &raw[2..2 + len];
+        Ok(match self.crypt_key(ino)? {
+            CryptKey::Unlocked(key) => cts_decrypt(&key, data).unwrap_or_default(),
+            _ => URL_SAFE_NO_PAD.encode(data).into_bytes(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_crypt() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(4 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions {
+            inode_size: 256,
+            features: vec!["encrypt,inline_data,metadata_csum".to_string()],
+            ..Default::default()
+        })?;
+        let master = [7u8; 64];
+        let id = fs.rfs_add_key(&master)?;
+        let (dir, _) = fs.make_node(EXT2_ROOT_INO, "secret", 0o755, Ext2FileType::Directory, 0, 0)?;
+        fs.rfs_set_policy(dir as u64, &id)?;
+        let (ino, inode) = fs.make_node(dir, "fixture.txt", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        assert!(RFS::<MemoryDiskDriver>::is_encrypted(&inode));
+        let text = (0..3000).map(|x| x as u8).collect::<Vec<_>>();
+        fs.rfs_write(ino as u64, 0, &text[..100])?;
+        fs.rfs_write(ino as u64, 100, &text[100..])?;
+        assert_eq!(fs.rfs_read(ino as u64, 0, 3000)?, text);
+        let block = fs.get_inode(ino)?.i_block[0] as usize;
+        assert_ne!(&fs.get_data_block(block)?[..100], &text[..100]);
+        fs.rfs_symlink(dir, "link", "fixture.txt", 0, 0)?;
+        let names = fs.rfs_readdir(dir as u64, 0)?.iter().map(|x| x.get_name()).collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "fixture.txt", "link"]);
+        assert_eq!(fs.rfs_lookup(dir, "fixture.txt")?.0, ino);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        // without the key names are encoded and contents are locked
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        let entries = fs.rfs_readdir(dir as u64, 0)?;
+        let stored = entries[2].get_name();
+        assert_ne!(stored, "fixture.txt");
+        assert_eq!(fs.rfs_lookup(dir, &stored)?.0, ino);
+        assert!(fs.rfs_read(ino as u64, 0, 10).is_err());
+        assert!(fs.make_node(dir, "new", 0o644, Ext2FileType::RegularFile, 0, 0).is_err());
+        fs.rfs_add_key(&master)?;
+        assert_eq!(fs.rfs_read(ino as u64, 0, 3000)?, text);
+        let link = fs.rfs_lookup(dir, "link")?.0;
+        assert_eq!(fs.crypt_readlink(link)?, b"fixture.txt");
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+/// Debugfs-style commands over an unmounted image.
+///
+/// `DebugShell` keeps a current directory and runs one command line at a time.
+/// Inodes are given by path, relative to the current directory if not starting
+/// with `/`, or as `<ino>` like debugfs. Changes by `set_inode_field`, `freeb`
+/// and `setb` reach the disk when the filesystem is destroyed.
+use std::collections::{BTreeSet, VecDeque};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use disk_driver::DiskDriver;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+
+pub const DEBUG_HELP: &str = "\
+cd <dir>                              change current directory
+ls [-l] [dir]                         list directory entries
+stat <file>                           show inode fields and blocks
+blocks <file>                         list data blocks
+icheck <block>...                     find inodes owning blocks
+ncheck <ino>...                       find paths of inodes
+cat <file>                            print file content
+dump_inode <file> <out>               save file content to native file <out>
+set_inode_field <file> <field> <val>  change one inode field, `set_inode_field -l` lists fields
+freeb <block> [count]                 mark blocks free
+setb <block> [count]                  mark blocks in use
+testb <block> [count]                 show whether blocks are in use
+help                                  show this message
+quit                                  leave";
+
+/// Fields accepted by `set_inode_field`
+const DEBUG_INODE_FIELDS: &[&str] = &["mode", "uid", "gid", "size", "atime", "ctime", "mtime", "dtime",
+    "links_count", "blocks", "flags", "generation", "file_acl", "block[N]"];
+
+/// Number in decimal, `0x` hex or `0` octal, like `strtoul` with base 0
+pub fn debug_parse_num(s: &str) -> Result<u64> {
+    let r = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if s.len() > 1 && s.starts_with('0') {
+        u64::from_str_radix(&s[1..], 8)
+    } else {
+        s.parse()
+    };
+    r.map_err(|_| anyhow!("bad number {}", s))
+}
+
+fn debug_time(t: u32) -> String {
+    format!("0x{:08x} -- {}", t, DateTime::<Utc>::from(utc_time(t)).format("%Y-%m-%d %H:%M:%S"))
+}
+
+/// `(index, block)` pairs as debugfs ranges, like `(0-11):100-111`
+fn debug_block_ranges(blocks: &[(usize, usize)]) -> String {
+    let mut ranges = vec![];
+    let mut i = 0;
+    while i < blocks.len() {
+        let mut j = i;
+        while j + 1 < blocks.len() && blocks[j + 1].0 == blocks[j].0 + 1 && blocks[j + 1].1 == blocks[j].1 + 1 { j += 1; }
+        ranges.push(if i == j { format!("({}):{}", blocks[i].0, blocks[i].1) } else {
+            format!("({}-{}):{}-{}", blocks[i].0, blocks[j].0, blocks[i].1, blocks[j].1)
+        });
+        i = j + 1;
+    }
+    ranges.join(", ")
+}
+
+/// Current directory of a debug session
+#[derive(Debug, Clone)]
+pub struct DebugShell {
+    pub cwd: usize,
+}
+
+impl Default for DebugShell {
+    fn default() -> Self {
+        Self { cwd: EXT2_ROOT_INO }
+    }
+}
+
+impl DebugShell {
+    /// Inode of `<ino>` or path
+    pub fn resolve<T: DiskDriver>(&self, fs: &mut RFS<T>, spec: &str) -> Result<usize> {
+        if let Some(ino) = spec.strip_prefix('<').and_then(|x| x.strip_suffix('>')) {
+            let ino = debug_parse_num(ino)? as usize;
+            if ino == 0 || ino > fs.super_block.s_inodes_count as usize {
+                return Err(anyhow!("bad inode number {}", ino));
+            }
+            return Ok(ino);
+        }
+        let mut ino = if spec.starts_with('/') { EXT2_ROOT_INO } else { self.cwd };
+        for name in spec.split('/').filter(|x| !x.is_empty()) {
+            ino = fs.rfs_lookup(ino, name).map_err(|_| anyhow!("{}: not found", spec))?.0;
+        }
+        Ok(ino)
+    }
+
+    fn ls<T: DiskDriver>(&self, fs: &mut RFS<T>, ino: usize, long: bool) -> Result<String> {
+        let mut out = String::new();
+        for e in fs.get_dir_entries(ino)? {
+            if long {
+                let inode = fs.get_inode(e.inode as usize)?;
+                out += &format!("{:>8} {:>6o} {:>5} {:>5} {:>5} {:>10} {} {}\n", e.inode, inode.i_mode, inode.i_links_count,
+                                inode.uid(), inode.gid(), inode.i_size as u64 | (inode.i_size_high as u64) << 32,
+                                DateTime::<Utc>::from(utc_time(inode.i_mtime)).format("%Y-%m-%d %H:%M"), e.get_name());
+            } else {
+                out += &format!("{:>8} ({}) {}\n", e.inode, e.rec_len, e.get_name());
+            }
+        }
+        Ok(out)
+    }
+
+    fn stat<T: DiskDriver>(&self, fs: &mut RFS<T>, ino: usize) -> Result<String> {
+        let inode = fs.get_inode(ino)?;
+        let file_type = Ext2FileType::try_from((inode.i_mode >> 12) as usize).unwrap_or(Ext2FileType::Unknown);
+        let mut out = format!("Inode: {}   Type: {:?}   Mode: {:04o}   Flags: 0x{:x}\n",
+                              ino, file_type, inode.i_mode & 0xFFF, inode.i_flags);
+        out += &format!("Generation: {}   Version: {}\n", inode.i_generation, inode.i_version);
+        out += &format!("User: {:>5}   Group: {:>5}   Size: {}\n", inode.uid(), inode.gid(),
+                        inode.i_size as u64 | (inode.i_size_high as u64) << 32);
+        out += &format!("File ACL: {}\n", inode.i_file_acl);
+        out += &format!("Links: {}   Blockcount: {}\n", inode.i_links_count, inode.i_blocks);
+        out += &format!(" ctime: {}\n atime: {}\n mtime: {}\n", debug_time(inode.i_ctime),
+                        debug_time(inode.i_atime), debug_time(inode.i_mtime));
+        if inode.i_dtime != 0 { out += &format!(" dtime: {}\n", debug_time(inode.i_dtime)); }
+        if RFS::<T>::is_inline(&inode) {
+            out += "Size of inline data: stored in inode\n";
+            return Ok(out);
+        }
+        let (mut meta, _) = fs.image_inode_blocks(ino, false)?;
+        meta.retain(|x| *x != inode.i_file_acl as usize);
+        if !meta.is_empty() {
+            out += &format!("INDEX BLOCKS: {}\n", meta.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" "));
+        }
+        if file_type == Ext2FileType::RegularFile || file_type == Ext2FileType::Directory {
+            out += &format!("BLOCKS:\n{}\n", debug_block_ranges(&fs.defrag_blocks(ino)?));
+        }
+        Ok(out)
+    }
+
+    fn icheck<T: DiskDriver>(&self, fs: &mut RFS<T>, blocks: &[usize]) -> Result<String> {
+        let mut owners = vec![None; blocks.len()];
+        for ino in 1..=fs.super_block.s_inodes_count as usize {
+            if owners.iter().all(|x| x.is_some()) { break; }
+            let used = RFS::<T>::bitmap_get(&fs.alloc().bitmap_inode, ino);
+            if !used || fs.get_inode(ino)?.i_mode == 0 { continue; }
+            let (meta, data) = fs.image_inode_blocks(ino, true)?;
+            for (i, block) in blocks.iter().enumerate() {
+                if owners[i].is_none() && (meta.contains(block) || data.contains(block)) { owners[i] = Some(ino); }
+            }
+        }
+        let mut out = "Block\tInode number\n".to_string();
+        for (block, owner) in blocks.iter().zip(owners) {
+            out += &match owner {
+                Some(ino) => format!("{}\t{}\n", block, ino),
+                None => format!("{}\t<block not found>\n", block),
+            };
+        }
+        Ok(out)
+    }
+
+    fn ncheck<T: DiskDriver>(&self, fs: &mut RFS<T>, inodes: &[usize]) -> Result<String> {
+        let mut out = "Inode\tPathname\n".to_string();
+        let mut visited = BTreeSet::from([EXT2_ROOT_INO]);
+        let mut queue = VecDeque::from([(EXT2_ROOT_INO, String::new())]);
+        while let Some((dir, path)) = queue.pop_front() {
+            for e in fs.get_dir_entries(dir)? {
+                let name = e.get_name();
+                if name == "." || name == ".." { continue; }
+                let ino = e.inode as usize;
+                let child = format!("{}/{}", path, name);
+                if inodes.contains(&ino) { out += &format!("{}\t{}\n", ino, child); }
+                if e.file_type == EXT2_FT_DIR && visited.insert(ino) { queue.push_back((ino, child)); }
+            }
+        }
+        Ok(out)
+    }
+
+    fn set_inode_field<T: DiskDriver>(&self, fs: &mut RFS<T>, ino: usize, field: &str, value: &str) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        let v = debug_parse_num(value)?;
+        match field {
+            "mode" => inode.i_mode = v as u16,
+            "uid" => inode.set_uid(v as u32),
+            "gid" => inode.set_gid(v as u32),
+            "size" => {
+                inode.i_size = v as u32;
+                inode.i_size_high = (v >> 32) as u32;
+            }
+            "atime" => inode.i_atime = v as u32,
+            "ctime" => inode.i_ctime = v as u32,
+            "mtime" => inode.i_mtime = v as u32,
+            "dtime" => inode.i_dtime = v as u32,
+            "links_count" => inode.i_links_count = v as u16,
+            "blocks" => inode.i_blocks = v as u32,
+            "flags" => inode.i_flags = v as u32,
+            "generation" => inode.i_generation = v as u32,
+            "file_acl" => inode.i_file_acl = v as u32,
+            _ => {
+                let index = field.strip_prefix("block[").and_then(|x| x.strip_suffix(']'))
+                    .and_then(|x| x.parse::<usize>().ok()).filter(|x| *x < EXT2_N_BLOCKS)
+                    .ok_or(anyhow!("unknown field {}", field))?;
+                inode.i_block[index] = v as u32;
+            }
+        }
+        // names cached for a directory may no longer be there
+        fs.dentry_cache.invalidate(ino);
+        fs.set_inode(ino, &inode)
+    }
+
+    /// Mark `count` blocks from `block` in use or free, `None` to only test them
+    fn bitmap_blocks<T: DiskDriver>(&self, fs: &mut RFS<T>, block: usize, count: usize, set: Option<bool>) -> Result<String> {
+        let first = fs.super_block.s_first_data_block as usize;
+        if block < first || block + count > fs.super_block.s_blocks_count as usize {
+            return Err(anyhow!("blocks {}..{} out of range", block, block + count));
+        }
+        let mut out = String::new();
+        for b in block..block + count {
+            let bit = fs.block_bit(b);
+            let used = RFS::<T>::bitmap_get(&fs.alloc().bitmap_data, bit);
+            match set {
+                None => out += &format!("Block {} {}\n", b, if used { "marked in use" } else { "not in use" }),
+                Some(set) if set == used => out += &format!("Warning: block {} already {}\n", b, if used { "set" } else { "clear" }),
+                Some(set) => RFS::<T>::bitmap_set_value(&mut fs.alloc().bitmap_data, bit, set),
+            }
+        }
+        fs.sync_free_counts();
+        Ok(out)
+    }
+
+    /// Run one command line and return its output
+    pub fn run<T: DiskDriver>(&mut self, fs: &mut RFS<T>, line: &str) -> Result<String> {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        let Some((cmd, args)) = args.split_first() else { return Ok(String::new()); };
+        let arg = |i: usize| args.get(i).copied().ok_or(anyhow!("missing argument, see `help`"));
+        let nums = || args.iter().map(|x| debug_parse_num(x).map(|x| x as usize)).collect::<Result<Vec<_>>>();
+        match *cmd {
+            "help" | "?" => Ok(format!("{}\n", DEBUG_HELP)),
+            "cd" => {
+                let ino = self.resolve(fs, arg(0)?)?;
+                fs.get_dir_entries(ino)?;
+                self.cwd = ino;
+                Ok(String::new())
+            }
+            "ls" => {
+                let long = args.first() == Some(&"-l");
+                let path = args.iter().find(|x| **x != "-l");
+                let ino = match path {
+                    Some(path) => self.resolve(fs, path)?,
+                    None => self.cwd,
+                };
+                self.ls(fs, ino, long)
+            }
+            "stat" => {
+                let ino = self.resolve(fs, arg(0)?)?;
+                self.stat(fs, ino)
+            }
+            "blocks" => {
+                let ino = self.resolve(fs, arg(0)?)?;
+                let blocks = fs.defrag_blocks(ino)?;
+                Ok(format!("{}\n", blocks.iter().map(|x| x.1.to_string()).collect::<Vec<_>>().join(" ")))
+            }
+            "icheck" => {
+                arg(0)?;
+                self.icheck(fs, &nums()?)
+            }
+            "ncheck" => {
+                arg(0)?;
+                self.ncheck(fs, &nums()?)
+            }
+            "cat" | "dump_inode" => {
+                let ino = self.resolve(fs, arg(0)?)?;
+                let inode = fs.get_inode(ino)?;
+                let data = fs.rfs_read(ino as u64, 0, inode.i_size)?;
+                if *cmd == "cat" { return Ok(String::from_utf8_lossy(&data).to_string()); }
+                let out = arg(1)?;
+                std::fs::write(out, &data).map_err(|e| anyhow!("cannot write {}: {}", out, e))?;
+                Ok(String::new())
+            }
+            "set_inode_field" => {
+                if arg(0)? == "-l" { return Ok(format!("{}\n", DEBUG_INODE_FIELDS.join("\n"))); }
+                let ino = self.resolve(fs, arg(0)?)?;
+                self.set_inode_field(fs, ino, arg(1)?, arg(2)?)?;
+                Ok(String::new())
+            }
+            "freeb" | "setb" | "testb" => {
+                let block = debug_parse_num(arg(0)?)? as usize;
+                let count = args.get(1).map_or(Ok(1), |x| debug_parse_num(x))? as usize;
+                let set = match *cmd {
+                    "freeb" => Some(false),
+                    "setb" => Some(true),
+                    _ => None,
+                };
+                self.bitmap_blocks(fs, block, count, set)
+            }
+            _ => Err(anyhow!("unknown command, see `help`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let bs = fs.block_size();
+        let (dir, _) = fs.make_node(EXT2_ROOT_INO, "dir", 0o755, Ext2FileType::Directory, 0, 0)?;
+        let (file, _) = fs.make_node(dir, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(file as u64, 0, &vec![b'x'; bs * 2])?;
+        let mut shell = DebugShell::default();
+
+        assert!(shell.run(&mut fs, "ls")?.contains(" dir\n"));
+        shell.run(&mut fs, "cd dir")?;
+        assert_eq!(shell.cwd, dir);
+        assert!(shell.run(&mut fs, "ls -l /dir")?.contains("100644"));
+        assert_eq!(shell.resolve(&mut fs, "../dir/file")?, file);
+        let stat = shell.run(&mut fs, &format!("stat <{}>", file))?;
+        assert!(stat.contains(&format!("Size: {}", bs * 2)), "{}", stat);
+        let blocks = shell.run(&mut fs, "blocks file")?;
+        let block = debug_parse_num(blocks.split_whitespace().next().unwrap())? as usize;
+        assert_eq!(shell.run(&mut fs, &format!("icheck {}", block))?, format!("Block\tInode number\n{}\t{}\n", block, file));
+        assert_eq!(shell.run(&mut fs, &format!("ncheck {}", file))?, format!("Inode\tPathname\n{}\t/dir/file\n", file));
+        assert_eq!(shell.run(&mut fs, "cat file")?, "x".repeat(bs * 2));
+
+        shell.run(&mut fs, "set_inode_field file uid 0x3e8")?;
+        assert_eq!(fs.get_inode(file)?.uid(), 1000);
+        assert!(shell.run(&mut fs, "set_inode_field file nothing 1").is_err());
+        let free = fs.alloc().free_blocks_count;
+        assert_eq!(shell.run(&mut fs, &format!("freeb {}", block))?, "");
+        assert_eq!(fs.alloc().free_blocks_count, free + 1);
+        assert!(shell.run(&mut fs, &format!("testb {}", block))?.contains("not in use"));
+        shell.run(&mut fs, &format!("setb {}", block))?;
+        assert!(shell.run(&mut fs, &format!("setb {}", block))?.starts_with("Warning"));
+        assert_eq!(fs.alloc().free_blocks_count, free);
+        assert!(shell.run(&mut fs, "stat missing").is_err());
+        Ok(())
+    }
+}
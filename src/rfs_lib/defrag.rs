@@ -0,0 +1,204 @@
+/// Offline defragmentation, fragmented files are moved into contiguous free runs.
+///
+/// Fragments of a file are runs of physically consecutive data blocks in logical
+/// order, holes do not break a run. A fragmented file is copied into the first
+/// free run large enough for all its data blocks, remapped by its `BlockMap` and
+/// its old blocks released; index blocks stay where they are. Files sharing
+/// blocks are left alone, and images with snapshots are refused because snapshot
+/// bitmaps track fixed block positions.
+use std::fmt::{Display, Formatter};
+use anyhow::{Error, Result};
+use disk_driver::DiskDriver;
+use libc::EBUSY;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// Fragmentation of one file
+#[derive(Debug, Clone)]
+pub struct DefragFile {
+    pub ino: usize,
+    /// Data blocks, holes and index blocks not included
+    pub blocks: usize,
+    pub fragments: usize,
+    /// Moved into one contiguous run
+    pub relocated: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DefragReport {
+    /// Files with more than one fragment before defragmentation
+    pub files: Vec<DefragFile>,
+    /// Regular files and directories holding data blocks
+    pub total_files: usize,
+    pub total_blocks: usize,
+    /// Fragments of all files, after relocation if done
+    pub total_fragments: usize,
+}
+
+impl DefragReport {
+    /// Files still fragmented
+    pub fn fragmented(&self) -> usize {
+        self.files.iter().filter(|x| !x.relocated).count()
+    }
+}
+
+impl Display for DefragReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for file in self.files.iter() {
+            writeln!(f, "inode {}: {} blocks in {} fragments{}", file.ino, file.blocks, file.fragments,
+                     if file.relocated { ", relocated" } else { "" })?;
+        }
+        let percent = if self.total_files == 0 { 0.0 } else { self.fragmented() as f64 * 100.0 / self.total_files as f64 };
+        writeln!(f, "{} files, {} fragmented ({:.1}%), {} blocks in {} fragments",
+                 self.total_files, self.fragmented(), percent, self.total_blocks, self.total_fragments)
+    }
+}
+
+/// Count runs of consecutive blocks in `(index, block)` pairs sorted by index
+pub fn defrag_fragments(blocks: &[(usize, usize)]) -> usize {
+    blocks.iter().enumerate().filter(|(i, x)| *i == 0 || blocks[i - 1].1 + 1 != x.1).count()
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Mapped data blocks of inode as `(index, block)` in logical order
+    pub fn defrag_blocks(&mut self, ino: usize) -> Result<Vec<(usize, usize)>> {
+        let inode = self.get_inode(ino)?;
+        let end = self.blocks_end(ino, &inode)?;
+        let mut blocks = vec![];
+        if end == 0 { return Ok(blocks); }
+        self.visit_blocks_inode(ino, 0, &mut |block, index| {
+            if block != 0 { blocks.push((index, block)); }
+            Ok((index + 1 < end, false))
+        })?;
+        Ok(blocks)
+    }
+
+    /// Regular files and directories in use
+    fn defrag_inodes(&mut self) -> Result<Vec<usize>> {
+        let mut inodes = vec![];
+        let first_ino = self.super_block.s_first_ino as usize;
+        for ino in [EXT2_ROOT_INO].into_iter().chain(first_ino..=self.super_block.s_inodes_count as usize) {
+            if !Self::bitmap_get(&self.alloc().bitmap_inode, ino) { continue; }
+            let inode = self.get_inode(ino)?;
+            if inode.i_links_count == 0 || Self::is_inline(&inode) { continue; }
+            let file_type = Ext2FileType::try_from((inode.i_mode >> 12) as usize)?;
+            if file_type == Ext2FileType::RegularFile || file_type == Ext2FileType::Directory {
+                inodes.push(ino);
+            }
+        }
+        Ok(inodes)
+    }
+
+    /// First block of a free run of `count` data blocks
+    fn defrag_find_run(&self, count: usize) -> Option<usize> {
+        let reserved = self.block_bit(self.data_start_block()) - 1;
+        let limit = self.block_bit(self.super_block.s_blocks_count as usize - 1);
+        let mut run = 0;
+        for i in reserved..limit {
+            run = if Self::bitmap_get(&self.alloc().bitmap_data, i + 1) { 0 } else { run + 1 };
+            if run == count {
+                return Some(i + 1 - count + self.super_block.s_first_data_block as usize);
+            }
+        }
+        None
+    }
+
+    /// Move data blocks of inode into one free run, false if not possible
+    fn defrag_file(&mut self, ino: usize, blocks: &[(usize, usize)]) -> Result<bool> {
+        if blocks.iter().any(|x| self.refcounts.get(x.1) > 1) {
+            info!("inode {} shares blocks, skipped", ino);
+            return Ok(false);
+        }
+        let start = match self.defrag_find_run(blocks.len()) {
+            Some(start) => start,
+            None => {
+                warn!("no free run of {} blocks for inode {}", blocks.len(), ino);
+                return Ok(false);
+            }
+        };
+        debug!("move inode {} to blocks {}..{}", ino, start, start + blocks.len());
+        self.transaction(|fs| {
+            let mut mappings = vec![];
+            for (i, (index, block)) in blocks.iter().enumerate() {
+                let data = fs.get_data_block(*block)?;
+                fs.write_data_block(start + i, &data)?;
+                // claimed before mapping may allocate index blocks
+                let bit = fs.block_bit(start + i);
+                Self::bitmap_set(&mut fs.alloc().bitmap_data, bit);
+                mappings.push((*index, start + i));
+            }
+            let inode = fs.get_inode(ino)?;
+            Self::block_map(&inode).map(fs, ino, &mappings)?;
+            for (_, block) in blocks {
+                let bit = fs.block_bit(*block);
+                Self::bitmap_unset(&mut fs.alloc().bitmap_data, bit);
+            }
+            Ok(true)
+        })
+    }
+
+    /// Report fragmentation, and relocate fragmented files if `relocate`
+    pub fn rfs_defrag(&mut self, relocate: bool) -> Result<DefragReport> {
+        if relocate {
+            self.check_writable()?;
+            if self.super_block.s_snapshot_list != 0 || self.super_block.s_snapshot_inum != 0 {
+                return Err(Error::new(Errno(EBUSY)).context("cannot move blocks while snapshots exist"));
+            }
+        }
+        let mut report = DefragReport::default();
+        for ino in self.defrag_inodes()? {
+            let blocks = self.defrag_blocks(ino)?;
+            if blocks.is_empty() { continue; }
+            let fragments = defrag_fragments(&blocks);
+            report.total_files += 1;
+            report.total_blocks += blocks.len();
+            report.total_fragments += fragments;
+            if fragments < 2 { continue; }
+            let relocated = relocate && self.defrag_file(ino, &blocks)?;
+            if relocated { report.total_fragments -= fragments - 1; }
+            report.files.push(DefragFile { ino, blocks: blocks.len(), fragments, relocated });
+        }
+        if relocate { self.rfs_dump()?; }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defrag() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let bs = fs.block_size();
+        let (a, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let (b, _) = fs.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        // interleave blocks of both files, past indirect blocks
+        for i in 0..20 {
+            fs.rfs_write(a as u64, (i * bs) as i64, &vec![i as u8 + 1; bs])?;
+            fs.rfs_write(b as u64, (i * bs) as i64, &vec![0x80 | i as u8; bs])?;
+        }
+        let free = fs.alloc().free_blocks_count;
+        let report = fs.rfs_defrag(false)?;
+        assert_eq!(report.fragmented(), 2);
+        assert!(defrag_fragments(&fs.defrag_blocks(a)?) > 1);
+
+        let report = fs.rfs_defrag(true)?;
+        assert_eq!(report.fragmented(), 0);
+        assert_eq!(report.total_fragments, report.total_files);
+        assert_eq!(fs.alloc().free_blocks_count, free);
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        for ino in [a, b] {
+            assert_eq!(defrag_fragments(&fs.defrag_blocks(ino)?), 1);
+        }
+        for i in 0..20 {
+            assert_eq!(fs.rfs_read(a as u64, (i * bs) as i64, bs as u32)?, vec![i as u8 + 1; bs]);
+            assert_eq!(fs.rfs_read(b as u64, (i * bs) as i64, bs as u32)?, vec![0x80 | i as u8; bs]);
+        }
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
@@ -15,6 +15,7 @@ use rand::Rng;
 use crate::prv;
 use crate::rfs_lib::types::{le16, le32, s16};
 use crate::rfs_lib::utils::up_align;
+use crate::rfs_lib::layout::FsGroupLayout;
 
 pub const EXT2_DEFAULT_PREALLOC_BLOCKS: usize = 8;
 
@@ -55,6 +56,10 @@ pub const EXT2_GOOD_OLD_FIRST_INO: usize = 11;
  * The second extended file system magic number
  */
 pub const EXT2_SUPER_MAGIC: u16 = 0xEF53;
+/**
+ * Byte offset of super block from the start of device
+ */
+pub const EXT2_SUPER_BLOCK_OFFSET: usize = 1024;
 /**
  * Maximal count of links to a file
  */
@@ -136,14 +141,13 @@ impl Default for Ext2GroupDesc {
     }
 }
 
-impl From<FsLayoutArgs> for Ext2GroupDesc {
-    fn from(l: FsLayoutArgs) -> Self {
+impl From<&FsGroupLayout> for Ext2GroupDesc {
+    fn from(l: &FsGroupLayout) -> Self {
         Self {
             bg_inode_bitmap: l.inode_map as u32,
             bg_block_bitmap: l.data_map as u32,
             bg_inode_table: l.inode_table as u32,
-            bg_free_blocks_count: l.block_count as u16,
-            bg_free_inodes_count: l.inode_count as u16,
+            // free counts are taken from bitmaps when dumping
             bg_used_dirs_count: 0,
             ..Self::default()
         }
@@ -373,6 +377,22 @@ pub enum Ext2FileType {
     Symlink = 0xa,
 }
 
+impl Ext2FileType {
+    /// Value of `file_type` in directory entries
+    pub fn dir_entry_type(&self) -> u8 {
+        match self {
+            Ext2FileType::Unknown => EXT2_FT_UNKNOWN,
+            Ext2FileType::RegularFile => EXT2_FT_REG_FILE,
+            Ext2FileType::Directory => EXT2_FT_DIR,
+            Ext2FileType::CharDevice => EXT2_FT_CHRDEV,
+            Ext2FileType::BlockDevice => EXT2_FT_BLKDEV,
+            Ext2FileType::NamedPipe => EXT2_FT_FIFO,
+            Ext2FileType::Socket => EXT2_FT_SOCK,
+            Ext2FileType::Symlink => EXT2_FT_SYMLINK,
+        }
+    }
+}
+
 impl Ext2INode {
     pub fn to_attr(&self, ino: usize, blksize: usize) -> FileAttr {
         prv!("to_attr", ino, self);
@@ -402,13 +422,29 @@ impl Ext2INode {
             // low 12 bits: use/group and access rights
             perm,
             nlink: self.i_links_count as u32,
-            uid: self.i_uid as u32 + (self.i_uid_high as u32) << 16,
-            gid: self.i_gid as u32 + (self.i_uid_high as u32) << 16,
+            uid: self.uid(),
+            gid: self.gid(),
             rdev: 0,
             blksize: blksize as u32,
             flags: 0,
         }
     }
+
+    /// Full 32 bits owner uid
+    pub fn uid(&self) -> u32 { self.i_uid as u32 | ((self.i_uid_high as u32) << 16) }
+
+    /// Full 32 bits owner gid
+    pub fn gid(&self) -> u32 { self.i_gid as u32 | ((self.i_gid_high as u32) << 16) }
+
+    pub fn set_uid(&mut self, uid: u32) {
+        self.i_uid = (uid & 0xFFFF) as u16;
+        self.i_uid_high = (uid >> 16) as u16;
+    }
+
+    pub fn set_gid(&mut self, gid: u32) {
+        self.i_gid = (gid & 0xFFFF) as u16;
+        self.i_gid_high = (gid >> 16) as u16;
+    }
 }
 
 impl Default for Ext2INode {
@@ -768,6 +804,17 @@ pub fn create_uuid() -> [u8; 16] {
     (0..16).map(|_| { rng.gen::<u8>() }).collect::<Vec<u8>>().try_into().unwrap()
 }
 
+/// Group 0 and 1 and powers of 3, 5 and 7 keep super block copies with `sparse_super`,
+/// otherwise every group does
+pub fn group_has_super(group: usize, sparse: bool) -> bool {
+    if !sparse || group <= 1 { return true; }
+    [3, 5, 7].iter().any(|base| {
+        let mut n = group;
+        while n % base == 0 { n /= base; }
+        n == 1
+    })
+}
+
 impl Ext2SuperBlock {
     pub fn new(s_inodes_count: u32, s_blocks_count: u32, s_first_data_block: u32,
                s_log_block_size: u32) -> Self {
@@ -795,13 +842,15 @@ pub struct FsLayoutArgs {
     pub data_map: usize,
     pub inode_map: usize,
     pub inode_table: usize,
+    /// Inodes count of one group
     pub inode_count: usize,
+    pub groups: Vec<FsGroupLayout>,
 }
 
 impl From<FsLayoutArgs> for Ext2SuperBlock {
     fn from(l: FsLayoutArgs) -> Self {
         let mut r =
-            Self::new(l.inode_count as u32, l.block_count as u32,
+            Self::new((l.inode_count * l.groups.len()) as u32, l.block_count as u32,
                       if l.block_size < 2 * 0x400 { 1 } else { 0 },
                       match l.block_size {
                           1024 => 0,
@@ -809,10 +858,13 @@ impl From<FsLayoutArgs> for Ext2SuperBlock {
                           4096 => 2,
                           _ => panic!("unsupported block size")
                       });
-        r.s_free_blocks_count = (l.block_count - 1 - 1 - 1 - 1) as u32;
-        r.s_free_inodes_count = (l.inode_count -
-            (1 + 1 + 1 + 1 + 1 + l.inode_count / size_of::<Ext2INode>() + 1)
-        ) as u32;
+        r.s_blocks_per_group = (l.block_size * 8) as u32;
+        r.s_clusters_per_group = r.s_blocks_per_group;
+        r.s_inodes_per_group = l.inode_count as u32;
+        let inode_table_blocks = (l.inode_count * EXT2_INODE_SIZE).div_ceil(l.block_size);
+        r.s_free_blocks_count = (l.block_count - (l.inode_table + inode_table_blocks)) as u32;
+        // reserved inodes are always in use
+        r.s_free_inodes_count = r.s_inodes_count - (EXT2_GOOD_OLD_FIRST_INO - 1) as u32;
         r
     }
 }
@@ -1083,6 +1135,8 @@ impl Ext2DirEntry {
     pub fn update_name(&mut self, name: &str) {
         let name_bytes = name.as_bytes();
         assert!(name_bytes.len() < EXT2_NAME_LEN);
+        // bytes after name are padding and should be zero
+        self.name.fill(0);
         self.name[..name_bytes.len()].copy_from_slice(name_bytes);
         self.name_len = name_bytes.len() as u8;
         assert!(name.len() < 256, "Too long filename!");
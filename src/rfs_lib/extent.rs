@@ -0,0 +1,389 @@
+/// Extent trees, block map of ext4 enabled by `extent` incompat feature.
+///
+/// Same format as ext4: `i_block` holds the root node, each node is a header
+/// followed by index entries pointing to nodes one level lower, or by extents
+/// of up to 32768 contiguous blocks in leaves. A tree is loaded as a sorted list
+/// of extents, changed in memory and rebuilt reusing its node blocks.
+use std::cmp::{max, min};
+use anyhow::Result;
+use disk_driver::DiskDriver;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::block_map::BlockMap;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::serialize_row;
+
+pub const EXT4_EXT_MAGIC: u16 = 0xf30a;
+/// Size of node header, index and extent entries
+pub const EXT4_EXT_ENTRY_SIZE: usize = 12;
+/// Longest initialized extent, larger `ee_len` marks an uninitialized one
+const EXT4_EXT_INIT_MAX_LEN: usize = 32768;
+const EXT4_EXT_MAX_DEPTH: usize = 5;
+/// Entries in root node kept in `i_block`
+const EXT4_EXT_ROOT_ENTRIES: usize = EXT2_N_BLOCKS * 4 / EXT4_EXT_ENTRY_SIZE - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// First logical block
+    pub index: usize,
+    pub len: usize,
+    /// First disk block
+    pub start: usize,
+    /// Allocated but never written, read as zeros
+    pub uninit: bool,
+}
+
+impl Extent {
+    pub fn end(&self) -> usize { self.index + self.len }
+
+    /// Disk block of logical `index`
+    pub fn block(&self, index: usize) -> usize { self.start + index - self.index }
+}
+
+/// Extents of one inode, with blocks holding the tree nodes
+#[derive(Debug, Default)]
+pub struct ExtentTree {
+    pub extents: Vec<Extent>,
+    pub nodes: Vec<usize>,
+}
+
+impl ExtentTree {
+    /// Position of extent containing `index`, or the first one after it
+    fn find(&self, index: usize) -> usize {
+        self.extents.partition_point(|e| e.end() <= index)
+    }
+
+    pub fn lookup(&self, index: usize) -> Option<Extent> {
+        self.extents.get(self.find(index)).filter(|e| e.index <= index).copied()
+    }
+
+    /// Map unmapped `index` to `block`, merged into contiguous neighbours
+    fn insert(&mut self, index: usize, block: usize) {
+        let p = self.find(index);
+        let mergeable = |e: &Extent| !e.uninit && e.len < EXT4_EXT_INIT_MAX_LEN;
+        if p > 0 && mergeable(&self.extents[p - 1]) &&
+            self.extents[p - 1].end() == index && self.extents[p - 1].block(index) == block {
+            self.extents[p - 1].len += 1;
+            let prev = self.extents[p - 1];
+            if let Some(next) = self.extents.get(p).copied() {
+                if !next.uninit && prev.end() == next.index && prev.block(next.index) == next.start &&
+                    prev.len + next.len <= EXT4_EXT_INIT_MAX_LEN {
+                    self.extents[p - 1].len += next.len;
+                    self.extents.remove(p);
+                }
+            }
+            return;
+        }
+        if let Some(next) = self.extents.get_mut(p) {
+            if mergeable(next) && next.index == index + 1 && next.start == block + 1 {
+                next.index -= 1;
+                next.start -= 1;
+                next.len += 1;
+                return;
+            }
+        }
+        self.extents.insert(p, Extent { index, len: 1, start: block, uninit: false });
+    }
+
+    /// Unmap logical blocks in `from..to`, returns disk blocks unmapped
+    fn remove(&mut self, from: usize, to: usize) -> Vec<usize> {
+        let mut removed = vec![];
+        let mut kept = vec![];
+        for e in self.extents.drain(..) {
+            let (l, r) = (max(e.index, from), min(e.end(), to));
+            if l >= r {
+                kept.push(e);
+                continue;
+            }
+            removed.extend(e.block(l)..e.block(r));
+            if e.index < l { kept.push(Extent { len: l - e.index, ..e }); }
+            if r < e.end() { kept.push(Extent { index: r, len: e.end() - r, start: e.block(r), ..e }); }
+        }
+        self.extents = kept;
+        removed
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([data[offset], data[offset + 1]]) as usize
+}
+
+fn le32(data: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+/// Write node header and `entries` to `data`
+fn node_write(data: &mut [u8], entries: &[(usize, [u8; EXT4_EXT_ENTRY_SIZE])], max_entries: usize, depth: usize) {
+    data[..2].copy_from_slice(&EXT4_EXT_MAGIC.to_le_bytes());
+    data[2..4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    data[4..6].copy_from_slice(&(max_entries as u16).to_le_bytes());
+    data[6..8].copy_from_slice(&(depth as u16).to_le_bytes());
+    data[8..12].fill(0);
+    for (i, (_, entry)) in entries.iter().enumerate() {
+        data[EXT4_EXT_ENTRY_SIZE * (i + 1)..][..EXT4_EXT_ENTRY_SIZE].copy_from_slice(entry);
+    }
+}
+
+fn extent_entry(e: &Extent) -> [u8; EXT4_EXT_ENTRY_SIZE] {
+    let mut entry = [0; EXT4_EXT_ENTRY_SIZE];
+    let len = if e.uninit { e.len + EXT4_EXT_INIT_MAX_LEN } else { e.len };
+    entry[..4].copy_from_slice(&(e.index as u32).to_le_bytes());
+    entry[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    entry[6..8].copy_from_slice(&((e.start >> 32) as u16).to_le_bytes());
+    entry[8..12].copy_from_slice(&(e.start as u32).to_le_bytes());
+    entry
+}
+
+fn index_entry(index: usize, block: usize) -> [u8; EXT4_EXT_ENTRY_SIZE] {
+    let mut entry = [0; EXT4_EXT_ENTRY_SIZE];
+    entry[..4].copy_from_slice(&(index as u32).to_le_bytes());
+    entry[4..8].copy_from_slice(&(block as u32).to_le_bytes());
+    entry[8..10].copy_from_slice(&((block >> 32) as u16).to_le_bytes());
+    entry
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn extent_enabled(&self) -> bool {
+        self.super_block.s_feature_incompat & EXT3_FEATURE_INCOMPAT_EXTENTS as u32 != 0
+    }
+
+    /// Give new inode an empty extent tree if `extent` feature is enabled
+    pub fn extent_init(&self, inode: &mut Ext2INode) {
+        if !self.extent_enabled() { return; }
+        let mut root = [0; EXT2_N_BLOCKS * 4];
+        node_write(&mut root, &[], EXT4_EXT_ROOT_ENTRIES, 0);
+        for (i, x) in root.chunks(4).enumerate() {
+            inode.i_block[i] = u32::from_le_bytes(x.try_into().unwrap());
+        }
+        inode.i_flags |= EXT4_EXTENTS_FL as u32;
+    }
+
+    fn extent_node_load(&mut self, ino: usize, data: &[u8], depth: Option<usize>, tree: &mut ExtentTree) -> Result<()> {
+        let (entries, max_entries, node_depth) = (le16(data, 2), le16(data, 4), le16(data, 6));
+        if le16(data, 0) != EXT4_EXT_MAGIC as usize || entries > max_entries ||
+            EXT4_EXT_ENTRY_SIZE * (max_entries + 1) > data.len() ||
+            node_depth > EXT4_EXT_MAX_DEPTH || depth.is_some_and(|d| d != node_depth) {
+            return Err(self.fs_error(format!("inode {} has bad extent node header", ino)));
+        }
+        for i in 1..=entries {
+            let entry = &data[EXT4_EXT_ENTRY_SIZE * i..][..EXT4_EXT_ENTRY_SIZE];
+            let index = le32(entry, 0);
+            if node_depth == 0 {
+                let (len, uninit) = match le16(entry, 4) {
+                    len if len > EXT4_EXT_INIT_MAX_LEN => (len - EXT4_EXT_INIT_MAX_LEN, true),
+                    len => (len, false),
+                };
+                let start = le16(entry, 6) << 32 | le32(entry, 8);
+                if len == 0 || !self.is_data_block(start) || !self.is_data_block(start + len - 1) {
+                    return Err(self.fs_error(format!("inode {} has bad extent {}+{} at {}", ino, start, len, index)));
+                }
+                tree.extents.push(Extent { index, len, start, uninit });
+            } else {
+                let block = le16(entry, 8) << 32 | le32(entry, 4);
+                if !self.is_data_block(block) {
+                    return Err(self.fs_error(format!("inode {} has bad extent node block {}", ino, block)));
+                }
+                let node = self.get_data_block(block)?;
+                self.extent_block_verify(ino, block, &node)?;
+                tree.nodes.push(block);
+                self.extent_node_load(ino, &node, Some(node_depth - 1), tree)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn extent_load(&mut self, ino: usize, inode: &Ext2INode) -> Result<ExtentTree> {
+        let root = unsafe { serialize_row(&inode.i_block) }.to_vec();
+        let mut tree = ExtentTree::default();
+        self.extent_node_load(ino, &root, None, &mut tree)?;
+        if tree.extents.windows(2).any(|x| x[0].end() > x[1].index) {
+            return Err(self.fs_error(format!("inode {} has unsorted extents", ino)));
+        }
+        Ok(tree)
+    }
+
+    /// Write `tree` to `inode` and node blocks, nodes are reused, allocated or released as needed.
+    /// `inode` should be saved by caller
+    pub(crate) fn extent_store(&mut self, ino: usize, inode: &mut Ext2INode, tree: ExtentTree) -> Result<()> {
+        let per_block = (self.block_size() - EXT4_EXT_ENTRY_SIZE) / EXT4_EXT_ENTRY_SIZE;
+        let mut unused = tree.nodes;
+        unused.reverse();
+        let mut level = tree.extents.iter().map(|e| (e.index, extent_entry(e))).collect::<Vec<_>>();
+        let mut depth = 0;
+        while level.len() > EXT4_EXT_ROOT_ENTRIES {
+            let mut upper = vec![];
+            for entries in level.chunks(per_block) {
+                let block = match unused.pop() {
+                    Some(block) => block,
+                    None => self.allocate_block_for(ino, inode)?,
+                };
+                let mut data = self.create_block_vec();
+                node_write(&mut data, entries, per_block, depth);
+                self.extent_tail_set(ino, &mut data)?;
+                self.write_meta_block(block, &data)?;
+                upper.push((entries[0].0, index_entry(entries[0].0, block)));
+            }
+            level = upper;
+            depth += 1;
+        }
+        for block in unused {
+            self.free_block_for(ino, inode, block)?;
+        }
+        let mut root = [0; EXT2_N_BLOCKS * 4];
+        node_write(&mut root, &level, EXT4_EXT_ROOT_ENTRIES, depth);
+        for (i, x) in root.chunks(4).enumerate() {
+            inode.i_block[i] = u32::from_le_bytes(x.try_into().unwrap());
+        }
+        Ok(())
+    }
+}
+
+/// ext4 extent tree rooted in `i_block`
+pub struct ExtentMap;
+
+impl<T: DiskDriver> BlockMap<T> for ExtentMap {
+    fn visit(&self, fs: &mut RFS<T>, ino: usize, block_index: usize,
+             f: &mut dyn FnMut(usize, usize) -> Result<(bool, bool)>) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        let mut tree = fs.extent_load(ino, &inode)?;
+        let mut modified = false;
+        let mut index = block_index;
+        let r = loop {
+            let mapped = tree.lookup(index);
+            let block = mapped.filter(|e| !e.uninit).map_or(0, |e| e.block(index));
+            let (next, allocate) = match f(block, index) {
+                Ok(r) => r,
+                Err(e) => break Err(e),
+            };
+            if allocate {
+                match mapped {
+                    // block of uninitialized extent is kept and marked written
+                    Some(e) => {
+                        tree.remove(index, index + 1);
+                        tree.insert(index, e.block(index));
+                    }
+                    None => match fs.allocate_block_for(ino, &mut inode) {
+                        Ok(block) => tree.insert(index, block),
+                        Err(e) => break Err(e),
+                    },
+                }
+                modified = true;
+                continue;
+            }
+            if !next { break Ok(()); }
+            index += 1;
+        };
+        // keep blocks allocated before reachable from this inode
+        if modified {
+            fs.extent_store(ino, &mut inode, tree)?;
+            fs.set_inode(ino, &inode)?;
+        }
+        r
+    }
+
+    fn end(&self, fs: &mut RFS<T>, ino: usize, inode: &Ext2INode) -> Result<usize> {
+        Ok(fs.extent_load(ino, inode)?.extents.last().map_or(0, |e| e.end()))
+    }
+
+    fn meta_blocks(&self, fs: &mut RFS<T>, ino: usize, inode: &Ext2INode) -> Result<Vec<usize>> {
+        Ok(fs.extent_load(ino, inode)?.nodes)
+    }
+
+    fn map(&self, fs: &mut RFS<T>, ino: usize, mappings: &[(usize, usize)]) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        let mut tree = fs.extent_load(ino, &inode)?;
+        for &(index, block) in mappings {
+            tree.remove(index, index + 1);
+            if block != 0 { tree.insert(index, block); }
+        }
+        fs.extent_store(ino, &mut inode, tree)?;
+        fs.set_inode(ino, &inode)
+    }
+
+    fn truncate(&self, fs: &mut RFS<T>, ino: usize, keep: usize) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        let mut tree = fs.extent_load(ino, &inode)?;
+        for block in tree.remove(keep, usize::MAX) {
+            fs.free_block_for(ino, &mut inode, block)?;
+        }
+        fs.extent_store(ino, &mut inode, tree)?;
+        fs.set_inode(ino, &inode)
+    }
+
+    fn relocate(&self, fs: &mut RFS<T>, ino: usize,
+                f: &mut dyn FnMut(&mut RFS<T>, usize) -> Result<usize>) -> Result<()> {
+        let mut inode = fs.get_inode(ino)?;
+        let tree = fs.extent_load(ino, &inode)?;
+        let mut moved = ExtentTree::default();
+        for block in tree.nodes {
+            moved.nodes.push(f(fs, block)?);
+        }
+        for e in tree.extents {
+            for index in e.index..e.end() {
+                let block = f(fs, e.block(index))?;
+                // uninitialized length has one bit less
+                match moved.extents.last_mut() {
+                    Some(last) if last.uninit == e.uninit && last.end() == index && last.block(index) == block &&
+                        last.len < EXT4_EXT_INIT_MAX_LEN - e.uninit as usize => last.len += 1,
+                    _ => moved.extents.push(Extent { index, len: 1, start: block, uninit: e.uninit }),
+                }
+            }
+        }
+        fs.extent_store(ino, &mut inode, moved)?;
+        fs.set_inode(ino, &inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_extent() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(4 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions { features: vec!["extent,metadata_csum".to_string()], ..Default::default() })?;
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let (other, _) = fs.make_node(EXT2_ROOT_INO, "other", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let bs = fs.block_size();
+        let free = fs.alloc().free_blocks_count;
+
+        // contiguous blocks are one extent
+        let data = (0..bs * 300).map(|x| (x / bs) as u8).collect::<Vec<_>>();
+        fs.rfs_write(ino as u64, 0, &data)?;
+        let inode = fs.get_inode(ino)?;
+        let tree = fs.extent_load(ino, &inode)?;
+        assert_eq!(tree.extents.len(), 1);
+        assert_eq!(tree.extents[0].len, 300);
+        assert_eq!(fs.alloc().free_blocks_count, free - 300);
+        assert_eq!(fs.rfs_read(ino as u64, 0, data.len() as u32)?, data);
+
+        // interleaved writes of two files need a deeper tree
+        for i in 0..200 {
+            fs.rfs_write(ino as u64, ((300 + i * 2) * bs) as i64, &vec![i as u8; bs])?;
+            fs.rfs_write(other as u64, (i * bs) as i64, &vec![1; bs])?;
+        }
+        let inode = fs.get_inode(ino)?;
+        let tree = fs.extent_load(ino, &inode)?;
+        assert_eq!(tree.extents.len(), 200);
+        assert_eq!(tree.nodes.len(), 3);
+        assert_eq!(inode.i_blocks as usize, (500 + 3) * bs / 512);
+        assert_eq!(fs.rfs_read(ino as u64, (302 * bs) as i64, bs as u32)?, vec![1; bs]);
+        assert_eq!(fs.rfs_read(ino as u64, (303 * bs) as i64, bs as u32)?, vec![0; bs]);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        // truncate releases blocks and nodes
+        fs.rfs_setattr(ino as u64, None, None, None, Some((100 * bs + 1) as u64), None, None, None, None, None)?;
+        let inode = fs.get_inode(ino)?;
+        assert_eq!(fs.extent_load(ino, &inode)?.nodes.len(), 0);
+        assert_eq!(inode.i_blocks as usize, 101 * bs / 512);
+        fs.rfs_unlink(EXT2_ROOT_INO, "other")?;
+        fs.rfs_unlink(EXT2_ROOT_INO, "file")?;
+        assert_eq!(fs.alloc().free_blocks_count, free);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+/// Typed super block feature flags, and the mount gate built on them.
+///
+/// Same rules as ext2 in kernel: unknown compat features are ignored, unknown
+/// ro_compat features force a read-only mount and unknown incompat features
+/// refuse mounting.
+use std::fmt::{Display, Formatter};
+use anyhow::{anyhow, Error, Result};
+use disk_driver::DiskDriver;
+use libc::EROFS;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::Errno;
+
+/// Names used by e2fsprogs, unknown bits are shown as `FEATURE_<kind><bit>`
+const COMPAT_NAMES: [(usize, &str); 11] = [
+    (EXT2_FEATURE_COMPAT_DIR_PREALLOC, "dir_prealloc"),
+    (EXT2_FEATURE_COMPAT_IMAGIC_INODES, "imagic_inodes"),
+    (EXT3_FEATURE_COMPAT_HAS_JOURNAL, "has_journal"),
+    (EXT2_FEATURE_COMPAT_EXT_ATTR, "ext_attr"),
+    (EXT2_FEATURE_COMPAT_RESIZE_INODE, "resize_inode"),
+    (EXT2_FEATURE_COMPAT_DIR_INDEX, "dir_index"),
+    (EXT2_FEATURE_COMPAT_LAZY_BG, "lazy_bg"),
+    (EXT2_FEATURE_COMPAT_EXCLUDE_BITMAP, "snapshot_bitmap"),
+    (EXT4_FEATURE_COMPAT_SPARSE_SUPER2, "sparse_super2"),
+    (EXT4_FEATURE_COMPAT_FAST_COMMIT, "fast_commit"),
+    (EXT4_FEATURE_COMPAT_STABLE_INODES, "stable_inodes"),
+];
+
+const INCOMPAT_NAMES: [(usize, &str); 16] = [
+    (EXT2_FEATURE_INCOMPAT_COMPRESSION, "compression"),
+    (EXT2_FEATURE_INCOMPAT_FILETYPE, "filetype"),
+    (EXT3_FEATURE_INCOMPAT_RECOVER, "needs_recovery"),
+    (EXT3_FEATURE_INCOMPAT_JOURNAL_DEV, "journal_dev"),
+    (EXT2_FEATURE_INCOMPAT_META_BG, "meta_bg"),
+    (EXT3_FEATURE_INCOMPAT_EXTENTS, "extent"),
+    (EXT4_FEATURE_INCOMPAT_64BIT, "64bit"),
+    (EXT4_FEATURE_INCOMPAT_MMP, "mmp"),
+    (EXT4_FEATURE_INCOMPAT_FLEX_BG, "flex_bg"),
+    (EXT4_FEATURE_INCOMPAT_EA_INODE, "ea_inode"),
+    (EXT4_FEATURE_INCOMPAT_DIRDATA, "dirdata"),
+    (EXT4_FEATURE_INCOMPAT_CSUM_SEED, "metadata_csum_seed"),
+    (EXT4_FEATURE_INCOMPAT_LARGEDIR, "large_dir"),
+    (EXT4_FEATURE_INCOMPAT_INLINE_DATA, "inline_data"),
+    (EXT4_FEATURE_INCOMPAT_ENCRYPT, "encrypt"),
+    (EXT4_FEATURE_INCOMPAT_CASEFOLD, "casefold"),
+];
+
+const RO_COMPAT_NAMES: [(usize, &str); 15] = [
+    (EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER, "sparse_super"),
+    (EXT2_FEATURE_RO_COMPAT_LARGE_FILE, "large_file"),
+    (EXT4_FEATURE_RO_COMPAT_HUGE_FILE, "huge_file"),
+    (EXT4_FEATURE_RO_COMPAT_GDT_CSUM, "uninit_bg"),
+    (EXT4_FEATURE_RO_COMPAT_DIR_NLINK, "dir_nlink"),
+    (EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE, "extra_isize"),
+    (EXT4_FEATURE_RO_COMPAT_HAS_SNAPSHOT, "snapshot"),
+    (EXT4_FEATURE_RO_COMPAT_QUOTA, "quota"),
+    (EXT4_FEATURE_RO_COMPAT_BIGALLOC, "bigalloc"),
+    (EXT4_FEATURE_RO_COMPAT_METADATA_CSUM, "metadata_csum"),
+    (EXT4_FEATURE_RO_COMPAT_REPLICA, "replica"),
+    (EXT4_FEATURE_RO_COMPAT_READONLY, "read-only"),
+    (EXT4_FEATURE_RO_COMPAT_PROJECT, "project"),
+    (EXT4_FEATURE_RO_COMPAT_SHARED_BLOCKS, "shared_blocks"),
+    (EXT4_FEATURE_RO_COMPAT_VERITY, "verity"),
+];
+
+/// Features rfs can handle, compat features are informational only
+pub const RFS_FEATURE_COMPAT_SUPP: usize = EXT3_FEATURE_COMPAT_HAS_JOURNAL | EXT2_FEATURE_COMPAT_EXT_ATTR |
+    EXT2_FEATURE_COMPAT_RESIZE_INODE | EXT2_FEATURE_COMPAT_DIR_INDEX;
+pub const RFS_FEATURE_INCOMPAT_SUPP: usize = EXT2_FEATURE_INCOMPAT_FILETYPE | EXT3_FEATURE_INCOMPAT_RECOVER |
+    EXT3_FEATURE_INCOMPAT_EXTENTS | EXT4_FEATURE_INCOMPAT_INLINE_DATA | EXT4_FEATURE_INCOMPAT_ENCRYPT;
+pub const RFS_FEATURE_RO_COMPAT_SUPP: usize = EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER | EXT2_FEATURE_RO_COMPAT_LARGE_FILE |
+    EXT4_FEATURE_RO_COMPAT_METADATA_CSUM | EXT4_FEATURE_RO_COMPAT_SHARED_BLOCKS;
+
+macro_rules! feature_set {
+    ($name:ident, $names:ident, $supp:ident, $kind:literal) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            pub fn contains(&self, flag: usize) -> bool {
+                self.0 & flag as u32 == flag as u32
+            }
+
+            /// Features not supported by rfs
+            pub fn unsupported(&self) -> Self {
+                Self(self.0 & !($supp as u32))
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.0 == 0
+            }
+
+            pub fn names(&self) -> Vec<String> {
+                (0..32).map(|bit| 1usize << bit).filter(|flag| self.contains(*flag))
+                    .map(|flag| match $names.iter().find(|x| x.0 == flag) {
+                        Some((_, name)) => name.to_string(),
+                        None => format!("FEATURE_{}{}", $kind, flag.trailing_zeros()),
+                    }).collect()
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                if self.is_empty() { f.write_str("(none)") } else { f.write_str(&self.names().join(" ")) }
+            }
+        }
+    };
+}
+
+feature_set!(CompatFeatures, COMPAT_NAMES, RFS_FEATURE_COMPAT_SUPP, "C");
+feature_set!(IncompatFeatures, INCOMPAT_NAMES, RFS_FEATURE_INCOMPAT_SUPP, "I");
+feature_set!(RoCompatFeatures, RO_COMPAT_NAMES, RFS_FEATURE_RO_COMPAT_SUPP, "R");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ext2Features {
+    pub compat: CompatFeatures,
+    pub incompat: IncompatFeatures,
+    pub ro_compat: RoCompatFeatures,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMode {
+    ReadWrite,
+    ReadOnly,
+}
+
+impl Ext2Features {
+    pub fn new(compat: u32, incompat: u32, ro_compat: u32) -> Self {
+        Self { compat: CompatFeatures(compat), incompat: IncompatFeatures(incompat), ro_compat: RoCompatFeatures(ro_compat) }
+    }
+
+    /// Decide how to mount, refuse if incompat features are unknown
+    pub fn mount_mode(&self) -> Result<MountMode> {
+        if self.incompat.contains(EXT3_FEATURE_INCOMPAT_RECOVER) && !self.compat.contains(EXT3_FEATURE_COMPAT_HAS_JOURNAL) {
+            return Err(anyhow!("needs_recovery is set without has_journal"));
+        }
+        let incompat = self.incompat.unsupported();
+        if !incompat.is_empty() {
+            return Err(anyhow!("unsupported incompat features: {}", incompat));
+        }
+        if self.ro_compat.unsupported().is_empty() { Ok(MountMode::ReadWrite) } else { Ok(MountMode::ReadOnly) }
+    }
+}
+
+impl From<&Ext2SuperBlock> for Ext2Features {
+    fn from(sb: &Ext2SuperBlock) -> Self {
+        Self::new(sb.s_feature_compat, sb.s_feature_incompat, sb.s_feature_ro_compat)
+    }
+}
+
+impl Display for Ext2Features {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "compat: {}, incompat: {}, ro_compat: {}", self.compat, self.incompat, self.ro_compat)
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn features(&self) -> Ext2Features {
+        Ext2Features::new(self.super_block.s_feature_compat, self.super_block.s_feature_incompat,
+                          self.super_block.s_feature_ro_compat)
+    }
+
+    /// Check features of super block before loading, set `read_only` if needed
+    pub fn features_check(&mut self, super_block: &Ext2SuperBlock) -> Result<()> {
+        let features = Ext2Features::from(super_block);
+        info!("features: {}", features);
+        let compat = features.compat.unsupported();
+        if !compat.is_empty() {
+            info!("ignore unsupported compat features: {}", compat);
+        }
+        match features.mount_mode() {
+            Ok(MountMode::ReadWrite) => Ok(()),
+            Ok(MountMode::ReadOnly) => {
+                warn!("unsupported ro_compat features: {}, mount read-only", features.ro_compat.unsupported());
+                self.read_only = true;
+                Ok(())
+            }
+            Err(e) => {
+                error!("refuse to mount: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Fails with EROFS if mounted read-only
+    pub fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(Error::new(Errno(EROFS)).context("filesystem is read-only"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::utils::get_errno;
+
+    #[test]
+    fn test_features() -> Result<()> {
+        let features = Ext2Features::new(0x38, 0x2, 0x3 | 0x80000000);
+        assert_eq!(features.to_string(), "compat: ext_attr resize_inode dir_index, incompat: filetype, ro_compat: sparse_super large_file FEATURE_R31");
+        assert_eq!(features.mount_mode()?, MountMode::ReadOnly);
+        assert!(Ext2Features::new(0, EXT4_FEATURE_INCOMPAT_MMP as u32, 0).mount_mode().is_err());
+        assert!(Ext2Features::new(0, EXT3_FEATURE_INCOMPAT_RECOVER as u32, 0).mount_mode().is_err());
+
+        let mut fs = crate::rfs_lib::test_fs()?;
+        fs.super_block.s_feature_ro_compat |= EXT4_FEATURE_RO_COMPAT_REPLICA as u32;
+        fs.rfs_dump()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert!(fs.read_only);
+        let e = fs.transaction(|fs| fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)).unwrap_err();
+        assert_eq!(get_errno(&e, 0), EROFS);
+
+        fs.read_only = false;
+        fs.super_block.s_feature_incompat |= EXT4_FEATURE_INCOMPAT_64BIT as u32;
+        fs.rfs_dump()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        assert!(fs.rfs_init("mem").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+/// Offline consistency checker and repairer, like `e2fsck`.
+///
+/// Walks every inode from root by directory blocks and `visit_blocks_inode`,
+/// then compares what is found with bitmaps, link counts and super block counters.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::cmp::min;
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+use anyhow::Result;
+use disk_driver::DiskDriver;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::reflink::RFS_REFCOUNT_INO;
+use crate::rfs_lib::utils::deserialize_row;
+
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// Directory block has broken `rec_len` chain starting at `offset`
+    RecLen { dir: usize, block: usize, offset: usize },
+    /// Directory block checksum tail is missing or wrong
+    DirChecksum { dir: usize, block: usize },
+    /// Block number out of range or inside metadata area
+    BadBlock { ino: usize, block: usize },
+    /// Block claimed more than once
+    DuplicateBlock { block: usize, inodes: Vec<usize> },
+    /// Owners of shared data block differ from refcount file
+    Refcount { block: usize, found: u32, counted: u32 },
+    /// Inode in use but not reachable from root
+    Orphan { ino: usize },
+    LinkCount { ino: usize, found: u16, counted: u16 },
+    /// Bitmap bit differs from usage, `used` is the real state
+    BlockBitmap { block: usize, used: bool },
+    InodeBitmap { ino: usize, used: bool },
+    FreeBlocksCount { found: u32, counted: u32 },
+    FreeInodesCount { found: u32, counted: u32 },
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsckProblem::RecLen { dir, block, offset } =>
+                write!(f, "directory {} block {} has invalid rec_len at offset {}", dir, block, offset),
+            FsckProblem::DirChecksum { dir, block } =>
+                write!(f, "directory {} block {} has wrong checksum", dir, block),
+            FsckProblem::BadBlock { ino, block } =>
+                write!(f, "inode {} has illegal block {}", ino, block),
+            FsckProblem::DuplicateBlock { block, inodes } =>
+                write!(f, "block {} is claimed by inodes {:?}", block, inodes),
+            FsckProblem::Refcount { block, found, counted } =>
+                write!(f, "block {} ref count is {}, should be {}", block, found, counted),
+            FsckProblem::Orphan { ino } =>
+                write!(f, "inode {} is not connected to any directory", ino),
+            FsckProblem::LinkCount { ino, found, counted } =>
+                write!(f, "inode {} ref count is {}, should be {}", ino, found, counted),
+            FsckProblem::BlockBitmap { block, used } =>
+                write!(f, "block {} is {} but marked {} in bitmap", block,
+                       if *used { "used" } else { "free" }, if *used { "free" } else { "used" }),
+            FsckProblem::InodeBitmap { ino, used } =>
+                write!(f, "inode {} is {} but marked {} in bitmap", ino,
+                       if *used { "used" } else { "free" }, if *used { "free" } else { "used" }),
+            FsckProblem::FreeBlocksCount { found, counted } =>
+                write!(f, "free blocks count wrong ({}, counted={})", found, counted),
+            FsckProblem::FreeInodesCount { found, counted } =>
+                write!(f, "free inodes count wrong ({}, counted={})", found, counted),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    /// Problems are repaired and written back
+    pub repaired: bool,
+    pub inodes: usize,
+    pub blocks: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for FsckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for p in self.problems.iter() {
+            writeln!(f, "{}{}", p, if self.repaired { ", fixed" } else { "" })?;
+        }
+        writeln!(f, "{} problems, {} inodes, {} blocks in use", self.problems.len(), self.inodes, self.blocks)
+    }
+}
+
+/// Result of walking the filesystem once
+#[derive(Debug, Default)]
+struct FsckScan {
+    /// Directory entries referring each inode, including "." and ".."
+    refs: BTreeMap<usize, u16>,
+    /// Inodes marked used in bitmap but not reachable
+    orphans: Vec<usize>,
+    /// All inodes in use
+    used: BTreeSet<usize>,
+    /// Owners of each block, with logical index for data blocks
+    claims: BTreeMap<usize, Vec<(usize, Option<usize>)>>,
+}
+
+impl<T: DiskDriver> RFS<T> {
+    fn fsck_is_dir(inode: &Ext2INode) -> bool {
+        inode.i_mode as usize >> 12 == Ext2FileType::Directory.into()
+    }
+
+    fn fsck_block_valid(&self, block: usize) -> bool {
+        self.is_data_block(block)
+    }
+
+    /// Set one block pointer of inode, `index` is logical block index
+    fn fsck_set_block(&mut self, ino: usize, index: usize, block: usize) -> Result<()> {
+        let inode = self.get_inode(ino)?;
+        Self::block_map(&inode).map(self, ino, &[(index, block)])
+    }
+
+    /// Check index blocks, bad ones are reported and dropped when repairing
+    fn fsck_index_blocks(&mut self, ino: usize, report: &mut FsckReport, repair: bool) -> Result<Vec<usize>> {
+        let mut inode = self.get_inode(ino)?;
+        // extent nodes are checked when loading
+        if inode.i_flags & EXT4_EXTENTS_FL as u32 != 0 { return self.index_blocks(ino, &inode); }
+        let mut modified = false;
+        for i in 12..15 {
+            let block = inode.i_block[i] as usize;
+            if block != 0 && !self.fsck_block_valid(block) {
+                report.problems.push(FsckProblem::BadBlock { ino, block });
+                inode.i_block[i] = 0;
+                modified = true;
+            }
+        }
+        if inode.i_block[13] != 0 {
+            let table = inode.i_block[13] as usize;
+            let mut data = self.get_data_block(table)?;
+            let mut table_modified = false;
+            for p in (0..data.len()).step_by(4) {
+                let block = u32::from_le_bytes(data[p..p + 4].try_into().unwrap()) as usize;
+                if block != 0 && !self.fsck_block_valid(block) {
+                    report.problems.push(FsckProblem::BadBlock { ino, block });
+                    data[p..p + 4].fill(0);
+                    table_modified = true;
+                }
+            }
+            if table_modified && repair { self.write_meta_block(table, &data)?; }
+        }
+        if !modified {
+            return self.index_blocks(ino, &inode);
+        }
+        if repair { self.set_inode(ino, &inode)?; }
+        // do not follow bad pointers even if not repairing
+        self.index_blocks(ino, &inode)
+    }
+
+    /// Data blocks with logical index, holes skipped
+    fn fsck_data_blocks(&mut self, ino: usize, report: &mut FsckReport, repair: bool) -> Result<Vec<(usize, usize)>> {
+        let inode = self.get_inode(ino)?;
+        if inode.i_mode as usize >> 12 == Ext2FileType::Symlink.into() && inode.i_blocks == 0 {
+            // fast symlink keeps path in i_block
+            return Ok(vec![]);
+        }
+        let covered = self.blocks_end(ino, &inode)?;
+        let mut blocks = vec![];
+        self.visit_blocks_inode(ino, 0, &mut |block, index| {
+            if block != 0 { blocks.push((block, index)); }
+            Ok((index + 1 < covered, false))
+        })?;
+        let mut valid = vec![];
+        for (block, index) in blocks {
+            if self.fsck_block_valid(block) {
+                valid.push((block, index));
+            } else {
+                report.problems.push(FsckProblem::BadBlock { ino, block });
+                if repair { self.fsck_set_block(ino, index, 0)?; }
+            }
+        }
+        Ok(valid)
+    }
+
+    /// Check `rec_len` chain and checksum of one directory block, returns entries before broken place
+    fn fsck_dir_block(&mut self, dir: usize, block: usize, report: &mut FsckReport, repair: bool) -> Result<Vec<Ext2DirEntry>> {
+        let mut data = self.get_data_block(block)?;
+        let sz = self.dir_block_space();
+        let mut p = 0;
+        let mut last = None;
+        let mut entries = vec![];
+        if self.metadata_csum() {
+            let mut expected = data.clone();
+            self.dir_tail_set(dir, &mut expected)?;
+            if expected[sz..] != data[sz..] {
+                report.problems.push(FsckProblem::DirChecksum { dir, block });
+                if repair { data[sz..].copy_from_slice(&expected[sz..]); }
+            }
+        }
+        while p < sz {
+            let valid = p + 8 <= sz && {
+                let rec_len = u16::from_le_bytes([data[p + 4], data[p + 5]]) as usize;
+                rec_len >= 8 && rec_len.is_multiple_of(4) && p + rec_len <= sz && 8 + data[p + 6] as usize <= rec_len
+            };
+            if !valid {
+                report.problems.push(FsckProblem::RecLen { dir, block, offset: p });
+                if repair {
+                    // drop entries from here
+                    match last {
+                        Some(l) => data[l + 4..l + 6].copy_from_slice(&((sz - l) as u16).to_le_bytes()),
+                        None => {
+                            data[..8].fill(0);
+                            data[4..6].copy_from_slice(&(sz as u16).to_le_bytes());
+                        }
+                    }
+                    self.dir_tail_set(dir, &mut data)?;
+                    self.write_meta_block(block, &data)?;
+                    self.dentry_cache.invalidate(dir);
+                }
+                return Ok(entries);
+            }
+            let mut raw = [0; size_of::<Ext2DirEntry>()];
+            let n = min(raw.len(), data.len() - p);
+            raw[..n].copy_from_slice(&data[p..p + n]);
+            let entry: Ext2DirEntry = unsafe { deserialize_row(&raw) };
+            if entry.inode != 0 { entries.push(entry); }
+            last = Some(p);
+            p += u16::from_le_bytes([data[p + 4], data[p + 5]]) as usize;
+        }
+        if repair && report.problems.last() == Some(&FsckProblem::DirChecksum { dir, block }) {
+            self.write_meta_block(block, &data)?;
+        }
+        Ok(entries)
+    }
+
+    /// Inodes in use which are not linked in directories
+    fn fsck_reserved_inodes(&self) -> Vec<usize> {
+        let refcount = if self.reflink_enabled() { RFS_REFCOUNT_INO as u32 } else { 0 };
+        [self.super_block.s_usr_quota_inum, self.super_block.s_grp_quota_inum, self.super_block.s_journal_inum, refcount]
+            .into_iter().filter(|x| *x != 0).map(|x| x as usize).collect()
+    }
+
+    fn fsck_scan(&mut self, report: &mut FsckReport, repair: bool) -> Result<FsckScan> {
+        let mut scan = FsckScan::default();
+        let inodes_count = self.super_block.s_inodes_count as usize;
+        // walk directories from root
+        let mut queue = VecDeque::from([EXT2_ROOT_INO]);
+        scan.used.insert(EXT2_ROOT_INO);
+        let mut data_blocks = BTreeMap::new();
+        while let Some(dir) = queue.pop_front() {
+            let blocks = self.fsck_data_blocks(dir, report, repair)?;
+            let mut entries = vec![];
+            for (block, _) in blocks.iter() {
+                entries.extend(self.fsck_dir_block(dir, *block, report, repair)?);
+            }
+            data_blocks.insert(dir, blocks);
+            for e in entries {
+                let ino = e.inode as usize;
+                if ino == 0 || ino > inodes_count { continue; }
+                *scan.refs.entry(ino).or_insert(0) += 1;
+                let name = e.get_name();
+                if name == "." || name == ".." || scan.used.contains(&ino) { continue; }
+                scan.used.insert(ino);
+                if Self::fsck_is_dir(&self.get_inode(ino)?) { queue.push_back(ino); }
+            }
+        }
+        for ino in self.fsck_reserved_inodes() {
+            scan.used.insert(ino);
+        }
+        let first_ino = self.super_block.s_first_ino as usize;
+        for ino in first_ino..=inodes_count {
+            if scan.used.contains(&ino) || !Self::bitmap_get(&self.alloc().bitmap_inode, ino) { continue; }
+            if self.get_inode(ino)?.i_mode != 0 {
+                scan.orphans.push(ino);
+                scan.used.insert(ino);
+            }
+        }
+        // block claims
+        for ino in scan.used.clone() {
+            let blocks = match data_blocks.remove(&ino) {
+                Some(b) => b,
+                None => self.fsck_data_blocks(ino, report, repair)?,
+            };
+            for (block, index) in blocks {
+                scan.claims.entry(block).or_insert_with(Vec::new).push((ino, Some(index)));
+            }
+            for block in self.fsck_index_blocks(ino, report, repair)? {
+                scan.claims.entry(block).or_insert_with(Vec::new).push((ino, None));
+            }
+        }
+        // other blocks of resize inode are reserved group desc blocks in metadata area
+        if self.super_block.s_feature_compat & EXT2_FEATURE_COMPAT_RESIZE_INODE as u32 != 0 {
+            let block = self.get_inode(EXT2_RESIZE_INO)?.i_block[EXT2_DIND_BLOCK] as usize;
+            if self.is_data_block(block) {
+                scan.claims.entry(block).or_insert_with(Vec::new).push((EXT2_RESIZE_INO, None));
+            }
+        }
+        Ok(scan)
+    }
+
+    /// Give each later claimer of a duplicate block its own copy
+    fn fsck_clone_block(&mut self, block: usize, owners: &[(usize, Option<usize>)]) -> Result<()> {
+        let data = self.get_data_block(block)?;
+        for (ino, index) in owners.iter().skip(1) {
+            match index {
+                Some(index) => {
+                    let new_block = self.allocate_block()?;
+                    self.write_data_block(new_block, &data)?;
+                    self.fsck_set_block(*ino, *index, new_block)?;
+                    debug!("clone block {} to {} for inode {}", block, new_block, ino);
+                }
+                None => warn!("index block {} of inode {} is shared, not cloned", block, ino),
+            }
+        }
+        Ok(())
+    }
+
+    /// Link orphan to lost+found, named by its inode number
+    fn fsck_reconnect(&mut self, ino: usize) -> Result<()> {
+        let lost = match self.rfs_lookup(EXT2_ROOT_INO, LOST_AND_FOUND) {
+            Ok((lost, _)) => lost,
+            Err(_) => self.make_node(EXT2_ROOT_INO, LOST_AND_FOUND, 0o700, Ext2FileType::Directory, 0, 0)?.0,
+        };
+        let inode = self.get_inode(ino)?;
+        let file_type = Ext2FileType::try_from(inode.i_mode as usize >> 12).unwrap_or(Ext2FileType::Unknown);
+        self.add_dir_entry(lost, Ext2DirEntry::new(&format!("#{}", ino), ino, file_type.dir_entry_type()))?;
+        if Self::fsck_is_dir(&inode) {
+            let mut entries = self.get_dir_entries(ino)?;
+            for e in entries.iter_mut().filter(|x| x.get_name() == "..") {
+                e.inode = lost as u32;
+            }
+            self.format_directory_entries(&mut entries)?;
+            self.apply_directory_entries(ino, &entries, 0)?;
+            self.add_links(lost, 1)?;
+        }
+        info!("inode {} connected to /{}/#{}", ino, LOST_AND_FOUND, ino);
+        Ok(())
+    }
+
+    /// Check whole filesystem, and repair found problems if `repair`
+    pub fn rfs_fsck(&mut self, repair: bool) -> Result<FsckReport> {
+        if repair { self.check_writable()?; }
+        let mut report = FsckReport { repaired: repair, ..Default::default() };
+        let mut scan = self.fsck_scan(&mut report, repair)?;
+
+        // used blocks and inodes must be allocated before any repair allocates
+        for block in scan.claims.keys().copied().collect::<Vec<_>>() {
+            let bit = self.block_bit(block);
+            if !Self::bitmap_get(&self.alloc().bitmap_data, bit) {
+                report.problems.push(FsckProblem::BlockBitmap { block, used: true });
+                if repair { Self::bitmap_set(&mut self.alloc().bitmap_data, bit); }
+            }
+        }
+        for ino in scan.used.iter().copied() {
+            if !Self::bitmap_get(&self.alloc().bitmap_inode, ino) {
+                report.problems.push(FsckProblem::InodeBitmap { ino, used: true });
+                if repair { Self::bitmap_set(&mut self.alloc().bitmap_inode, ino); }
+            }
+        }
+
+        let mut rescan = false;
+        let shared_enabled = self.reflink_enabled();
+        for (block, owners) in scan.claims.iter().filter(|x| x.1.len() > 1) {
+            // data blocks may be shared, owners are counted in refcount file
+            if shared_enabled && owners.iter().all(|x| x.1.is_some()) {
+                let (found, counted) = (self.refcounts.get(*block), owners.len() as u32);
+                if found != counted {
+                    report.problems.push(FsckProblem::Refcount { block: *block, found, counted });
+                    if repair { self.refcounts.set(*block, counted); }
+                }
+                continue;
+            }
+            let inodes = owners.iter().map(|x| x.0).collect();
+            report.problems.push(FsckProblem::DuplicateBlock { block: *block, inodes });
+            if repair {
+                self.fsck_clone_block(*block, owners)?;
+                rescan = true;
+            }
+        }
+        let unshared = self.refcounts.counts.keys().map(|x| *x as usize)
+            .filter(|x| scan.claims.get(x).map_or(0, |o| o.len()) < 2).collect::<Vec<_>>();
+        for block in unshared {
+            let counted = scan.claims.get(&block).map_or(0, |o| o.len()) as u32;
+            report.problems.push(FsckProblem::Refcount { block, found: self.refcounts.get(block), counted });
+            if repair { self.refcounts.set(block, counted); }
+        }
+        for ino in scan.orphans.iter().copied() {
+            report.problems.push(FsckProblem::Orphan { ino });
+            if repair {
+                self.fsck_reconnect(ino)?;
+                rescan = true;
+            }
+        }
+        if rescan {
+            scan = self.fsck_scan(&mut report, repair)?;
+            for block in scan.claims.keys().copied().collect::<Vec<_>>() {
+                let bit = self.block_bit(block);
+                Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+            }
+        }
+
+        let reserved = self.fsck_reserved_inodes();
+        for ino in scan.used.iter().copied() {
+            if reserved.contains(&ino) || (!repair && scan.orphans.contains(&ino)) { continue; }
+            let mut inode = self.get_inode(ino)?;
+            let counted = scan.refs.get(&ino).copied().unwrap_or(0);
+            if inode.i_links_count != counted {
+                report.problems.push(FsckProblem::LinkCount { ino, found: inode.i_links_count, counted });
+                if repair {
+                    inode.i_links_count = counted;
+                    self.set_inode(ino, &inode)?;
+                }
+            }
+        }
+
+        let data_start = self.data_start_block();
+        let blocks_count = self.super_block.s_blocks_count as usize;
+        let data_blocks = (data_start..blocks_count).filter(|x| self.is_data_block(*x)).collect::<Vec<_>>();
+        for block in data_blocks.iter().copied() {
+            let bit = self.block_bit(block);
+            if Self::bitmap_get(&self.alloc().bitmap_data, bit) && !scan.claims.contains_key(&block) {
+                report.problems.push(FsckProblem::BlockBitmap { block, used: false });
+                if repair { Self::bitmap_unset(&mut self.alloc().bitmap_data, bit); }
+            }
+        }
+        let first_ino = self.super_block.s_first_ino as usize;
+        for ino in first_ino..=self.super_block.s_inodes_count as usize {
+            if Self::bitmap_get(&self.alloc().bitmap_inode, ino) && !scan.used.contains(&ino) {
+                report.problems.push(FsckProblem::InodeBitmap { ino, used: false });
+                if repair { Self::bitmap_unset(&mut self.alloc().bitmap_inode, ino); }
+            }
+        }
+
+        let used_blocks = scan.claims.keys().filter(|x| self.is_data_block(**x)).count();
+        let used_inodes = scan.used.iter().filter(|x| **x >= first_ino).count();
+        let free_blocks = (data_blocks.len() - used_blocks) as u32;
+        let free_inodes = (self.super_block.s_inodes_count as usize - (first_ino - 1) - used_inodes) as u32;
+        if self.alloc().free_blocks_count != free_blocks {
+            report.problems.push(FsckProblem::FreeBlocksCount { found: self.alloc().free_blocks_count, counted: free_blocks });
+            if repair { self.alloc().free_blocks_count = free_blocks; }
+        }
+        if self.alloc().free_inodes_count != free_inodes {
+            report.problems.push(FsckProblem::FreeInodesCount { found: self.alloc().free_inodes_count, counted: free_inodes });
+            if repair { self.alloc().free_inodes_count = free_inodes; }
+        }
+        if repair {
+            // free counters of groups are synced from bitmaps when dumping
+            for gd in self.alloc().group_desc_table.iter_mut() { gd.bg_used_dirs_count = 0; }
+            for ino in scan.used.iter().copied() {
+                if self.get_inode(ino).is_ok_and(|i| Self::fsck_is_dir(&i)) {
+                    let group = self.inode_group(ino);
+                    self.alloc().group_desc_table[group].bg_used_dirs_count += 1;
+                }
+            }
+            // errors are fixed, and this mount counts as checked
+            self.super_block.s_state &= !(EXT2_ERROR_FS as u16);
+            self.super_block.s_mnt_count = 0;
+            self.super_block.s_lastcheck = get_time_now();
+            self.rfs_dump()?;
+        }
+        report.inodes = scan.used.len();
+        report.blocks = scan.claims.len();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fsck() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let (dir, _) = fs.make_node(EXT2_ROOT_INO, "dir", 0o755, Ext2FileType::Directory, 0, 0)?;
+        for i in 0..100 {
+            fs.make_node(dir, &format!("file-{}", i), 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        }
+        let (a, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(a as u64, 0, &vec![1; fs.block_size() * 20])?;
+        let (b, _) = fs.make_node(dir, "b", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(b as u64, 0, &vec![2; fs.block_size()])?;
+        fs.rfs_unlink(dir, "file-0")?;
+        fs.rfs_rename(dir, "file-1", EXT2_ROOT_INO, "c")?;
+        fs.make_node(dir, "sub", 0o755, Ext2FileType::Directory, 0, 0)?;
+        fs.rfs_rename(dir, "sub", EXT2_ROOT_INO, "sub")?;
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+
+        // break it
+        let block_a = fs.get_inode(a)?.i_block[0];
+        let mut inode_b = fs.get_inode(b)?;
+        let block_b = inode_b.i_block[0] as usize;
+        inode_b.i_block[0] = block_a;
+        inode_b.i_links_count = 5;
+        fs.set_inode(b, &inode_b)?;
+        let lost = fs.remove_dir_entry(dir, "file-2")?.inode as usize;
+        let dir_block = fs.get_inode(dir)?.i_block[0] as usize;
+        let mut data = fs.get_data_block(dir_block)?;
+        data[12 + 4..12 + 6].copy_from_slice(&3u16.to_le_bytes());
+        fs.write_data_block(dir_block, &data)?;
+        fs.alloc().free_inodes_count += 3;
+
+        let report = fs.rfs_fsck(false)?;
+        for p in [
+            FsckProblem::DuplicateBlock { block: block_a as usize, inodes: vec![a, b] },
+            FsckProblem::LinkCount { ino: b, found: 5, counted: 1 },
+            FsckProblem::Orphan { ino: lost },
+            FsckProblem::RecLen { dir, block: dir_block, offset: 12 },
+            FsckProblem::BlockBitmap { block: block_b, used: false },
+        ] {
+            assert!(report.problems.contains(&p), "{:?} not found in {}", p, report);
+        }
+        assert!(report.problems.iter().any(|x| matches!(x, FsckProblem::FreeInodesCount { .. })));
+
+        let report = fs.rfs_fsck(true)?;
+        assert!(!report.is_clean());
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        let (lost_found, _) = fs.rfs_lookup(EXT2_ROOT_INO, LOST_AND_FOUND)?;
+        assert_eq!(fs.rfs_lookup(lost_found, &format!("#{}", lost))?.0, lost);
+        assert_ne!(fs.get_inode(b)?.i_block[0], block_a);
+        Ok(())
+    }
+}
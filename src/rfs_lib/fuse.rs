@@ -1,42 +1,131 @@
-/// FUSE operations.
+/// FUSE operations, served by `RfsServer` with locks of `server`.
 use std::ffi::OsStr;
+use std::mem::size_of;
 use std::path::Path;
 use zerocopy::AsBytes;
 use std::time::SystemTime;
 use disk_driver::DiskDriver;
-use fuser::{Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyWrite, Request, TimeOrNow};
-use libc::{c_int, ENOENT};
+use fuser::{Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyStatfs, ReplyWrite, Request, TimeOrNow};
+use libc::{c_int, EINVAL, ENOENT, ENOTTY};
 use log::*;
-use crate::rfs_lib::desc::Ext2FileType;
-use crate::rfs_lib::{TTL, RFS, DEVICE_FILE};
+use crate::rfs_lib::desc::{Ext2FileType, EXT2_NAME_LEN};
+use crate::rfs_lib::reflink::RFS_IOC_CLONE;
+use crate::rfs_lib::compress::{RFS_IOC_GETFLAGS, RFS_IOC_SETFLAGS};
+use crate::rfs_lib::crypt::{RfsKeyArg, RFS_IOC_ADD_KEY, RFS_IOC_REMOVE_KEY, RFS_IOC_SET_POLICY};
+use crate::rfs_lib::resize::RFS_IOC_GROW;
+use crate::rfs_lib::TTL;
+use crate::rfs_lib::server::{RfsServer, SharedRfs};
 use crate::rfs_lib::utils::*;
 
-impl<T: DiskDriver> Filesystem for RFS<T> {
+/// Run an ioctl holding the inode or all of filesystem
+fn rfs_ioctl<T: DiskDriver>(fs: &mut SharedRfs<T>, ino: u64, cmd: u32, in_data: &[u8], reply: ReplyIoctl) {
+    if cmd == RFS_IOC_GETFLAGS {
+        rep!(reply, flags, fs.rfs_get_flags(ino));
+        reply.ioctl(0, &flags.to_ne_bytes());
+        return;
+    }
+    if cmd == RFS_IOC_SETFLAGS {
+        let flags = match in_data.get(..4).map(|x| u32::from_ne_bytes(x.try_into().unwrap())) {
+            Some(v) => v,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        rep!(reply, fs.transaction(|fs| fs.rfs_set_flags(ino, flags)));
+        reply.ioctl(0, &[]);
+        return;
+    }
+    if cmd == RFS_IOC_ADD_KEY {
+        if in_data.len() != size_of::<RfsKeyArg>() {
+            reply.error(EINVAL);
+            return;
+        }
+        let arg: RfsKeyArg = unsafe { deserialize_row(in_data) };
+        let raw = &arg.raw[..(arg.size as usize).min(arg.raw.len())];
+        rep!(reply, fs.rfs_add_key(raw));
+        reply.ioctl(0, &[]);
+        return;
+    }
+    if cmd == RFS_IOC_REMOVE_KEY || cmd == RFS_IOC_SET_POLICY {
+        let id = match in_data.try_into() {
+            Ok(v) => v,
+            Err(_) => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if cmd == RFS_IOC_REMOVE_KEY {
+            rep!(reply, fs.rfs_remove_key(&id));
+        } else {
+            rep!(reply, fs.transaction(|fs| fs.rfs_set_policy(ino, &id)));
+        }
+        reply.ioctl(0, &[]);
+        return;
+    }
+    if cmd == RFS_IOC_GROW {
+        rep!(reply, blocks, fs.transaction(|fs| fs.rfs_grow()));
+        reply.ioctl(0, &(blocks as u64).to_ne_bytes());
+        return;
+    }
+    if cmd != RFS_IOC_CLONE {
+        reply.error(ENOTTY);
+        return;
+    }
+    let src = match in_data.try_into() {
+        Ok(v) => u64::from_ne_bytes(v),
+        Err(_) => {
+            reply.error(EINVAL);
+            return;
+        }
+    };
+    rep!(reply, fs.transaction(|fs| fs.rfs_clone(src, ino)));
+    reply.ioctl(0, &[]);
+}
+
+impl<T: DiskDriver + Send + 'static> Filesystem for RfsServer<T> {
     fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
-        let file = DEVICE_FILE.read().unwrap().clone();
-        ret(self.rfs_init(&file))
+        let mut fs = self.shared.fs.lock().unwrap();
+        let file = fs.config.device.clone();
+        ret(fs.rfs_init(&file))
     }
 
     fn destroy(&mut self) {
-        self.rfs_destroy().unwrap();
+        self.join();
+        self.shared.fs.lock().unwrap().rfs_destroy().unwrap();
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        self.spawn(move |s| {
+            let alloc = s.alloc.lock().unwrap();
+            let free = alloc.free_blocks_count as u64;
+            let bs = alloc.block_size;
+            reply.statfs(alloc.blocks_count as u64, free, free.saturating_sub(alloc.r_blocks_count as u64),
+                         alloc.inodes_count as u64, alloc.free_inodes_count as u64, bs, EXT2_NAME_LEN as u32, bs);
+        });
     }
 
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
         prv!("lookup", parent, name);
-        rep!(reply, r, self.rfs_lookup(parent as usize, name.to_str().unwrap()));
-        let (ino, inode) = r;
-        let attr = inode.to_attr(ino as usize, self.block_size());
-        debug!("file {} found! attr: {:?}", name.to_str().unwrap(), attr);
-        reply.entry(&TTL, &attr, 0);
+        let name = name.to_str().unwrap().to_string();
+        self.spawn(move |s| s.run(&[parent], &[], |fs| {
+            rep!(reply, r, fs.rfs_lookup(parent as usize, &name));
+            let (ino, inode) = r;
+            let attr = inode.to_attr(ino as usize, fs.block_size());
+            debug!("file {} found! attr: {:?}", name, attr);
+            reply.entry(&TTL, &attr, 0);
+        }));
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
         prv!("getattr", ino);
-        let ino = RFS::<T>::shift_ino(ino as usize);
-        rep!(reply, node, self.get_inode(ino));
-        let attr = node.to_attr(ino, self.block_size());
-        prv!(attr);
-        reply.attr(&TTL, &attr);
+        self.spawn(move |s| s.run(&[ino], &[], |fs| {
+            let ino = SharedRfs::<T>::shift_ino(ino as usize);
+            rep!(reply, node, fs.get_inode(ino));
+            let attr = node.to_attr(ino, fs.block_size());
+            prv!(attr);
+            reply.attr(&TTL, &attr);
+        }));
     }
 
     fn setattr(&mut self, _req: &Request<'_>, ino: u64, mode: Option<u32>,
@@ -45,111 +134,174 @@ impl<T: DiskDriver> Filesystem for RFS<T> {
                _fh: Option<u64>, _crtime: Option<SystemTime>, chgtime: Option<SystemTime>,
                bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
         prv!("setattr", ino, atime, mtime, size);
-        rep!(reply, node, self.rfs_setattr(ino, mode, uid, gid, size,
-            time_or_now_convert(atime), time_or_now_convert(mtime), chgtime, bkuptime, flags));
-        let attr = node.to_attr(ino as usize, self.block_size());
-        reply.attr(&TTL, &attr);
+        let (atime, mtime) = (time_or_now_convert(atime), time_or_now_convert(mtime));
+        self.spawn(move |s| s.run(&[], &[ino], |fs| {
+            rep!(reply, node, fs.transaction(|fs| fs.rfs_setattr(ino, mode, uid, gid, size,
+                atime, mtime, chgtime, bkuptime, flags)));
+            let attr = node.to_attr(ino as usize, fs.block_size());
+            reply.attr(&TTL, &attr);
+        }));
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        rep!(reply, inode, self.get_inode(ino as usize));
-        let data = inode.i_block.to_vec().as_bytes().to_vec().into_iter().collect::<Vec<u8>>();
-        let mut i = 0;
-        while data[i] != 0 && i < data.len() {
-            i += 1;
-        }
-        warn!("read link: {}", String::from_utf8(data.clone()).unwrap());
-        reply.data(&data[..i]);
+        self.spawn(move |s| s.run(&[ino], &[], |fs| {
+            rep!(reply, inode, fs.get_inode(ino as usize));
+            if SharedRfs::<T>::is_encrypted(&inode) {
+                rep!(reply, data, fs.crypt_readlink(ino as usize));
+                reply.data(&data);
+                return;
+            }
+            let data = inode.i_block.to_vec().as_bytes().to_vec().into_iter().collect::<Vec<u8>>();
+            let mut i = 0;
+            while data[i] != 0 && i < data.len() {
+                i += 1;
+            }
+            warn!("read link: {}", String::from_utf8(data.clone()).unwrap());
+            reply.data(&data[..i]);
+        }));
     }
 
-    fn mknod(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, _rdev: u32, reply: ReplyEntry) {
+    fn mknod(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, _rdev: u32, reply: ReplyEntry) {
         prv!("mknod", parent, name, mode);
-        let parent = RFS::<T>::shift_ino(parent as usize);
-        rep!(reply, inode_info, self.make_node(parent, name.to_str().unwrap(), mode as usize, Ext2FileType::RegularFile));
-        let (ino, inode) = inode_info;
-        let attr = inode.to_attr(ino, self.block_size());
-        reply.entry(&TTL, &attr, 0);
-        debug!("mknod done");
+        let (name, uid, gid) = (name.to_str().unwrap().to_string(), req.uid(), req.gid());
+        self.spawn(move |s| s.run(&[], &[parent], |fs| {
+            let parent = SharedRfs::<T>::shift_ino(parent as usize);
+            rep!(reply, inode_info, fs.transaction(|fs| fs.make_node(parent, &name, mode as usize, Ext2FileType::RegularFile, uid, gid)));
+            let (ino, inode) = inode_info;
+            let attr = inode.to_attr(ino, fs.block_size());
+            reply.entry(&TTL, &attr, 0);
+            debug!("mknod done");
+        }));
     }
 
-    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
         prv!("mkdir", parent, name, mode);
-        let parent = RFS::<T>::shift_ino(parent as usize);
-        rep!(reply, inode_info, self.make_node(parent, name.to_str().unwrap(), mode as usize, Ext2FileType::Directory));
-        let (ino, inode) = inode_info;
-        let attr = inode.to_attr(ino, self.block_size());
-        reply.entry(&TTL, &attr, 0);
-        debug!("mkdir done");
+        let (name, uid, gid) = (name.to_str().unwrap().to_string(), req.uid(), req.gid());
+        self.spawn(move |s| s.run(&[], &[parent], |fs| {
+            let parent = SharedRfs::<T>::shift_ino(parent as usize);
+            rep!(reply, inode_info, fs.transaction(|fs| fs.make_node(parent, &name, mode as usize, Ext2FileType::Directory, uid, gid)));
+            let (ino, inode) = inode_info;
+            let attr = inode.to_attr(ino, fs.block_size());
+            reply.entry(&TTL, &attr, 0);
+            debug!("mkdir done");
+        }));
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        rep!(reply, self.rfs_unlink(parent as usize, name.to_str().unwrap()));
-        reply.ok();
+        let name = name.to_str().unwrap().to_string();
+        self.spawn(move |s| s.run_exclusive(|fs| {
+            rep!(reply, fs.transaction(|fs| fs.rfs_unlink(parent as usize, &name)));
+            reply.ok();
+        }));
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        rep!(reply, self.rfs_rmdir(parent as usize, name.to_str().unwrap()));
-        reply.ok();
+        let name = name.to_str().unwrap().to_string();
+        self.spawn(move |s| s.run_exclusive(|fs| {
+            rep!(reply, fs.transaction(|fs| fs.rfs_rmdir(parent as usize, &name)));
+            reply.ok();
+        }));
     }
 
-    fn symlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
         prv!("symlink", parent, name, link);
-        let link = link.to_str().unwrap();
+        let link = link.to_str().unwrap().to_string();
         assert!(link.len() <= 60);
-        let parent = RFS::<T>::shift_ino(parent as usize);
-        rep!(reply, inode_info, self.rfs_symlink(parent, name.to_str().unwrap(), link.to_string().as_str()));
-        let (ino, inode) = inode_info;
-        rep!(reply, self.set_inode(ino, &inode));
-        let attr = inode.to_attr(ino, self.block_size());
-        reply.entry(&TTL, &attr, 0);
-        debug!("symlink done");
+        let (name, uid, gid) = (name.to_str().unwrap().to_string(), req.uid(), req.gid());
+        self.spawn(move |s| s.run(&[], &[parent], |fs| {
+            let parent = SharedRfs::<T>::shift_ino(parent as usize);
+            rep!(reply, inode_info, fs.transaction(|fs| fs.rfs_symlink(parent, &name, &link, uid, gid)));
+            let (ino, inode) = inode_info;
+            rep!(reply, fs.set_inode(ino, &inode));
+            let attr = inode.to_attr(ino, fs.block_size());
+            reply.entry(&TTL, &attr, 0);
+            debug!("symlink done");
+        }));
     }
 
     fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
-        rep!(reply, self.rfs_rename(parent as usize, name.to_str().unwrap(), newparent as usize, newname.to_str().unwrap()));
-        reply.ok();
+        let (name, newname) = (name.to_str().unwrap().to_string(), newname.to_str().unwrap().to_string());
+        self.spawn(move |s| s.run_exclusive(|fs| {
+            rep!(reply, fs.transaction(|fs| fs.rfs_rename(parent as usize, &name, newparent as usize, &newname)));
+            reply.ok();
+        }));
     }
 
     fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32,
             _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
         prv!("read", ino, offset, size);
-        rep!(reply, data, self.rfs_read(ino, offset, size));
-        reply.data(&data);
+        self.spawn(move |s| {
+            rep!(reply, data, s.read(ino, offset, size));
+            reply.data(&data);
+        });
     }
 
     fn write(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, data: &[u8],
              _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
         prv!("write", ino, offset, data.len());
-        rep!(reply, written, self.rfs_write(ino, offset, data));
-        reply.written(written);
+        let data = data.to_vec();
+        self.spawn(move |s| {
+            rep!(reply, written, s.write(ino, offset, &data));
+            reply.written(written);
+        });
+    }
+
+    fn copy_file_range(&mut self, _req: &Request<'_>, ino_in: u64, _fh_in: u64, offset_in: i64,
+                       ino_out: u64, _fh_out: u64, offset_out: i64, len: u64, _flags: u32, reply: ReplyWrite) {
+        prv!("copy_file_range", ino_in, offset_in, ino_out, offset_out, len);
+        self.spawn(move |s| s.run(&[ino_in], &[ino_out], |fs| {
+            // copied size is replied in u32, caller continues with the rest
+            let len = len.min(u32::MAX as u64 / fs.block_size() as u64 * fs.block_size() as u64);
+            rep!(reply, copied, fs.transaction(|fs| fs.rfs_copy_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len)));
+            reply.written(copied as u32);
+        }));
+    }
+
+    fn ioctl(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _flags: u32, cmd: u32,
+             in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        prv!("ioctl", ino, cmd, in_data.len());
+        let in_data = in_data.to_vec();
+        if cmd == RFS_IOC_GROW || cmd == RFS_IOC_CLONE {
+            self.spawn(move |s| s.run_exclusive(|fs| rfs_ioctl(fs, ino, cmd, &in_data, reply)));
+        } else {
+            self.spawn(move |s| s.run(&[], &[ino], |fs| rfs_ioctl(fs, ino, cmd, &in_data, reply)));
+        }
     }
 
     fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        rep!(reply, self.rfs_dump());
-        reply.ok();
+        self.spawn(move |s| s.run(&[], &[], |fs| {
+            rep!(reply, fs.rfs_dump());
+            reply.ok();
+        }));
     }
 
     fn release(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
-        rep!(reply, self.rfs_dump());
-        reply.ok();
+        self.spawn(move |s| s.run(&[], &[], |fs| {
+            rep!(reply, fs.rfs_dump());
+            reply.ok();
+        }));
     }
 
 
     fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
         prv!("readdir", ino, offset);
-        rep!(reply, entries, self.rfs_readdir(ino, offset));
-        for (i, d) in entries.iter().enumerate() {
-            let o = i + offset as usize;
-            rep!(reply, inode, self.get_inode(d.inode as usize));
-            debug!("readdir entry[{}] [{}]", o, d.to_string());
-            let _ = reply.add(d.inode as u64, (o + 1) as i64, inode.to_attr(d.inode as usize, self.block_size()).kind, d.get_name());
-        }
-        reply.ok();
+        self.spawn(move |s| s.run(&[ino], &[], |fs| {
+            rep!(reply, entries, fs.rfs_readdir(ino, offset));
+            for (i, d) in entries.iter().enumerate() {
+                let o = i + offset as usize;
+                rep!(reply, inode, fs.get_inode(d.inode as usize));
+                debug!("readdir entry[{}] [{}]", o, d.to_string());
+                let _ = reply.add(d.inode as u64, (o + 1) as i64, inode.to_attr(d.inode as usize, fs.block_size()).kind, d.get_name());
+            }
+            reply.ok();
+        }));
     }
 
     fn releasedir(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: i32, reply: ReplyEmpty) {
-        rep!(reply, self.rfs_dump());
-        reply.ok();
+        self.spawn(move |s| s.run(&[], &[], |fs| {
+            rep!(reply, fs.rfs_dump());
+            reply.ok();
+        }));
     }
 
     // fn setxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, _value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
@@ -157,17 +309,22 @@ impl<T: DiskDriver> Filesystem for RFS<T> {
     // }
 
     fn access(&mut self, _req: &Request<'_>, ino: u64, _mask: i32, reply: ReplyEmpty) {
-        let ino = RFS::<T>::shift_ino(ino as usize);
-        rep!(reply, self.get_inode(ino));
-        reply.ok();
+        self.spawn(move |s| s.run(&[ino], &[], |fs| {
+            let ino = SharedRfs::<T>::shift_ino(ino as usize);
+            rep!(reply, fs.get_inode(ino));
+            reply.ok();
+        }));
     }
 
-    fn create(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
         prv!("create", parent, name, mode);
-        let parent = RFS::<T>::shift_ino(parent as usize);
-        rep!(reply, inode_info, self.make_node(parent, name.to_str().unwrap(), mode as usize, Ext2FileType::RegularFile));
-        let (ino, inode) = inode_info;
-        let attr = inode.to_attr(ino, self.block_size());
-        reply.created(&TTL, &attr, 0, 0, 0);
+        let (name, uid, gid) = (name.to_str().unwrap().to_string(), req.uid(), req.gid());
+        self.spawn(move |s| s.run(&[], &[parent], |fs| {
+            let parent = SharedRfs::<T>::shift_ino(parent as usize);
+            rep!(reply, inode_info, fs.transaction(|fs| fs.make_node(parent, &name, mode as usize, Ext2FileType::RegularFile, uid, gid)));
+            let (ino, inode) = inode_info;
+            let attr = inode.to_attr(ino, fs.block_size());
+            reply.created(&TTL, &attr, 0, 0, 0);
+        }));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,252 @@
+/// Metadata-only images, like `e2image`, for reproducing bugs without file data.
+///
+/// An image is a header followed by `(block, data)` records of super blocks,
+/// group desc tables, bitmaps, inode tables, directory, index and xattr blocks,
+/// plus all blocks of the journal and quota files. Restoring writes the records
+/// back to an empty device of the original size, leaving file data as holes.
+/// With `scramble`, names in directory blocks are replaced by generated names
+/// of the same length, unique in each directory. Inline file data is part of
+/// inode tables and kept as is.
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use anyhow::{anyhow, Result};
+use disk_driver::DiskDriver;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+
+pub const RFS_IMAGE_MAGIC: &[u8; 8] = b"RFSIMAGE";
+
+/// Characters of scrambled names
+const RFS_IMAGE_NAME_CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Header of image file, numbers are little endian
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageHeader {
+    pub block_size: u32,
+    /// Size of original device in bytes
+    pub disk_size: u64,
+    /// Block records following header
+    pub count: u64,
+}
+
+impl ImageHeader {
+    pub const SIZE: usize = 28;
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_all(RFS_IMAGE_MAGIC)?;
+        out.write_all(&self.block_size.to_le_bytes())?;
+        out.write_all(&self.disk_size.to_le_bytes())?;
+        out.write_all(&self.count.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self> {
+        let mut buf = [0u8; Self::SIZE];
+        input.read_exact(&mut buf)?;
+        if &buf[..8] != RFS_IMAGE_MAGIC { return Err(anyhow!("not an rfs image")); }
+        let header = Self {
+            block_size: u32::from_le_bytes(buf[8..12].try_into()?),
+            disk_size: u64::from_le_bytes(buf[12..20].try_into()?),
+            count: u64::from_le_bytes(buf[20..28].try_into()?),
+        };
+        if !header.block_size.is_power_of_two() || header.block_size < 1024 {
+            return Err(anyhow!("bad block size {} in image", header.block_size));
+        }
+        Ok(header)
+    }
+}
+
+/// Generated name of `len` bytes for the `index`-th name of that length
+fn image_scrambled_name(index: usize, len: usize) -> Vec<u8> {
+    let base = RFS_IMAGE_NAME_CHARS.len();
+    let mut n = index;
+    let mut name = vec![0; len];
+    for c in name.iter_mut().rev() {
+        *c = RFS_IMAGE_NAME_CHARS[n % base];
+        n /= base;
+    }
+    name
+}
+
+/// Write blocks of image from `input` to `output` at their places, returns the header
+pub fn image_restore<R: Read, W: Write + Seek>(input: &mut R, output: &mut W) -> Result<ImageHeader> {
+    let header = ImageHeader::read_from(input)?;
+    let bs = header.block_size as usize;
+    let mut buf = vec![0; bs];
+    let mut index = [0u8; 8];
+    for _ in 0..header.count {
+        input.read_exact(&mut index)?;
+        input.read_exact(&mut buf)?;
+        let offset = u64::from_le_bytes(index) * bs as u64;
+        if offset + bs as u64 > header.disk_size {
+            return Err(anyhow!("block {} out of device in image", u64::from_le_bytes(index)));
+        }
+        output.seek(SeekFrom::Start(offset))?;
+        output.write_all(&buf)?;
+    }
+    Ok(header)
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Blocks of group metadata: backup super block, group desc table, bitmaps and inode table
+    fn image_group_blocks(&self, group: usize) -> Vec<usize> {
+        let desc = self.alloc().group_desc_table[group];
+        let mut blocks = vec![desc.bg_block_bitmap as usize, desc.bg_inode_bitmap as usize];
+        blocks.extend(desc.bg_inode_table as usize..self.group_data_start(group));
+        if self.group_has_super(group) {
+            let first = self.group_first_block(group);
+            blocks.extend(first..first + 1 + self.group_desc_blocks());
+        }
+        blocks
+    }
+
+    /// Inodes whose data blocks are all kept in image
+    fn image_special_inode(&self, ino: usize) -> bool {
+        let sb = &self.super_block;
+        ino < sb.s_first_ino as usize && ino != EXT2_ROOT_INO
+            || [sb.s_journal_inum, sb.s_usr_quota_inum, sb.s_grp_quota_inum].contains(&(ino as u32))
+    }
+
+    /// Metadata blocks of one inode, and its data blocks if `with_data`
+    pub(crate) fn image_inode_blocks(&mut self, ino: usize, with_data: bool) -> Result<(Vec<usize>, Vec<usize>)> {
+        let inode = self.get_inode(ino)?;
+        let mut meta = vec![];
+        if inode.i_file_acl != 0 { meta.push(inode.i_file_acl as usize); }
+        let mut data = vec![];
+        let file_type = Ext2FileType::try_from((inode.i_mode >> 12) as usize)?;
+        // fast symlinks and device files keep no block numbers in `i_block`
+        if Self::is_inline(&inode) || file_type != Ext2FileType::RegularFile && file_type != Ext2FileType::Directory
+            && inode.i_blocks == 0 {
+            return Ok((meta, data));
+        }
+        meta.extend(self.index_blocks(ino, &inode)?);
+        if with_data {
+            let end = self.blocks_end(ino, &inode)?;
+            if end > 0 {
+                self.visit_blocks_inode(ino, 0, &mut |block, index| {
+                    if block != 0 { data.push(block); }
+                    Ok((index + 1 < end, false))
+                })?;
+            }
+        }
+        Ok((meta, data))
+    }
+
+    /// Replace names in directory block of `ino`, counting names of each length in `counts`
+    fn image_scramble_dir_block(&mut self, ino: usize, data: &mut [u8], counts: &mut HashMap<usize, usize>) -> Result<()> {
+        let space = self.dir_block_space();
+        let mut p = 0;
+        while p + 8 <= space {
+            let entry_ino = u32::from_le_bytes(data[p..p + 4].try_into()?);
+            let rec_len = u16::from_le_bytes(data[p + 4..p + 6].try_into()?) as usize;
+            let name_len = data[p + 6] as usize;
+            if rec_len < 8 + name_len || p + rec_len > space { break; }
+            let name = &mut data[p + 8..p + 8 + name_len];
+            if entry_ino != 0 && name != b"." && name != b".." {
+                let count = counts.entry(name_len).or_insert(0);
+                name.copy_from_slice(&image_scrambled_name(*count, name_len));
+                *count += 1;
+            }
+            p += rec_len;
+        }
+        self.dir_tail_set(ino, data)
+    }
+
+    /// Write metadata image to `out`, returns blocks written
+    pub fn rfs_image<W: Write>(&mut self, out: &mut W, scramble: bool) -> Result<usize> {
+        // metadata in memory goes to disk first
+        self.rfs_dump()?;
+        // boot block holds super block if blocks are larger than 1 KiB
+        let mut blocks = vec![0, self.super_block.s_first_data_block as usize];
+        for group in 0..self.groups_count() {
+            blocks.extend(self.image_group_blocks(group));
+        }
+        let mut dirs = vec![];
+        for ino in 1..=self.super_block.s_inodes_count as usize {
+            if !Self::bitmap_get(&self.alloc().bitmap_inode, ino) { continue; }
+            let inode = self.get_inode(ino)?;
+            if inode.i_mode == 0 || inode.i_links_count == 0 && !self.image_special_inode(ino) { continue; }
+            let is_dir = inode.i_mode as usize >> 12 == Ext2FileType::Directory.into();
+            let (meta, data) = self.image_inode_blocks(ino, is_dir || self.image_special_inode(ino))?;
+            blocks.extend(meta);
+            if is_dir { dirs.push((ino, data)); } else { blocks.extend(data); }
+        }
+        let mut scrambled = HashMap::new();
+        for (ino, data) in dirs {
+            let mut counts = HashMap::new();
+            for block in data {
+                if scramble {
+                    let mut buf = self.get_data_block(block)?;
+                    self.image_scramble_dir_block(ino, &mut buf, &mut counts)?;
+                    scrambled.insert(block, buf);
+                }
+                blocks.push(block);
+            }
+        }
+        blocks.sort();
+        blocks.dedup();
+        blocks.retain(|x| *x < self.super_block.s_blocks_count as usize);
+        let header = ImageHeader {
+            block_size: self.block_size() as u32,
+            disk_size: self.disk_size() as u64,
+            count: blocks.len() as u64,
+        };
+        header.write_to(out)?;
+        for block in blocks.iter() {
+            let data = match scrambled.remove(block) {
+                Some(data) => data,
+                None => self.get_data_block(*block)?,
+            };
+            out.write_all(&(*block as u64).to_le_bytes())?;
+            out.write_all(&data)?;
+        }
+        info!("image of {} blocks, {} blocks in filesystem", blocks.len(), self.super_block.s_blocks_count);
+        Ok(blocks.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_image() -> Result<()> {
+        let size = 8 * 0x400 * 0x400;
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(size), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions { features: vec!["metadata_csum".to_string()], ..MkfsOptions::default() })?;
+        let bs = fs.block_size();
+        let (dir, _) = fs.make_node(EXT2_ROOT_INO, "secret", 0o755, Ext2FileType::Directory, 0, 0)?;
+        for i in 0..100 {
+            let (ino, _) = fs.make_node(dir, &format!("file-{}", i), 0o644, Ext2FileType::RegularFile, 0, 0)?;
+            fs.rfs_write(ino as u64, 0, &vec![0xaa; bs * 3])?;
+        }
+
+        let mut image = vec![];
+        let count = fs.rfs_image(&mut image, true)?;
+        assert_eq!(image.len(), ImageHeader::SIZE + count * (8 + bs));
+        assert!(count < fs.super_block.s_blocks_count as usize - fs.alloc().free_blocks_count as usize);
+        assert!(!image.windows(7).any(|x| x == b"file-42"));
+
+        let mut disk = Cursor::new(vec![0u8; size]);
+        let header = image_restore(&mut Cursor::new(&image), &mut disk)?;
+        assert_eq!(header.disk_size, size as u64);
+        let mut driver = MemoryDiskDriver::with_size(size);
+        driver.mem = disk.into_inner();
+        let mut fs = RFS::new(driver, Default::default());
+        fs.rfs_init("mem")?;
+        let entries = fs.get_dir_entries(EXT2_ROOT_INO)?;
+        let entry = entries.iter().find(|x| x.name_len == 6 && x.get_name() != "secret").unwrap();
+        let names = fs.get_dir_entries(entry.inode as usize)?.iter().map(|x| x.get_name()).collect::<Vec<_>>();
+        assert_eq!(names.len(), 102);
+        assert!(names.iter().all(|x| !x.starts_with("file-")));
+        let file = fs.get_dir_entries(entry.inode as usize)?[2].inode;
+        assert_eq!(fs.rfs_read(file as u64, 0, bs as u32)?, vec![0; bs]);
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
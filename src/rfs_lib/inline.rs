@@ -0,0 +1,214 @@
+/// Inline data, tiny regular files stored inside the inode, enabled by `inline_data` feature.
+///
+/// Same format as ext4: the first 60 bytes are kept in `i_block` and, for inodes
+/// larger than 128 bytes, the rest in the value of `system.data` xattr in the
+/// extra inode space. Files are converted to block-mapped storage when they grow
+/// out of the inode.
+use anyhow::Result;
+use disk_driver::DiskDriver;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::xattr::EXT2_EXT_ATTR_MAGIC;
+
+/// Bytes of data kept in `i_block`
+pub const EXT2_MIN_INLINE_DATA_SIZE: usize = EXT2_N_BLOCKS * 4;
+/// `i_extra_isize` set on inodes with inline data, same as mke2fs default
+const EXT2_INLINE_EXTRA_ISIZE: usize = 32;
+/// Index of `system.` xattr names
+const EXT2_XATTR_INDEX_SYSTEM: u8 = 7;
+const EXT2_XATTR_DATA_NAME: &[u8] = b"data";
+/// Header of `system.data` entry and the end mark after it, value follows
+const EXT2_XATTR_DATA_VALUE_OFFS: usize = 20 + 4;
+/// Header of one in-inode xattr entry before its name
+const EXT2_XATTR_ENTRY_SIZE: usize = 16;
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn inline_data_enabled(&self) -> bool {
+        self.super_block.s_feature_incompat & EXT4_FEATURE_INCOMPAT_INLINE_DATA as u32 != 0
+    }
+
+    pub fn is_inline(inode: &Ext2INode) -> bool {
+        inode.i_flags & EXT4_INLINE_DATA_FL as u32 != 0
+    }
+
+    /// Start of in-inode xattr space after `i_extra_isize` fields, 0 if no space for `system.data`
+    fn inline_xattr_start(&self) -> usize {
+        let start = EXT2_GOOD_OLD_INODE_SIZE + EXT2_INLINE_EXTRA_ISIZE;
+        if self.inode_size() >= start + 4 + EXT2_XATTR_DATA_VALUE_OFFS { start } else { 0 }
+    }
+
+    /// Max size of file stored inline
+    pub fn inline_capacity(&self) -> usize {
+        match self.inline_xattr_start() {
+            0 => EXT2_MIN_INLINE_DATA_SIZE,
+            start => EXT2_MIN_INLINE_DATA_SIZE + (self.inode_size() - start - 4 - EXT2_XATTR_DATA_VALUE_OFFS) / 4 * 4,
+        }
+    }
+
+    /// Read raw inode, returns (block, offset, block data)
+    fn inline_raw(&mut self, ino: usize) -> Result<(usize, usize, Vec<u8>)> {
+        let (block, offset) = self.fetch_inode_block_offset(ino)?;
+        let data = self.get_data_block(block)?;
+        Ok((block, offset, data))
+    }
+
+    /// Set the only in-inode xattr to `value` with name `index.name`,
+    /// or remove all in-inode xattrs if None. Also used for encryption contexts.
+    pub(crate) fn ibody_xattr_set(&mut self, ino: usize, index: u8, name: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let start = self.inline_xattr_start();
+        if start == 0 { return Ok(()); }
+        let inode_size = self.inode_size();
+        let (block, offset, mut data) = self.inline_raw(ino)?;
+        let raw = &mut data[offset..offset + inode_size];
+        raw[EXT2_GOOD_OLD_INODE_SIZE..].fill(0);
+        if let Some(value) = value {
+            raw[EXT2_GOOD_OLD_INODE_SIZE..][..2].copy_from_slice(&(EXT2_INLINE_EXTRA_ISIZE as u16).to_le_bytes());
+            raw[start..][..4].copy_from_slice(&(EXT2_EXT_ATTR_MAGIC as u32).to_le_bytes());
+            let entry = &mut raw[start + 4..];
+            // value follows the entry and the end mark
+            let value_offs = EXT2_XATTR_ENTRY_SIZE + name.len().div_ceil(4) * 4 + 4;
+            entry[0] = name.len() as u8;
+            entry[1] = index;
+            if !value.is_empty() {
+                entry[2..4].copy_from_slice(&(value_offs as u16).to_le_bytes());
+            }
+            entry[8..12].copy_from_slice(&(value.len() as u32).to_le_bytes());
+            entry[EXT2_XATTR_ENTRY_SIZE..][..name.len()].copy_from_slice(name);
+            entry[value_offs..][..value.len()].copy_from_slice(value);
+        }
+        self.inode_csum_set(ino, &mut data[offset..]);
+        self.write_meta_block(block, &data)
+    }
+
+    /// Value of the first in-inode xattr if its name is `index.name`
+    pub(crate) fn ibody_xattr_get(&mut self, ino: usize, index: u8, name: &[u8]) -> Result<Option<Vec<u8>>> {
+        let start = self.inline_xattr_start();
+        if start == 0 { return Ok(None); }
+        let (_, offset, data) = self.inline_raw(ino)?;
+        let raw = &data[offset..offset + self.inode_size()];
+        if u32::from_le_bytes(raw[start..start + 4].try_into().unwrap()) != EXT2_EXT_ATTR_MAGIC as u32 { return Ok(None); }
+        let entry = &raw[start + 4..];
+        if entry[1] != index || entry.get(EXT2_XATTR_ENTRY_SIZE..EXT2_XATTR_ENTRY_SIZE + entry[0] as usize) != Some(name) {
+            return Ok(None);
+        }
+        let offs = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+        let size = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+        Ok(Some(entry.get(offs..offs + size).map_or(vec![], |x| x.to_vec())))
+    }
+
+    /// Set `system.data` xattr to `value`, or remove all in-inode xattrs if None
+    fn inline_xattr_set(&mut self, ino: usize, value: Option<&[u8]>) -> Result<()> {
+        self.ibody_xattr_set(ino, EXT2_XATTR_INDEX_SYSTEM, EXT2_XATTR_DATA_NAME, value)
+    }
+
+    fn inline_xattr_get(&mut self, ino: usize) -> Result<Vec<u8>> {
+        Ok(self.ibody_xattr_get(ino, EXT2_XATTR_INDEX_SYSTEM, EXT2_XATTR_DATA_NAME)?.unwrap_or_default())
+    }
+
+    /// Whole content of inline file
+    pub fn inline_read(&mut self, ino: usize) -> Result<Vec<u8>> {
+        let inode = self.get_inode(ino)?;
+        let size = inode.i_size as usize;
+        let mut data = unsafe { crate::rfs_lib::utils::serialize_row(&inode.i_block) }.to_vec();
+        if size > EXT2_MIN_INLINE_DATA_SIZE { data.extend(self.inline_xattr_get(ino)?); }
+        data.resize(size, 0);
+        Ok(data)
+    }
+
+    /// Store whole content of file in inode
+    fn inline_store(&mut self, ino: usize, inode: &mut Ext2INode, content: &[u8]) -> Result<()> {
+        let mut i_block = [0u8; EXT2_MIN_INLINE_DATA_SIZE];
+        let head = content.len().min(EXT2_MIN_INLINE_DATA_SIZE);
+        i_block[..head].copy_from_slice(&content[..head]);
+        for (i, x) in i_block.chunks(4).enumerate() {
+            inode.i_block[i] = u32::from_le_bytes(x.try_into().unwrap());
+        }
+        inode.i_size = content.len() as u32;
+        inode.i_flags = (inode.i_flags | EXT4_INLINE_DATA_FL as u32) & !(EXT4_EXTENTS_FL as u32);
+        self.set_inode(ino, inode)?;
+        self.inline_xattr_set(ino, Some(&content[head..]))
+    }
+
+    /// Move content of inline file to data blocks
+    pub fn inline_convert(&mut self, ino: usize) -> Result<()> {
+        let content = self.inline_read(ino)?;
+        let mut inode = self.get_inode(ino)?;
+        inode.i_block = [0; EXT2_N_BLOCKS];
+        inode.i_size = 0;
+        inode.i_flags &= !(EXT4_INLINE_DATA_FL as u32);
+        self.extent_init(&mut inode);
+        self.set_inode(ino, &inode)?;
+        self.inline_xattr_set(ino, None)?;
+        if !content.is_empty() { self.rfs_write_blocks(ino as u64, 0, &content)?; }
+        Ok(())
+    }
+
+    /// Write to inline file or empty file which fits in inode, returns None if blocks are needed
+    pub fn inline_write(&mut self, ino: usize, offset: usize, data: &[u8]) -> Result<Option<u32>> {
+        let mut inode = self.get_inode(ino)?;
+        // the in-inode xattr space of encrypted files holds their context
+        if inode.i_mode as usize >> 12 != Ext2FileType::RegularFile.into() || Self::is_encrypted(&inode) { return Ok(None); }
+        let end = offset + data.len();
+        let inline = Self::is_inline(&inode);
+        let empty = inode.i_size == 0 && inode.i_size_high == 0 && inode.i_blocks == 0;
+        if !(inline || empty && self.inline_data_enabled()) { return Ok(None); }
+        if end > self.inline_capacity() {
+            if inline { self.inline_convert(ino)?; }
+            return Ok(None);
+        }
+        let mut content = if inline { self.inline_read(ino)? } else { vec![] };
+        if content.len() < end { content.resize(end, 0); }
+        content[offset..end].copy_from_slice(data);
+        self.inline_store(ino, &mut inode, &content)?;
+        Ok(Some(data.len() as u32))
+    }
+
+    /// Set size of inline file, converted to blocks if too large
+    pub fn inline_truncate(&mut self, ino: usize, size: usize) -> Result<()> {
+        if size > self.inline_capacity() { return self.inline_convert(ino); }
+        let mut content = self.inline_read(ino)?;
+        content.resize(size, 0);
+        let mut inode = self.get_inode(ino)?;
+        self.inline_store(ino, &mut inode, &content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_inline() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(4 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions {
+            inode_size: 256,
+            features: vec!["inline_data,metadata_csum".to_string()],
+            ..Default::default()
+        })?;
+        assert_eq!(fs.inline_capacity(), 128);
+        let free = fs.alloc().free_blocks_count;
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        assert_eq!(fs.alloc().free_blocks_count, free);
+
+        let text = (0..100).map(|x| x as u8).collect::<Vec<_>>();
+        fs.rfs_write(ino as u64, 0, &text[..50])?;
+        fs.rfs_write(ino as u64, 50, &text[50..])?;
+        assert!(RFS::<MemoryDiskDriver>::is_inline(&fs.get_inode(ino)?));
+        assert_eq!(fs.alloc().free_blocks_count, free);
+        assert_eq!(fs.rfs_read(ino as u64, 0, 100)?, text);
+
+        // grow out of inode
+        fs.rfs_write(ino as u64, 100, &[1; 100])?;
+        let inode = fs.get_inode(ino)?;
+        assert!(!RFS::<MemoryDiskDriver>::is_inline(&inode));
+        assert_eq!(inode.i_size, 200);
+        assert_eq!(fs.alloc().free_blocks_count, free - 1);
+        assert_eq!(&fs.rfs_read(ino as u64, 0, 1024)?[..200], [&text[..], &[1; 100]].concat());
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
@@ -0,0 +1,654 @@
+/// JBD2 compatible metadata journal.
+///
+/// Journal lives in `EXT2_JOURNAL_INO` like ext3. Metadata writes in one
+/// transaction are staged in memory by `write_meta_block`, then written to the
+/// log as descriptor blocks + block copies + one commit block, and finally
+/// checkpointed to their home locations. All journal fields are big endian.
+use std::collections::BTreeMap;
+use anyhow::{anyhow, Error, Result};
+use libc::EFBIG;
+use disk_driver::DiskDriver;
+use log::*;
+use crate::rfs_lib::{RFS, RfsAlloc};
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::mem::Ext2SuperBlockMem;
+use crate::rfs_lib::quota::QuotaTable;
+use crate::rfs_lib::reflink::RefcountTable;
+use crate::rfs_lib::snapshot::SnapshotState;
+use crate::rfs_lib::utils::*;
+
+pub const JBD2_MAGIC_NUMBER: u32 = 0xC03B3998;
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+pub const JBD2_SUPERBLOCK_V1: u32 = 3;
+pub const JBD2_SUPERBLOCK_V2: u32 = 4;
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+
+/// Block is escaped, first 4 bytes in log are zeroed
+pub const JBD2_FLAG_ESCAPE: u16 = 1;
+/// Block has same uuid as previous tag, uuid field omitted
+pub const JBD2_FLAG_SAME_UUID: u16 = 2;
+pub const JBD2_FLAG_DELETED: u16 = 4;
+pub const JBD2_FLAG_LAST_TAG: u16 = 8;
+
+/// Size of `journal_header_t`
+pub const JBD2_HEADER_SIZE: usize = 12;
+/// Size of `journal_block_tag_t` without 64bit and csum features
+pub const JBD2_TAG_SIZE: usize = 8;
+pub const JBD2_UUID_SIZE: usize = 16;
+
+/// Default journal size in blocks
+pub const RFS_JOURNAL_DEFAULT_BLOCKS: usize = 1024;
+/// Smallest journal we accept, in blocks
+pub const RFS_JOURNAL_MIN_BLOCKS: usize = 16;
+
+fn get_be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_be32(buf: &mut [u8], offset: usize, v: u32) {
+    buf[offset..offset + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+fn get_be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn put_be16(buf: &mut [u8], offset: usize, v: u16) {
+    buf[offset..offset + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+/// `journal_header_t`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JournalHeader {
+    pub h_magic: u32,
+    pub h_blocktype: u32,
+    pub h_sequence: u32,
+}
+
+impl JournalHeader {
+    pub fn new(h_blocktype: u32, h_sequence: u32) -> Self {
+        Self { h_magic: JBD2_MAGIC_NUMBER, h_blocktype, h_sequence }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            h_magic: get_be32(buf, 0),
+            h_blocktype: get_be32(buf, 4),
+            h_sequence: get_be32(buf, 8),
+        }
+    }
+
+    pub fn write_to(&self, buf: &mut [u8]) {
+        put_be32(buf, 0, self.h_magic);
+        put_be32(buf, 4, self.h_blocktype);
+        put_be32(buf, 8, self.h_sequence);
+    }
+}
+
+/// Static part of `journal_superblock_t`, stored in the first journal block
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JournalSuperBlock {
+    pub s_header: JournalHeader,
+    /// Journal device blocksize
+    pub s_blocksize: u32,
+    /// Total blocks in journal file
+    pub s_maxlen: u32,
+    /// First block of log information
+    pub s_first: u32,
+    /// First commit ID expected in log
+    pub s_sequence: u32,
+    /// Block number of start of log, 0 means journal is clean
+    pub s_start: u32,
+    /// Error value, as set by jbd2_journal_abort()
+    pub s_errno: u32,
+    pub s_feature_compat: u32,
+    pub s_feature_incompat: u32,
+    pub s_feature_ro_compat: u32,
+    pub s_uuid: [u8; 16],
+    /// Number of filesystems sharing log
+    pub s_nr_users: u32,
+}
+
+impl JournalSuperBlock {
+    pub fn new(block_size: usize, blocks: usize) -> Self {
+        Self {
+            s_header: JournalHeader::new(JBD2_SUPERBLOCK_V2, 0),
+            s_blocksize: block_size as u32,
+            s_maxlen: blocks as u32,
+            s_first: 1,
+            s_sequence: 1,
+            s_uuid: create_uuid(),
+            s_nr_users: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let s_header = JournalHeader::from_bytes(buf);
+        if s_header.h_magic != JBD2_MAGIC_NUMBER ||
+            (s_header.h_blocktype != JBD2_SUPERBLOCK_V1 && s_header.h_blocktype != JBD2_SUPERBLOCK_V2) {
+            return Err(anyhow!("bad journal super block header {:x?}", s_header));
+        }
+        let sb = Self {
+            s_header,
+            s_blocksize: get_be32(buf, 0x0c),
+            s_maxlen: get_be32(buf, 0x10),
+            s_first: get_be32(buf, 0x14),
+            s_sequence: get_be32(buf, 0x18),
+            s_start: get_be32(buf, 0x1c),
+            s_errno: get_be32(buf, 0x20),
+            s_feature_compat: get_be32(buf, 0x24),
+            s_feature_incompat: get_be32(buf, 0x28),
+            s_feature_ro_compat: get_be32(buf, 0x2c),
+            s_uuid: buf[0x30..0x40].try_into().unwrap(),
+            s_nr_users: get_be32(buf, 0x40),
+        };
+        if sb.s_feature_incompat != 0 {
+            return Err(anyhow!("unsupported journal incompat features 0x{:x}", sb.s_feature_incompat));
+        }
+        Ok(sb)
+    }
+
+    /// Serialize to a zeroed block
+    pub fn write_to(&self, buf: &mut [u8]) {
+        self.s_header.write_to(buf);
+        put_be32(buf, 0x0c, self.s_blocksize);
+        put_be32(buf, 0x10, self.s_maxlen);
+        put_be32(buf, 0x14, self.s_first);
+        put_be32(buf, 0x18, self.s_sequence);
+        put_be32(buf, 0x1c, self.s_start);
+        put_be32(buf, 0x20, self.s_errno);
+        put_be32(buf, 0x24, self.s_feature_compat);
+        put_be32(buf, 0x28, self.s_feature_incompat);
+        put_be32(buf, 0x2c, self.s_feature_ro_compat);
+        buf[0x30..0x40].copy_from_slice(&self.s_uuid);
+        put_be32(buf, 0x40, self.s_nr_users);
+    }
+
+    /// Tags one descriptor block can hold
+    pub fn tags_per_descriptor(&self) -> usize {
+        (self.s_blocksize as usize - JBD2_HEADER_SIZE - JBD2_UUID_SIZE) / JBD2_TAG_SIZE
+    }
+}
+
+/// Journal state in memory
+#[derive(Debug, Default, Clone)]
+pub struct Journal {
+    /// Physical block of each journal block
+    pub blocks: Vec<usize>,
+    pub sb: JournalSuperBlock,
+    /// Nested transaction depth, staging only works when > 0
+    pub depth: usize,
+    /// Metadata blocks staged by running transaction
+    pub running: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Journal {
+    /// Next log block, wrapping to `s_first`
+    fn next(&self, pos: usize) -> usize {
+        if pos + 1 >= self.sb.s_maxlen as usize { self.sb.s_first as usize } else { pos + 1 }
+    }
+
+    /// Metadata blocks one transaction can carry
+    pub fn max_transaction(&self) -> usize {
+        let log = self.sb.s_maxlen as usize - self.sb.s_first as usize;
+        let per = self.sb.tags_per_descriptor();
+        // descriptors and one commit block
+        (log - 1) * per / (per + 1)
+    }
+}
+
+/// Metadata in memory when the outermost transaction starts, put back if it fails
+struct TransactionSaved {
+    super_block: Ext2SuperBlockMem,
+    alloc: RfsAlloc,
+    root_dir: Ext2INode,
+    quota_tables: [Option<QuotaTable>; 2],
+    refcounts: RefcountTable,
+    snapshot: SnapshotState,
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn journal_enabled(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Physical blocks of journal file, in logical order
+    fn journal_collect_blocks(&mut self, ino: usize) -> Result<Vec<usize>> {
+        let mut blocks = vec![];
+        self.visit_blocks_inode(ino, 0, &mut |block, _index| {
+            if block != 0 { blocks.push(block); }
+            Ok((block != 0, false))
+        })?;
+        Ok(blocks)
+    }
+
+    /// Create journal file of `blocks` blocks and enable journaling
+    pub fn journal_create(&mut self, blocks: usize) -> Result<()> {
+        self.check_writable()?;
+        if self.super_block.s_journal_inum != 0 {
+            return Err(anyhow!("journal already exists on inode {}", self.super_block.s_journal_inum));
+        }
+        if blocks < RFS_JOURNAL_MIN_BLOCKS {
+            return Err(anyhow!("journal needs at least {} blocks", RFS_JOURNAL_MIN_BLOCKS));
+        }
+        let ino = EXT2_JOURNAL_INO;
+        info!("create journal on inode {}, {} blocks", ino, blocks);
+        let inode = Ext2INode {
+            i_mode: ((usize::from(Ext2FileType::RegularFile) << 12) | 0o600) as u16,
+            i_links_count: 1,
+            ..Default::default()
+        };
+        self.set_inode(ino, &inode)?;
+        Self::bitmap_set(&mut self.alloc().bitmap_inode, ino);
+        let sz = self.block_size();
+        self.rfs_write(ino as u64, 0, &vec![0; blocks * sz])?;
+        let journal_blocks = self.journal_collect_blocks(ino)?;
+        if journal_blocks.len() != blocks {
+            return Err(anyhow!("journal file has {} blocks, expected {}", journal_blocks.len(), blocks));
+        }
+        let sb = JournalSuperBlock::new(sz, blocks);
+        let mut buf = self.create_block_vec();
+        sb.write_to(&mut buf);
+        self.write_data_block(journal_blocks[0], &buf)?;
+        self.super_block.s_journal_inum = ino as u32;
+        self.super_block.s_feature_compat |= EXT3_FEATURE_COMPAT_HAS_JOURNAL as u32;
+        self.journal = Some(Journal { blocks: journal_blocks, sb, ..Default::default() });
+        self.rfs_dump()
+    }
+
+    /// Load journal recorded in super block, replay it if not clean.
+    /// Returns true if any transaction is replayed and metadata should be reloaded.
+    pub fn journal_load(&mut self) -> Result<bool> {
+        self.journal = None;
+        let ino = self.super_block.s_journal_inum as usize;
+        if ino == 0 { return Ok(false); }
+        let blocks = self.journal_collect_blocks(ino)?;
+        if blocks.is_empty() {
+            return Err(anyhow!("journal inode {} has no blocks", ino));
+        }
+        let mut buf = self.create_block_vec();
+        self.read_data_block(blocks[0], &mut buf)?;
+        let sb = JournalSuperBlock::from_bytes(&buf)?;
+        if sb.s_blocksize as usize != self.block_size() || sb.s_maxlen as usize > blocks.len() ||
+            sb.s_first == 0 || sb.s_first >= sb.s_maxlen {
+            return Err(anyhow!("bad journal super block {:?}", sb));
+        }
+        debug!("journal: {:?}", sb);
+        let mut journal = Journal { blocks, sb, ..Default::default() };
+        let replayed = if sb.s_start != 0 {
+            warn!("journal needs recovery, start at {} sequence {}", sb.s_start, sb.s_sequence);
+            self.journal_recover(&mut journal)?
+        } else { 0 };
+        info!("journal loaded, {} blocks, sequence {}", journal.sb.s_maxlen, journal.sb.s_sequence);
+        self.journal = Some(journal);
+        Ok(replayed > 0)
+    }
+
+    /// Replay committed transactions to their home locations, returns transactions count
+    fn journal_recover(&mut self, journal: &mut Journal) -> Result<usize> {
+        let mut sequence = journal.sb.s_sequence;
+        let mut pos = journal.sb.s_start as usize;
+        let mut pending: Vec<(usize, Vec<u8>)> = vec![];
+        let mut replayed = 0;
+        let mut buf = self.create_block_vec();
+        loop {
+            self.read_data_block(journal.blocks[pos], &mut buf)?;
+            let header = JournalHeader::from_bytes(&buf);
+            if header.h_magic != JBD2_MAGIC_NUMBER || header.h_sequence != sequence { break; }
+            match header.h_blocktype {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    let mut p = JBD2_HEADER_SIZE;
+                    loop {
+                        if p + JBD2_TAG_SIZE > buf.len() { break; }
+                        let target = get_be32(&buf, p) as usize;
+                        let flags = get_be16(&buf, p + 6);
+                        p += JBD2_TAG_SIZE;
+                        if flags & JBD2_FLAG_SAME_UUID == 0 { p += JBD2_UUID_SIZE; }
+                        pos = journal.next(pos);
+                        let mut data = self.create_block_vec();
+                        self.read_data_block(journal.blocks[pos], &mut data)?;
+                        if flags & JBD2_FLAG_ESCAPE != 0 {
+                            put_be32(&mut data, 0, JBD2_MAGIC_NUMBER);
+                        }
+                        pending.push((target, data));
+                        if flags & JBD2_FLAG_LAST_TAG != 0 { break; }
+                    }
+                }
+                JBD2_COMMIT_BLOCK => {
+                    debug!("replay transaction {}, {} blocks", sequence, pending.len());
+                    for (target, data) in pending.drain(..) {
+                        self.write_data_block(target, &data)?;
+                    }
+                    sequence += 1;
+                    replayed += 1;
+                }
+                JBD2_REVOKE_BLOCK => {}
+                _ => break,
+            }
+            pos = journal.next(pos);
+        }
+        if !pending.is_empty() {
+            warn!("drop uncommitted transaction {}, {} blocks", sequence, pending.len());
+        }
+        info!("journal recovery done, {} transactions replayed", replayed);
+        journal.sb.s_sequence = sequence;
+        journal.sb.s_start = 0;
+        self.journal_write_super(journal)?;
+        Ok(replayed)
+    }
+
+    fn journal_write_super(&mut self, journal: &Journal) -> Result<()> {
+        let mut buf = self.create_block_vec();
+        journal.sb.write_to(&mut buf);
+        self.write_data_block(journal.blocks[0], &buf)?;
+        self.get_driver().ddriver_flush()
+    }
+
+    /// Write one metadata block, staged in running transaction if any.
+    /// Fails with EFBIG when the transaction would not fit in the log
+    pub fn write_meta_block(&mut self, block: usize, buf: &[u8]) -> Result<()> {
+        match self.journal.as_ref() {
+            None => return self.write_data_block(block, buf),
+            Some(j) if j.depth == 0 => return self.write_data_block(block, buf),
+            Some(j) if !j.running.contains_key(&block) && j.running.len() >= j.max_transaction() =>
+                return Err(Error::new(Errno(EFBIG)).context(format!(
+                    "transaction of more than {} blocks does not fit in journal", j.max_transaction()))),
+            _ => {}
+        }
+        // saved now, checkpoint may not change snapshot files
+        self.snapshot_cow(block)?;
+        let data = if buf.len() == self.block_size() { buf.to_vec() } else {
+            let mut data = self.create_block_vec();
+            self.read_data_block(block, &mut data)?;
+            data[..buf.len()].copy_from_slice(buf);
+            data
+        };
+        self.inode_cache_written(block, &data);
+        self.journal.as_mut().unwrap().running.insert(block, data);
+        Ok(())
+    }
+
+    /// Staged copy of block in running transaction
+    pub fn journal_staged(&self, block: usize) -> Option<&Vec<u8>> {
+        self.journal.as_ref().and_then(|j| j.running.get(&block))
+    }
+
+    pub fn journal_start(&mut self) {
+        if let Some(j) = self.journal.as_mut() { j.depth += 1; }
+    }
+
+    /// End one transaction level, commit when the outermost level ends
+    pub fn journal_stop(&mut self) -> Result<()> {
+        let depth = match self.journal.as_ref() {
+            Some(j) => j.depth,
+            None => return Ok(()),
+        };
+        let r = if depth == 1 {
+            // bitmaps, group desc and super block go in the same transaction
+            self.write_fs_meta().and_then(|_| self.journal_commit())
+        } else { Ok(()) };
+        self.journal.as_mut().unwrap().depth = depth - 1;
+        r
+    }
+
+    /// Run `f` in one transaction. If the outermost `f` fails, or its
+    /// metadata does not fit in the journal, its staged blocks are dropped
+    /// and metadata in memory is put back as before it
+    pub fn transaction<R, F>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&mut Self) -> Result<R> {
+        self.check_writable()?;
+        let saved = match self.journal.as_ref() {
+            Some(j) if j.depth == 0 => Some(self.transaction_save()?),
+            _ => None,
+        };
+        self.journal_start();
+        let r = f(self);
+        let saved = match saved {
+            Some(saved) => saved,
+            None => {
+                let c = self.journal_stop();
+                let r = r?;
+                c?;
+                return Ok(r);
+            }
+        };
+        // bitmaps, group desc and super block go in the same transaction
+        match r.and_then(|r| self.write_fs_meta().map(|_| r)) {
+            Ok(r) => {
+                self.journal.as_mut().unwrap().depth = 0;
+                self.journal_commit()?;
+                Ok(r)
+            }
+            Err(e) => {
+                self.transaction_abort(saved);
+                Err(e)
+            }
+        }
+    }
+
+    fn transaction_save(&mut self) -> Result<TransactionSaved> {
+        // inodes changed out of transactions are kept when one fails
+        self.inode_cache_flush(None)?;
+        Ok(TransactionSaved {
+            super_block: self.super_block,
+            alloc: self.alloc().clone(),
+            root_dir: self.root_dir.clone(),
+            quota_tables: self.quota_tables.clone(),
+            refcounts: self.refcounts.clone(),
+            snapshot: self.snapshot.clone(),
+        })
+    }
+
+    /// Drop the failed outermost transaction, nothing of it reached the disk
+    fn transaction_abort(&mut self, saved: TransactionSaved) {
+        let journal = self.journal.as_mut().unwrap();
+        warn!("transaction failed, {} staged blocks dropped", journal.running.len());
+        journal.running.clear();
+        journal.depth = 0;
+        self.super_block = saved.super_block;
+        *self.alloc() = saved.alloc;
+        self.root_dir = saved.root_dir;
+        self.quota_tables = saved.quota_tables;
+        self.refcounts = saved.refcounts;
+        self.snapshot = saved.snapshot;
+        // cached inodes and names may come from dropped blocks, none is dirty from before
+        self.cache_clear();
+    }
+
+    /// Write staged blocks to log, then checkpoint them
+    pub fn journal_commit(&mut self) -> Result<()> {
+        let staged = match self.journal.as_mut() {
+            Some(j) if !j.running.is_empty() => std::mem::take(&mut j.running).into_iter().collect::<Vec<_>>(),
+            _ => return Ok(()),
+        };
+        // writes below go to disk directly
+        let mut journal = self.journal.take().unwrap();
+        let r = self.journal_checkpoint(&mut journal, &staged);
+        self.journal = Some(journal);
+        r
+    }
+
+    fn journal_checkpoint(&mut self, journal: &mut Journal, staged: &[(usize, Vec<u8>)]) -> Result<()> {
+        let sequence = journal.sb.s_sequence;
+        debug!("commit transaction {}, {} blocks", sequence, staged.len());
+        self.journal_write_log(journal, staged)?;
+        for (block, data) in staged.iter() {
+            self.write_data_block(*block, data)?;
+        }
+        self.get_driver().ddriver_flush()?;
+        journal.sb.s_start = 0;
+        journal.sb.s_sequence = sequence + 1;
+        self.journal_write_super(journal)
+    }
+
+    /// Write descriptor blocks, block copies and commit block, then mark log live
+    pub(crate) fn journal_write_log(&mut self, journal: &mut Journal, staged: &[(usize, Vec<u8>)]) -> Result<()> {
+        let sequence = journal.sb.s_sequence;
+        let per = journal.sb.tags_per_descriptor();
+        let mut pos = journal.sb.s_first as usize;
+        for chunk in staged.chunks(per) {
+            let mut desc = self.create_block_vec();
+            JournalHeader::new(JBD2_DESCRIPTOR_BLOCK, sequence).write_to(&mut desc);
+            let desc_pos = pos;
+            let mut p = JBD2_HEADER_SIZE;
+            for (i, (block, data)) in chunk.iter().enumerate() {
+                let mut flags = if i == 0 { 0 } else { JBD2_FLAG_SAME_UUID };
+                if i == chunk.len() - 1 { flags |= JBD2_FLAG_LAST_TAG; }
+                let mut copy = data.clone();
+                if get_be32(&copy, 0) == JBD2_MAGIC_NUMBER {
+                    flags |= JBD2_FLAG_ESCAPE;
+                    put_be32(&mut copy, 0, 0);
+                }
+                put_be32(&mut desc, p, *block as u32);
+                put_be16(&mut desc, p + 6, flags);
+                p += JBD2_TAG_SIZE;
+                if i == 0 {
+                    desc[p..p + JBD2_UUID_SIZE].copy_from_slice(&journal.sb.s_uuid);
+                    p += JBD2_UUID_SIZE;
+                }
+                pos = journal.next(pos);
+                self.write_data_block(journal.blocks[pos], &copy)?;
+            }
+            self.write_data_block(journal.blocks[desc_pos], &desc)?;
+            pos = journal.next(pos);
+        }
+        let mut commit = self.create_block_vec();
+        JournalHeader::new(JBD2_COMMIT_BLOCK, sequence).write_to(&mut commit);
+        // commit_sec
+        put_be32(&mut commit, 0x34, get_time_now());
+        self.write_data_block(journal.blocks[pos], &commit)?;
+        self.get_driver().ddriver_flush()?;
+        journal.sb.s_start = journal.sb.s_first;
+        self.journal_write_super(journal)
+    }
+
+    /// Human readable journal state
+    pub fn journal_report(&self) -> String {
+        match self.journal.as_ref() {
+            None => "journal is not enabled\n".to_string(),
+            Some(j) => format!("journal on inode {}: {} blocks of {} B, first {}, sequence {}, {}\n",
+                               self.super_block.s_journal_inum, j.sb.s_maxlen, j.sb.s_blocksize,
+                               j.sb.s_first, j.sb.s_sequence,
+                               if j.sb.s_start == 0 { "clean" } else { "needs recovery" }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_counts<T: DiskDriver>(fs: &RFS<T>) -> (u32, u32) {
+        let alloc = fs.alloc();
+        (alloc.free_blocks_count, alloc.free_inodes_count)
+    }
+
+    #[test]
+    fn test_journal_super_block_bytes() -> Result<()> {
+        let sb = JournalSuperBlock::new(1024, 64);
+        let mut buf = vec![0; 1024];
+        sb.write_to(&mut buf);
+        assert_eq!(&buf[..4], &[0xc0, 0x3b, 0x39, 0x98]);
+        let loaded = JournalSuperBlock::from_bytes(&buf)?;
+        assert_eq!((loaded.s_maxlen, loaded.s_first, loaded.s_sequence), (64, 1, 1));
+        assert_eq!(loaded.s_uuid, sb.s_uuid);
+        assert!(JournalSuperBlock::from_bytes(&[0; 1024]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_replay() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        fs.journal_create(64)?;
+        // escaped block should be restored
+        let mut magic = fs.create_block_vec();
+        put_be32(&mut magic, 0, JBD2_MAGIC_NUMBER);
+        fs.journal_start();
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let target = fs.allocate_block()?;
+        fs.write_meta_block(target, &magic)?;
+        fs.write_fs_meta()?;
+        // crash after commit block, before checkpoint
+        let mut journal = fs.journal.take().unwrap();
+        let staged = std::mem::take(&mut journal.running).into_iter().collect::<Vec<_>>();
+        fs.journal_write_log(&mut journal, &staged)?;
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "a").is_err());
+
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.rfs_lookup(EXT2_ROOT_INO, "a")?.0, ino);
+        assert_eq!(fs.get_data_block(target)?, magic);
+        assert!(RFS::<disk_driver::memory::MemoryDiskDriver>::bitmap_get(&fs.alloc().bitmap_data, target));
+        assert_eq!(fs.journal.as_ref().unwrap().sb.s_start, 0);
+        assert_eq!(fs.journal.as_ref().unwrap().sb.s_sequence, journal.sb.s_sequence + 1);
+
+        // uncommitted transaction is dropped
+        fs.journal_start();
+        fs.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.journal.as_mut().unwrap().running.clear();
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "b").is_err());
+        // committed by transaction
+        fs.transaction(|fs| fs.make_node(EXT2_ROOT_INO, "c", 0o644, Ext2FileType::RegularFile, 0, 0))?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "c").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_abort() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        fs.journal_create(64)?;
+        let free = free_counts(&fs);
+        let r = fs.transaction(|fs| -> Result<()> {
+            let (ino, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+            fs.rfs_write(ino as u64, 0, &[1; 5000])?;
+            assert!(!fs.journal.as_ref().unwrap().running.is_empty());
+            Err(anyhow!("failed halfway"))
+        });
+        assert!(r.is_err());
+        assert!(fs.journal.as_ref().unwrap().running.is_empty());
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "a").is_err());
+        assert_eq!(free_counts(&fs), free);
+        fs.transaction(|fs| fs.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 0, 0))?;
+
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "a").is_err());
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "b").is_ok());
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        Ok(())
+    }
+    #[test]
+    fn test_transaction_too_large() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        fs.journal_create(RFS_JOURNAL_MIN_BLOCKS)?;
+        let max = fs.journal.as_ref().unwrap().max_transaction();
+        let free = free_counts(&fs);
+        // every directory takes one new block
+        let e = fs.transaction(|fs| -> Result<()> {
+            for i in 0..max {
+                fs.make_node(EXT2_ROOT_INO, &format!("d{}", i), 0o755, Ext2FileType::Directory, 0, 0)?;
+            }
+            Ok(())
+        }).unwrap_err();
+        assert_eq!(get_errno(&e, 0), EFBIG);
+        assert!(fs.journal.as_ref().unwrap().running.is_empty());
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "d0").is_err());
+        assert_eq!(free_counts(&fs), free);
+        fs.transaction(|fs| fs.make_node(EXT2_ROOT_INO, "a", 0o755, Ext2FileType::Directory, 0, 0))?;
+
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "d0").is_err());
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "a").is_ok());
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        Ok(())
+    }
+}
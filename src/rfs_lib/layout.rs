@@ -0,0 +1,410 @@
+/// Parser of `include/*.layout` files.
+///
+/// ```text
+/// layout  := (comment | bsize | group)*
+/// comment := '#' any
+/// bsize   := '|' "BSIZE" '=' NUMBER 'B' '|'
+/// group   := '|' region ('|' region)* '|' ('x' NUMBER)?
+/// region  := NAME '(' (NUMBER | '*') ')'
+/// ```
+///
+/// Each group line describes one block group, `x N` repeats it N times.
+/// Groups 0, 1 and powers of 3, 5 and 7 start with Super and GroupDesc, as
+/// backups of sparse_super, and DATA follows Inode Table at the end of groups.
+/// Names are case-insensitive: Boot, Super, GroupDesc, DATA Map, Inode Map, Inode Table and DATA.
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+use crate::rfs_lib::desc::*;
+
+pub const DEFAULT_LAYOUT: &str = "
+| BSIZE = 1024 B |
+| Boot(1) | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(128) | DATA(*) |";
+
+/// Error with 1-based position in layout file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for LayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+type LayoutResult<T> = std::result::Result<T, LayoutError>;
+
+fn error<T>(line: usize, column: usize, message: String) -> LayoutResult<T> {
+    Err(LayoutError { line, column, message })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Boot,
+    Super,
+    GroupDesc,
+    DataMap,
+    InodeMap,
+    InodeTable,
+    Data,
+}
+
+impl RegionKind {
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+        Some(match name.as_str() {
+            "boot" => RegionKind::Boot,
+            "super" => RegionKind::Super,
+            "groupdesc" | "group desc" => RegionKind::GroupDesc,
+            "data map" => RegionKind::DataMap,
+            "inode map" => RegionKind::InodeMap,
+            "inode table" => RegionKind::InodeTable,
+            "data" => RegionKind::Data,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for RegionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RegionKind::Boot => "Boot",
+            RegionKind::Super => "Super",
+            RegionKind::GroupDesc => "GroupDesc",
+            RegionKind::DataMap => "DATA Map",
+            RegionKind::InodeMap => "Inode Map",
+            RegionKind::InodeTable => "Inode Table",
+            RegionKind::Data => "DATA",
+        })
+    }
+}
+
+/// One region in layout file, `blocks` is None for `*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub blocks: Option<usize>,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Parsed layout file before resolving offsets
+#[derive(Debug, Clone, Default)]
+pub struct FsLayout {
+    pub block_size: usize,
+    pub groups: Vec<Vec<Region>>,
+}
+
+/// Offsets of one block group, in absolute block numbers
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsGroupLayout {
+    pub start: usize,
+    pub blocks: usize,
+    /// Super block and group desc copies, in groups 0, 1 and powers of 3, 5 and 7
+    pub super_block: Option<usize>,
+    pub group_desc: Option<usize>,
+    pub data_map: usize,
+    pub inode_map: usize,
+    pub inode_table: usize,
+    pub inode_table_blocks: usize,
+    pub data: usize,
+}
+
+/// Parse one number at `column` of line
+fn parse_number(s: &str, line: usize, column: usize) -> LayoutResult<usize> {
+    s.trim().parse::<usize>().or_else(|_| error(line, column, format!("expected number, found `{}`", s.trim())))
+}
+
+/// Column of `part` inside `line`, `part` should be a sub slice of `line`
+fn column_of(line: &str, part: &str) -> usize {
+    let offset = part.as_ptr() as usize - line.as_ptr() as usize;
+    line[..offset].chars().count() + 1
+}
+
+impl FsLayout {
+    pub fn parse(text: &str) -> LayoutResult<Self> {
+        let mut layout = FsLayout::default();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let content = line.trim();
+            if content.is_empty() || content.starts_with('#') { continue; }
+            if !content.starts_with('|') {
+                return error(line_no, column_of(line, content), "expected `|` or `#`".to_string());
+            }
+            let last = content.rfind('|').unwrap();
+            if last == 0 {
+                return error(line_no, column_of(line, content) + content.chars().count(), "expected `|`".to_string());
+            }
+            let cells = content[1..last].split('|').collect::<Vec<_>>();
+            let suffix = content[last + 1..].trim();
+            let repeat = if suffix.is_empty() { 1 } else {
+                let col = column_of(line, suffix);
+                match suffix.strip_prefix('x') {
+                    Some(n) => parse_number(n, line_no, col + 1)?,
+                    None => return error(line_no, col, format!("expected `x N` after `|`, found `{}`", suffix)),
+                }
+            };
+            let first = cells[0].trim();
+            if first.to_lowercase().starts_with("bsize") {
+                let col = column_of(line, first);
+                if cells.len() > 1 || repeat != 1 {
+                    return error(line_no, col, "BSIZE should be in a single line".to_string());
+                }
+                if layout.block_size != 0 {
+                    return error(line_no, col, "duplicated BSIZE".to_string());
+                }
+                if !layout.groups.is_empty() {
+                    return error(line_no, col, "BSIZE should be before groups".to_string());
+                }
+                let value = match first[5..].trim_start().strip_prefix('=') {
+                    Some(v) => v.trim(),
+                    None => return error(line_no, col + 5, "expected `=`".to_string()),
+                };
+                let value_col = column_of(line, value);
+                let number = match value.strip_suffix('B').or_else(|| value.strip_suffix('b')) {
+                    Some(n) => n,
+                    None => return error(line_no, value_col, "block size should end with `B`".to_string()),
+                };
+                let block_size = parse_number(number, line_no, value_col)?;
+                if ![1024, 2048, 4096].contains(&block_size) {
+                    return error(line_no, value_col, format!("unsupported block size {}", block_size));
+                }
+                layout.block_size = block_size;
+                continue;
+            }
+            let mut group = vec![];
+            for cell in cells {
+                let s = cell.trim();
+                let col = column_of(line, s);
+                let (open, close) = match (s.find('('), s.rfind(')')) {
+                    (Some(open), Some(close)) if open < close && close == s.len() - 1 => (open, close),
+                    _ => return error(line_no, col, format!("expected `NAME(N)`, found `{}`", s)),
+                };
+                let kind = RegionKind::from_name(&s[..open])
+                    .map_or_else(|| error(line_no, col, format!("unknown region `{}`", s[..open].trim())), Ok)?;
+                let arg = s[open + 1..close].trim();
+                let blocks = if arg == "*" { None } else { Some(parse_number(arg, line_no, col + open + 1)?) };
+                group.push(Region { kind, blocks, line: line_no, column: col });
+            }
+            for _ in 0..repeat {
+                layout.groups.push(group.clone());
+            }
+        }
+        if layout.block_size == 0 {
+            return error(text.lines().count().max(1), 1, "missing BSIZE".to_string());
+        }
+        if layout.groups.is_empty() {
+            return error(text.lines().count().max(1), 1, "missing group".to_string());
+        }
+        Ok(layout)
+    }
+
+    /// Resolve block offsets for a device of `disk_size` bytes, and check the regions
+    pub fn resolve(&self, disk_size: usize) -> LayoutResult<FsLayoutArgs> {
+        let bs = self.block_size;
+        let blocks_per_group = bs * 8;
+        let first_data_block = if bs == 1024 { 1 } else { 0 };
+        let disk_blocks = disk_size / bs;
+        let inodes_per_block = bs / EXT2_INODE_SIZE;
+        let mut args = FsLayoutArgs { block_size: bs, ..Default::default() };
+        for (g, regions) in self.groups.iter().enumerate() {
+            let start = if g == 0 { 0 } else { first_data_block + g * blocks_per_group };
+            let end = (first_data_block + (g + 1) * blocks_per_group).min(disk_blocks);
+            let first = &regions[0];
+            if start >= end {
+                return error(first.line, first.column, format!("group {} starts at block {} out of device ({} blocks)", g, start, disk_blocks));
+            }
+            let mut group = FsGroupLayout { start, ..Default::default() };
+            let mut offset = start;
+            let mut inode_table = None;
+            let mut data = None;
+            let (mut data_map, mut inode_map) = (None, None);
+            for (i, r) in regions.iter().enumerate() {
+                let fixed = match r.kind {
+                    RegionKind::Boot | RegionKind::Super | RegionKind::DataMap | RegionKind::InodeMap => Some(1),
+                    _ => None,
+                };
+                let blocks = match (r.blocks, fixed) {
+                    (Some(n), Some(f)) if n != f => return error(r.line, r.column, format!("{} should be {} block", r.kind, f)),
+                    (Some(0), _) => return error(r.line, r.column, format!("{} should not be empty", r.kind)),
+                    (Some(n), _) => n,
+                    (None, _) if r.kind == RegionKind::Data => end.saturating_sub(offset),
+                    (None, _) => return error(r.line, r.column, format!("only DATA can use `*`, not {}", r.kind)),
+                };
+                let duplicated = regions[..i].iter().any(|x| x.kind == r.kind);
+                if duplicated {
+                    return error(r.line, r.column, format!("duplicated {} in group {}", r.kind, g));
+                }
+                match r.kind {
+                    RegionKind::Boot => {
+                        if g != 0 || i != 0 {
+                            return error(r.line, r.column, "Boot should be the first region of group 0".to_string());
+                        }
+                        if bs != 1024 {
+                            return error(r.line, r.column, format!("Boot overlaps super block at byte {} when BSIZE is {}", EXT2_SUPER_BLOCK_OFFSET, bs));
+                        }
+                        args.boot = true;
+                    }
+                    RegionKind::Super => {
+                        // super block is read at byte 1024 with boot block or 4K block, else at byte 0
+                        let expected = if g == 0 { if args.boot { 1 } else { 0 } } else { start };
+                        if offset != expected {
+                            return error(r.line, r.column, format!("Super at block {} overlaps, super block should be at block {}", offset, expected));
+                        }
+                        group.super_block = Some(offset);
+                    }
+                    RegionKind::GroupDesc => {
+                        if group.super_block != Some(offset.wrapping_sub(1)) {
+                            return error(r.line, r.column, "GroupDesc should follow Super".to_string());
+                        }
+                        let needed = (self.groups.len() * size_of::<Ext2GroupDesc>()).div_ceil(bs);
+                        if blocks < needed {
+                            return error(r.line, r.column, format!("GroupDesc needs {} blocks for {} groups", needed, self.groups.len()));
+                        }
+                        group.group_desc = Some(offset);
+                    }
+                    RegionKind::DataMap => data_map = Some(offset),
+                    RegionKind::InodeMap => inode_map = Some(offset),
+                    RegionKind::InodeTable => {
+                        if blocks * inodes_per_block > blocks_per_group {
+                            return error(r.line, r.column, format!("Inode Table has more than {} inodes", blocks_per_group));
+                        }
+                        inode_table = Some((offset, blocks));
+                    }
+                    RegionKind::Data => {
+                        // blocks after the inode table are data, as `group_data_start` takes it
+                        if i != regions.len() - 1 {
+                            return error(r.line, r.column, "DATA should be the last region".to_string());
+                        }
+                        if inode_table.map(|(start, blocks)| start + blocks) != Some(offset) {
+                            return error(r.line, r.column, "DATA should follow Inode Table".to_string());
+                        }
+                        data = Some(offset);
+                    }
+                }
+                offset += blocks;
+                if offset > end {
+                    return error(r.line, r.column, format!("{} ends at block {}, out of group {} which ends at block {}", r.kind, offset, g, end));
+                }
+            }
+            let missing = |kind: RegionKind| LayoutError {
+                line: first.line,
+                column: first.column,
+                message: format!("missing {} in group {}", kind, g),
+            };
+            // backups are kept in groups 0, 1 and powers of 3, 5 and 7 with sparse_super
+            if group_has_super(g, true) != group.super_block.is_some() {
+                let message = if group.super_block.is_some() {
+                    format!("group {} keeps no backup, Super should be only in groups 0, 1 and powers of 3, 5 and 7", g)
+                } else {
+                    format!("missing {} in group {}", RegionKind::Super, g)
+                };
+                return error(first.line, first.column, message);
+            }
+            if group.super_block.is_some() && group.group_desc.is_none() { return Err(missing(RegionKind::GroupDesc)); }
+            group.data_map = data_map.ok_or_else(|| missing(RegionKind::DataMap))?;
+            group.inode_map = inode_map.ok_or_else(|| missing(RegionKind::InodeMap))?;
+            (group.inode_table, group.inode_table_blocks) = inode_table.ok_or_else(|| missing(RegionKind::InodeTable))?;
+            group.data = data.ok_or_else(|| missing(RegionKind::Data))?;
+            if g > 0 && group.inode_table_blocks != args.groups[0].inode_table_blocks {
+                return error(first.line, first.column, "Inode Table should be the same size in all groups".to_string());
+            }
+            group.blocks = offset - start;
+            args.groups.push(group);
+        }
+        let group0 = args.groups[0].clone();
+        args.super_block = group0.super_block.unwrap();
+        args.group_desc = group0.group_desc.unwrap();
+        args.data_map = group0.data_map;
+        args.inode_map = group0.inode_map;
+        args.inode_table = group0.inode_table;
+        args.inode_count = group0.inode_table_blocks * inodes_per_block;
+        let last = args.groups.last().unwrap();
+        args.block_count = last.start + last.blocks;
+        Ok(args)
+    }
+}
+
+/// Parse and resolve layout text for device of `disk_size` bytes
+pub fn parse_layout(text: &str, disk_size: usize) -> anyhow::Result<FsLayoutArgs> {
+    Ok(FsLayout::parse(text)?.resolve(disk_size)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk_driver::memory::MemoryDiskDriver;
+    use crate::rfs_lib::RFS;
+    use crate::rfs_lib::config::RfsConfig;
+
+    #[test]
+    fn test_layout() {
+        let args = parse_layout(DEFAULT_LAYOUT, 4 * 0x400 * 0x400).unwrap();
+        assert_eq!((args.block_count, args.super_block, args.group_desc), (4096, 1, 2));
+        assert_eq!((args.data_map, args.inode_map, args.inode_table, args.inode_count), (3, 4, 5, 1024));
+        assert!(args.boot);
+
+        let text = "| BSIZE = 1024 B |\n\
+            | Boot(1) | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(16) | DATA(*) |\n\
+            # backup group\n\
+            | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(16) | DATA(*) |\n\
+            | DATA Map(1) | Inode Map(1) | Inode Table(16) | DATA(*) |\n";
+        let args = parse_layout(text, 20 * 0x400 * 0x400).unwrap();
+        assert_eq!(args.groups.len(), 3);
+        assert_eq!(args.groups[2], FsGroupLayout {
+            start: 16385, blocks: 4095, super_block: None, group_desc: None,
+            data_map: 16385, inode_map: 16386, inode_table: 16387, inode_table_blocks: 16, data: 16403,
+        });
+
+        let err = |text: &str| FsLayout::parse(text).and_then(|x| x.resolve(4 * 0x400 * 0x400)).unwrap_err();
+        assert_eq!(err("| BSIZE = 1024 B |\n| Super(1) | Inode  Mep(2) |").to_string(), "2:14: unknown region `Inode  Mep`");
+        assert_eq!(err("| BSIZE = 1000 B |").to_string(), "1:11: unsupported block size 1000");
+        assert_eq!(err("| BSIZE = 4096 B |\n| Boot(1) | Super(1) |").to_string(),
+                   "2:3: Boot overlaps super block at byte 1024 when BSIZE is 4096");
+        assert_eq!(err("| BSIZE = 1024 B |\n| Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(16) | DATA(5000) |").to_string(),
+                   "2:76: DATA ends at block 5020, out of group 0 which ends at block 4096");
+        let repeated = "| BSIZE = 1024 B |\n\
+            | Boot(1) | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(16) | DATA(*) |\n\
+            | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(16) | DATA(*) | x 2";
+        assert_eq!(FsLayout::parse(repeated).unwrap().resolve(20 * 0x400 * 0x400).unwrap_err().to_string(),
+                   "3:3: group 2 keeps no backup, Super should be only in groups 0, 1 and powers of 3, 5 and 7");
+        assert_eq!(err("| BSIZE = 1024 B |\n| Super(1) | GroupDesc(1) | Inode Table(16) | DATA(*) | DATA Map(1) |").to_string(),
+                   "2:47: DATA should be the last region");
+    }
+
+    #[test]
+    fn test_layout_groups() -> anyhow::Result<()> {
+        let text = "| BSIZE = 1024 B |\n\
+            | Boot(1) | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(64) | DATA(*) |\n\
+            | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(64) | DATA(*) |\n";
+        let path = std::env::temp_dir().join(format!("rfs-test-{}.layout", std::process::id()));
+        std::fs::write(&path, text)?;
+        let config = RfsConfig { layout: path.to_str().unwrap().to_string(), ..Default::default() };
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(16 * 0x400 * 0x400), config);
+        fs.rfs_init("mem")?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(fs.groups_count(), 2);
+        assert_eq!(fs.super_block_backups(), vec![8193]);
+        assert_eq!(fs.super_block.s_inodes_count, 1024);
+        // inodes and blocks of both groups are used
+        for i in 0..600 {
+            fs.make_node(EXT2_ROOT_INO, &format!("f{}", i), 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        }
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "big", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        assert!(ino > fs.inodes_per_group());
+        let data = vec![7; 10 * 0x400 * 0x400];
+        fs.rfs_write(ino as u64, 0, &data)?;
+        fs.rfs_dump()?;
+
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.rfs_read(ino as u64, 0, data.len() as u32)?, data);
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        Ok(())
+    }
+}
@@ -7,6 +7,8 @@ use crate::rfs_lib::Ext2SuperBlock;
 pub struct Ext2SuperBlockMem {
     /// Inodes count
     pub s_inodes_count: u32,
+    /// Blocks count
+    pub s_blocks_count: u32,
     /// Reserved blocks count
     pub s_r_blocks_count: u32,
     /// Free blocks count
@@ -17,9 +19,55 @@ pub struct Ext2SuperBlockMem {
     pub s_first_data_block: u32,
     /// Block size
     pub s_log_block_size: u32,
+    /// # Blocks per group
+    pub s_blocks_per_group: u32,
+    /// # Inodes per group
+    pub s_inodes_per_group: u32,
+    /// Mount time
+    pub s_mtime: u32,
+    /// Write time
+    pub s_wtime: u32,
+    /// Mount count
+    pub s_mnt_count: u16,
+    /// Maximal mount count
+    pub s_max_mnt_count: u16,
+    /// File system state
+    pub s_state: u16,
+    /// Behaviour when detecting errors
+    pub s_errors: u16,
+    /// time of last check
+    pub s_lastcheck: u32,
 
+    /// Revision level
+    pub s_rev_level: u32,
     /// First non-reserved inode
     pub s_first_ino: u32,
+    /// Size of inode structure
+    pub s_inode_size: u16,
+
+    /// Compatible feature set
+    pub s_feature_compat: u32,
+    /// Incompatible feature set
+    pub s_feature_incompat: u32,
+    /// Readonly-compatible feature set
+    pub s_feature_ro_compat: u32,
+    /// Inode number of journal file
+    pub s_journal_inum: u32,
+
+    /// Inode of user quota file, 0 if disabled
+    pub s_usr_quota_inum: u32,
+    /// Inode of group quota file, 0 if disabled
+    pub s_grp_quota_inum: u32,
+
+    /// Inode of newest snapshot, 0 if none
+    pub s_snapshot_inum: u32,
+    /// Id of the last snapshot taken
+    pub s_snapshot_id: u32,
+    /// Directory of snapshot files
+    pub s_snapshot_list: u32,
+
+    /// 128-bit uuid for volume, seed of metadata checksums
+    pub s_uuid: [u8; 16],
 }
 
 impl Ext2SuperBlockMem {
@@ -0,0 +1,287 @@
+/// Native `mkfs.ext2`, writes a revision 1 ext2 image through `DiskDriver`.
+///
+/// Each group starts with a backup of super block and group desc table if it
+/// has one (see `group_has_super`), then block bitmap, inode bitmap and inode table.
+use anyhow::{anyhow, Result};
+use disk_driver::DiskDriver;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::journal::RFS_JOURNAL_DEFAULT_BLOCKS;
+use crate::rfs_lib::utils::serialize_row;
+use std::cmp::{max, min};
+use std::mem::size_of;
+
+/// Features which can be selected by `-O`, as (name, compat, incompat, ro_compat)
+const MKFS_FEATURES: [(&str, usize, usize, usize); 10] = [
+    ("has_journal", EXT3_FEATURE_COMPAT_HAS_JOURNAL, 0, 0),
+    ("ext_attr", EXT2_FEATURE_COMPAT_EXT_ATTR, 0, 0),
+    ("dir_index", EXT2_FEATURE_COMPAT_DIR_INDEX, 0, 0),
+    ("filetype", 0, EXT2_FEATURE_INCOMPAT_FILETYPE, 0),
+    ("extent", 0, EXT3_FEATURE_INCOMPAT_EXTENTS, 0),
+    ("inline_data", 0, EXT4_FEATURE_INCOMPAT_INLINE_DATA, 0),
+    ("encrypt", 0, EXT4_FEATURE_INCOMPAT_ENCRYPT, 0),
+    ("sparse_super", 0, 0, EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER),
+    ("large_file", 0, 0, EXT2_FEATURE_RO_COMPAT_LARGE_FILE),
+    ("metadata_csum", 0, 0, EXT4_FEATURE_RO_COMPAT_METADATA_CSUM),
+];
+
+#[derive(Debug, Clone)]
+pub struct MkfsOptions {
+    pub block_size: usize,
+    /// Inodes count, calculated by `bytes_per_inode` if None
+    pub inodes_count: Option<usize>,
+    pub bytes_per_inode: usize,
+    pub inode_size: usize,
+    pub label: String,
+    /// Random uuid if None
+    pub uuid: Option<[u8; 16]>,
+    /// Percentage of blocks reserved for super user
+    pub reserved_percent: u32,
+    /// Features to enable, or disable if starts with `^`, applied on default features
+    pub features: Vec<String>,
+    /// Behaviour when detecting errors, `s_errors`
+    pub errors: u16,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 1024,
+            inodes_count: None,
+            bytes_per_inode: 4096,
+            inode_size: EXT2_GOOD_OLD_INODE_SIZE,
+            label: String::new(),
+            uuid: None,
+            reserved_percent: 5,
+            features: vec![],
+            errors: EXT2_ERRORS_DEFAULT as u16,
+        }
+    }
+}
+
+impl MkfsOptions {
+    /// Parse feature list and return (compat, incompat, ro_compat)
+    pub fn feature_set(&self) -> Result<(u32, u32, u32)> {
+        let mut r = (0, EXT2_FEATURE_INCOMPAT_FILETYPE, EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER);
+        for feature in self.features.iter().flat_map(|x| x.split(',')).filter(|x| !x.is_empty()) {
+            let (name, enable) = match feature.strip_prefix('^') {
+                Some(name) => (name, false),
+                None => (feature, true),
+            };
+            let (_, compat, incompat, ro_compat) = MKFS_FEATURES.iter().find(|x| x.0 == name)
+                .ok_or_else(|| anyhow!("Unsupported feature {}", name))?;
+            if enable {
+                r = (r.0 | compat, r.1 | incompat, r.2 | ro_compat);
+            } else {
+                r = (r.0 & !compat, r.1 & !incompat, r.2 & !ro_compat);
+            }
+        }
+        Ok((r.0 as u32, r.1 as u32, r.2 as u32))
+    }
+
+    /// Parse uuid like `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+    pub fn parse_uuid(s: &str) -> Result<[u8; 16]> {
+        let hex = s.replace('-', "");
+        if hex.len() != 32 { return Err(anyhow!("Invalid uuid {}", s)); }
+        let mut uuid = [0u8; 16];
+        for (i, b) in uuid.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| anyhow!("Invalid uuid {}", s))?;
+        }
+        Ok(uuid)
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Format opened disk as ext2 revision 1, with root directory and lost+found
+    pub fn rfs_mkfs(&mut self, options: &MkfsOptions) -> Result<()> {
+        self.cache_clear();
+        let bs = options.block_size;
+        let log_block_size = match bs {
+            1024 => 0,
+            2048 => 1,
+            4096 => 2,
+            _ => return Err(anyhow!("Unsupported block size {}", bs)),
+        };
+        if !options.inode_size.is_power_of_two() || options.inode_size < EXT2_GOOD_OLD_INODE_SIZE || options.inode_size > bs {
+            return Err(anyhow!("Invalid inode size {}", options.inode_size));
+        }
+        if options.reserved_percent > 50 {
+            return Err(anyhow!("Too many reserved blocks: {}%", options.reserved_percent));
+        }
+        let (mut compat, incompat, ro_compat) = options.feature_set()?;
+        if incompat & EXT4_FEATURE_INCOMPAT_INLINE_DATA as u32 != 0 && options.inode_size < 256 {
+            return Err(anyhow!("{} byte inodes are too small for inline data, use 256 or larger", options.inode_size));
+        }
+        if incompat & EXT4_FEATURE_INCOMPAT_ENCRYPT as u32 != 0 && options.inode_size < 256 {
+            return Err(anyhow!("{} byte inodes are too small for encryption contexts, use 256 or larger", options.inode_size));
+        }
+        if incompat & EXT4_FEATURE_INCOMPAT_ENCRYPT as u32 != 0 {
+            // contexts are kept in xattrs
+            compat |= EXT2_FEATURE_COMPAT_EXT_ATTR as u32;
+        }
+        let sparse = ro_compat & EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER as u32 != 0;
+        let first_data_block = if bs == 1024 { 1 } else { 0 };
+        let blocks_per_group = bs * 8;
+        let inodes_per_block = bs / options.inode_size;
+        let mut blocks_count = min(self.disk_size() / bs, u32::MAX as usize);
+        let (groups, inodes_per_group, group_desc_blocks, inode_table_blocks) = loop {
+            let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+            let inodes_count = options.inodes_count.unwrap_or(blocks_count * bs / options.bytes_per_inode);
+            // bitmap of each group is joined by bytes in memory
+            let inodes_per_group = inodes_count.div_ceil(groups)
+                .max(EXT2_GOOD_OLD_FIRST_INO + 5)
+                .next_multiple_of(max(inodes_per_block, 8))
+                .min(blocks_per_group);
+            let group_desc_blocks = (groups * size_of::<Ext2GroupDesc>()).div_ceil(bs);
+            let inode_table_blocks = inodes_per_group / inodes_per_block;
+            let last = groups - 1;
+            let last_blocks = blocks_count - first_data_block - last * blocks_per_group;
+            let overhead = if group_has_super(last, sparse) { 1 + group_desc_blocks } else { 0 } + 2 + inode_table_blocks;
+            // drop the last group if it is too small to hold any data
+            if groups > 1 && last_blocks < overhead + 50 {
+                warn!("drop last group of {} blocks", last_blocks);
+                blocks_count = first_data_block + last * blocks_per_group;
+                continue;
+            }
+            break (groups, inodes_per_group, group_desc_blocks, inode_table_blocks);
+        };
+        let inodes_count = inodes_per_group * groups;
+        // super block, group desc table, block bitmap, inode bitmap, inode table of each group
+        let group_meta = |group: usize| {
+            let start = first_data_block + group * blocks_per_group;
+            let block_bitmap = start + if group_has_super(group, sparse) { 1 + group_desc_blocks } else { 0 };
+            (start, block_bitmap, block_bitmap + 1, block_bitmap + 2)
+        };
+        let data_start = group_meta(0).3 + inode_table_blocks;
+        // root and lost+found need one block for each
+        if data_start + 2 > blocks_count {
+            return Err(anyhow!("Too small disk for {} inodes: {} blocks", inodes_count, blocks_count));
+        }
+        if compat & EXT3_FEATURE_COMPAT_HAS_JOURNAL as u32 != 0 && blocks_count < RFS_JOURNAL_DEFAULT_BLOCKS * 2 {
+            return Err(anyhow!("Too small disk for a journal: {} blocks", blocks_count));
+        }
+        if options.label.len() > EXT2_LABEL_LEN {
+            return Err(anyhow!("Too long label {}", options.label));
+        }
+
+        let mut sb = Ext2SuperBlock::new(inodes_count as u32, blocks_count as u32,
+                                         first_data_block as u32, log_block_size);
+        sb.s_r_blocks_count = (blocks_count as u64 * options.reserved_percent as u64 / 100) as u32;
+        sb.s_log_cluster_size = log_block_size;
+        sb.s_blocks_per_group = blocks_per_group as u32;
+        sb.s_clusters_per_group = blocks_per_group as u32;
+        sb.s_inodes_per_group = inodes_per_group as u32;
+        sb.s_rev_level = EXT2_DYNAMIC_REV as u32;
+        sb.s_first_ino = EXT2_GOOD_OLD_FIRST_INO as u32;
+        sb.s_inode_size = options.inode_size as u16;
+        sb.s_feature_compat = compat;
+        sb.s_feature_incompat = incompat;
+        sb.s_feature_ro_compat = ro_compat;
+        sb.s_errors = options.errors;
+        if sb.has_metadata_csum() { sb.s_checksum_type = EXT2_CRC32C_CHKSUM as u8; }
+        sb.s_uuid = options.uuid.unwrap_or_else(create_uuid);
+        sb.s_volume_name = [0; EXT2_LABEL_LEN];
+        sb.s_volume_name[..options.label.len()].copy_from_slice(options.label.as_bytes());
+        sb.s_hash_seed = create_uuid().chunks(4).map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect::<Vec<_>>().try_into().unwrap();
+        sb.s_reserved_gdt_blocks = 0;
+        sb.s_overhead_clusters = 0;
+        sb.s_min_extra_isize = 0;
+        sb.s_want_extra_isize = 0;
+        sb.s_mkfs_time = get_time_now();
+        sb.s_wtime = sb.s_mkfs_time;
+        sb.s_lastcheck = sb.s_mkfs_time;
+        let gds = (0..groups).map(|group| {
+            let (_, block_bitmap, inode_bitmap, inode_table) = group_meta(group);
+            Ext2GroupDesc {
+                bg_block_bitmap: block_bitmap as u32,
+                bg_inode_bitmap: inode_bitmap as u32,
+                bg_inode_table: inode_table as u32,
+                bg_used_dirs_count: 0,
+                bg_flags: 0,
+                ..Default::default()
+            }
+        }).collect::<Vec<_>>();
+        info!("mkfs: {} blocks of {} B, {} inodes of {} B, {} groups",
+            blocks_count, bs, inodes_count, options.inode_size, groups);
+
+        self.super_block.apply_from(&sb);
+        self.alloc_load_counts();
+        self.alloc().group_desc_table = gds;
+        self.filesystem_first_block = 1;
+        self.journal = None;
+        let zero = self.create_block_vec();
+        for group in 0..groups {
+            let (start, _, _, inode_table) = group_meta(group);
+            for block in start..inode_table + inode_table_blocks {
+                self.write_data_block(block, &zero)?;
+            }
+        }
+        if first_data_block > 0 { self.write_data_block(0, &zero)?; }
+        // super block is kept in memory, write the whole struct once
+        let offset = EXT2_SUPER_BLOCK_OFFSET;
+        let mut data = self.create_block_vec();
+        data[offset % bs..][..size_of::<Ext2SuperBlock>()].copy_from_slice(unsafe { serialize_row(&sb) });
+        self.write_data_block(offset / bs, &data)?;
+
+        // metadata blocks and bits after the end of last group are always used
+        self.alloc().bitmap_data = vec![0; groups * blocks_per_group / 8];
+        for group in 0..groups {
+            let (start, _, _, inode_table) = group_meta(group);
+            for block in start..inode_table + inode_table_blocks {
+                let bit = self.block_bit(block);
+                Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+            }
+        }
+        for bit in blocks_count - first_data_block..groups * blocks_per_group {
+            Self::bitmap_set(&mut self.alloc().bitmap_data, bit + 1);
+        }
+        self.alloc().bitmap_inode = vec![0; groups * inodes_per_group / 8];
+        for ino in 1..EXT2_GOOD_OLD_FIRST_INO {
+            Self::bitmap_set(&mut self.alloc().bitmap_inode, ino);
+        }
+        self.sync_free_counts();
+
+        self.make_node(1, ".", 0o755, Ext2FileType::Directory, 0, 0)?;
+        self.make_node(EXT2_ROOT_INO, crate::rfs_lib::fsck::LOST_AND_FOUND, 0o700, Ext2FileType::Directory, 0, 0)?;
+        self.rfs_dump()?;
+        if compat & EXT3_FEATURE_COMPAT_HAS_JOURNAL as u32 != 0 {
+            // journal_create sets the flag itself
+            self.super_block.s_feature_compat &= !(EXT3_FEATURE_COMPAT_HAS_JOURNAL as u32);
+            self.journal_create(RFS_JOURNAL_DEFAULT_BLOCKS)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mkfs() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let options = MkfsOptions {
+            block_size: 2048,
+            inode_size: 256,
+            label: "rfs".to_string(),
+            features: vec!["^sparse_super,large_file".to_string()],
+            ..Default::default()
+        };
+        fs.rfs_mkfs(&options)?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.block_size(), 2048);
+        assert_eq!(fs.inode_size(), 256);
+        assert_eq!(fs.super_block.s_feature_ro_compat, EXT2_FEATURE_RO_COMPAT_LARGE_FILE as u32);
+        let (lost, _) = fs.rfs_lookup(EXT2_ROOT_INO, crate::rfs_lib::fsck::LOST_AND_FOUND)?;
+        assert_eq!(fs.get_inode(EXT2_ROOT_INO)?.i_links_count, 3);
+        let (ino, _) = fs.make_node(lost, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(ino as u64, 0, &[1; 4096])?;
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        assert!(MkfsOptions { features: vec!["compression".to_string()], ..Default::default() }.feature_set().is_err());
+        Ok(())
+    }
+}
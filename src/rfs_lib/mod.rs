@@ -1,16 +1,14 @@
 /// Filesystem logics
 use std::cmp::{max, min};
-use std::fs::File;
-use std::io::Read;
 use std::mem::size_of;
 use std::path::Path;
-use std::process::Stdio;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 pub use disk_driver;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Error, Result};
 use disk_driver::{DiskDriver, DiskInfo, IOC_REQ_DEVICE_IO_SZ, IOC_REQ_DEVICE_SIZE, SeekType};
 use disk_driver::cache::int_log2;
-use execute::Execute;
+use libc::{EISDIR, ENOTDIR, ENOTEMPTY};
 use log::*;
 use num::range_step;
 // use macro_tools::*;
@@ -22,27 +20,88 @@ pub mod types;
 pub mod mem;
 pub mod fuse;
 pub mod xattr;
+pub mod quota;
+pub mod journal;
+pub mod fsck;
+pub mod mkfs;
+pub mod layout;
+pub mod features;
+pub mod backup;
+pub mod state;
+pub mod checksum;
+pub mod inline;
+pub mod block_map;
+pub mod extent;
+pub mod reflink;
+pub mod snapshot;
+pub mod compress;
+pub mod crypt;
+pub mod defrag;
+pub mod resize;
+pub mod image;
+pub mod debug;
+pub mod api;
+pub mod config;
+pub mod server;
+pub mod cache;
 
 use utils::*;
 use mem::*;
 use desc::*;
-use crate::{DEVICE_FILE, FORCE_FORMAT, LAYOUT_FILE, MKFS_FORMAT};
+use quota::*;
+use reflink::*;
+use snapshot::*;
+use crypt::*;
+use journal::*;
+use mkfs::*;
+use layout::*;
+use config::RfsConfig;
+use cache::*;
 
 /// Data TTL, 1 second default
 const TTL: Duration = Duration::from_secs(1);
 
+/// Bitmaps, group descriptors and free counters. Counters here are the live
+/// ones, those of the super block are set from them when metadata is written.
+#[derive(Default, Clone)]
+pub struct RfsAlloc {
+    pub group_desc_table: Vec<Ext2GroupDesc>,
+    /// bitmap in memory
+    pub bitmap_inode: Vec<u8>,
+    pub bitmap_data: Vec<u8>,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    /// Copied from super block when it is loaded or written, for `statfs`
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    pub inodes_count: u32,
+    pub block_size: u32,
+}
+
 #[derive(Default, Clone)]
 pub struct RFSBase {
     pub driver_info: DiskInfo,
     pub super_block: Ext2SuperBlockMem,
-    pub group_desc_table: Vec<Ext2GroupDesc>,
+    pub alloc: RfsAlloc,
     /// ext2 may has boot reserved 1 block prefix
     pub filesystem_first_block: usize,
-    /// bitmap in memory
-    pub bitmap_inode: Vec<u8>,
-    pub bitmap_data: Vec<u8>,
     /// Root directory
     pub root_dir: Ext2INode,
+    /// User and group quota tables, indexed by `QuotaType`
+    pub quota_tables: [Option<QuotaTable>; 2],
+    /// Owner counts of shared blocks
+    pub refcounts: RefcountTable,
+    /// Copy-on-write state of snapshots
+    pub snapshot: SnapshotState,
+    /// Master keys of encrypted directories
+    pub crypt: CryptKeys,
+    /// Metadata journal, `None` if not enabled
+    pub journal: Option<Journal>,
+    /// Forced by unsupported ro_compat features
+    pub read_only: bool,
+    pub config: RfsConfig,
+    pub inode_cache: InodeCache,
+    pub dentry_cache: DentryCache,
 }
 
 impl RFSBase {
@@ -50,11 +109,18 @@ impl RFSBase {
     pub fn set(&mut self, d: Self) {
         self.driver_info = d.driver_info;
         self.super_block = d.super_block;
-        self.group_desc_table = d.group_desc_table;
+        self.alloc = d.alloc;
         self.filesystem_first_block = d.filesystem_first_block;
-        self.bitmap_inode = d.bitmap_inode;
-        self.bitmap_data = d.bitmap_data;
         self.root_dir = d.root_dir;
+        self.quota_tables = d.quota_tables;
+        self.refcounts = d.refcounts;
+        self.snapshot = d.snapshot;
+        self.crypt = d.crypt;
+        self.journal = d.journal;
+        self.read_only = d.read_only;
+        self.config = d.config;
+        self.inode_cache = d.inode_cache;
+        self.dentry_cache = d.dentry_cache;
     }
 }
 
@@ -65,14 +131,27 @@ pub struct RFS<T: DiskDriver> {
     pub driver: T,
     pub driver_info: DiskInfo,
     pub super_block: Ext2SuperBlockMem,
-    pub group_desc_table: Vec<Ext2GroupDesc>,
+    /// Free space, behind its own lock to be read without the filesystem
+    pub alloc: Arc<Mutex<RfsAlloc>>,
     /// ext2 may has boot reserved 1 block prefix
     pub filesystem_first_block: usize,
-    /// bitmap in memory
-    pub bitmap_inode: Vec<u8>,
-    pub bitmap_data: Vec<u8>,
     /// Root directory
     pub root_dir: Ext2INode,
+    /// User and group quota tables, indexed by `QuotaType`
+    pub quota_tables: [Option<QuotaTable>; 2],
+    /// Owner counts of shared blocks
+    pub refcounts: RefcountTable,
+    /// Copy-on-write state of snapshots
+    pub snapshot: SnapshotState,
+    /// Master keys of encrypted directories
+    pub crypt: CryptKeys,
+    /// Metadata journal, `None` if not enabled
+    pub journal: Option<Journal>,
+    /// Forced by unsupported ro_compat features
+    pub read_only: bool,
+    pub config: RfsConfig,
+    pub inode_cache: InodeCache,
+    pub dentry_cache: DentryCache,
 }
 
 impl<T: DiskDriver> Into<RFSBase> for RFS<T> {
@@ -80,11 +159,18 @@ impl<T: DiskDriver> Into<RFSBase> for RFS<T> {
         RFSBase {
             driver_info: self.driver_info,
             super_block: self.super_block,
-            group_desc_table: self.group_desc_table,
+            alloc: self.alloc.lock().unwrap().clone(),
             filesystem_first_block: self.filesystem_first_block,
-            bitmap_inode: self.bitmap_inode,
-            bitmap_data: self.bitmap_data,
             root_dir: self.root_dir,
+            quota_tables: self.quota_tables,
+            refcounts: self.refcounts,
+            snapshot: self.snapshot,
+            crypt: self.crypt,
+            journal: self.journal,
+            read_only: self.read_only,
+            config: self.config,
+            inode_cache: self.inode_cache,
+            dentry_cache: self.dentry_cache,
         }
     }
 }
@@ -92,16 +178,23 @@ impl<T: DiskDriver> Into<RFSBase> for RFS<T> {
 impl<T: DiskDriver> RFS<T> {
     /// Create RFS object from selected DiskDriver
     #[allow(dead_code)]
-    pub fn new(driver: T) -> Self {
+    pub fn new(driver: T, config: RfsConfig) -> Self {
         Self {
             driver,
             driver_info: Default::default(),
             super_block: Default::default(),
-            group_desc_table: vec![],
+            alloc: Default::default(),
             filesystem_first_block: 0,
-            bitmap_inode: vec![],
-            bitmap_data: vec![],
             root_dir: Default::default(),
+            quota_tables: [None, None],
+            refcounts: Default::default(),
+            snapshot: Default::default(),
+            crypt: Default::default(),
+            journal: None,
+            read_only: false,
+            config,
+            inode_cache: Default::default(),
+            dentry_cache: Default::default(),
         }
     }
 
@@ -111,14 +204,26 @@ impl<T: DiskDriver> RFS<T> {
             driver,
             driver_info: that.driver_info,
             super_block: that.super_block,
-            group_desc_table: that.group_desc_table,
+            alloc: Arc::new(Mutex::new(that.alloc)),
             filesystem_first_block: that.filesystem_first_block,
-            bitmap_inode: that.bitmap_inode,
-            bitmap_data: that.bitmap_data,
             root_dir: that.root_dir,
+            quota_tables: that.quota_tables,
+            refcounts: that.refcounts,
+            snapshot: that.snapshot,
+            crypt: that.crypt,
+            journal: that.journal,
+            read_only: that.read_only,
+            config: that.config,
+            inode_cache: that.inode_cache,
+            dentry_cache: that.dentry_cache,
         }
     }
 
+    /// Lock free space state. Methods locking it again must not be called while the guard lives
+    pub fn alloc(&self) -> MutexGuard<'_, RfsAlloc> {
+        self.alloc.lock().unwrap()
+    }
+
     /// Get disk unit, available after init
     fn disk_block_size(&self) -> usize { self.driver_info.consts.iounit_size as usize }
 
@@ -128,6 +233,158 @@ impl<T: DiskDriver> RFS<T> {
     /// Get filesystem block size, available after init
     pub fn block_size(&self) -> usize { (1 << self.super_block.s_log_block_size) * 0x400 as usize }
 
+    /// Get on-disk inode size, which is fixed for revision 0
+    pub fn inode_size(&self) -> usize {
+        if self.super_block.s_rev_level as usize == EXT2_GOOD_OLD_REV { EXT2_GOOD_OLD_INODE_SIZE } else {
+            self.super_block.s_inode_size as usize
+        }
+    }
+
+    /// Bit index + 1 of block in data bitmap, as bit 0 is `s_first_data_block`
+    pub fn block_bit(&self, block: usize) -> usize {
+        block + 1 - self.super_block.s_first_data_block as usize
+    }
+
+    pub fn blocks_per_group(&self) -> usize { self.super_block.s_blocks_per_group as usize }
+
+    pub fn inodes_per_group(&self) -> usize { self.super_block.s_inodes_per_group as usize }
+
+    pub fn groups_count(&self) -> usize {
+        ((self.super_block.s_blocks_count - self.super_block.s_first_data_block) as usize)
+            .div_ceil(self.blocks_per_group())
+    }
+
+    /// First block of group, where backup super block is placed
+    pub fn group_first_block(&self, group: usize) -> usize {
+        self.super_block.s_first_data_block as usize + group * self.blocks_per_group()
+    }
+
+    /// Blocks count of group, the last group may be smaller
+    pub fn group_blocks(&self, group: usize) -> usize {
+        min(self.blocks_per_group(), self.super_block.s_blocks_count as usize - self.group_first_block(group))
+    }
+
+    /// Group which block belongs to
+    pub fn block_group(&self, block: usize) -> usize {
+        (block - self.super_block.s_first_data_block as usize) / self.blocks_per_group()
+    }
+
+    /// Group which inode belongs to
+    pub fn inode_group(&self, ino: usize) -> usize {
+        (ino - 1) / self.inodes_per_group()
+    }
+
+    /// Inode table blocks of one group
+    pub fn inode_table_blocks(&self) -> usize {
+        (self.inodes_per_group() * self.inode_size()).div_ceil(self.block_size())
+    }
+
+    /// First data block of group, after its bitmaps and inode table
+    pub fn group_data_start(&self, group: usize) -> usize {
+        self.alloc().group_desc_table[group].bg_inode_table as usize + self.inode_table_blocks()
+    }
+
+    /// Block is in data area of its group, not metadata
+    pub fn is_data_block(&self, block: usize) -> bool {
+        block >= self.super_block.s_first_data_block as usize && block < self.super_block.s_blocks_count as usize
+            && block >= self.group_data_start(self.block_group(block))
+    }
+
+    /// First block of primary group desc table
+    pub fn group_desc_block(&self) -> usize {
+        self.super_block.s_first_data_block as usize + self.filesystem_first_block
+    }
+
+    /// Blocks of group desc table
+    pub fn group_desc_blocks(&self) -> usize {
+        (self.groups_count() * size_of::<Ext2GroupDesc>()).div_ceil(self.block_size())
+    }
+
+    /// Group keeps a backup of super block and group desc table
+    pub fn group_has_super(&self, group: usize) -> bool {
+        group_has_super(group, self.super_block.s_feature_ro_compat & EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER as u32 != 0)
+    }
+
+    /// Blocks of backup super blocks
+    pub fn super_block_backups(&self) -> Vec<usize> {
+        (1..self.groups_count()).filter(|g| self.group_has_super(*g)).map(|g| self.group_first_block(g)).collect()
+    }
+
+    /// Bytes of one group in `bitmap_data` or `bitmap_inode`
+    fn group_bitmap_bytes(&self, is_data: bool) -> usize {
+        if is_data { self.blocks_per_group() / 8 } else { self.inodes_per_group() / 8 }
+    }
+
+    /// Rebuild bitmap of group flagged uninitialized, only its metadata blocks are used
+    fn group_bitmap_uninit(&mut self, group: usize, is_data: bool) {
+        let bytes = self.group_bitmap_bytes(is_data);
+        if !is_data {
+            self.alloc().bitmap_inode[group * bytes..][..bytes].fill(0);
+            return;
+        }
+        self.alloc().bitmap_data[group * bytes..][..bytes].fill(0);
+        let first = self.group_first_block(group);
+        let used = (first..self.group_data_start(group))
+            .chain(first + self.group_blocks(group)..first + self.blocks_per_group());
+        for block in used {
+            let bit = self.block_bit(block);
+            Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+        }
+    }
+
+    /// Write bitmap of one group, bits after the group are padded with 1
+    fn write_group_bitmap(&mut self, group: usize, is_data: bool) -> Result<()> {
+        let n = self.group_bitmap_bytes(is_data);
+        let mut buf = vec![0xff; self.block_size()];
+        let block = {
+            let alloc = self.alloc();
+            let gd = &alloc.group_desc_table[group];
+            let bitmap = if is_data { &alloc.bitmap_data } else { &alloc.bitmap_inode };
+            buf[..n].copy_from_slice(&bitmap[group * n..][..n]);
+            (if is_data { gd.bg_block_bitmap } else { gd.bg_inode_bitmap }) as usize
+        };
+        self.write_meta_block(block, &buf)
+    }
+
+    /// Count free bits of one group
+    fn group_free_count(&self, group: usize, is_data: bool) -> usize {
+        let n = self.group_bitmap_bytes(is_data);
+        let alloc = self.alloc();
+        let bitmap = if is_data { &alloc.bitmap_data } else { &alloc.bitmap_inode };
+        bitmap[group * n..][..n].iter().map(|x| x.count_zeros() as usize).sum()
+    }
+
+    /// Set free counters of groups and super block from bitmaps
+    pub fn sync_free_counts(&mut self) {
+        for group in 0..self.groups_count() {
+            let free_blocks = self.group_free_count(group, true);
+            let free_inodes = self.group_free_count(group, false);
+            let mut alloc = self.alloc();
+            let gd = &mut alloc.group_desc_table[group];
+            gd.bg_free_blocks_count = free_blocks as u16;
+            gd.bg_free_inodes_count = free_inodes as u16;
+        }
+        let alloc = self.alloc.lock().unwrap();
+        self.super_block.s_free_blocks_count = alloc.group_desc_table.iter().map(|x| x.bg_free_blocks_count as u32).sum();
+        self.super_block.s_free_inodes_count = alloc.group_desc_table.iter().map(|x| x.bg_free_inodes_count as u32).sum();
+        drop(alloc);
+        // sizes change when resizing
+        self.alloc_load_counts();
+    }
+
+    /// Take free counters and sizes from super block
+    pub fn alloc_load_counts(&mut self) {
+        let block_size = self.block_size() as u32;
+        let sb = &self.super_block;
+        let mut alloc = self.alloc.lock().unwrap();
+        alloc.free_blocks_count = sb.s_free_blocks_count;
+        alloc.free_inodes_count = sb.s_free_inodes_count;
+        alloc.blocks_count = sb.s_blocks_count;
+        alloc.r_blocks_count = sb.s_r_blocks_count;
+        alloc.inodes_count = sb.s_inodes_count;
+        alloc.block_size = block_size;
+    }
+
     pub fn get_driver(&mut self) -> &mut T {
         &mut self.driver
     }
@@ -213,14 +470,17 @@ impl<T: DiskDriver> RFS<T> {
     }
 
     /// Get `Ext2GroupDesc`, available after init
-    fn get_group_desc(&self) -> &Ext2GroupDesc {
-        self.group_desc_table.get(0).unwrap()
+    fn get_group_desc(&self) -> Ext2GroupDesc {
+        *self.alloc().group_desc_table.first().unwrap()
     }
 
     /// Print basic fs info
     /// see: https://lostjeffle.bitcron.com/blog/MWeb/docs/media/15901301484642/15247422226670.jpg
     pub fn print_stats(&self) {
         info!("fs stats: {}", self.super_block.to_string());
+        info!("fs state: {}, mounted {} times, errors behavior: {}",
+            if self.super_block.s_state & EXT2_ERROR_FS as u16 != 0 { "with errors" } else { "ok" },
+            self.super_block.s_mnt_count, state::errors_behavior_name(self.super_block.s_errors));
         info!("fs layout:");
         info!("| BSIZE = {} B |", self.block_size());
         let mut block_layout: Vec<String> = vec![];
@@ -230,7 +490,7 @@ impl<T: DiskDriver> RFS<T> {
         block_layout.push("DATA Map(1)".to_string());
         block_layout.push("Inode Map(1)".to_string());
         block_layout.push(format!("Inode Table({})", self.super_block.s_inodes_count as usize
-            / (self.block_size() / size_of::<Ext2INode>())));
+            / (self.block_size() / self.inode_size())));
         block_layout.push("DATA(*)".to_string());
         info!("| {} |", block_layout.join(" | "));
         info!("For inode bitmap, see @ {:x}", self.get_group_desc().bg_inode_bitmap as usize * self.block_size());
@@ -240,50 +500,65 @@ impl<T: DiskDriver> RFS<T> {
     /// Calculate block number and offset in a block for inode
     fn fetch_inode_block_offset(&self, ino: usize) -> Result<(usize, usize)> {
         // should ino minus 1?
-        let inodes_per_block = self.block_size() / EXT2_INODE_SIZE;
-        // assert only one group
-        // let block_group = (ino - 1) / inodes_per_block;
+        let inode_size = self.inode_size();
+        let inodes_per_block = self.block_size() / inode_size;
         let ino = if ino <= 1 { ino } else { ino - 1 };
-        let offset = (ino % inodes_per_block) * EXT2_INODE_SIZE;
-        let block_number = ino / inodes_per_block + self.get_group_desc().bg_inode_table as usize;
+        let group = ino / self.inodes_per_group();
+        let index = ino % self.inodes_per_group();
+        let offset = (index % inodes_per_block) * inode_size;
+        let block_number = index / inodes_per_block + self.alloc().group_desc_table[group].bg_inode_table as usize;
         // prv!(ino, block_number, offset / EXT2_INODE_SIZE);
         Ok((block_number, offset))
     }
 
+    /// Inode numbers out of range come from corrupted metadata
+    fn check_ino(&mut self, ino: usize) -> Result<()> {
+        if ino > self.super_block.s_inodes_count as usize {
+            return Err(self.fs_error(format!("ino {} out of range, inodes count {}", ino, self.super_block.s_inodes_count)));
+        }
+        Ok(())
+    }
+
     /// Read inode struct according to ino number
     pub fn get_inode(&mut self, ino: usize) -> Result<Ext2INode> {
+        self.check_ino(ino)?;
+        if let Some(inode) = self.inode_cache_get(ino) { return Ok(inode); }
         let (block_number, offset) = self.fetch_inode_block_offset(ino)?;
         debug!("get_inode: inode {} at block {} offset {:x}, disk offset is {:x}",
             ino, block_number, offset, block_number * self.block_size());
         let mut buf = self.create_block_vec();
-        self.seek_block(block_number)?;
-        self.read_block(&mut buf)?;
-        Ok(unsafe { deserialize_row(&buf[offset..]) })
+        self.read_data_block(block_number, &mut buf)?;
+        self.inode_csum_verify(ino, &buf[offset..])?;
+        let inode = unsafe { deserialize_row(&buf[offset..]) };
+        self.inode_cache_put(ino, &inode, false)?;
+        Ok(inode)
     }
 
-    /// Write inode struct according to ino number
+    /// Write inode struct according to ino number, kept in cache until metadata is written
     pub fn set_inode(&mut self, ino: usize, inode: &Ext2INode) -> Result<()> {
-        let (block_number, offset) = self.fetch_inode_block_offset(ino)?;
-        let mut buf = self.create_block_vec();
-        self.seek_block(block_number)?;
-        self.read_block(&mut buf)?;
-        self.seek_block(block_number)?;
-        buf[offset..offset + size_of::<Ext2INode>()]
-            .copy_from_slice(unsafe { serialize_row(inode) });
-        self.write_block(&buf)?;
-        Ok(())
+        self.check_ino(ino)?;
+        self.inode_cache_put(ino, inode, true)
     }
 
     /// Read one data block and return one Vec<u8>
     pub fn get_data_block(&mut self, block: usize) -> Result<Vec<u8>> {
-        self.seek_block(block)?;
         let mut buf = self.create_block_vec();
-        self.read_block(&mut buf)?;
+        self.read_data_block(block, &mut buf)?;
         Ok(buf)
     }
 
     /// Read one data block to mutable slice inplace
     pub fn read_data_block(&mut self, block: usize, buf: &mut [u8]) -> Result<()> {
+        if let Some(data) = self.journal_staged(block) {
+            buf.copy_from_slice(&data[..buf.len()]);
+            return Ok(());
+        }
+        self.inode_cache_before_read(block)?;
+        let block = self.snapshot_block(block);
+        let blocks_count = self.super_block.s_blocks_count as usize;
+        if blocks_count != 0 && block >= blocks_count {
+            return Err(self.fs_error(format!("block {} out of range, blocks count {}", block, blocks_count)));
+        }
         self.seek_block(block)?;
         self.read_block(buf)?;
         Ok(())
@@ -291,6 +566,13 @@ impl<T: DiskDriver> RFS<T> {
 
     /// Write one data block from slice inplace
     pub fn write_data_block(&mut self, block: usize, buf: &[u8]) -> Result<()> {
+        self.inode_cache_written(block, buf);
+        if let Some(data) = self.journal.as_mut().and_then(|j| j.running.get_mut(&block)) {
+            // block staged as metadata in running transaction, keep staged copy newest
+            data[..buf.len()].copy_from_slice(buf);
+            return Ok(());
+        }
+        self.snapshot_cow(block)?;
         self.seek_block(block)?;
         assert!(buf.len() <= self.block_size(), "support sz <= block");
         if buf.len() % self.block_size() == 0 {
@@ -307,16 +589,22 @@ impl<T: DiskDriver> RFS<T> {
     }
 
     /// Read all directory entries in one block
-    pub fn get_block_dir_entries(&mut self, block: usize) -> Result<Vec<Ext2DirEntry>> {
+    pub fn get_block_dir_entries(&mut self, ino: usize, block: usize) -> Result<Vec<Ext2DirEntry>> {
         if block == 0 { return Ok(vec![]); }
         let data_block = self.get_data_block(block)?;
+        self.dir_block_verify(ino, block, &data_block)?;
         let mut p = 0;
         let mut dirs = vec![];
-        while p <= data_block.len() {
+        while p + 8 <= data_block.len() {
             let dir: Ext2DirEntry = unsafe { deserialize_row(&data_block[p..]) };
             if dir.inode == 0 || dir.inode >= self.super_block.s_inodes_count || dir.rec_len == 0 {
                 break;
             }
+            if dir.rec_len % 4 != 0 || (dir.rec_len as usize) < 8 + dir.name_len as usize
+                || p + dir.rec_len as usize > data_block.len() {
+                return Err(self.fs_error(format!("bad dir entry in block {} at {}: rec_len {}, name_len {}",
+                                                 block, p, dir.rec_len, dir.name_len)));
+            }
             debug!("[p {:x}] name_len = {}, rec_len = {}", p, dir.name_len, dir.rec_len);
             p += dir.rec_len as usize;
             debug!("next p: {:x}; dir: {}", p, dir.to_string());
@@ -339,19 +627,16 @@ impl<T: DiskDriver> RFS<T> {
             debug!("dir walk to block {} index {}", block, index);
             if block != 0 {
                 blocks.push(block as u32);
-                Ok((false, false))
-            } else {
-                Ok((true, false))
             }
+            Ok((block != 0, false))
         })?;
 
         // layer 1-3 directory entries supporting
-        Ok(blocks.iter()
-            .map(|b| match self.get_block_dir_entries(*b as usize) {
-                Ok(e) => e,
-                Err(_) => vec![]
-            }).into_iter()
-            .filter(|x| !x.is_empty()).flatten().collect())
+        let mut entries = vec![];
+        for b in blocks {
+            entries.extend(self.get_block_dir_entries(ino, b as usize)?);
+        }
+        Ok(entries)
     }
 
     /// Block index layer threshold
@@ -378,8 +663,18 @@ impl<T: DiskDriver> RFS<T> {
         }
     }
 
+    /// Visit blocks of inode from `block_index` in order by its block map, see `BlockMap::visit`
     pub fn visit_blocks_inode<F>(&mut self, ino: usize, block_index: usize, f: &mut F) -> Result<()>
         where F: FnMut(usize, usize) -> Result<(bool, bool)> {
+        let inode = self.get_inode(ino)?;
+        // i_block holds data instead of block numbers
+        if Self::is_inline(&inode) { return Ok(()); }
+        Self::block_map(&inode).visit(self, ino, block_index, f)
+    }
+
+    /// Visit blocks mapped by 12 direct and 3 indirect pointers in `i_block`
+    pub(crate) fn visit_blocks_indirect(&mut self, ino: usize, block_index: usize,
+                                        f: &mut dyn FnMut(usize, usize) -> Result<(bool, bool)>) -> Result<()> {
         let mut inode = self.get_inode(ino)?;
         let mut inode_modified = false;
         macro_rules! save_inode_and_exit {
@@ -391,20 +686,6 @@ impl<T: DiskDriver> RFS<T> {
                 save_inode_and_exit!(true);
             }
         }
-        for i in block_index..self.threshold(0) {
-            loop {
-                let r = f(inode.i_block[i] as usize, i)?;
-                if r.1 {
-                    // reach data end, and need to allocate new block
-                    let new_block = self.allocate_block()?;
-                    inode.i_block[i] = new_block as u32;
-                    inode_modified = true;
-                } else {
-                    if !r.0 { save_inode_and_exit!(inode_modified); }
-                    break;
-                }
-            }
-        }
         let layer_size = self.block_size() / 4;
         let mut layer_index = [usize::MAX; 3];
         let mut layer_modified = [false; 3];
@@ -415,24 +696,60 @@ impl<T: DiskDriver> RFS<T> {
                 self.set_inode(ino, &inode)?;
                 debug!("modified: {}, layer_index[{}]: {}", layer_modified[$l], $l, layer_index[$l]);
                 if layer_modified[$l] && layer_index[$l] != 0 && layer_index[$l] != usize::MAX {
-                    self.write_data_block(layer_index[$l], &layer_data[$l])?;
+                    self.write_meta_block(layer_index[$l], &layer_data[$l])?;
                     layer_modified[$l] = false;
                 }
             };
         }
+        macro_rules! allocate_or_exit {
+            () => {
+                match self.allocate_block_for(ino, &mut inode) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        // keep blocks allocated before reachable from this inode
+                        dump_index_table!(0);
+                        dump_index_table!(1);
+                        return Err(e);
+                    }
+                }
+            };
+        }
+        for i in block_index..self.threshold(0) {
+            loop {
+                let r = f(inode.i_block[i] as usize, i)?;
+                if r.1 {
+                    // reach data end, and need to allocate new block
+                    let new_block = allocate_or_exit!();
+                    inode.i_block[i] = new_block as u32;
+                    inode_modified = true;
+                } else {
+                    if !r.0 { save_inode_and_exit!(inode_modified); }
+                    break;
+                }
+            }
+        }
         // 12 -> L1
         for i in max(block_index, self.threshold(0))..self.threshold(1) {
             let base_block_number = inode.i_block[12];
+            // result of visiting this zero block before index block allocated
+            let mut pending = None;
             if base_block_number == 0 {
+                // no index block yet, only allocate it when a new block is required
+                let r = f(0, i)?;
+                if !r.1 {
+                    if !r.0 { save_inode_and_exit!(inode_modified); }
+                    continue;
+                }
+                pending = Some(r);
                 // alloc block for layer index data
-                let new_layer_block = self.allocate_block()?;
+                let new_layer_block = allocate_or_exit!();
                 inode.i_block[12] = new_layer_block as u32;
+                inode_modified = true;
                 debug!("new_block for layer index block: {}", new_layer_block);
                 // clear data
-                let layer_index_data = self.create_block_vec();
-                self.write_data_block(new_layer_block, &layer_index_data)?;
-                self.read_data_block(base_block_number as usize, &mut layer_data[0])?;
-                layer_index[0] = base_block_number as usize;
+                layer_data[0].fill(0);
+                self.write_meta_block(new_layer_block, &layer_data[0])?;
+                layer_index[0] = new_layer_block;
             }
             loop {
                 let block_number = inode.i_block[12] as usize;
@@ -447,11 +764,14 @@ impl<T: DiskDriver> RFS<T> {
 
                 let layer_slice = &mut layer_data[0][offset..offset + 4];
                 buf_u32.copy_from_slice(layer_slice);
-                let block = u32::from_be_bytes(buf_u32.clone()) as usize;
-                let r = f(block, i)?;
+                let block = u32::from_le_bytes(buf_u32.clone()) as usize;
+                let r = match pending.take() {
+                    Some(r) if block == 0 => r,
+                    _ => f(block, i)?,
+                };
                 if r.1 {
-                    let new_block = self.allocate_block()? as u32;
-                    layer_slice.copy_from_slice(&new_block.to_be_bytes());
+                    let new_block = allocate_or_exit!() as u32;
+                    layer_slice.copy_from_slice(&new_block.to_le_bytes());
                     layer_modified[0] = true;
                 } else {
                     if !r.0 {
@@ -470,16 +790,25 @@ impl<T: DiskDriver> RFS<T> {
         // warn!("L2!");
         for i in max(block_index, self.threshold(1))..self.threshold(2) {
             let base_block_number = inode.i_block[13];
+            // result of visiting this zero block before index block allocated
+            let mut pending = None;
             if base_block_number == 0 {
+                // no index block yet, only allocate it when a new block is required
+                let r = f(0, i)?;
+                if !r.1 {
+                    if !r.0 { save_inode_and_exit!(inode_modified); }
+                    continue;
+                }
+                pending = Some(r);
                 // alloc block for layer index data
-                let new_layer_block = self.allocate_block()?;
+                let new_layer_block = allocate_or_exit!();
                 inode.i_block[13] = new_layer_block as u32;
+                inode_modified = true;
                 debug!("new_block for layer index block: {}", new_layer_block);
                 // clear data
-                let layer_index_data = self.create_block_vec();
-                self.write_data_block(new_layer_block, &layer_index_data)?;
-                self.read_data_block(base_block_number as usize, &mut layer_data[0])?;
-                layer_index[0] = base_block_number as usize;
+                layer_data[0].fill(0);
+                self.write_meta_block(new_layer_block, &layer_data[0])?;
+                layer_index[0] = new_layer_block;
             }
             // let base_block_number = inode.i_block[13];
             loop {
@@ -495,7 +824,7 @@ impl<T: DiskDriver> RFS<T> {
                 let offset = ((i - self.threshold(1)) / layer_size) << 2;
                 let layer_slice = &mut layer_data[0][offset..offset + 4];
                 buf_u32.copy_from_slice(layer_slice);
-                let block_number2 = u32::from_be_bytes(buf_u32.clone()) as usize;
+                let block_number2 = u32::from_le_bytes(buf_u32.clone()) as usize;
                 if layer_index[1] != block_number2 && block_number2 != 0 {
                     debug!("L2.1: saving layer index data at block {}", layer_index[1]);
                     dump_index_table!(1);
@@ -507,23 +836,29 @@ impl<T: DiskDriver> RFS<T> {
                 let offset2 = ((i - 12) % layer_size) << 2;
                 let layer_slice2 = &mut layer_data[1][offset2..offset2 + 4];
                 buf_u32.copy_from_slice(layer_slice2);
-                let block2 = u32::from_be_bytes(buf_u32.clone()) as usize;
+                // layer 1 table not allocated, do not read stale data
+                let block2 = if block_number2 == 0 { 0 } else { u32::from_le_bytes(buf_u32.clone()) as usize };
                 debug!("ldata[0][{}..+4] = {}, ldata[1][{}..+4] = {}", offset, block_number2, offset2, block2);
 
-                let r = f(block2, i)?;
+                let r = match pending.take() {
+                    Some(r) if block2 == 0 => r,
+                    _ => f(block2, i)?,
+                };
                 if r.1 {
                     if block_number2 == 0 {
-                        let new_block = self.allocate_block()? as u32;
+                        // previous layer 1 table is done
+                        dump_index_table!(1);
+                        let new_block = allocate_or_exit!() as u32;
                         debug!("full, allocate on layer 1, new block: {}, offset: {}", new_block, offset);
                         let layer_index_data = self.create_block_vec();
-                        self.write_data_block(new_block as usize, &layer_index_data)?;
-                        layer_data[0][offset..offset + 4].copy_from_slice(&new_block.to_be_bytes());
+                        self.write_meta_block(new_block as usize, &layer_index_data)?;
+                        layer_data[0][offset..offset + 4].copy_from_slice(&new_block.to_le_bytes());
                         layer_modified[0] = true;
                         self.read_data_block(new_block as usize, &mut layer_data[1])?;
                         layer_index[1] = new_block as usize;
                     }
-                    let new_block = self.allocate_block()? as u32;
-                    layer_data[1][offset2..offset2 + 4].copy_from_slice(&new_block.to_be_bytes());
+                    let new_block = allocate_or_exit!() as u32;
+                    layer_data[1][offset2..offset2 + 4].copy_from_slice(&new_block.to_le_bytes());
                     layer_modified[1] = true;
                 } else {
                     if !r.0 {
@@ -551,7 +886,7 @@ impl<T: DiskDriver> RFS<T> {
             }
             let offset = ((i - self.threshold(1)) << 2) / layer_size;
             buf_u32.copy_from_slice(&layer_data[0][offset..offset + 4]);
-            let block = u32::from_be_bytes(buf_u32.clone()) as usize;
+            let block = u32::from_le_bytes(buf_u32.clone()) as usize;
 
             for j in i..i + layer_size * layer_size {
                 if block_index > j { continue; }
@@ -562,7 +897,7 @@ impl<T: DiskDriver> RFS<T> {
                 }
                 let offset = (((j - 12) % layer_size) / layer_size) << 2;
                 buf_u32.copy_from_slice(&layer_data[1][offset..offset + 4]);
-                let block = u32::from_be_bytes(buf_u32.clone()) as usize;
+                let block = u32::from_le_bytes(buf_u32.clone()) as usize;
 
                 for k in j..j + layer_size {
                     if block_index > k { continue; }
@@ -573,7 +908,7 @@ impl<T: DiskDriver> RFS<T> {
                     }
                     let offset = ((k - 12) % layer_size) << 2;
                     buf_u32.copy_from_slice(&layer_data[2][offset..offset + 4]);
-                    let block = u32::from_be_bytes(buf_u32.clone()) as usize;
+                    let block = u32::from_le_bytes(buf_u32.clone()) as usize;
 
                     let r = f(block, k)?;
                     if !r.0 { return Ok(()); }
@@ -591,16 +926,15 @@ impl<T: DiskDriver> RFS<T> {
         if ino == 0 { 1 } else { if ino == 1 { EXT2_ROOT_INO } else { ino } }
     }
 
-    pub fn bitmap_search(bitmap: &[u8], reserved: usize) -> Result<usize> {
-        for (i, byte) in bitmap.iter().enumerate().skip(reserved) {
-            let b = *byte;
-            for j in 0..8 {
-                if (b >> j) & 0x1 == 0 {
-                    // found free bit, return
-                    return Ok(i * 8 + j + 1);
-                }
+    /// Search first free bit in `reserved..limit` bits, returns index like `bitmap_set`
+    pub fn bitmap_search(bitmap: &[u8], reserved: usize, limit: usize) -> Result<usize> {
+        for i in reserved..min(limit, bitmap.len() * 8) {
+            if bitmap[i / 8] == 0xff { continue; }
+            if (bitmap[i / 8] >> (i % 8)) & 0x1 == 0 {
+                // found free bit, return
+                return Ok(i + 1);
             }
-        };
+        }
         Err(anyhow!("Bitmap full!"))
     }
 
@@ -615,6 +949,11 @@ impl<T: DiskDriver> RFS<T> {
         bitmap[index / 8] = b;
     }
 
+    pub fn bitmap_get(bitmap: &[u8], index: usize) -> bool {
+        let index = if index == 0 { 0 } else { index - 1 };
+        (bitmap[index / 8] >> (index % 8)) & 0x1 != 0
+    }
+
     pub fn bitmap_set(bitmap: &mut [u8], index: usize) {
         Self::bitmap_set_value(bitmap, index, true);
     }
@@ -634,33 +973,49 @@ impl<T: DiskDriver> RFS<T> {
 
     /// Write entries to disk, can skip blocks, entries should be formatted.
     fn apply_directory_entries(&mut self, ino: usize, entries: &Vec<Ext2DirEntry>, block_offset: usize) -> Result<Vec<usize>> {
+        self.dentry_cache.invalidate(ino);
         let total_size = entries.iter().map(|x| x.rec_len as usize).sum::<usize>();
-        let sz = self.block_size();
+        let sz = self.dir_block_space();
         let total_blocks = total_size / sz + if total_size % sz == 0 { 0 } else { 1 };
         let mut blocks = vec![];
 
         self.visit_blocks_inode(ino, block_offset, &mut |block, index| {
-            let continues = (total_blocks + block_offset) > block;
+            let continues = total_blocks + block_offset > index + 1;
             debug!("apply dir walk to block {} index {}, continue={}", block, index, continues);
             if block == 0 {
-                return Ok((continues, continues));
+                return Ok((continues, true));
             }
             blocks.push(block);
             Ok((continues, false))
         })?;
+        // blocks left by removed entries should not be listed again
+        let mut unused = vec![];
+        self.visit_blocks_inode(ino, total_blocks + block_offset, &mut |block, _index| {
+            if block != 0 { unused.push(block); }
+            Ok((block != 0, false))
+        })?;
+        let mut empty = self.create_block_vec();
+        empty[4..6].copy_from_slice(&(sz as u16).to_le_bytes());
+        self.dir_tail_set(ino, &mut empty)?;
+        for block in unused {
+            self.write_meta_block(block, &empty)?;
+        }
         let mut offset = 0 as usize;
         let mut block_index = 0;
-        let mut buf = vec![0 as u8; sz];
+        let mut buf = self.create_block_vec();
+        // file_type is only valid with filetype feature
+        let filetype = self.super_block.s_feature_incompat & EXT2_FEATURE_INCOMPAT_FILETYPE as u32 != 0;
         for (i, e) in entries.iter().enumerate() {
             let l = min(e.rec_len as usize, size_of::<Ext2DirEntry>());
             buf[offset..(offset + l)].copy_from_slice(&unsafe {
                 serialize_row(e)
             }[..l]);
+            if !filetype { buf[offset + 7] = EXT2_FT_UNKNOWN; }
             if offset + e.rec_len as usize >= sz {
                 assert_eq!(offset + e.rec_len as usize, sz);
-                self.write_data_block(blocks[block_index], &buf)?;
+                self.dir_tail_set(ino, &mut buf)?;
+                self.write_meta_block(blocks[block_index], &buf)?;
                 buf.fill(0);
-                assert_eq!(buf.len(), sz);
                 block_index += 1;
                 offset = 0;
                 if block_index == blocks.len() {
@@ -677,7 +1032,7 @@ impl<T: DiskDriver> RFS<T> {
 
     /// Format entries, align to blocks
     fn format_directory_entries(&mut self, entries: &mut Vec<Ext2DirEntry>) -> Result<()> {
-        let sz = self.block_size();
+        let sz = self.dir_block_space();
         let mut offset = 0 as usize;
         let entries_size = entries.len();
         for i in 0..entries.len() {
@@ -700,83 +1055,141 @@ impl<T: DiskDriver> RFS<T> {
         Ok(())
     }
 
-    pub fn make_node(&mut self, parent: usize, name: &str,
-                     mode: usize, node_type: Ext2FileType) -> Result<(usize, Ext2INode)> {
-        debug!("make_node(parent={}, name={})", parent, name);
+    pub fn make_node(&mut self, parent: usize, name: &str, mode: usize,
+                     node_type: Ext2FileType, uid: u32, gid: u32) -> Result<(usize, Ext2INode)> {
+        debug!("make_node(parent={}, name={}, uid={}, gid={})", parent, name, uid, gid);
         let file_type: usize = node_type.clone().into();
-        let ino_free = if parent == 1 { EXT2_ROOT_INO } else { self.allocate_inode()? };
+        self.crypt_check_create(parent)?;
+        let name = self.crypt_name(parent, name)?;
+        let name = name.as_str();
+        self.quota_charge(uid, gid, 0, 1)?;
+        let ino_free = if parent == 1 { EXT2_ROOT_INO } else {
+            match self.allocate_inode() {
+                Ok(ino) => ino,
+                Err(e) => {
+                    self.quota_charge(uid, gid, 0, -1)?;
+                    return Err(e);
+                }
+            }
+        };
         if parent == 1 {
             debug!("allocate bit for root ino");
-            Self::bitmap_set(&mut self.bitmap_inode, EXT2_ROOT_INO);
-            let bitmap_clone: Vec<u8> = self.bitmap_inode.clone();
-            let bitmap_block = self.get_group_desc().bg_inode_bitmap as usize;
-            self.write_data_block(bitmap_block, &bitmap_clone)?;
+            Self::bitmap_set(&mut self.alloc().bitmap_inode, EXT2_ROOT_INO);
+            self.write_group_bitmap(0, false)?;
         }
-        let mut entry = Ext2DirEntry::new(name, ino_free, file_type as u8);
+        let mut entry = Ext2DirEntry::new(name, ino_free, node_type.dir_entry_type());
         entry.inode = ino_free as u32;
 
         let mut inode = Ext2INode::default();
         inode.i_mode = (mode & 0xFFF) as u16 | (file_type << 12) as u16;
+        inode.i_links_count = if node_type == Ext2FileType::Directory { 2 } else { 1 };
+        inode.set_uid(uid);
+        inode.set_gid(gid);
+        if node_type == Ext2FileType::Directory || node_type == Ext2FileType::RegularFile {
+            self.extent_init(&mut inode);
+            // compression is inherited from parent directory like ext2
+            let inherited = parent >= EXT2_ROOT_INO && self.get_inode(parent)?.i_flags & EXT2_COMPR_FL as u32 != 0;
+            if inherited || node_type == Ext2FileType::RegularFile && self.compress_default() {
+                inode.i_flags |= EXT2_COMPR_FL as u32;
+            }
+        }
         if node_type == Ext2FileType::Directory {
-            let mut entries = self.init_directory(parent, &entry)?;
+            // owner should be on disk before blocks are charged
+            self.set_inode(ino_free, &inode)?;
+            // ".." of root is itself
+            let mut entries = self.init_directory(if parent == 1 { EXT2_ROOT_INO } else { parent }, &entry)?;
             self.format_directory_entries(&mut entries)?;
-            let blocks = self.apply_directory_entries(ino_free, &entries, 0)?
-                .into_iter().map(|x| x as u32).collect::<Vec<u32>>();
-            let blocks_slice = &blocks[..(if blocks.len() < 15 { blocks.len() } else { 15 })];
-            inode.i_block[..blocks_slice.len()].copy_from_slice(blocks_slice);
-            inode.i_blocks = blocks.len() as u32;
-            inode.i_size = self.block_size() as u32;
-        } else if node_type == Ext2FileType::RegularFile {
-            inode.i_block[0] = self.allocate_block()? as u32;
-        } else if node_type == Ext2FileType::Symlink {
-            // do not allocate blocks
+            let blocks = self.apply_directory_entries(ino_free, &entries, 0)?;
+            inode = self.get_inode(ino_free)?;
+            inode.i_size = (blocks.len() * self.block_size()) as u32;
+        } else if node_type == Ext2FileType::RegularFile || node_type == Ext2FileType::Symlink {
+            // blocks are allocated when writing
         } else {
             panic!("unsupported type {:?}!", node_type);
         }
+        self.set_inode(ino_free, &inode)?;
+        self.crypt_inherit(parent, ino_free)?;
+        let inode = self.get_inode(ino_free)?;
         if parent >= EXT2_ROOT_INO {
             // update parent entries
-            let mut inode_parent = self.get_inode(parent as usize)?;
-            let mut entries_parent = self.get_dir_entries(parent)?;
-            entries_parent.push(entry);
-            self.format_directory_entries(&mut entries_parent)?;
-            let blocks = self.apply_directory_entries(parent, &entries_parent, 0)?
-                .into_iter().map(|x| x as u32).collect::<Vec<u32>>();
-            let blocks_slice = &blocks[..(if blocks.len() < 15 { blocks.len() } else { 15 })];
-            inode_parent.i_block[..blocks_slice.len()].copy_from_slice(blocks_slice);
-            inode_parent.i_blocks = blocks.len() as u32;
-            self.set_inode(parent, &inode_parent)?;
+            self.add_dir_entry(parent, entry)?;
+            if node_type == Ext2FileType::Directory { self.add_links(parent, 1)?; }
+        }
+        if node_type == Ext2FileType::Directory {
+            let group = self.inode_group(ino_free);
+            self.alloc().group_desc_table[group].bg_used_dirs_count += 1;
         }
-        self.set_inode(ino_free, &inode)?;
 
         Ok((ino_free, inode))
     }
 
-    fn allocate_bitmap(&mut self, bitmap_block: usize, is_data: bool) -> Result<usize> {
-        let bitmap = if is_data { &mut self.bitmap_data } else { &mut self.bitmap_inode };
-        let reserved_blocks = 1 + 1 + 1 + 1 + 1 + self.super_block.s_inodes_count as usize / size_of::<Ext2INode>() + 1;
-        let block_free = Self::bitmap_search(bitmap, if is_data {
-            reserved_blocks
-        } else { self.super_block.s_first_ino as usize + 1 })?;
-        Self::bitmap_set(bitmap, block_free);
-        // save bitmap
-        let bitmap_clone: Vec<u8> = bitmap.clone();
-        self.write_data_block(bitmap_block, &bitmap_clone)?;
-        Ok(block_free)
+    /// First block after inode table
+    pub fn data_start_block(&self) -> usize {
+        self.group_data_start(0)
+    }
+
+    fn allocate_bitmap(&mut self, is_data: bool) -> Result<usize> {
+        let (reserved, limit) = if is_data {
+            (self.block_bit(self.data_start_block()) - 1, self.block_bit(self.super_block.s_blocks_count as usize - 1))
+        } else {
+            (self.super_block.s_first_ino as usize - 1, self.super_block.s_inodes_count as usize)
+        };
+        // blocks used by snapshots need saving before written
+        let block_free = match if is_data { self.snapshot_search(reserved, limit)? } else { None } {
+            Some(block_free) => block_free,
+            None => {
+                let alloc = self.alloc();
+                Self::bitmap_search(if is_data { &alloc.bitmap_data } else { &alloc.bitmap_inode }, reserved, limit)?
+            }
+        };
+        let mut alloc = self.alloc();
+        Self::bitmap_set(if is_data { &mut alloc.bitmap_data } else { &mut alloc.bitmap_inode }, block_free);
+        drop(alloc);
+        // save bitmap of the group
+        let group = (block_free - 1) / if is_data { self.blocks_per_group() } else { self.inodes_per_group() };
+        self.write_group_bitmap(group, is_data)?;
+        Ok(if is_data { block_free + self.super_block.s_first_data_block as usize - 1 } else { block_free })
     }
 
     pub fn allocate_block(&mut self) -> Result<usize> {
-        let block = self.get_group_desc().bg_block_bitmap as usize;
-        let r = self.allocate_bitmap(block, true)?;
+        let r = self.allocate_bitmap(true)?;
         debug!("allocate new block: {}", r);
-        self.super_block.s_free_blocks_count -= 1;
+        self.alloc().free_blocks_count -= 1;
         Ok(r)
     }
 
+    /// Allocate one block used by inode, charging quota and `i_blocks`
+    pub fn allocate_block_for(&mut self, ino: usize, inode: &mut Ext2INode) -> Result<usize> {
+        let tracked = self.quota_tracked(ino);
+        if tracked { self.quota_charge(inode.uid(), inode.gid(), 1, 0)?; }
+        let block = match self.allocate_block() {
+            Ok(block) => block,
+            Err(e) => {
+                if tracked { self.quota_charge(inode.uid(), inode.gid(), -1, 0)?; }
+                return Err(e);
+            }
+        };
+        inode.i_blocks += (self.block_size() / 512) as u32;
+        Ok(block)
+    }
+
+    /// Release one block used by inode, reverse of `allocate_block_for`.
+    /// Shared blocks are kept for other owners.
+    pub fn free_block_for(&mut self, ino: usize, inode: &mut Ext2INode, block: usize) -> Result<()> {
+        if self.quota_tracked(ino) { self.quota_charge(inode.uid(), inode.gid(), -1, 0)?; }
+        if !self.refcount_release(block) {
+            let bit = self.block_bit(block);
+            Self::bitmap_unset(&mut self.alloc().bitmap_data, bit);
+            self.alloc().free_blocks_count += 1;
+        }
+        inode.i_blocks = inode.i_blocks.saturating_sub((self.block_size() / 512) as u32);
+        Ok(())
+    }
+
     pub fn allocate_inode(&mut self) -> Result<usize> {
-        let block = self.get_group_desc().bg_inode_bitmap as usize;
-        let r = self.allocate_bitmap(block, false)?;
+        let r = self.allocate_bitmap(false)?;
         debug!("allocate new ino: {}", r);
-        self.super_block.s_free_inodes_count -= 1;
+        self.alloc().free_inodes_count -= 1;
         Ok(r)
     }
 
@@ -784,8 +1197,9 @@ impl<T: DiskDriver> RFS<T> {
         // read super block
         let super_blk_count = size_of::<Ext2SuperBlock>() / self.disk_block_size();
         let disk_block_size = self.disk_block_size();
-        info!("super block size {} disk block ({} bytes)", super_blk_count, super_blk_count * self.disk_block_size());
+        debug!("super block size {} disk block ({} bytes)", super_blk_count, super_blk_count * self.disk_block_size());
         let mut data_blocks_head = [0 as u8].repeat((disk_block_size * super_blk_count) as usize);
+        self.seek_disk_block(0)?;
         self.read_disk_blocks(&mut data_blocks_head, super_blk_count)?;
         let mut super_block: Ext2SuperBlock = unsafe { deserialize_row(&data_blocks_head) };
         if !super_block.magic_matched() {
@@ -799,7 +1213,48 @@ impl<T: DiskDriver> RFS<T> {
         Ok(super_block)
     }
 
-    pub fn rfs_init(&mut self, file: &str) -> Result<()> {
+    /// Load super block fields, group desc table and bitmaps
+    fn load_fs_meta(&mut self, super_block: &Ext2SuperBlock) -> Result<()> {
+        self.super_block.apply_from(super_block);
+        if !super_block.checksum_valid() {
+            return Err(self.fs_error("super block checksum mismatch".to_string()));
+        }
+        // read block group desc table
+        let groups = self.groups_count();
+        debug!("first start block: {}, {} groups", self.super_block.s_first_data_block, groups);
+        let sz = self.block_size();
+        let mut data = self.create_blocks_vec(self.group_desc_blocks());
+        for i in 0..self.group_desc_blocks() {
+            self.read_data_block(self.group_desc_block() + i, &mut data[i * sz..][..sz])?;
+        }
+        self.alloc().group_desc_table = (0..groups)
+            .map(|g| unsafe { deserialize_row(&data[g * size_of::<Ext2GroupDesc>()..]) })
+            .collect();
+        debug!("groups: {:x?}", self.alloc().group_desc_table);
+        self.alloc_load_counts();
+
+        // bitmaps of groups are joined in memory
+        self.alloc().bitmap_data.clear();
+        self.alloc().bitmap_inode.clear();
+        let mut block = self.create_block_vec();
+        for group in 0..groups {
+            let gd = self.alloc().group_desc_table[group].clone();
+            self.read_data_block(gd.bg_block_bitmap as usize, &mut block)?;
+            self.alloc().bitmap_data.extend_from_slice(&block[..self.group_bitmap_bytes(true)]);
+            self.read_data_block(gd.bg_inode_bitmap as usize, &mut block)?;
+            self.alloc().bitmap_inode.extend_from_slice(&block[..self.group_bitmap_bytes(false)]);
+        }
+        // groups from mke2fs with metadata_csum may have bitmaps never initialized
+        for group in 0..groups {
+            let flags = self.alloc().group_desc_table[group].bg_flags as usize;
+            if flags & EXT2_BG_BLOCK_UNINIT != 0 { self.group_bitmap_uninit(group, true); }
+            if flags & EXT2_BG_INODE_UNINIT != 0 { self.group_bitmap_uninit(group, false); }
+        }
+        self.group_csum_verify()
+    }
+
+    /// Open disk driver and read disk info
+    pub fn driver_open(&mut self, file: &str) -> Result<()> {
         self.get_driver().ddriver_open(file)?;
         // get and check size
         let mut buf = [0 as u8; 4];
@@ -819,212 +1274,132 @@ impl<T: DiskDriver> RFS<T> {
             return Err(anyhow!("Too small disk! disk size is 0x{:x}", self.disk_size()));
         }
         info!("disk info: {:?}", self.driver_info);
+        Ok(())
+    }
+
+    pub fn rfs_init(&mut self, file: &str) -> Result<()> {
+        self.journal = None;
+        self.read_only = false;
+        self.driver_open(file)?;
+        let backup = self.config.super_block;
+        if backup != 0 { self.restore_super_block(backup)?; }
         let mut super_block = self.read_super_block()?;
-        let format = FORCE_FORMAT.read().unwrap().clone();
+        let format = self.config.format;
         if !super_block.magic_matched() || format {
             if !format { warn!("FileSystem not found! creating super block..."); } else {
                 warn!("Will format disk!");
                 self.get_driver().ddriver_reset()?;
             }
-            let mkfs = MKFS_FORMAT.read().unwrap().clone();
-            if mkfs {
-                // let's use mkfs.ext2
-                debug!("close driver");
-                self.get_driver().ddriver_close()?;
-                // create file
-                let mut command = execute::command_args!("dd", format!("of={}", file), "if=/dev/zero",
-                format!("bs={}", self.disk_block_size()),
-                format!("count={}", self.disk_size() / self.disk_block_size()));
-                command.stdout(Stdio::piped());
-                let output = command.execute_output().unwrap();
-                info!("{}", String::from_utf8(output.stdout).unwrap());
-                // use version 0
-                let mut command = execute::command_args!("mkfs.ext2", file, "-t", "ext2", "-r", "0");
-                command.stdout(Stdio::piped());
-                let output = command.execute_output().unwrap();
-                info!("{}", String::from_utf8(output.stdout).unwrap());
-                // reload disk driver
-                self.get_driver().ddriver_open(&file)?;
+            if self.config.mkfs {
+                self.rfs_mkfs(&MkfsOptions::default())?;
                 super_block = self.read_super_block()?;
-                if super_block.magic_matched() {
-                    self.filesystem_first_block = 1;
-                    info!("Disk driver reloaded.");
-                } else {
-                    return Err(anyhow!("Make filesystem failed!"));
-                }
             } else {
                 // use manual fs layout
                 // reload disk driver
                 self.get_driver().ddriver_flush()?;
                 self.seek_block(0)?;
-                let default_layout_str = "
-| BSIZE = 1024 B |
-| Boot(1) | Super(1) | GroupDesc(1) | DATA Map(1) | Inode Map(1) | Inode Table(128) | DATA(*) |";
-                let layout_file = LAYOUT_FILE.read().unwrap().clone();
+                let layout_file = self.config.layout.clone();
                 debug!("loading {}...", layout_file);
                 let path = Path::new(&layout_file);
-                let mut layout_string = default_layout_str.to_string();
-                if path.exists() {
-                    let mut file = File::open(path).unwrap();
-                    let mut data = vec![];
-                    file.read_to_end(&mut data).unwrap();
-                    layout_string = String::from_utf8(data).unwrap();
+                let layout_string = if path.exists() {
+                    std::fs::read_to_string(path)?
                 } else {
-                    warn!("{}({}) not found! use default layout: {}", layout_file, path.to_str().unwrap(), default_layout_str);
+                    warn!("{}({}) not found! use default layout: {}", layout_file, path.to_str().unwrap(), DEFAULT_LAYOUT);
+                    DEFAULT_LAYOUT.to_string()
+                };
+                let layout = parse_layout(&layout_string, self.disk_size())
+                    .map_err(|e| anyhow!("{}:{}", layout_file, e))?;
+                info!("read fs.layout: {:#?}", layout);
+                super_block = Ext2SuperBlock::from(layout.clone());
+                // apply settings, enable functions
+                // super block is always at byte 1024 unless block 0 is all for it
+                self.filesystem_first_block = if layout.boot || layout.block_size > 1024 { 1 } else { 0 };
+                self.super_block.apply_from(&super_block);
+                self.alloc_load_counts();
+                self.alloc().group_desc_table = layout.groups.iter().map(Ext2GroupDesc::from).collect();
+                // clear disk before data of each group, backups are written when dumping
+                let block_data = self.create_block_vec();
+                for group in layout.groups.iter() {
+                    for i in group.start..group.data {
+                        self.write_data_block(i, &block_data)?;
+                    }
                 }
-                let lines = layout_string.lines();
-                let mut layout = FsLayoutArgs::default();
-                for line in lines {
-                    if line.is_empty() || !line.starts_with("|") { continue; }
-                    let line = line.to_lowercase();
-                    if line.contains("bsize") {
-                        let splits = line.split(" ").collect::<Vec<&str>>();
-                        // debug!("split = {:?}", splits);
-                        let n = splits[3];
-                        // debug!("split n = {}", n);
-                        layout.block_size = str::parse::<usize>(n).unwrap();
-                        info!("block_size = {}", layout.block_size);
-                    } else {
-                        let splits = line.split("|")
-                            .map(|x| x.trim())
-                            .filter(|x| x.len() > 0)
-                            .filter(|x| x.contains("("))
-                            .collect::<Vec<&str>>();
-                        debug!("splits: {:?}", splits);
-                        let mut offset_block = 0;
-                        for s in splits {
-                            let v = if s.contains("*") {
-                                0 as usize
-                            } else {
-                                str::parse::<usize>(&s[s.find("(").unwrap() + 1..s.len() - 1]).unwrap()
-                            };
-                            let name = &s[..s.find("(").unwrap()];
-                            debug!("{} = {}", name, v);
-                            match name {
-                                "boot" => {
-                                    layout.boot = true;
-                                    offset_block += 1;
-                                }
-                                "super" => {
-                                    layout.super_block = offset_block;
-                                    offset_block += v;
-                                }
-                                "groupdesc" => {
-                                    layout.group_desc = offset_block;
-                                    offset_block += v;
-                                }
-                                "data map" => {
-                                    layout.data_map = offset_block;
-                                    offset_block += 1;
-                                }
-                                "inode map" => {
-                                    layout.inode_map = offset_block;
-                                    offset_block += 1;
-                                }
-                                "inode table" => {
-                                    layout.inode_table = offset_block;
-                                    layout.inode_count = v * (layout.block_size / size_of::<Ext2INode>());
-                                    offset_block += v;
-                                }
-                                "data" => {}
-                                _ => {
-                                    warn!("unused layout option: {} = {}", name, v)
-                                }
-                            };
-                        }
-                        layout.block_count = self.disk_size() / layout.block_size;
-                        info!("read fs.layout: {:#?}", layout);
-                        super_block = Ext2SuperBlock::from(layout.clone());
-                        let group = Ext2GroupDesc::from(layout.clone());
-                        // apply settings, enable functions
-                        self.filesystem_first_block = if layout.boot { 1 } else { 0 };
-                        self.super_block.apply_from(&super_block);
-                        self.group_desc_table.clear();
-                        self.group_desc_table.push(group);
-                        self.seek_block(0)?;
-                        // clear disk
-                        let block_data = self.create_block_vec();
-                        // for i in 0..self.disk_size() / self.block_size() {
-                        for i in 0..6 {
-                            self.write_data_block(i, &block_data)?;
-                        }
-                        self.seek_block(0)?;
-                        if layout.boot { self.seek_block(1)?; }
-                        debug!("write super_block");
-                        let mut block_data = self.create_block_vec();
-                        block_data[..size_of::<Ext2SuperBlock>()].copy_from_slice(unsafe { serialize_row(&super_block) });
-                        self.write_block(&block_data)?;
-
-                        debug!("write group_desc");
-                        self.seek_block(self.super_block.s_first_data_block as usize + self.filesystem_first_block)?;
-                        let mut block_data = self.create_block_vec();
-                        block_data[..size_of::<Ext2GroupDesc>()].copy_from_slice(unsafe { serialize_row(&self.group_desc_table[0]) });
-                        self.write_block(&block_data)?;
-
-                        let bg_block_bitmap = self.get_group_desc().bg_block_bitmap as usize;
-                        debug!("block bitmap at {} block", bg_block_bitmap);
-                        self.seek_block(bg_block_bitmap)?;
-                        let bitmap_data_block = self.create_block_vec();
-                        self.write_block(&bitmap_data_block)?;
-                        self.bitmap_data.clear();
-                        self.bitmap_data.extend_from_slice(&bitmap_data_block);
-
-                        let bg_inode_bitmap = self.get_group_desc().bg_inode_bitmap as usize;
-                        debug!("inode bitmap at {} block", bg_inode_bitmap);
-                        self.seek_block(bg_inode_bitmap)?;
-                        let bitmap_inode = self.create_block_vec();
-                        self.write_block(&bitmap_inode)?;
-                        self.bitmap_inode.clear();
-                        self.bitmap_inode.extend_from_slice(&bitmap_inode);
-
-                        // create root directory
-                        self.make_node(1, ".", 0o755, Ext2FileType::Directory)?;
-                        // self.make_node(EXT2_ROOT_INO, "lost+found", 0o755, Ext2FileType::Directory)?;
-                        debug!("dump all, reload fs");
-                        self.rfs_dump()?;
+                debug!("write super_block");
+                let offset = self.filesystem_first_block * EXT2_SUPER_BLOCK_OFFSET;
+                let mut block_data = self.create_block_vec();
+                block_data[offset % layout.block_size..][..size_of::<Ext2SuperBlock>()]
+                    .copy_from_slice(unsafe { serialize_row(&super_block) });
+                self.write_data_block(offset / layout.block_size, &block_data)?;
+
+                debug!("write group_desc");
+                let mut block_data = self.create_blocks_vec(self.group_desc_blocks());
+                for (i, gd) in self.alloc.lock().unwrap().group_desc_table.iter().enumerate() {
+                    block_data[i * size_of::<Ext2GroupDesc>()..][..size_of::<Ext2GroupDesc>()]
+                        .copy_from_slice(unsafe { serialize_row(gd) });
+                }
+                for i in 0..self.group_desc_blocks() {
+                    self.write_data_block(layout.group_desc + i, &block_data[i * layout.block_size..][..layout.block_size])?;
+                }
+
+                // metadata blocks, bits after the end of each group and reserved inodes are used
+                let groups = layout.groups.len();
+                self.alloc().bitmap_data = vec![0; groups * self.group_bitmap_bytes(true)];
+                for (g, group) in layout.groups.iter().enumerate() {
+                    for block in self.group_first_block(g)..group.data {
+                        let bit = self.block_bit(block);
+                        Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
                     }
+                    for bit in self.block_bit(group.start + group.blocks)..=(g + 1) * self.blocks_per_group() {
+                        Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+                    }
+                }
+                self.alloc().bitmap_inode = vec![0; groups * self.group_bitmap_bytes(false)];
+                for ino in 1..self.super_block.s_first_ino as usize {
+                    Self::bitmap_set(&mut self.alloc().bitmap_inode, ino);
                 }
+                for g in 0..groups {
+                    self.write_group_bitmap(g, true)?;
+                    self.write_group_bitmap(g, false)?;
+                }
+
+                // create root directory
+                self.make_node(1, ".", 0o755, Ext2FileType::Directory, 0, 0)?;
+                // self.make_node(EXT2_ROOT_INO, "lost+found", 0o755, Ext2FileType::Directory)?;
+                debug!("dump all, reload fs");
+                self.rfs_dump()?;
+                // counters changed by creating root
+                super_block = self.read_super_block()?;
             }
         } else {
             info!("FileSystem found!");
             debug!("fs: {:x?}", super_block);
         }
-        self.super_block.apply_from(&super_block);
-        // read block group desc table
-        debug!("first start block: {}", self.super_block.s_first_data_block);
-        self.seek_block(self.super_block.s_first_data_block as usize + self.filesystem_first_block)?;
-        let mut data_block = self.create_block_vec();
-        self.read_block(&mut data_block)?;
-        // just assert there is only one group now
-        let group: Ext2GroupDesc = unsafe { deserialize_row(&data_block) };
-        // debug!("group desc data: {:x?}", data_block);
-        debug!("group: {:x?}", group);
-        self.group_desc_table.clear();
-        self.group_desc_table.push(group);
-
-        let bg_block_bitmap = self.get_group_desc().bg_block_bitmap as usize;
-        debug!("block bitmap at {} block", bg_block_bitmap);
-        self.seek_block(bg_block_bitmap)?;
-        let mut bitmap_data_block = self.create_block_vec();
-        // ino 1 and 2 reserved
-        bitmap_data_block[0] = 0x3;
-        self.read_block(&mut bitmap_data_block)?;
-        debug!("block bit map: {:?}", &bitmap_data_block[..32]);
-        self.bitmap_data.clear();
-        self.bitmap_data.extend_from_slice(&bitmap_data_block);
-
-        let bg_inode_bitmap = self.get_group_desc().bg_inode_bitmap as usize;
-        debug!("inode bitmap at {} block", bg_inode_bitmap);
-        self.seek_block(bg_inode_bitmap)?;
-        let mut bitmap_inode = self.create_block_vec();
-        self.read_block(&mut bitmap_inode)?;
-        debug!("inode bit map: {:?}", &bitmap_inode[..32]);
-        self.bitmap_inode.clear();
-        self.bitmap_inode.extend_from_slice(&bitmap_inode);
+        self.rfs_load(&super_block)
+    }
+
+    /// Load metadata of the filesystem with `super_block` read from opened driver
+    pub(crate) fn rfs_load(&mut self, super_block: &Ext2SuperBlock) -> Result<()> {
+        self.cache_clear();
+        self.features_check(super_block)?;
+        self.load_fs_meta(super_block)?;
+        if self.journal_load()? {
+            // replayed blocks may contain super block, group desc and bitmaps
+            let super_block = self.read_super_block()?;
+            self.load_fs_meta(&super_block)?;
+        }
+        if self.journal_enabled() {
+            // cleared when unmounted cleanly
+            self.super_block.s_feature_incompat |= EXT3_FEATURE_INCOMPAT_RECOVER as u32;
+        }
 
         // load root dir
         self.root_dir = self.get_inode(EXT2_ROOT_INO)?;
         debug!("root dir inode: {:?}", self.root_dir);
+        self.quota_load()?;
+        self.refcount_load()?;
+        self.snapshot_load()?;
+        self.crypt_load()?;
+        self.mount_state_begin()?;
 
         self.print_stats();
         debug!("Init done.");
@@ -1032,20 +1407,28 @@ impl<T: DiskDriver> RFS<T> {
     }
 
     pub fn rfs_destroy(&mut self) -> Result<()> {
+        self.super_block.s_feature_incompat &= !(EXT3_FEATURE_INCOMPAT_RECOVER as u32);
+        self.mount_state_end();
         self.rfs_dump()?;
         self.get_driver().ddriver_close()
     }
 
     pub fn rfs_lookup(&mut self, parent: usize, name: &str) -> Result<(usize, Ext2INode)> {
         let parent = RFS::<T>::shift_ino(parent);
-        let entries = self.get_dir_entries(parent)?;
-        for d in entries {
-            debug!("dir entry [{}] {} type {}", d.inode, d.get_name(), d.file_type);
-            if d.get_name() == name {
-                return Ok((d.inode as usize, self.get_inode(d.inode as usize)?));
+        let name = self.crypt_name(parent, name)?;
+        let name = name.as_str();
+        let found = match self.dentry_cache.get(parent, name) {
+            Some(found) => found,
+            None => {
+                let found = self.get_dir_entries(parent)?.into_iter().find(|d| d.get_name() == name).map(|d| d.inode as usize);
+                self.dentry_cache.insert(parent, name, found);
+                found
             }
+        };
+        match found {
+            Some(ino) => Ok((ino, self.get_inode(ino)?)),
+            None => Err(anyhow!("file not found")),
         }
-        Err(anyhow!("file not found"))
     }
 
     pub fn rfs_setattr(&mut self, ino: u64, mode: Option<u32>,
@@ -1054,28 +1437,36 @@ impl<T: DiskDriver> RFS<T> {
                        chgtime: Option<SystemTime>,
                        bkuptime: Option<SystemTime>, flags: Option<u32>) -> Result<Ext2INode> {
         let ino = RFS::<T>::shift_ino(ino as usize);
+        self.snapshot_check_protected(ino)?;
+        if let Some(v) = flags { self.rfs_set_flags(ino as u64, v)?; }
         let mut node = self.get_inode(ino)?;
+        if let Some(v) = size.filter(|_| Self::is_inline(&node)) {
+            self.inline_truncate(ino, v as usize)?;
+            node = self.get_inode(ino)?;
+        } else if let Some(v) = size.filter(|v| *v < node.i_size as u64 | (node.i_size_high as u64) << 32) {
+            if Self::is_encrypted(&node) { self.crypt_truncate(ino, v as usize)?; }
+            if Self::is_compressed(&node) { self.compress_truncate(ino, v as usize)?; }
+            self.truncate_blocks(ino, v as usize)?;
+            node = self.get_inode(ino)?;
+        }
         match mode {
             Some(v) => node.i_mode = v as u16,
             _ => {}
         };
+        if uid.is_some() || gid.is_some() {
+            self.quota_transfer(ino, &node, uid.unwrap_or(node.uid()), gid.unwrap_or(node.gid()))?;
+        }
         match uid {
-            Some(v) => {
-                node.i_uid = (v & 0xFF) as u16;
-                node.i_uid_high = (v >> 16) as u16;
-            }
+            Some(v) => node.set_uid(v),
             _ => {}
         };
         match gid {
-            Some(v) => {
-                node.i_gid = (v & 0xFF) as u16;
-                node.i_gid_high = (v >> 16) as u16;
-            }
+            Some(v) => node.set_gid(v),
             _ => {}
         };
         match size {
             Some(v) => {
-                node.i_size = (v & 0xFFFF) as u32;
+                node.i_size = v as u32;
                 node.i_size_high = (v >> 32) as u32;
             }
             _ => {}
@@ -1097,10 +1488,6 @@ impl<T: DiskDriver> RFS<T> {
             Some(v) => node.i_dtime = v.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
             _ => {}
         };
-        match flags {
-            Some(v) => node.i_flags = v,
-            _ => {}
-        };
         self.set_inode(ino, &node)?;
         Ok(node)
     }
@@ -1111,6 +1498,35 @@ impl<T: DiskDriver> RFS<T> {
         let size = size as usize;
         let sz = self.block_size();
         let ino = RFS::<T>::shift_ino(ino as usize);
+        let inode = self.get_inode(ino)?;
+        if Self::is_encrypted(&inode) { return self.crypt_read(ino, offset, size); }
+        if Self::is_compressed(&inode) { return self.compress_read(ino, offset, size); }
+        if Self::is_inline(&inode) {
+            let mut data = self.inline_read(ino)?;
+            data.resize(max(data.len(), offset + size), 0);
+            return Ok(data[offset..offset + size].to_vec());
+        }
+        let blocks = self.read_block_list(ino, offset, size)?;
+        debug!("reading blocks: {:?}", blocks);
+        let mut data: Vec<u8> = [0 as u8].repeat(size);
+        for (i, block) in blocks.iter().enumerate() {
+            // if i * sz >= size { break; }
+            let block = *block;
+            let right = min((i + 1) * sz, size);
+            if block == usize::MAX {
+                // this is an un-allocated block but use zero data
+                data[(i * sz)..right].copy_from_slice(&[0 as u8].repeat(sz));
+            } else {
+                self.read_data_block(block, &mut data[(i * sz)..right])?;
+            }
+            offset += right - (i * sz);
+        }
+        Ok(data)
+    }
+
+    /// Blocks of a plain file to read `size` bytes at `offset`, `usize::MAX` for holes
+    pub(crate) fn read_block_list(&mut self, ino: usize, offset: usize, size: usize) -> Result<Vec<usize>> {
+        let sz = self.block_size();
         let mut blocks: Vec<usize> = vec![];
         let start_index = offset / self.block_size();
         assert_eq!(offset % self.block_size(), 0);
@@ -1152,42 +1568,75 @@ impl<T: DiskDriver> RFS<T> {
             last_block = block;
             Ok((will_continue, false))
         })?;
-        debug!("reading blocks: {:?}", blocks);
-        let mut data: Vec<u8> = [0 as u8].repeat(size);
-        for (i, block) in blocks.iter().enumerate() {
-            // if i * sz >= size { break; }
-            let block = *block;
-            let right = min((i + 1) * sz, size);
-            if block == usize::MAX {
-                // this is an un-allocated block but use zero data
-                data[(i * sz)..right].copy_from_slice(&[0 as u8].repeat(sz));
-            } else {
-                self.read_data_block(block, &mut data[(i * sz)..right])?;
-            }
-            offset += right - (i * sz);
-        }
-        Ok(data)
+        Ok(blocks)
     }
 
     pub fn rfs_write(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u32> {
+        self.snapshot_check_protected(ino as usize)?;
+        let ino_shifted = RFS::<T>::shift_ino(ino as usize);
+        if Self::is_encrypted(&self.get_inode(ino_shifted)?) {
+            return self.crypt_write(ino_shifted, offset as usize, data);
+        }
+        if let Some(written) = self.inline_write(RFS::<T>::shift_ino(ino as usize), offset as usize, data)? {
+            return Ok(written);
+        }
+        if Self::is_compressed(&self.get_inode(ino_shifted)?) {
+            return self.compress_write(ino_shifted, offset as usize, data);
+        }
+        self.rfs_write_blocks(ino, offset, data)
+    }
+
+    /// Write data to blocks mapped by `i_block`
+    fn rfs_write_blocks(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u32> {
         let sz = self.block_size();
         let size = data.len() as usize;
         if offset as usize % sz != 0 {
             debug!("unaligned write! offset=0x{:x}, len={}", offset, size);
             let sz_log = int_log2(sz as u64) as usize;
             let offset_aligned = down_align(offset as usize, sz_log);
-            let size_aligned = up_align(size, sz_log) + sz;
+            let size_aligned = (offset as usize + size).div_ceil(sz) * sz - offset_aligned;
             let mut data_read = self.rfs_read(ino, offset_aligned as i64, size_aligned as u32)?;
             // let mut data_read = self.rfs_read(ino, offset_aligned as i64, size as u32)?;
             data_read[(offset as usize - offset_aligned)..(size + offset as usize - offset_aligned)].copy_from_slice(data);
-            self.rfs_write(ino, offset_aligned as i64, &data_read)?;
+            let ino_shifted = RFS::<T>::shift_ino(ino as usize);
+            let filesize = self.get_inode(ino_shifted)?.i_size as usize;
+            self.rfs_write_blocks(ino, offset_aligned as i64, &data_read)?;
+            // padding read for alignment is not part of file
+            let mut inode = self.get_inode(ino_shifted)?;
+            inode.i_size = max(filesize, offset as usize + size) as u32;
+            self.set_inode(ino_shifted, &inode)?;
             return Ok(size as u32);
         }
         debug!("#write: offset = {:x}, size = {:x}", offset, size);
         let mut offset = offset as usize;
         let base = offset;
         let ino = RFS::<T>::shift_ino(ino as usize);
-        let start_index = offset as usize / self.block_size();
+        let blocks = self.write_block_map(ino, offset, size)?;
+        debug!("writing blocks: {:?}", blocks);
+        for (i, block) in blocks.iter().enumerate() {
+            // if i * sz >= size { break; }
+            let right = min((i + 1) * sz, size);
+            self.write_data_block(*block, &data[(i * sz)..right])?;
+            offset += right - (i * sz);
+        }
+        debug!("update file stats");
+        let mut inode = self.get_inode(ino)?;
+        let filesize = inode.i_size as i64 | ((inode.i_size_high as i64) << 32);
+        if offset as i64 > filesize {
+            // TODO: large file
+            inode.i_size = offset as u32;
+            inode.i_size_high = (offset >> 32) as u32;
+            self.set_inode(ino, &inode)?;
+        }
+        let written = offset - base;
+        debug!("#write: reply written = {}", written);
+        Ok(written as u32)
+    }
+
+    /// Blocks to write `size` bytes at aligned `offset`, allocated and unshared if needed
+    pub(crate) fn write_block_map(&mut self, ino: usize, offset: usize, size: usize) -> Result<Vec<usize>> {
+        let sz = self.block_size();
+        let start_index = offset / sz;
 
         let mut blocks: Vec<usize> = vec![];
 
@@ -1224,83 +1673,144 @@ impl<T: DiskDriver> RFS<T> {
             last_block = block;
             Ok((will_continue, false))
         })?;
-        debug!("writing blocks: {:?}", blocks);
-        for (i, block) in blocks.iter().enumerate() {
-            // if i * sz >= size { break; }
-            let right = min((i + 1) * sz, size);
-            self.write_data_block(*block, &data[(i * sz)..right])?;
-            offset += right - (i * sz);
-        }
-        debug!("update file stats");
-        let mut inode = self.get_inode(ino)?;
-        let filesize = inode.i_size as i64 | ((inode.i_size_high as i64) << 32);
-        if offset as i64 > filesize {
-            // TODO: large file
-            inode.i_size = offset as u32;
-            inode.i_size_high = (offset >> 32) as u32;
-            self.set_inode(ino, &inode)?;
-        }
-        let written = offset - base;
-        debug!("#write: reply written = {}", written);
-        Ok(written as u32)
+        self.reflink_unshare(ino, start_index, blocks, size)
     }
 
     pub fn rfs_readdir(&mut self, ino: u64, offset: i64) -> Result<Vec<Ext2DirEntry>> {
         let ino = RFS::<T>::shift_ino(ino as usize);
-        let entries = self.get_dir_entries(ino)?.into_iter()
+        let mut entries = self.get_dir_entries(ino)?.into_iter()
             .skip(offset as usize).collect::<Vec<Ext2DirEntry>>();
+        self.crypt_entries(ino, &mut entries)?;
         Ok(entries)
     }
 
-    /// Dump all data in memory to disk
-    pub fn rfs_dump(&mut self) -> Result<()> {
+    /// Write super block, group desc and bitmaps
+    pub fn write_fs_meta(&mut self) -> Result<()> {
+        self.inode_cache_flush(None)?;
+        // counters are always the same as bitmaps
+        self.sync_free_counts();
+        self.group_csum_set();
+        self.super_block.s_wtime = get_time_now();
         debug!("dump super block");
         let mut super_block = self.read_super_block()?;
         self.super_block.apply_to(&mut super_block);
+        super_block.s_block_group_nr = 0;
+        super_block.update_checksum();
         let super_block_data = unsafe { serialize_row(&super_block) };
-        self.write_data_block(self.filesystem_first_block, super_block_data)?;
+        // super block may share one block with boot sector
+        let offset = self.filesystem_first_block * EXT2_SUPER_BLOCK_OFFSET;
+        let mut data_block = self.get_data_block(offset / self.block_size())?;
+        data_block[offset % self.block_size()..][..super_block_data.len()].copy_from_slice(super_block_data);
+        self.write_meta_block(offset / self.block_size(), &data_block)?;
         debug!("dump group desc");
-        let mut data_block = self.create_block_vec();
-        assert_eq!(self.group_desc_table.len(), 1);
-        let group_desc_data = unsafe { serialize_row(self.group_desc_table.get(0).unwrap()) };
-        data_block[..group_desc_data.len()].copy_from_slice(group_desc_data);
-        self.write_data_block(self.super_block.s_first_data_block as usize + self.filesystem_first_block, &data_block)?;
+        let sz = self.block_size();
+        let mut table = self.create_blocks_vec(self.group_desc_blocks());
+        for (i, gd) in self.alloc.lock().unwrap().group_desc_table.iter().enumerate() {
+            let gd_data = unsafe { serialize_row(gd) };
+            table[i * gd_data.len()..][..gd_data.len()].copy_from_slice(gd_data);
+        }
+        for i in 0..self.group_desc_blocks() {
+            self.write_meta_block(self.group_desc_block() + i, &table[i * sz..][..sz])?;
+        }
+        debug!("dump backups");
+        for block in self.super_block_backups() {
+            let mut backup: Ext2SuperBlock = unsafe { deserialize_row(super_block_data) };
+            backup.s_block_group_nr = self.block_group(block) as u16;
+            backup.update_checksum();
+            let mut data_block = self.create_block_vec();
+            let backup_data = unsafe { serialize_row(&backup) };
+            data_block[..backup_data.len()].copy_from_slice(backup_data);
+            self.write_meta_block(block, &data_block)?;
+            for i in 0..self.group_desc_blocks() {
+                self.write_meta_block(block + 1 + i, &table[i * sz..][..sz])?;
+            }
+        }
         debug!("dump bitmaps");
-        let inode_block_number = self.get_group_desc().bg_inode_bitmap as usize;
-        let bitmap_data_clone = self.bitmap_inode.clone();
-        self.write_data_block(inode_block_number, &bitmap_data_clone)?;
-        let data_block_number = self.get_group_desc().bg_block_bitmap as usize;
-        let bitmap_data_clone = self.bitmap_data.clone();
-        self.write_data_block(data_block_number, &bitmap_data_clone)?;
+        for group in 0..self.groups_count() {
+            self.write_group_bitmap(group, false)?;
+            self.write_group_bitmap(group, true)?;
+        }
+        Ok(())
+    }
+
+    /// Dump all data in memory to disk
+    pub fn rfs_dump(&mut self) -> Result<()> {
+        if self.read_only { return Ok(()); }
+        self.quota_save()?;
+        self.refcount_save()?;
+        self.write_fs_meta()?;
         debug!("flush disk");
         self.driver.ddriver_flush()?;
         Ok(())
     }
 
-    /// Remove a file
-    pub fn rfs_unlink(&mut self, parent: usize, name: &str) -> Result<()> {
-        let parent = RFS::<T>::shift_ino(parent);
+    /// Append one entry to directory and update its size
+    pub fn add_dir_entry(&mut self, parent: usize, entry: Ext2DirEntry) -> Result<()> {
+        let mut entries = self.get_dir_entries(parent)?;
+        entries.push(entry);
+        self.format_directory_entries(&mut entries)?;
+        let blocks = self.apply_directory_entries(parent, &entries, 0)?;
+        let mut inode = self.get_inode(parent)?;
+        inode.i_size = (blocks.len() * self.block_size()) as u32;
+        self.set_inode(parent, &inode)
+    }
+
+    /// Remove one entry from directory, returns removed entry
+    pub fn remove_dir_entry(&mut self, parent: usize, name: &str) -> Result<Ext2DirEntry> {
         let entries = self.get_dir_entries(parent)?;
         let d = match entries.iter().find(|x| x.get_name() == name) {
             Some(d) => d.clone(),
             None => return Err(anyhow!("No such of file {}!", name)),
         };
-        // debug!("get file inode");
-        // let inode = self.get_inode(d.inode as usize)?;
+        let mut others = entries.into_iter().filter(|x| x.get_name() != name).collect::<Vec<_>>();
+        self.format_directory_entries(&mut others)?;
+        let blocks = self.apply_directory_entries(parent, &others, 0)?;
+        let mut inode = self.get_inode(parent)?;
+        inode.i_size = (blocks.len() * self.block_size()) as u32;
+        self.set_inode(parent, &inode)?;
+        Ok(d)
+    }
+
+    /// Change `i_links_count` of one inode
+    fn add_links(&mut self, ino: usize, delta: i32) -> Result<()> {
+        let mut inode = self.get_inode(ino)?;
+        inode.i_links_count = (inode.i_links_count as i32 + delta).max(0) as u16;
+        self.set_inode(ino, &inode)
+    }
+
+    /// Index blocks used by inode, not including data blocks
+    pub fn index_blocks(&mut self, ino: usize, inode: &Ext2INode) -> Result<Vec<usize>> {
+        if Self::is_inline(inode) { return Ok(vec![]); }
+        Self::block_map(inode).meta_blocks(self, ino, inode)
+    }
+
+    /// Release blocks and inode bitmap of one inode
+    fn free_inode(&mut self, ino: usize) -> Result<()> {
+        self.dentry_cache.invalidate(ino);
+        let inode = self.get_inode(ino)?;
+        if self.quota_tracked(ino) {
+            let blocks = self.count_inode_blocks(ino)? as i64;
+            self.quota_charge(inode.uid(), inode.gid(), -blocks, -1)?;
+        }
         debug!("unset bitmaps");
-        let file_type = Ext2FileType::try_from(d.file_type as usize).unwrap();
+        let file_type = Ext2FileType::try_from(inode.i_mode as usize >> 12).unwrap();
         match file_type {
             Ext2FileType::RegularFile | Ext2FileType::Directory => {
                 let mut remove_blocks = vec![];
-                self.visit_blocks_inode(d.inode as usize, 0, &mut |block, index| {
+                let end = self.blocks_end(ino, &inode)?;
+                self.visit_blocks_inode(ino, 0, &mut |block, index| {
                     debug!("remove walk to block {} index {}", block, index);
                     if block != 0 {
                         remove_blocks.push(block);
                     }
-                    Ok((block != 0, false))
+                    Ok((index + 1 < end, false))
                 })?;
+                remove_blocks.retain(|x| !self.refcount_release(*x));
+                remove_blocks.extend(self.index_blocks(ino, &inode)?);
+                self.alloc().free_blocks_count += remove_blocks.len() as u32;
                 for b in remove_blocks {
-                    Self::bitmap_unset(&mut self.bitmap_data, b);
+                    let bit = self.block_bit(b);
+                    Self::bitmap_unset(&mut self.alloc().bitmap_data, bit);
                 }
             }
             Ext2FileType::Symlink => {
@@ -1308,12 +1818,36 @@ impl<T: DiskDriver> RFS<T> {
             }
             _ => {}
         }
-        Self::bitmap_unset(&mut self.bitmap_inode, d.inode as usize);
-        let mut others = entries.into_iter().filter(|x| x.inode != d.inode).collect::<Vec<_>>();
-        self.format_directory_entries(&mut others)?;
-        // TODO: free blocks used by dir entries
-        self.apply_directory_entries(parent, &others, 0)?;
-        Ok(())
+        if file_type == Ext2FileType::Directory {
+            let group = self.inode_group(ino);
+            let mut alloc = self.alloc();
+            let gd = &mut alloc.group_desc_table[group];
+            gd.bg_used_dirs_count = gd.bg_used_dirs_count.saturating_sub(1);
+        }
+        Self::bitmap_unset(&mut self.alloc().bitmap_inode, ino);
+        self.alloc().free_inodes_count += 1;
+        self.set_inode(ino, &Ext2INode { i_dtime: get_time_now(), ..inode })
+    }
+
+    /// Remove a file
+    pub fn rfs_unlink(&mut self, parent: usize, name: &str) -> Result<()> {
+        let parent = RFS::<T>::shift_ino(parent);
+        let name = self.crypt_name(parent, name)?;
+        let name = name.as_str();
+        self.unlink_entry(parent, name)
+    }
+
+    /// Remove a file by the name stored in directory
+    fn unlink_entry(&mut self, parent: usize, name: &str) -> Result<()> {
+        if let Some(d) = self.get_dir_entries(parent)?.iter().find(|x| x.get_name() == name) {
+            self.snapshot_check_protected(d.inode as usize)?;
+        }
+        let d = self.remove_dir_entry(parent, name)?;
+        if self.get_inode(d.inode as usize)?.i_mode as usize >> 12 == Ext2FileType::Directory.into() {
+            // ".." of removed directory
+            self.add_links(parent, -1)?;
+        }
+        self.free_inode(d.inode as usize)
     }
 
     pub fn rfs_rmdir(&mut self, parent: usize, name: &str) -> Result<()> {
@@ -1324,24 +1858,55 @@ impl<T: DiskDriver> RFS<T> {
     pub fn rfs_rename(&mut self, parent: usize, name: &str, newparent: usize, newname: &str) -> Result<()> {
         let parent = RFS::<T>::shift_ino(parent);
         let newparent = RFS::<T>::shift_ino(newparent);
-        let entries = self.get_dir_entries(parent)?;
-        let mut d = match entries.iter().find(|x| x.get_name() == name) {
+        let name = self.crypt_name(parent, name)?;
+        let name = name.as_str();
+        let newname = self.crypt_name(newparent, newname)?;
+        let newname = newname.as_str();
+        let ino = match self.get_dir_entries(parent)?.iter().find(|x| x.get_name() == name) {
             None => return Err(anyhow!("No such of file {}!", name)),
-            Some(d) => d.clone(),
+            Some(d) => d.inode,
         };
-        self.rfs_unlink(parent, name)?;
+        self.snapshot_check_protected(ino as usize)?;
+        self.snapshot_check_protected(newparent)?;
+        self.crypt_check_create(parent)?;
+        self.crypt_check_link(newparent, ino as usize)?;
+        let is_dir = self.get_inode(ino as usize)?.i_mode as usize >> 12 == Ext2FileType::Directory.into();
+        if let Some(target) = self.get_dir_entries(newparent)?.iter().find(|x| x.get_name() == newname) {
+            if target.inode == ino { return Ok(()); }
+            let target = target.inode as usize;
+            let target_is_dir = self.get_inode(target)?.i_mode as usize >> 12 == Ext2FileType::Directory.into();
+            if target_is_dir && !is_dir {
+                return Err(Error::new(Errno(EISDIR)).context(format!("{} is a directory", newname)));
+            }
+            if !target_is_dir && is_dir {
+                return Err(Error::new(Errno(ENOTDIR)).context(format!("{} is not a directory", newname)));
+            }
+            if target_is_dir && self.get_dir_entries(target)?.iter().any(|x| x.get_name() != "." && x.get_name() != "..") {
+                return Err(Error::new(Errno(ENOTEMPTY)).context(format!("directory {} not empty", newname)));
+            }
+            self.unlink_entry(newparent, newname)?;
+        }
+        let mut d = self.remove_dir_entry(parent, name)?;
         d.update_name(newname);
-        let mut entries_new = self.get_dir_entries(newparent)?;
-        entries_new.push(d);
-        self.format_directory_entries(&mut entries_new)?;
-        self.apply_directory_entries(newparent, &entries_new, 0)?;
+        self.add_dir_entry(newparent, d)?;
+        if is_dir && parent != newparent {
+            let mut entries = self.get_dir_entries(ino as usize)?;
+            for e in entries.iter_mut().filter(|x| x.get_name() == "..") {
+                e.inode = newparent as u32;
+            }
+            self.format_directory_entries(&mut entries)?;
+            self.apply_directory_entries(ino as usize, &entries, 0)?;
+            self.add_links(parent, -1)?;
+            self.add_links(newparent, 1)?;
+        }
         Ok(())
     }
 
-    pub fn rfs_symlink(&mut self, parent: usize, name: &str, link: &str) -> Result<(usize, Ext2INode)> {
-        let (ino, mut inode) = self.make_node(parent, name, 0xfff, Ext2FileType::Symlink)?;
+    pub fn rfs_symlink(&mut self, parent: usize, name: &str, link: &str, uid: u32, gid: u32) -> Result<(usize, Ext2INode)> {
+        let (ino, mut inode) = self.make_node(parent, name, 0xfff, Ext2FileType::Symlink, uid, gid)?;
         // fill link path to i_block
-        let link_raw_data = link.as_bytes();
+        let encrypted = self.crypt_symlink(ino, link)?;
+        let link_raw_data = encrypted.as_deref().unwrap_or(link.as_bytes());
         let link_name_words = (link_raw_data.len() / 4) + (if link_raw_data.len() % 4 == 0 { 0 } else { 1 });
         let mut link_data = vec![0 as u32; link_name_words];
         let mut buf_u32 = [0 as u8; 4];
@@ -1353,7 +1918,45 @@ impl<T: DiskDriver> RFS<T> {
             link_data[i] = u32::from_le_bytes(buf_u32);
         }
         inode.i_block[..link_data.len()].copy_from_slice(&link_data);
+        if encrypted.is_some() { inode.i_size = link_raw_data.len() as u32; }
         self.set_inode(ino, &inode)?;
         Ok((ino, inode))
     }
 }
+
+/// Filesystem on a fresh memory disk for tests
+#[cfg(test)]
+pub(crate) fn test_fs() -> Result<RFS<disk_driver::memory::MemoryDiskDriver>> {
+    let mut fs = RFS::new(disk_driver::memory::MemoryDiskDriver::new(), RfsConfig::default());
+    fs.rfs_init("mem")?;
+    Ok(fs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_replace() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let (full, _) = fs.make_node(EXT2_ROOT_INO, "full", 0o755, Ext2FileType::Directory, 0, 0)?;
+        fs.make_node(full, "child", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.make_node(EXT2_ROOT_INO, "empty", 0o755, Ext2FileType::Directory, 0, 0)?;
+        fs.make_node(EXT2_ROOT_INO, "dir", 0o755, Ext2FileType::Directory, 0, 0)?;
+        fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.make_node(EXT2_ROOT_INO, "other", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let errno = |r: Result<()>| get_errno(&r.unwrap_err(), 0);
+        assert_eq!(errno(fs.rfs_rename(EXT2_ROOT_INO, "dir", EXT2_ROOT_INO, "full")), ENOTEMPTY);
+        assert_eq!(errno(fs.rfs_rename(EXT2_ROOT_INO, "file", EXT2_ROOT_INO, "dir")), EISDIR);
+        assert_eq!(errno(fs.rfs_rename(EXT2_ROOT_INO, "dir", EXT2_ROOT_INO, "file")), ENOTDIR);
+        assert_eq!(fs.rfs_lookup(full, "child")?.1.i_links_count, 1);
+        // empty directories and files are replaced
+        fs.rfs_rename(EXT2_ROOT_INO, "dir", EXT2_ROOT_INO, "empty")?;
+        fs.rfs_rename(EXT2_ROOT_INO, "file", EXT2_ROOT_INO, "other")?;
+        let names = fs.get_dir_entries(EXT2_ROOT_INO)?.iter().map(|x| x.get_name()).collect::<Vec<_>>();
+        assert!(!names.contains(&"dir".to_string()) && !names.contains(&"file".to_string()));
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        Ok(())
+    }
+}
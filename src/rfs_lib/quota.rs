@@ -0,0 +1,471 @@
+/// User and group disk quotas.
+///
+/// Quota files live in the reserved quota inodes, `EXT4_USR_QUOTA_INO` and
+/// `EXT4_GRP_QUOTA_INO`, and are enabled by setting `s_usr_quota_inum` and
+/// `s_grp_quota_inum` in super block. Each file is a `QuotaHeader`
+/// followed by `count` fixed size `QuotaEntry` records.
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use anyhow::{anyhow, Result};
+use disk_driver::DiskDriver;
+use libc::EDQUOT;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// "RFSQ"
+pub const RFS_QUOTA_MAGIC: u32 = 0x51534652;
+pub const RFS_QUOTA_VERSION: u32 = 1;
+/// Default grace period, 7 days
+pub const RFS_QUOTA_DEFAULT_GRACE: u32 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaType {
+    User = 0,
+    Group = 1,
+}
+
+impl QuotaType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuotaType::User => "user",
+            QuotaType::Group => "group",
+        }
+    }
+
+    /// Reserved inode for this quota file
+    pub fn ino(&self) -> usize {
+        match self {
+            QuotaType::User => EXT4_USR_QUOTA_INO,
+            QuotaType::Group => EXT4_GRP_QUOTA_INO,
+        }
+    }
+}
+
+/// Header of quota file
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct QuotaHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// Grace period of block soft limit, in seconds
+    pub block_grace: u32,
+    /// Grace period of inode soft limit, in seconds
+    pub inode_grace: u32,
+    /// Records count following this header
+    pub count: u32,
+}
+
+/// Quota record of one uid or gid, limits and usage in fs blocks or inodes.
+/// Limit 0 means no limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct QuotaEntry {
+    pub id: u32,
+    pub block_usage: u32,
+    pub block_soft: u32,
+    pub block_hard: u32,
+    /// Time when exceeded block soft limit turns to hard, 0 if not exceeded
+    pub block_grace: u32,
+    pub inode_usage: u32,
+    pub inode_soft: u32,
+    pub inode_hard: u32,
+    /// Time when exceeded inode soft limit turns to hard, 0 if not exceeded
+    pub inode_grace: u32,
+}
+
+/// Apply `delta` on one usage counter, following soft/hard limits and grace period.
+/// Only increasing usage could fail.
+fn charge_usage(usage: &mut u32, soft: u32, hard: u32, grace_until: &mut u32,
+                delta: i64, period: u32, now: u32) -> bool {
+    let new_usage = max_zero(*usage as i64 + delta);
+    if delta > 0 {
+        if hard != 0 && new_usage > hard {
+            return false;
+        }
+        if soft != 0 && new_usage > soft {
+            if *grace_until == 0 {
+                *grace_until = now + period;
+            } else if now >= *grace_until {
+                return false;
+            }
+        }
+    }
+    if soft == 0 || new_usage <= soft {
+        *grace_until = 0;
+    }
+    *usage = new_usage;
+    true
+}
+
+fn max_zero(v: i64) -> u32 {
+    if v < 0 { 0 } else { v as u32 }
+}
+
+impl QuotaEntry {
+    pub fn new(id: u32) -> Self {
+        Self { id, ..Default::default() }
+    }
+
+    /// Change usage, returns false and keeps unchanged when exceeding limits
+    pub fn charge(&mut self, blocks: i64, inodes: i64, table: &QuotaTable, now: u32) -> bool {
+        let mut e = *self;
+        if !charge_usage(&mut e.block_usage, e.block_soft, e.block_hard, &mut e.block_grace,
+                         blocks, table.block_grace, now) {
+            return false;
+        }
+        if !charge_usage(&mut e.inode_usage, e.inode_soft, e.inode_hard, &mut e.inode_grace,
+                         inodes, table.inode_grace, now) {
+            return false;
+        }
+        *self = e;
+        true
+    }
+
+    fn grace_string(grace: u32, now: u32) -> String {
+        if grace == 0 {
+            "".to_string()
+        } else if grace <= now {
+            "none".to_string()
+        } else {
+            let left = grace - now;
+            if left >= 24 * 60 * 60 {
+                format!("{}days", left / (24 * 60 * 60))
+            } else {
+                format!("{:02}:{:02}", left / (60 * 60), left / 60 % 60)
+            }
+        }
+    }
+}
+
+/// Quota records of one type
+#[derive(Debug, Clone)]
+pub struct QuotaTable {
+    pub block_grace: u32,
+    pub inode_grace: u32,
+    pub entries: BTreeMap<u32, QuotaEntry>,
+    pub dirty: bool,
+}
+
+impl Default for QuotaTable {
+    fn default() -> Self {
+        Self {
+            block_grace: RFS_QUOTA_DEFAULT_GRACE,
+            inode_grace: RFS_QUOTA_DEFAULT_GRACE,
+            entries: BTreeMap::new(),
+            dirty: true,
+        }
+    }
+}
+
+impl QuotaTable {
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < size_of::<QuotaHeader>() {
+            return Err(anyhow!("quota file too small"));
+        }
+        let header: QuotaHeader = unsafe { deserialize_row(data) };
+        if header.magic != RFS_QUOTA_MAGIC || header.version != RFS_QUOTA_VERSION {
+            return Err(anyhow!("bad quota file header {:x?}", header));
+        }
+        let mut entries = BTreeMap::new();
+        for i in 0..header.count as usize {
+            let p = size_of::<QuotaHeader>() + i * size_of::<QuotaEntry>();
+            if p + size_of::<QuotaEntry>() > data.len() {
+                return Err(anyhow!("quota file truncated at record {}", i));
+            }
+            let e: QuotaEntry = unsafe { deserialize_row(&data[p..]) };
+            entries.insert(e.id, e);
+        }
+        Ok(Self {
+            block_grace: header.block_grace,
+            inode_grace: header.inode_grace,
+            entries,
+            dirty: false,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = QuotaHeader {
+            magic: RFS_QUOTA_MAGIC,
+            version: RFS_QUOTA_VERSION,
+            block_grace: self.block_grace,
+            inode_grace: self.inode_grace,
+            count: self.entries.len() as u32,
+        };
+        let mut data = unsafe { serialize_row(&header) }.to_vec();
+        for e in self.entries.values() {
+            data.extend_from_slice(unsafe { serialize_row(e) });
+        }
+        data
+    }
+
+    pub fn entry(&mut self, id: u32) -> &mut QuotaEntry {
+        self.entries.entry(id).or_insert_with(|| QuotaEntry::new(id))
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn quota_table(&mut self, qtype: QuotaType) -> Option<&mut QuotaTable> {
+        self.quota_tables[qtype as usize].as_mut()
+    }
+
+    pub fn quota_enabled(&self) -> bool {
+        self.quota_tables.iter().any(|x| x.is_some())
+    }
+
+    /// Reserved inodes except root are not charged
+    pub fn quota_tracked(&self, ino: usize) -> bool {
+        ino == EXT2_ROOT_INO || ino >= self.super_block.s_first_ino as usize
+    }
+
+    fn quota_inum(&self, qtype: QuotaType) -> u32 {
+        match qtype {
+            QuotaType::User => self.super_block.s_usr_quota_inum,
+            QuotaType::Group => self.super_block.s_grp_quota_inum,
+        }
+    }
+
+    /// Charge usage to owner, fails with EDQUOT when any table exceeds limits.
+    /// Negative values release usage and never fail.
+    pub fn quota_charge(&mut self, uid: u32, gid: u32, blocks: i64, inodes: i64) -> Result<()> {
+        if (blocks == 0 && inodes == 0) || !self.quota_enabled() { return Ok(()); }
+        let now = get_time_now();
+        let mut changed = vec![];
+        for (qtype, id) in [(QuotaType::User, uid), (QuotaType::Group, gid)] {
+            if let Some(table) = self.quota_tables[qtype as usize].as_ref() {
+                let mut e = table.entries.get(&id).copied().unwrap_or_else(|| QuotaEntry::new(id));
+                if !e.charge(blocks, inodes, table, now) {
+                    warn!("{} {} exceeds quota, blocks {:+}, inodes {:+}: {:?}",
+                        qtype.name(), id, blocks, inodes, e);
+                    return Err(anyhow::Error::new(Errno(EDQUOT))
+                        .context(format!("{} {} disk quota exceeded", qtype.name(), id)));
+                }
+                changed.push((qtype, e));
+            }
+        }
+        for (qtype, e) in changed {
+            let table = self.quota_tables[qtype as usize].as_mut().unwrap();
+            table.entries.insert(e.id, e);
+            table.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Move usage of one inode when its owner changes
+    pub fn quota_transfer(&mut self, ino: usize, old: &Ext2INode, uid: u32, gid: u32) -> Result<()> {
+        if !self.quota_enabled() || !self.quota_tracked(ino) { return Ok(()); }
+        if old.uid() == uid && old.gid() == gid { return Ok(()); }
+        let blocks = self.count_inode_blocks(ino)? as i64;
+        self.quota_charge(uid, gid, blocks, 1)?;
+        self.quota_charge(old.uid(), old.gid(), -blocks, -1)
+    }
+
+    /// Blocks used by one inode, including index blocks
+    pub fn count_inode_blocks(&mut self, ino: usize) -> Result<usize> {
+        let inode = self.get_inode(ino)?;
+        if inode.i_mode as usize >> 12 == Ext2FileType::Symlink.into() {
+            return Ok(0);
+        }
+        let mut count = 0;
+        let end = self.blocks_end(ino, &inode)?;
+        self.visit_blocks_inode(ino, 0, &mut |block, index| {
+            if block != 0 { count += 1; }
+            Ok((index + 1 < end, false))
+        })?;
+        Ok(count + self.index_blocks(ino, &inode)?.len())
+    }
+
+    /// Load quota tables recorded in super block
+    pub fn quota_load(&mut self) -> Result<()> {
+        for qtype in [QuotaType::User, QuotaType::Group] {
+            let ino = self.quota_inum(qtype) as usize;
+            self.quota_tables[qtype as usize] = if ino == 0 { None } else {
+                let inode = self.get_inode(ino)?;
+                if inode.i_size == 0 {
+                    return Err(anyhow!("{} quota file on inode {} is empty", qtype.name(), ino));
+                }
+                let sz = self.block_size() as u32;
+                let data = self.rfs_read(ino as u64, 0, inode.i_size.div_ceil(sz) * sz)?;
+                let table = QuotaTable::from_bytes(&data)?;
+                info!("{} quota enabled, {} records", qtype.name(), table.entries.len());
+                Some(table)
+            };
+        }
+        Ok(())
+    }
+
+    /// Write dirty quota tables back to quota files
+    pub fn quota_save(&mut self) -> Result<()> {
+        for qtype in [QuotaType::User, QuotaType::Group] {
+            let data = match self.quota_table(qtype) {
+                Some(table) if table.dirty => {
+                    table.dirty = false;
+                    table.to_bytes()
+                }
+                _ => continue,
+            };
+            debug!("save {} quota file, {} bytes", qtype.name(), data.len());
+            self.rfs_write(self.quota_inum(qtype) as u64, 0, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Enable quota of this type, create quota file and count current usage
+    pub fn quota_enable(&mut self, qtype: QuotaType) -> Result<()> {
+        self.check_writable()?;
+        if self.quota_table(qtype).is_some() { return Ok(()); }
+        let ino = qtype.ino();
+        info!("enable {} quota on inode {}", qtype.name(), ino);
+        let inode = Ext2INode {
+            i_mode: ((usize::from(Ext2FileType::RegularFile) << 12) | 0o600) as u16,
+            i_links_count: 1,
+            ..Default::default()
+        };
+        self.set_inode(ino, &inode)?;
+        Self::bitmap_set(&mut self.alloc().bitmap_inode, ino);
+        match qtype {
+            QuotaType::User => self.super_block.s_usr_quota_inum = ino as u32,
+            QuotaType::Group => self.super_block.s_grp_quota_inum = ino as u32,
+        };
+        self.quota_tables[qtype as usize] = Some(QuotaTable::default());
+        self.quota_check()
+    }
+
+    /// Recount usage of all enabled tables by scanning used inodes
+    pub fn quota_check(&mut self) -> Result<()> {
+        if !self.quota_enabled() { return Ok(()); }
+        for table in self.quota_tables.iter_mut().flatten() {
+            for e in table.entries.values_mut() {
+                e.block_usage = 0;
+                e.inode_usage = 0;
+            }
+            table.dirty = true;
+        }
+        let first_ino = self.super_block.s_first_ino as usize;
+        let inos = [EXT2_ROOT_INO].into_iter()
+            .chain(first_ino..=self.super_block.s_inodes_count as usize)
+            .filter(|ino| Self::bitmap_get(&self.alloc().bitmap_inode, *ino))
+            .collect::<Vec<_>>();
+        for ino in inos {
+            let inode = self.get_inode(ino)?;
+            let blocks = self.count_inode_blocks(ino)? as u32;
+            for (qtype, id) in [(QuotaType::User, inode.uid()), (QuotaType::Group, inode.gid())] {
+                if let Some(table) = self.quota_table(qtype) {
+                    let e = table.entry(id);
+                    e.block_usage += blocks;
+                    e.inode_usage += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set limits of one id, `None` keeps current value
+    pub fn quota_set(&mut self, qtype: QuotaType, id: u32,
+                     block_soft: Option<u32>, block_hard: Option<u32>,
+                     inode_soft: Option<u32>, inode_hard: Option<u32>) -> Result<()> {
+        self.quota_enable(qtype)?;
+        let table = self.quota_table(qtype).unwrap();
+        let e = table.entry(id);
+        if let Some(v) = block_soft { e.block_soft = v; }
+        if let Some(v) = block_hard { e.block_hard = v; }
+        if let Some(v) = inode_soft { e.inode_soft = v; }
+        if let Some(v) = inode_hard { e.inode_hard = v; }
+        if e.block_soft == 0 || e.block_usage <= e.block_soft { e.block_grace = 0; }
+        if e.inode_soft == 0 || e.inode_usage <= e.inode_soft { e.inode_grace = 0; }
+        table.dirty = true;
+        Ok(())
+    }
+
+    /// Set grace periods in seconds
+    pub fn quota_set_grace(&mut self, qtype: QuotaType, block_grace: Option<u32>, inode_grace: Option<u32>) -> Result<()> {
+        self.quota_enable(qtype)?;
+        let table = self.quota_table(qtype).unwrap();
+        if let Some(v) = block_grace { table.block_grace = v; }
+        if let Some(v) = inode_grace { table.inode_grace = v; }
+        table.dirty = true;
+        Ok(())
+    }
+
+    /// Format quota table like `repquota`
+    pub fn quota_report(&mut self, qtype: QuotaType) -> String {
+        let block_size = self.block_size();
+        let table = match self.quota_table(qtype) {
+            None => return format!("{} quota is not enabled\n", qtype.name()),
+            Some(t) => t,
+        };
+        let now = get_time_now();
+        let mut r = format!("*** Report for {} quotas, block size {} B\n", qtype.name(), block_size);
+        r += &format!("Block grace time: {}s; Inode grace time: {}s\n", table.block_grace, table.inode_grace);
+        r += &format!("{:>10} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8}\n",
+                      "id", "blocks", "soft", "hard", "grace", "files", "soft", "hard", "grace");
+        for e in table.entries.values() {
+            r += &format!("{:>10} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8}\n",
+                          e.id, e.block_usage, e.block_soft, e.block_hard,
+                          QuotaEntry::grace_string(e.block_grace, now),
+                          e.inode_usage, e.inode_soft, e.inode_hard,
+                          QuotaEntry::grace_string(e.inode_grace, now));
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_limits() {
+        let table = QuotaTable { block_grace: 100, ..Default::default() };
+        let mut e = QuotaEntry { block_soft: 10, block_hard: 20, inode_hard: 1, ..QuotaEntry::new(1000) };
+        assert!(e.charge(10, 1, &table, 1000));
+        assert_eq!(e.block_grace, 0);
+        // over soft limit starts grace period
+        assert!(e.charge(5, 0, &table, 1000));
+        assert_eq!(e.block_grace, 1100);
+        // hard limit
+        assert!(!e.charge(6, 0, &table, 1000));
+        assert!(!e.charge(0, 1, &table, 1000));
+        assert_eq!(e.block_usage, 15);
+        // grace period expired
+        assert!(!e.charge(1, 0, &table, 1100));
+        // release always works and resets grace
+        assert!(e.charge(-6, -1, &table, 1200));
+        assert_eq!((e.block_usage, e.inode_usage, e.block_grace), (9, 0, 0));
+    }
+
+    #[test]
+    fn test_quota_table_bytes() -> Result<()> {
+        let mut table = QuotaTable::default();
+        table.entry(0).block_usage = 3;
+        table.entry(1000).inode_hard = 5;
+        let loaded = QuotaTable::from_bytes(&table.to_bytes())?;
+        assert_eq!(loaded.entries, table.entries);
+        assert_eq!(loaded.block_grace, RFS_QUOTA_DEFAULT_GRACE);
+        assert!(QuotaTable::from_bytes(&[0; 4]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota_edquot() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        fs.quota_set(QuotaType::User, 1000, None, Some(3), None, Some(2))?;
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 1000, 1000)?;
+        fs.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 1000, 1000)?;
+        let e = fs.make_node(EXT2_ROOT_INO, "c", 0o644, Ext2FileType::RegularFile, 1000, 1000).unwrap_err();
+        assert_eq!(get_errno(&e, 0), EDQUOT);
+        // files are created without blocks, only three blocks are allowed
+        let e = fs.rfs_write(ino as u64, 0, &vec![1; fs.block_size() * 4]).unwrap_err();
+        assert_eq!(get_errno(&e, 0), EDQUOT);
+        fs.rfs_unlink(EXT2_ROOT_INO, "b")?;
+        let e = fs.quota_table(QuotaType::User).unwrap().entry(1000);
+        assert_eq!((e.block_usage, e.inode_usage), (3, 1));
+        // usage is counted again after reload
+        fs.rfs_dump()?;
+        fs.quota_check()?;
+        let e = fs.quota_table(QuotaType::User).unwrap().entry(1000);
+        assert_eq!((e.block_usage, e.inode_usage), (3, 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+/// Reflink clones, data blocks shared between files with copy-on-write.
+///
+/// Blocks owned by more than one file are listed with their owner counts in a
+/// refcount file on reserved inode `RFS_REFCOUNT_INO`, a `RefcountHeader`
+/// followed by `count` `RefcountEntry` records. Sharing sets ro_compat
+/// `shared_blocks`, so e2fsck accepts blocks claimed by several inodes.
+/// Each owner is charged for shared blocks in `i_blocks` and quota.
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use anyhow::{anyhow, Error, Result};
+use disk_driver::DiskDriver;
+use libc::EINVAL;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// "RFSR"
+pub const RFS_REFCOUNT_MAGIC: u32 = 0x52534652;
+pub const RFS_REFCOUNT_VERSION: u32 = 1;
+/// Reserved inode holding refcount file
+pub const RFS_REFCOUNT_INO: usize = EXT2_BOOT_LOADER_INO;
+/// `_IOW('r', 1, u64)`, clone whole file of inode number in argument to the opened file,
+/// like `FICLONE` which does not reach FUSE
+pub const RFS_IOC_CLONE: u32 = (1 << 30) | ((size_of::<u64>() as u32) << 16) | ((b'r' as u32) << 8) | 1;
+/// Bytes copied by one read when blocks cannot be shared
+const RFS_COPY_CHUNK: usize = 0x10_0000;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RefcountHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// Records count following this header
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RefcountEntry {
+    pub block: u32,
+    /// Owners of this block, at least 2
+    pub count: u32,
+}
+
+/// Owner counts of shared blocks, blocks not listed have one owner
+#[derive(Debug, Default, Clone)]
+pub struct RefcountTable {
+    pub counts: BTreeMap<u32, u32>,
+    pub dirty: bool,
+}
+
+impl RefcountTable {
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < size_of::<RefcountHeader>() {
+            return Err(anyhow!("refcount file too small"));
+        }
+        let header: RefcountHeader = unsafe { deserialize_row(data) };
+        if header.magic != RFS_REFCOUNT_MAGIC || header.version != RFS_REFCOUNT_VERSION {
+            return Err(anyhow!("bad refcount file header {:x?}", header));
+        }
+        let mut counts = BTreeMap::new();
+        for i in 0..header.count as usize {
+            let p = size_of::<RefcountHeader>() + i * size_of::<RefcountEntry>();
+            if p + size_of::<RefcountEntry>() > data.len() {
+                return Err(anyhow!("refcount file truncated at record {}", i));
+            }
+            let e: RefcountEntry = unsafe { deserialize_row(&data[p..]) };
+            counts.insert(e.block, e.count);
+        }
+        Ok(Self { counts, dirty: false })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = RefcountHeader {
+            magic: RFS_REFCOUNT_MAGIC,
+            version: RFS_REFCOUNT_VERSION,
+            count: self.counts.len() as u32,
+        };
+        let mut data = unsafe { serialize_row(&header) }.to_vec();
+        for (block, count) in self.counts.iter() {
+            data.extend_from_slice(unsafe { serialize_row(&RefcountEntry { block: *block, count: *count }) });
+        }
+        data
+    }
+
+    /// Owners of block, 1 if not shared
+    pub fn get(&self, block: usize) -> u32 {
+        self.counts.get(&(block as u32)).copied().unwrap_or(1)
+    }
+
+    pub fn set(&mut self, block: usize, count: u32) {
+        if count < 2 {
+            self.counts.remove(&(block as u32));
+        } else {
+            self.counts.insert(block as u32, count);
+        }
+        self.dirty = true;
+    }
+}
+
+impl<T: DiskDriver> RFS<T> {
+    pub fn reflink_enabled(&self) -> bool {
+        self.super_block.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_SHARED_BLOCKS as u32 != 0
+    }
+
+    pub fn refcount_load(&mut self) -> Result<()> {
+        self.refcounts = RefcountTable::default();
+        if !self.reflink_enabled() { return Ok(()); }
+        let inode = self.get_inode(RFS_REFCOUNT_INO)?;
+        if inode.i_size == 0 { return Ok(()); }
+        let sz = self.block_size() as u32;
+        let data = self.rfs_read(RFS_REFCOUNT_INO as u64, 0, inode.i_size.div_ceil(sz) * sz)?;
+        self.refcounts = RefcountTable::from_bytes(&data)?;
+        info!("{} shared blocks", self.refcounts.counts.len());
+        Ok(())
+    }
+
+    /// Write dirty refcount table back to refcount file
+    pub fn refcount_save(&mut self) -> Result<()> {
+        if !self.refcounts.dirty { return Ok(()); }
+        self.refcounts.dirty = false;
+        let data = self.refcounts.to_bytes();
+        debug!("save refcount file, {} bytes", data.len());
+        self.rfs_write(RFS_REFCOUNT_INO as u64, 0, &data)?;
+        let mut inode = self.get_inode(RFS_REFCOUNT_INO)?;
+        if inode.i_size as usize > data.len() {
+            inode.i_size = data.len() as u32;
+            self.set_inode(RFS_REFCOUNT_INO, &inode)?;
+        }
+        Ok(())
+    }
+
+    /// Set `shared_blocks` and create refcount file
+    fn reflink_enable(&mut self) -> Result<()> {
+        if self.reflink_enabled() { return Ok(()); }
+        info!("enable shared blocks, refcount file on inode {}", RFS_REFCOUNT_INO);
+        let inode = Ext2INode {
+            i_mode: ((usize::from(Ext2FileType::RegularFile) << 12) | 0o600) as u16,
+            i_links_count: 1,
+            ..Default::default()
+        };
+        self.set_inode(RFS_REFCOUNT_INO, &inode)?;
+        Self::bitmap_set(&mut self.alloc().bitmap_inode, RFS_REFCOUNT_INO);
+        self.super_block.s_feature_ro_compat |= EXT4_FEATURE_RO_COMPAT_SHARED_BLOCKS as u32;
+        self.refcounts.dirty = true;
+        Ok(())
+    }
+
+    /// Add one owner to block
+    fn refcount_share(&mut self, block: usize) {
+        *self.refcounts.counts.entry(block as u32).or_insert(1) += 1;
+        self.refcounts.dirty = true;
+    }
+
+    /// Drop one owner of block, returns true if other owners still use it
+    pub fn refcount_release(&mut self, block: usize) -> bool {
+        match self.refcounts.counts.get_mut(&(block as u32)) {
+            Some(count) => {
+                *count -= 1;
+                if *count <= 1 { self.refcounts.counts.remove(&(block as u32)); }
+                self.refcounts.dirty = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Give inode its own copies of shared blocks before they are written.
+    /// `blocks` are mapped from logical index `start` and the first `len` bytes of them are
+    /// overwritten, returns blocks to write to.
+    pub fn reflink_unshare(&mut self, ino: usize, start: usize, mut blocks: Vec<usize>, len: usize) -> Result<Vec<usize>> {
+        if self.refcounts.counts.is_empty() { return Ok(blocks); }
+        let sz = self.block_size();
+        let mut inode = self.get_inode(ino)?;
+        let mut mappings = vec![];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if self.refcounts.get(*block) < 2 { continue; }
+            let new_block = self.allocate_block_for(ino, &mut inode)?;
+            // block is written partially, keep the rest
+            if (i + 1) * sz > len {
+                let data = self.get_data_block(*block)?;
+                self.write_data_block(new_block, &data)?;
+            }
+            debug!("copy shared block {} to {} for inode {}", block, new_block, ino);
+            self.free_block_for(ino, &mut inode, *block)?;
+            mappings.push((start + i, new_block));
+            *block = new_block;
+        }
+        if mappings.is_empty() { return Ok(blocks); }
+        self.set_inode(ino, &inode)?;
+        Self::block_map(&inode).map(self, ino, &mappings)?;
+        Ok(blocks)
+    }
+
+    /// Share `count` blocks of `src` from logical `src_index` with `dst` at `dst_index`,
+    /// blocks mapped in `dst` before are released
+    pub fn reflink_blocks(&mut self, src: usize, src_index: usize, dst: usize, dst_index: usize, count: usize) -> Result<()> {
+        if count == 0 { return Ok(()); }
+        if src == dst && src_index < dst_index + count && dst_index < src_index + count {
+            return Err(Error::new(Errno(EINVAL)).context("overlapping clone ranges"));
+        }
+        let mut blocks = vec![];
+        self.visit_blocks_inode(src, src_index, &mut |block, index| {
+            blocks.push(block);
+            Ok((index + 1 < src_index + count, false))
+        })?;
+        blocks.resize(count, 0);
+        let mut old_blocks = vec![];
+        let inode = self.get_inode(dst)?;
+        let end = self.blocks_end(dst, &inode)?;
+        if dst_index < end {
+            self.visit_blocks_inode(dst, dst_index, &mut |block, index| {
+                if block != 0 { old_blocks.push(block); }
+                Ok((index + 1 < min(end, dst_index + count), false))
+            })?;
+        }
+        let shared = blocks.iter().filter(|x| **x != 0).count();
+        if shared > 0 { self.reflink_enable()?; }
+        if self.quota_tracked(dst) { self.quota_charge(inode.uid(), inode.gid(), shared as i64, 0)?; }
+        let mappings = blocks.iter().enumerate().map(|(i, b)| (dst_index + i, *b)).collect::<Vec<_>>();
+        Self::block_map(&inode).map(self, dst, &mappings)?;
+        let mut inode = self.get_inode(dst)?;
+        for block in old_blocks {
+            self.free_block_for(dst, &mut inode, block)?;
+        }
+        inode.i_blocks += (shared * self.block_size() / 512) as u32;
+        self.set_inode(dst, &inode)?;
+        for block in blocks.into_iter().filter(|x| *x != 0) {
+            self.refcount_share(block);
+        }
+        Ok(())
+    }
+
+    /// Copy bytes between files by reading and writing
+    fn reflink_copy_bytes(&mut self, src: u64, src_offset: usize, dst: u64, dst_offset: usize, len: usize) -> Result<()> {
+        let sz = self.block_size();
+        let mut done = 0;
+        while done < len {
+            let offset = src_offset + done;
+            let aligned = offset / sz * sz;
+            let size = min(len - done, RFS_COPY_CHUNK);
+            let data = self.rfs_read(src, aligned as i64, ((offset + size).div_ceil(sz) * sz - aligned) as u32)?;
+            self.rfs_write(dst, (dst_offset + done) as i64, &data[offset - aligned..][..size])?;
+            done += size;
+        }
+        Ok(())
+    }
+
+    fn reflink_check_file(&mut self, ino: usize) -> Result<Ext2INode> {
+        let inode = self.get_inode(ino)?;
+        if inode.i_mode as usize >> 12 != Ext2FileType::RegularFile.into() {
+            return Err(Error::new(Errno(EINVAL)).context(format!("inode {} is not a regular file", ino)));
+        }
+        Ok(inode)
+    }
+
+    /// Copy `len` bytes of file `src` to `dst`, sharing whole blocks when offsets allow.
+    /// Stops at end of `src`, returns bytes copied.
+    pub fn rfs_copy_range(&mut self, src: u64, src_offset: u64, dst: u64, dst_offset: u64, len: u64) -> Result<u64> {
+        let (src_ino, dst_ino) = (RFS::<T>::shift_ino(src as usize), RFS::<T>::shift_ino(dst as usize));
+        let src_inode = self.reflink_check_file(src_ino)?;
+        let dst_inode = self.reflink_check_file(dst_ino)?;
+        let src_size = src_inode.i_size as u64 | (src_inode.i_size_high as u64) << 32;
+        if src_offset >= src_size { return Ok(0); }
+        let len = min(len, src_size - src_offset) as usize;
+        let (src_offset, dst_offset) = (src_offset as usize, dst_offset as usize);
+        let sz = self.block_size();
+        let head = min((sz - src_offset % sz) % sz, len);
+        let count = (len - head) / sz;
+        // compressed clusters and encrypted blocks can not be shared block by block
+        if Self::is_inline(&src_inode) || Self::is_compressed(&src_inode) || Self::is_compressed(&dst_inode)
+            || Self::is_encrypted(&src_inode) || Self::is_encrypted(&dst_inode)
+            || src_offset % sz != dst_offset % sz || count == 0 {
+            self.reflink_copy_bytes(src, src_offset, dst, dst_offset, len)?;
+            return Ok(len as u64);
+        }
+        debug!("copy range of inode {} to {}, share {} blocks", src_ino, dst_ino, count);
+        self.reflink_copy_bytes(src, src_offset, dst, dst_offset, head)?;
+        if Self::is_inline(&self.get_inode(dst_ino)?) { self.inline_convert(dst_ino)?; }
+        self.reflink_blocks(src_ino, (src_offset + head) / sz, dst_ino, (dst_offset + head) / sz, count)?;
+        let shared_end = head + count * sz;
+        self.reflink_copy_bytes(src, src_offset + shared_end, dst, dst_offset + shared_end, len - shared_end)?;
+        let mut inode = self.get_inode(dst_ino)?;
+        let size = inode.i_size as u64 | (inode.i_size_high as u64) << 32;
+        let end = (dst_offset + len) as u64;
+        if end > size {
+            inode.i_size = end as u32;
+            inode.i_size_high = (end >> 32) as u32;
+            self.set_inode(dst_ino, &inode)?;
+        }
+        Ok(len as u64)
+    }
+
+    /// Make `dst` a clone of whole file `src`, like `FICLONE`
+    pub fn rfs_clone(&mut self, src: u64, dst: u64) -> Result<()> {
+        let (src_ino, dst_ino) = (RFS::<T>::shift_ino(src as usize), RFS::<T>::shift_ino(dst as usize));
+        if src_ino == dst_ino {
+            return Err(Error::new(Errno(EINVAL)).context(format!("cannot clone inode {} to itself", src_ino)));
+        }
+        let src_inode = self.reflink_check_file(src_ino)?;
+        // checked before truncating dst
+        self.reflink_check_file(dst_ino)?;
+        self.rfs_setattr(dst, None, None, None, Some(0), None, None, None, None, None)?;
+        let size = src_inode.i_size as u64 | (src_inode.i_size_high as u64) << 32;
+        self.rfs_copy_range(src, 0, dst, 0, size)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reflink() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let bs = fs.block_size();
+        let (src, _) = fs.make_node(EXT2_ROOT_INO, "src", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let (dst, _) = fs.make_node(EXT2_ROOT_INO, "dst", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let data = (0..bs * 40).map(|x| (x / bs) as u8).collect::<Vec<_>>();
+        fs.rfs_write(src as u64, 0, &data)?;
+        let free = fs.alloc().free_blocks_count;
+
+        // clone takes no data blocks, only an index block of dst
+        fs.rfs_clone(src as u64, dst as u64)?;
+        assert!(fs.reflink_enabled());
+        assert_eq!(fs.alloc().free_blocks_count, free - 1);
+        assert_eq!(fs.rfs_read(dst as u64, 0, data.len() as u32)?, data);
+        assert_eq!(fs.get_inode(dst)?.i_blocks, fs.get_inode(src)?.i_blocks);
+        assert_eq!(fs.refcounts.counts.len(), 40);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        // bad targets are refused before dst is truncated
+        assert_eq!(get_errno(&fs.rfs_clone(src as u64, src as u64).unwrap_err(), 0), EINVAL);
+        assert_eq!(fs.rfs_read(src as u64, 0, data.len() as u32)?, data);
+        let (dir, _) = fs.make_node(EXT2_ROOT_INO, "dir", 0o755, Ext2FileType::Directory, 0, 0)?;
+        fs.make_node(dir, "child", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        assert_eq!(get_errno(&fs.rfs_clone(src as u64, dir as u64).unwrap_err(), 0), EINVAL);
+        assert!(fs.rfs_lookup(dir, "child").is_ok());
+        fs.rfs_unlink(dir, "child")?;
+        fs.rfs_rmdir(EXT2_ROOT_INO, "dir")?;
+        fs.rfs_dump()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.refcounts.counts.len(), 40);
+        let free = fs.alloc().free_blocks_count;
+
+        // written blocks are copied, source is kept
+        fs.rfs_write(dst as u64, (bs * 5 + 1) as i64, &[0xff; 2])?;
+        assert_eq!(fs.alloc().free_blocks_count, free - 1);
+        assert_eq!(fs.rfs_read(src as u64, 0, data.len() as u32)?, data);
+        let mut expected = data.clone();
+        expected[bs * 5 + 1..bs * 5 + 3].fill(0xff);
+        assert_eq!(fs.rfs_read(dst as u64, 0, data.len() as u32)?, expected);
+        assert_eq!(fs.refcounts.counts.len(), 39);
+
+        // unaligned head is copied, following blocks shared
+        let copied = fs.rfs_copy_range(src as u64, 10, dst as u64, (bs * 40 + 10) as u64, u64::MAX)?;
+        assert_eq!(copied as usize, data.len() - 10);
+        assert_eq!(fs.alloc().free_blocks_count, free - 2);
+        assert_eq!(fs.rfs_read(dst as u64, (bs * 40) as i64, data.len() as u32)?[10..], data[10..]);
+        let block = fs.get_inode(src)?.i_block[1] as usize;
+        assert_eq!(fs.refcounts.get(block), 3);
+
+        // blocks are freed with the last owner
+        fs.rfs_unlink(EXT2_ROOT_INO, "src")?;
+        assert_eq!(fs.alloc().free_blocks_count, free - 1);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        fs.rfs_unlink(EXT2_ROOT_INO, "dst")?;
+        assert!(fs.refcounts.counts.is_empty());
+        assert_eq!(fs.alloc().free_blocks_count, free + 42);
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
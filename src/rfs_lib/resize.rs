@@ -0,0 +1,376 @@
+/// Offline resize, adding or removing block groups at the end of an image.
+///
+/// New groups are laid out as `rfs_mkfs` does. Shrinking first moves blocks past
+/// the new end into free blocks before it by `BlockMap::relocate`, then copies
+/// inodes of removed groups to free inodes and rewrites directory entries
+/// pointing to them. The group desc table stays in place, so sizes needing
+/// another number of its blocks are refused.
+///
+/// A mounted filesystem grows into space added to its disk by `RFS_IOC_GROW`.
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use anyhow::{anyhow, Error, Result};
+use disk_driver::{DiskDriver, IOC_REQ_DEVICE_SIZE};
+use libc::{EBUSY, ENOSPC};
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// `_IOR('r', 5, u64)`, grow mounted filesystem to the current disk size, returns blocks count
+pub const RFS_IOC_GROW: u32 = (2 << 30) | ((size_of::<u64>() as u32) << 16) | ((b'r' as u32) << 8) | 5;
+
+impl<T: DiskDriver> RFS<T> {
+    /// Blocks count rounded down, so that the last group can hold some data
+    fn resize_round(&self, blocks_count: usize) -> usize {
+        let (first, per_group) = (self.super_block.s_first_data_block as usize, self.blocks_per_group());
+        let groups = blocks_count.saturating_sub(first).div_ceil(per_group);
+        if groups < 2 { return blocks_count; }
+        let last = groups - 1;
+        let last_blocks = blocks_count - first - last * per_group;
+        let overhead = if self.group_has_super(last) { 1 + self.group_desc_blocks() } else { 0 } + 2 + self.inode_table_blocks();
+        if last_blocks < overhead + 50 {
+            warn!("drop last group of {} blocks", last_blocks);
+            first + last * per_group
+        } else { blocks_count }
+    }
+
+    /// Inode maps disk blocks by its block map
+    fn resize_has_blocks(inode: &Ext2INode) -> bool {
+        let file_type = inode.i_mode as usize >> 12;
+        !Self::is_inline(inode) &&
+            (file_type == Ext2FileType::RegularFile.into() || file_type == Ext2FileType::Directory.into())
+    }
+
+    /// Grow or shrink filesystem to `blocks_count` blocks, the disk must be large enough
+    pub fn rfs_resize(&mut self, blocks_count: usize) -> Result<()> {
+        self.check_writable()?;
+        if self.super_block.s_snapshot_list != 0 || self.super_block.s_snapshot_inum != 0 {
+            return Err(Error::new(Errno(EBUSY)).context("cannot resize while snapshots exist"));
+        }
+        let blocks_count = self.resize_round(blocks_count);
+        let old_count = self.super_block.s_blocks_count as usize;
+        if blocks_count == old_count { return Ok(()); }
+        if blocks_count * self.block_size() > self.disk_size() {
+            return Err(anyhow!("disk of {} bytes is too small for {} blocks", self.disk_size(), blocks_count));
+        }
+        let groups = (blocks_count - self.super_block.s_first_data_block as usize).div_ceil(self.blocks_per_group());
+        let inodes_count = groups * self.inodes_per_group();
+        if inodes_count > u32::MAX as usize {
+            return Err(anyhow!("too many inodes for {} groups", groups));
+        }
+        let desc_blocks = (groups * size_of::<Ext2GroupDesc>()).div_ceil(self.block_size());
+        if desc_blocks != self.group_desc_blocks() {
+            return Err(anyhow!("{} groups need {} group desc blocks instead of {}",
+                groups, desc_blocks, self.group_desc_blocks()));
+        }
+        if blocks_count < self.data_start_block() + 2 {
+            return Err(anyhow!("too small filesystem of {} blocks", blocks_count));
+        }
+        info!("resize from {} to {} blocks, {} groups", old_count, blocks_count, groups);
+        if blocks_count > old_count {
+            self.resize_grow(groups, blocks_count)?;
+        } else {
+            self.resize_shrink(groups, blocks_count)?;
+        }
+        self.super_block.s_r_blocks_count = (self.super_block.s_r_blocks_count as u64 * blocks_count as u64 / old_count as u64) as u32;
+        self.rfs_dump()?;
+        // inodes moved by shrinking are cached at their old numbers, all written by dump
+        self.cache_clear();
+        Ok(())
+    }
+
+    /// Grow into space added to the disk since it was opened, returns blocks count.
+    /// Only groups fitting in the current group desc blocks are added
+    pub fn rfs_grow(&mut self) -> Result<usize> {
+        let mut buf = [0; 4];
+        self.get_driver().ddriver_ioctl(IOC_REQ_DEVICE_SIZE, &mut buf)?;
+        let disk_size = u32::from_le_bytes(buf);
+        if disk_size > self.driver_info.consts.layout_size {
+            info!("disk enlarged from {} to {} bytes", self.driver_info.consts.layout_size, disk_size);
+            self.driver_info.consts.layout_size = disk_size;
+        }
+        let max_groups = self.group_desc_blocks() * (self.block_size() / size_of::<Ext2GroupDesc>());
+        let max_blocks = self.super_block.s_first_data_block as usize + max_groups * self.blocks_per_group();
+        let blocks_count = min(min(self.disk_size() / self.block_size(), max_blocks), u32::MAX as usize);
+        if self.resize_round(blocks_count) > self.super_block.s_blocks_count as usize {
+            self.rfs_resize(blocks_count)?;
+        }
+        Ok(self.super_block.s_blocks_count as usize)
+    }
+
+    fn resize_grow(&mut self, groups: usize, blocks_count: usize) -> Result<()> {
+        let old_groups = self.groups_count();
+        let old_count = self.super_block.s_blocks_count as usize;
+        // new blocks can be written from now
+        self.super_block.s_blocks_count = blocks_count as u32;
+        self.super_block.s_inodes_count = (groups * self.inodes_per_group()) as u32;
+        self.alloc().bitmap_data.resize(groups * self.blocks_per_group() / 8, 0);
+        self.alloc().bitmap_inode.resize(groups * self.inodes_per_group() / 8, 0);
+        // padding bits of the old last group
+        for block in old_count..self.group_first_block(old_groups).min(blocks_count) {
+            let bit = self.block_bit(block);
+            Self::bitmap_unset(&mut self.alloc().bitmap_data, bit);
+        }
+        let zero = self.create_block_vec();
+        for group in old_groups..groups {
+            let start = self.group_first_block(group);
+            let block_bitmap = start + if self.group_has_super(group) { 1 + self.group_desc_blocks() } else { 0 };
+            self.alloc().group_desc_table.push(Ext2GroupDesc {
+                bg_block_bitmap: block_bitmap as u32,
+                bg_inode_bitmap: block_bitmap as u32 + 1,
+                bg_inode_table: block_bitmap as u32 + 2,
+                bg_used_dirs_count: 0,
+                bg_flags: 0,
+                ..Default::default()
+            });
+            // super block and group desc backups are written with other metadata
+            for block in start..self.group_data_start(group) {
+                self.write_data_block(block, &zero)?;
+                let bit = self.block_bit(block);
+                Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+            }
+        }
+        for block in blocks_count..self.group_first_block(groups) {
+            let bit = self.block_bit(block);
+            Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+        }
+        Ok(())
+    }
+
+    fn resize_shrink(&mut self, groups: usize, blocks_count: usize) -> Result<()> {
+        let old_count = self.super_block.s_blocks_count as usize;
+        let old_inodes = self.super_block.s_inodes_count as usize;
+        let inodes_count = groups * self.inodes_per_group();
+        let tail_blocks = (blocks_count..old_count)
+            .filter(|x| self.is_data_block(*x) && Self::bitmap_get(&self.alloc().bitmap_data, self.block_bit(*x))).count();
+        let free_blocks = (self.data_start_block()..blocks_count)
+            .filter(|x| !Self::bitmap_get(&self.alloc().bitmap_data, self.block_bit(*x))).count();
+        let tail_inodes = (inodes_count + 1..=old_inodes).filter(|x| Self::bitmap_get(&self.alloc().bitmap_inode, *x)).collect::<Vec<_>>();
+        let free_inodes = (1..=inodes_count).filter(|x| !Self::bitmap_get(&self.alloc().bitmap_inode, *x)).count();
+        if tail_blocks > free_blocks || tail_inodes.len() > free_inodes {
+            return Err(Error::new(Errno(ENOSPC)).context(format!(
+                "{} blocks and {} inodes in use past the new end, only {} blocks and {} inodes free before it",
+                tail_blocks, tail_inodes.len(), free_blocks, free_inodes)));
+        }
+        let used = (1..=old_inodes).filter(|x| Self::bitmap_get(&self.alloc().bitmap_inode, *x)).collect::<Vec<_>>();
+        // nothing is allocated in the removed tail
+        for block in blocks_count..old_count {
+            let bit = self.block_bit(block);
+            Self::bitmap_set(&mut self.alloc().bitmap_data, bit);
+        }
+        for ino in inodes_count + 1..=old_inodes {
+            Self::bitmap_set(&mut self.alloc().bitmap_inode, ino);
+        }
+
+        let mut moved = BTreeMap::new();
+        for ino in used {
+            self.resize_relocate(ino, blocks_count, &mut moved)?;
+        }
+        debug!("{} blocks moved", moved.len());
+        for (block, new) in moved.iter() {
+            if let Some(count) = self.refcounts.counts.remove(&(*block as u32)) {
+                self.refcounts.counts.insert(*new as u32, count);
+                self.refcounts.dirty = true;
+            }
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            for block in journal.blocks.iter_mut() {
+                *block = moved.get(block).copied().unwrap_or(*block);
+            }
+        }
+
+        let mut inode_map = BTreeMap::new();
+        let mut moved_dirs = BTreeMap::new();
+        for ino in tail_inodes {
+            if self.get_inode(ino)?.i_mode == 0 { continue; }
+            let new = self.resize_move_inode(ino, &mut moved_dirs)?;
+            inode_map.insert(ino as u32, new as u32);
+        }
+        debug!("{} inodes moved", inode_map.len());
+        for ino in 1..=inodes_count {
+            if !Self::bitmap_get(&self.alloc().bitmap_inode, ino) { continue; }
+            let entries = match moved_dirs.remove(&ino) {
+                Some(entries) => Some(entries),
+                None => {
+                    let inode = self.get_inode(ino)?;
+                    if inode.i_mode as usize >> 12 != Ext2FileType::Directory.into() || inode.i_links_count == 0 { continue; }
+                    let entries = self.get_dir_entries(ino)?;
+                    if entries.iter().any(|x| inode_map.contains_key(&x.inode)) { Some(entries) } else { None }
+                }
+            };
+            if let Some(mut entries) = entries {
+                for e in entries.iter_mut() {
+                    e.inode = inode_map.get(&e.inode).copied().unwrap_or(e.inode);
+                }
+                self.format_directory_entries(&mut entries)?;
+                self.apply_directory_entries(ino, &entries, 0)?;
+            }
+        }
+
+        self.super_block.s_blocks_count = blocks_count as u32;
+        self.super_block.s_inodes_count = inodes_count as u32;
+        self.alloc().group_desc_table.truncate(groups);
+        self.alloc().bitmap_data.truncate(groups * self.blocks_per_group() / 8);
+        self.alloc().bitmap_inode.truncate(inodes_count / 8);
+        Ok(())
+    }
+
+    /// Move blocks of inode from `end` to free blocks before it, `moved` maps each moved block
+    fn resize_relocate(&mut self, ino: usize, end: usize, moved: &mut BTreeMap<usize, usize>) -> Result<()> {
+        let mut inode = self.get_inode(ino)?;
+        if inode.i_mode == 0 { return Ok(()); }
+        // shared blocks are moved once
+        let mut relocate = |fs: &mut Self, block: usize| -> Result<usize> {
+            if block < end { return Ok(block); }
+            if let Some(new) = moved.get(&block) { return Ok(*new); }
+            let new = fs.allocate_block()?;
+            let data = fs.get_data_block(block)?;
+            fs.write_data_block(new, &data)?;
+            moved.insert(block, new);
+            Ok(new)
+        };
+        if inode.i_file_acl != 0 {
+            let block = relocate(self, inode.i_file_acl as usize)?;
+            if block != inode.i_file_acl as usize {
+                inode.i_file_acl = block as u32;
+                self.set_inode(ino, &inode)?;
+            }
+        }
+        if Self::resize_has_blocks(&inode) {
+            Self::block_map(&inode).relocate(self, ino, &mut relocate)?;
+        }
+        Ok(())
+    }
+
+    /// Copy inode to a free inode, returns the new number. Entries of a moved
+    /// directory are read before its checksums change and saved in `dirs`
+    fn resize_move_inode(&mut self, ino: usize, dirs: &mut BTreeMap<usize, Vec<Ext2DirEntry>>) -> Result<usize> {
+        let inode = self.get_inode(ino)?;
+        let is_dir = inode.i_mode as usize >> 12 == Ext2FileType::Directory.into();
+        let entries = if is_dir { Some(self.get_dir_entries(ino)?) } else { None };
+        // extent nodes are checksummed with the inode number
+        let tree = if Self::resize_has_blocks(&inode) && inode.i_flags & EXT4_EXTENTS_FL as u32 != 0 {
+            Some(self.extent_load(ino, &inode)?)
+        } else { None };
+        let new = self.allocate_inode()?;
+        debug!("move inode {} to {}", ino, new);
+        // raw copy keeps extra fields and xattrs in inode
+        let size = self.inode_size();
+        let (block, offset) = self.fetch_inode_block_offset(ino)?;
+        let (new_block, new_offset) = self.fetch_inode_block_offset(new)?;
+        let src = self.get_data_block(block)?;
+        let mut dst = self.get_data_block(new_block)?;
+        dst[new_offset..][..size].copy_from_slice(&src[offset..][..size]);
+        self.inode_csum_set(new, &mut dst[new_offset..]);
+        self.write_meta_block(new_block, &dst)?;
+        if let Some(tree) = tree {
+            let mut inode = self.get_inode(new)?;
+            self.extent_store(new, &mut inode, tree)?;
+            self.set_inode(new, &inode)?;
+        }
+        if let Some(entries) = entries {
+            let group = self.inode_group(new);
+            self.alloc().group_desc_table[group].bg_used_dirs_count += 1;
+            dirs.insert(new, entries);
+        }
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    #[test]
+    fn test_resize() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(24 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions { features: vec!["extent,metadata_csum".to_string()], ..Default::default() })?;
+        let bs = fs.block_size();
+        let blocks_count = fs.super_block.s_blocks_count as usize;
+        assert_eq!(fs.groups_count(), 3);
+
+        // blocks and inodes in the last group, one file with plain indirect blocks
+        let tail = fs.group_first_block(2);
+        let block_filler = (fs.data_start_block()..tail)
+            .filter(|x| !RFS::<MemoryDiskDriver>::bitmap_get(&fs.alloc().bitmap_data, fs.block_bit(*x))).collect::<Vec<_>>();
+        let inode_filler = (1..=2 * fs.inodes_per_group())
+            .filter(|x| !RFS::<MemoryDiskDriver>::bitmap_get(&fs.alloc().bitmap_inode, *x)).collect::<Vec<_>>();
+        let fill = |fs: &mut RFS<MemoryDiskDriver>, set: bool| {
+            for block in block_filler.iter() {
+                let bit = fs.block_bit(*block);
+                RFS::<MemoryDiskDriver>::bitmap_set_value(&mut fs.alloc().bitmap_data, bit, set);
+            }
+            for ino in inode_filler.iter() {
+                RFS::<MemoryDiskDriver>::bitmap_set_value(&mut fs.alloc().bitmap_inode, *ino, set);
+            }
+        };
+        fill(&mut fs, true);
+        let (dir, _) = fs.make_node(EXT2_ROOT_INO, "dir", 0o755, Ext2FileType::Directory, 0, 0)?;
+        for i in 0..4 {
+            let (ino, mut inode) = fs.make_node(dir, &format!("f{}", i), 0o644, Ext2FileType::RegularFile, 0, 0)?;
+            if i == 0 {
+                inode.i_flags &= !(EXT4_EXTENTS_FL as u32);
+                inode.i_block = [0; EXT2_N_BLOCKS];
+                fs.set_inode(ino, &inode)?;
+            }
+            fs.rfs_write(ino as u64, 0, &vec![i as u8 + 1; bs * 300])?;
+        }
+        fill(&mut fs, false);
+        assert!(dir > 2 * fs.inodes_per_group());
+        assert_eq!(fs.rfs_lookup(EXT2_ROOT_INO, "dir")?.0, dir);
+        fs.rfs_dump()?;
+
+        fs.rfs_resize(tail)?;
+        assert_eq!(fs.groups_count(), 2);
+        // lookups cached before moving inodes are gone
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "dir")?.0 <= 2 * fs.inodes_per_group());
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        let (dir, _) = fs.rfs_lookup(EXT2_ROOT_INO, "dir")?;
+        assert!(dir <= 2 * fs.inodes_per_group());
+        let names = fs.get_dir_entries(dir)?.iter().map(|x| x.get_name()).collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "f0", "f1", "f2", "f3"]);
+        for i in 0..4 {
+            let (ino, _) = fs.rfs_lookup(dir, &format!("f{}", i))?;
+            assert_eq!(fs.rfs_read(ino as u64, 0, (bs * 300) as u32)?, vec![i as u8 + 1; bs * 300]);
+        }
+
+        fs.rfs_resize(blocks_count)?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.groups_count(), 3);
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "new", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(ino as u64, 0, &vec![9; bs * 4000])?;
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow() -> Result<()> {
+        let mut fs = RFS::new(MemoryDiskDriver::with_size(10 * 0x400 * 0x400), Default::default());
+        fs.driver_open("mem")?;
+        fs.rfs_mkfs(&MkfsOptions::default())?;
+        assert_eq!(fs.groups_count(), 2);
+        let size = 24 * 0x400 * 0x400;
+        fs.driver.mem.resize(size, 0);
+        fs.driver.info.consts.layout_size = size as u32;
+        let free = fs.alloc().free_blocks_count;
+        assert_eq!(fs.rfs_grow()?, size / fs.block_size());
+        assert_eq!(fs.groups_count(), 3);
+        assert!(fs.alloc().free_blocks_count > free);
+        assert_eq!(fs.rfs_grow()?, size / fs.block_size());
+        let (ino, _) = fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(ino as u64, 0, &vec![1; fs.block_size() * 16000])?;
+        let report = fs.rfs_fsck(false)?;
+        assert!(report.is_clean(), "{}", report);
+        Ok(())
+    }
+}
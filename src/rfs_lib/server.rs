@@ -0,0 +1,503 @@
+/// Serving FUSE requests concurrently by a pool of worker threads.
+///
+/// Locks are taken in order: `ops`, inode locks by index, the filesystem, the
+/// allocator, then the driver. Requests share `ops` and lock the inodes they
+/// work on, shared to look and exclusive to change. Requests that may free
+/// blocks of inodes not known before they run (unlink, rename, clone, grow) hold
+/// `ops` exclusively. Reads and writes map blocks under the filesystem lock and
+/// move data, encrypted or compressed, after releasing it, so slow data I/O
+/// only holds up requests on the same file. `statfs` only takes the allocator.
+use std::cmp::{max, min};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{Builder, JoinHandle};
+use anyhow::Result;
+use disk_driver::{DiskDriver, SeekType};
+use disk_driver::shared::SharedDiskDriver;
+use log::*;
+use crate::rfs_lib::{RFS, RfsAlloc};
+use crate::rfs_lib::compress::RFS_COMPRESS_CLUSTER;
+use crate::rfs_lib::config::RfsConfig;
+use crate::rfs_lib::crypt::xts_crypt;
+
+/// Inode locks, inodes share a lock when equal modulo this
+const RFS_INODE_LOCKS: usize = 256;
+
+pub type SharedRfs<T> = RFS<SharedDiskDriver<T>>;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    fn new(threads: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads).map(|i| {
+            let receiver = receiver.clone();
+            Builder::new().name(format!("rfs-worker-{}", i)).spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            }).unwrap()
+        }).collect();
+        Self { sender: Some(sender), workers }
+    }
+
+    fn execute(&self, job: Job) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Finish queued jobs and stop workers
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+/// How data of a read is kept in its blocks
+enum ReadKind {
+    Plain,
+    Crypt([u8; 64]),
+    Compress,
+}
+
+/// Blocks of a read mapped under the filesystem lock
+struct ReadPlan {
+    kind: ReadKind,
+    /// Disk blocks from logical block `first`, 0 for holes
+    blocks: Vec<usize>,
+    first: usize,
+    /// End of file data to read
+    end: usize,
+}
+
+/// Blocks of a write mapped under the filesystem lock
+struct WritePlan {
+    /// Disk blocks from logical block `first`
+    blocks: Vec<usize>,
+    first: usize,
+    /// Whole blocks of plain data
+    data: Vec<u8>,
+    key: Option<[u8; 64]>,
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Map blocks to read `size` bytes at `offset`, None if the read must be done under the lock
+    fn read_plan(&mut self, ino: u64, offset: usize, size: usize) -> Result<Option<ReadPlan>> {
+        let ino = Self::shift_ino(ino as usize);
+        let inode = self.get_inode(ino)?;
+        let end = min(offset + size, inode.i_size as usize | (inode.i_size_high as usize) << 32);
+        let sz = self.block_size();
+        let mut plan = if Self::is_encrypted(&inode) {
+            let key = self.crypt_require(ino)?.unwrap();
+            let first = offset / sz;
+            let blocks = if end > offset { self.crypt_blocks(ino, first, end.div_ceil(sz))? } else { vec![] };
+            ReadPlan { kind: ReadKind::Crypt(key), blocks, first, end }
+        } else if Self::is_compressed(&inode) {
+            let cs = self.compress_cluster_size();
+            let first = offset / cs;
+            let mut blocks = vec![];
+            for cluster in first..if end > offset { end.div_ceil(cs) } else { first } {
+                blocks.extend(self.compress_cluster_blocks(ino, cluster)?);
+            }
+            ReadPlan { kind: ReadKind::Compress, blocks, first, end }
+        } else if Self::is_inline(&inode) {
+            return Ok(None);
+        } else {
+            let blocks = self.read_block_list(ino, offset, size)?.into_iter()
+                .map(|x| if x == usize::MAX { 0 } else { x }).collect();
+            ReadPlan { kind: ReadKind::Plain, blocks, first: offset / sz, end: offset + size }
+        };
+        let blocks_count = self.super_block.s_blocks_count as usize;
+        // staged blocks are newer than disk, bad blocks are reported by `rfs_read`
+        if plan.blocks.iter().any(|x| *x != 0 && (*x >= blocks_count || self.journal_staged(*x).is_some())) {
+            return Ok(None);
+        }
+        plan.blocks.iter_mut().filter(|x| **x != 0).for_each(|x| *x = self.snapshot_block(*x));
+        Ok(Some(plan))
+    }
+
+    /// Map blocks to write `data` at `offset`, None if written under the lock already
+    fn write_plan(&mut self, ino: u64, offset: usize, data: &[u8]) -> Result<Option<WritePlan>> {
+        self.snapshot_check_protected(ino as usize)?;
+        if data.is_empty() { return Ok(None); }
+        let ino_shifted = Self::shift_ino(ino as usize);
+        if !Self::is_encrypted(&self.get_inode(ino_shifted)?) {
+            if self.inline_write(ino_shifted, offset, data)?.is_some() { return Ok(None); }
+            if Self::is_compressed(&self.get_inode(ino_shifted)?) {
+                self.compress_write(ino_shifted, offset, data)?;
+                return Ok(None);
+            }
+        }
+        let key = self.crypt_require(ino_shifted)?;
+        let sz = self.block_size();
+        let end = offset + data.len();
+        let (first, last) = (offset / sz, end.div_ceil(sz));
+        let mut buf = vec![0; (last - first) * sz];
+        // partial blocks at both ends keep the rest of their content
+        for index in [first, last - 1] {
+            if index * sz < offset || (index + 1) * sz > end {
+                let part = match key {
+                    Some(_) => self.crypt_read(ino_shifted, index * sz, sz)?,
+                    None => self.rfs_read(ino, (index * sz) as i64, sz as u32)?,
+                };
+                buf[(index - first) * sz..][..sz].copy_from_slice(&part);
+            }
+        }
+        buf[offset - first * sz..][..data.len()].copy_from_slice(data);
+        let blocks = self.write_block_map(ino_shifted, first * sz, buf.len())?;
+        self.snapshot_save_blocks(&blocks)?;
+        Ok(Some(WritePlan { blocks, first, data: buf, key }))
+    }
+
+    /// Grow file to `end` after its data is written
+    fn write_finish(&mut self, ino: usize, end: usize) -> Result<()> {
+        let mut inode = self.get_inode(ino)?;
+        let size = max(inode.i_size as usize | (inode.i_size_high as usize) << 32, end);
+        inode.i_size = size as u32;
+        inode.i_size_high = (size >> 32) as u32;
+        self.set_inode(ino, &inode)
+    }
+}
+
+/// Filesystem with the locks of concurrent requests
+pub struct RfsShared<T: DiskDriver> {
+    pub fs: Mutex<SharedRfs<T>>,
+    /// Allocator of `fs`, for `statfs` without the filesystem lock
+    pub alloc: Arc<Mutex<RfsAlloc>>,
+    /// Handle for data I/O outside the filesystem lock
+    driver: SharedDiskDriver<T>,
+    ops: RwLock<()>,
+    inodes: Vec<RwLock<()>>,
+}
+
+impl<T: DiskDriver> RfsShared<T> {
+    pub fn new(driver: T, config: RfsConfig) -> Self {
+        let driver = SharedDiskDriver::new(driver);
+        let fs = RFS::new(driver.clone(), config);
+        Self {
+            alloc: fs.alloc.clone(),
+            fs: Mutex::new(fs),
+            driver,
+            ops: RwLock::new(()),
+            inodes: (0..RFS_INODE_LOCKS).map(|_| RwLock::new(())).collect(),
+        }
+    }
+
+    fn inode_lock(&self, ino: u64) -> &RwLock<()> {
+        &self.inodes[SharedRfs::<T>::shift_ino(ino as usize) % RFS_INODE_LOCKS]
+    }
+
+    /// Run `f` on filesystem holding locks of inodes, shared on `read` and exclusive on `write`
+    pub fn run<R>(&self, read: &[u64], write: &[u64], f: impl FnOnce(&mut SharedRfs<T>) -> R) -> R {
+        let _ops = self.ops.read().unwrap();
+        let mut locks = read.iter().map(|x| (x, false)).chain(write.iter().map(|x| (x, true)))
+            .map(|(ino, exclusive)| (SharedRfs::<T>::shift_ino(*ino as usize) % RFS_INODE_LOCKS, exclusive))
+            .collect::<Vec<_>>();
+        // exclusive first for each lock, then one lock once
+        locks.sort_by_key(|(index, exclusive)| (*index, !*exclusive));
+        locks.dedup_by_key(|(index, _)| *index);
+        let mut shared_guards = vec![];
+        let mut exclusive_guards = vec![];
+        for (index, exclusive) in locks {
+            if exclusive {
+                exclusive_guards.push(self.inodes[index].write().unwrap());
+            } else {
+                shared_guards.push(self.inodes[index].read().unwrap());
+            }
+        }
+        f(&mut self.fs.lock().unwrap())
+    }
+
+    /// Run `f` on filesystem with no other request running
+    pub fn run_exclusive<R>(&self, f: impl FnOnce(&mut SharedRfs<T>) -> R) -> R {
+        let _ops = self.ops.write().unwrap();
+        f(&mut self.fs.lock().unwrap())
+    }
+
+    /// Same as `rfs_read`, reading blocks and decoding them without the filesystem lock
+    pub fn read(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+        let _ops = self.ops.read().unwrap();
+        // blocks stay with the file while its writers wait for this lock
+        let _inode = self.inode_lock(ino).read().unwrap();
+        let (plan, bs, unit) = {
+            let mut fs = self.fs.lock().unwrap();
+            match fs.read_plan(ino, offset as usize, size as usize)? {
+                Some(plan) => (plan, fs.block_size(), fs.disk_block_size()),
+                None => return fs.rfs_read(ino, offset, size),
+            }
+        };
+        let mut raw = self.read_blocks(&plan.blocks, bs, unit)?;
+        let (offset, size) = (offset as usize, size as usize);
+        let mut data = vec![0; size];
+        match plan.kind {
+            ReadKind::Plain => {
+                raw.resize(size, 0);
+                return Ok(raw);
+            }
+            ReadKind::Crypt(key) => {
+                for (i, buf) in raw.chunks_mut(bs).enumerate().filter(|(i, _)| plan.blocks[*i] != 0) {
+                    let index = plan.first + i;
+                    xts_crypt(&key, index as u64, buf, false);
+                    let (start, stop) = (max(offset, index * bs), min(plan.end, (index + 1) * bs));
+                    data[start - offset..stop - offset].copy_from_slice(&buf[start - index * bs..stop - index * bs]);
+                }
+            }
+            ReadKind::Compress => {
+                let cs = RFS_COMPRESS_CLUSTER * bs;
+                let ino = SharedRfs::<T>::shift_ino(ino as usize);
+                for (i, (blocks, buf)) in plan.blocks.chunks(RFS_COMPRESS_CLUSTER).zip(raw.chunks(cs)).enumerate() {
+                    let cluster = plan.first + i;
+                    let content = SharedRfs::<T>::compress_cluster_decode(ino, cluster, blocks, buf.to_vec(), bs);
+                    let base = cluster * cs;
+                    let (start, stop) = (max(offset, base), min(plan.end, base + cs));
+                    data[start - offset..stop - offset].copy_from_slice(&content[start - base..stop - base]);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Same as `rfs_write` in a transaction, writing data blocks without the filesystem lock.
+    /// The file grows in a second transaction after its data is on disk.
+    pub fn write(&self, ino: u64, offset: i64, data: &[u8]) -> Result<u32> {
+        let _ops = self.ops.read().unwrap();
+        // no request sees the mapped blocks before they are written
+        let _inode = self.inode_lock(ino).write().unwrap();
+        let (plan, bs, unit) = {
+            let mut fs = self.fs.lock().unwrap();
+            match fs.transaction(|fs| fs.write_plan(ino, offset as usize, data))? {
+                Some(plan) => (plan, fs.block_size(), fs.disk_block_size()),
+                None => return Ok(data.len() as u32),
+            }
+        };
+        let WritePlan { blocks, first, data: mut buf, key } = plan;
+        if let Some(key) = key {
+            for (i, chunk) in buf.chunks_mut(bs).enumerate() {
+                xts_crypt(&key, (first + i) as u64, chunk, true);
+            }
+        }
+        self.write_blocks(&blocks, &buf, bs, unit)?;
+        let ino_shifted = SharedRfs::<T>::shift_ino(ino as usize);
+        self.fs.lock().unwrap().transaction(|fs| fs.write_finish(ino_shifted, offset as usize + data.len()))?;
+        Ok(data.len() as u32)
+    }
+
+    /// Read whole blocks through the driver, zeros for holes
+    fn read_blocks(&self, blocks: &[usize], bs: usize, unit: usize) -> Result<Vec<u8>> {
+        let mut driver = self.driver.clone();
+        let mut data = vec![0; blocks.len() * bs];
+        for (block, buf) in blocks.iter().zip(data.chunks_mut(bs)) {
+            if *block == 0 { continue; }
+            driver.ddriver_seek((block * bs) as i64, SeekType::Set)?;
+            for unit_buf in buf.chunks_mut(unit) {
+                driver.ddriver_read(unit_buf, unit)?;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Write whole blocks through the driver
+    fn write_blocks(&self, blocks: &[usize], data: &[u8], bs: usize, unit: usize) -> Result<()> {
+        let mut driver = self.driver.clone();
+        for (block, buf) in blocks.iter().zip(data.chunks(bs)) {
+            driver.ddriver_seek((block * bs) as i64, SeekType::Set)?;
+            for unit_buf in buf.chunks(unit) {
+                driver.ddriver_write(unit_buf, unit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// FUSE server, requests are served by `threads` workers of `RfsConfig`,
+/// or in order on the session thread if not more than one
+pub struct RfsServer<T: DiskDriver> {
+    pub shared: Arc<RfsShared<T>>,
+    pool: Option<WorkerPool>,
+}
+
+impl<T: DiskDriver + Send + 'static> RfsServer<T> {
+    pub fn new(driver: T, config: RfsConfig) -> Self {
+        let threads = config.threads;
+        info!("serving with {} threads", threads.max(1));
+        Self {
+            shared: Arc::new(RfsShared::new(driver, config)),
+            pool: if threads > 1 { Some(WorkerPool::new(threads)) } else { None },
+        }
+    }
+
+    /// Run `job` on a worker
+    pub fn spawn(&self, job: impl FnOnce(&RfsShared<T>) + Send + 'static) {
+        match &self.pool {
+            Some(pool) => {
+                let shared = self.shared.clone();
+                pool.execute(Box::new(move || job(&shared)));
+            }
+            None => job(&self.shared),
+        }
+    }
+
+    /// Wait for running requests and stop workers
+    pub fn join(&mut self) {
+        self.pool.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use disk_driver::memory::MemoryDiskDriver;
+    use crate::rfs_lib::desc::*;
+    use crate::rfs_lib::mkfs::MkfsOptions;
+
+    /// Memory driver logging the byte of every written unit filled with one byte
+    struct LogDriver {
+        inner: MemoryDiskDriver,
+        log: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl DiskDriver for LogDriver {
+        fn ddriver_open(&mut self, path: &str) -> Result<()> { self.inner.ddriver_open(path) }
+        fn ddriver_close(&mut self) -> Result<()> { self.inner.ddriver_close() }
+        fn ddriver_seek(&mut self, offset: i64, whence: SeekType) -> Result<u64> { self.inner.ddriver_seek(offset, whence) }
+        fn ddriver_write(&mut self, buf: &[u8], size: usize) -> Result<usize> {
+            if buf.iter().all(|x| *x == buf[0]) { self.log.lock().unwrap().push(buf[0]); }
+            self.inner.ddriver_write(buf, size)
+        }
+        fn ddriver_read(&mut self, buf: &mut [u8], size: usize) -> Result<usize> { self.inner.ddriver_read(buf, size) }
+        fn ddriver_ioctl(&mut self, cmd: u32, arg: &mut [u8]) -> Result<()> { self.inner.ddriver_ioctl(cmd, arg) }
+        fn ddriver_reset(&mut self) -> Result<()> { self.inner.ddriver_reset() }
+        fn ddriver_flush(&mut self) -> Result<()> { self.inner.ddriver_flush() }
+        fn ddriver_flush_range(&mut self, left: u64, right: u64) -> Result<()> { self.inner.ddriver_flush_range(left, right) }
+    }
+
+    #[test]
+    fn test_server() -> Result<()> {
+        let mut server = RfsServer::new(MemoryDiskDriver::with_size(16 * 0x400 * 0x400), RfsConfig { threads: 8, ..Default::default() });
+        let shared = server.shared.clone();
+        shared.run_exclusive(|fs| -> Result<()> {
+            fs.driver_open("mem")?;
+            fs.rfs_mkfs(&MkfsOptions { features: vec!["has_journal".to_string()], ..Default::default() })
+        })?;
+        let bs = shared.fs.lock().unwrap().block_size();
+        let root = EXT2_ROOT_INO as u64;
+        let (hot, _) = shared.run(&[], &[root], |fs| fs.make_node(EXT2_ROOT_INO, "hot", 0o644, Ext2FileType::RegularFile, 0, 0))?;
+        let hot = hot as u64;
+        shared.run(&[], &[hot], |fs| fs.rfs_write(hot, 0, &vec![0; bs * 16]))?;
+
+        let (sender, receiver) = channel();
+        for i in 0..8u8 {
+            let sender = sender.clone();
+            server.spawn(move |s| {
+                let result = (|| -> Result<()> {
+                    let name = format!("file-{}", i);
+                    for round in 0..20u8 {
+                        let (ino, _) = s.run(&[], &[root], |fs| fs.transaction(|fs|
+                            fs.make_node(EXT2_ROOT_INO, &name, 0o644, Ext2FileType::RegularFile, 0, 0)))?;
+                        let data = vec![i ^ round; bs * (1 + round as usize % 5)];
+                        s.run(&[], &[ino as u64], |fs| fs.transaction(|fs| fs.rfs_write(ino as u64, 0, &data)))?;
+                        assert_eq!(s.read(ino as u64, 0, data.len() as u32)?, data);
+                        // readers of a file being rewritten see whole blocks of one write
+                        if i % 2 == 0 {
+                            s.run(&[], &[hot], |fs| fs.transaction(|fs| fs.rfs_write(hot, 0, &vec![round; bs * 16])))?;
+                        } else {
+                            let hot_data = s.read(hot, 0, (bs * 16) as u32)?;
+                            assert!(hot_data.chunks(bs).all(|x| x.iter().all(|b| *b == x[0])));
+                        }
+                        s.run_exclusive(|fs| fs.transaction(|fs| fs.rfs_unlink(EXT2_ROOT_INO, &name)))?;
+                    }
+                    Ok(())
+                })();
+                sender.send(result.map_err(|e| e.to_string())).unwrap();
+            });
+        }
+        drop(sender);
+        for result in receiver {
+            result.map_err(anyhow::Error::msg)?;
+        }
+        server.join();
+        let mut fs = shared.fs.lock().unwrap();
+        assert_eq!(fs.get_dir_entries(EXT2_ROOT_INO)?.len(), 4);
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_write_overlap() -> Result<()> {
+        let log = Arc::new(Mutex::new(vec![]));
+        let driver = LogDriver { inner: MemoryDiskDriver::with_size(16 * 0x400 * 0x400), log: log.clone() };
+        let mut server = RfsServer::new(driver, RfsConfig { threads: 4, ..Default::default() });
+        let shared = server.shared.clone();
+        // four plain files, then a compressed and an encrypted one
+        let inos = shared.run_exclusive(|fs| -> Result<Vec<usize>> {
+            fs.driver_open("mem")?;
+            fs.rfs_mkfs(&MkfsOptions { inode_size: 256, features: vec!["encrypt".to_string()], ..Default::default() })?;
+            let id = fs.rfs_add_key(&[7; 64])?;
+            let (dir, _) = fs.make_node(EXT2_ROOT_INO, "secret", 0o755, Ext2FileType::Directory, 0, 0)?;
+            fs.rfs_set_policy(dir as u64, &id)?;
+            let mut inos = (0..5).map(|i| fs.make_node(EXT2_ROOT_INO, &format!("file-{}", i), 0o644, Ext2FileType::RegularFile, 0, 0)
+                .map(|x| x.0)).collect::<Result<Vec<_>>>()?;
+            fs.rfs_set_flags(inos[4] as u64, EXT2_COMPR_FL as u32)?;
+            inos.push(fs.make_node(dir, "file-5", 0o644, Ext2FileType::RegularFile, 0, 0)?.0);
+            Ok(inos)
+        })?;
+        // every call writes its own byte
+        let pattern = |i: u8, round: u8| 0x80 | i << 3 | round;
+        let size = 0x40000;
+        let (sender, receiver) = channel();
+        for i in 0..4u8 {
+            let sender = sender.clone();
+            let ino = inos[i as usize];
+            server.spawn(move |s| {
+                let result = (|| -> Result<()> {
+                    for round in 0..4u8 {
+                        let data = vec![pattern(i, round); size];
+                        assert_eq!(s.write(ino as u64, 1, &data)? as usize, size);
+                        assert_eq!(s.read(ino as u64, 0, size as u32 + 1)?[1..], data);
+                    }
+                    Ok(())
+                })();
+                sender.send(result.map_err(|e| e.to_string())).unwrap();
+            });
+        }
+        drop(sender);
+        for result in receiver {
+            result.map_err(anyhow::Error::msg)?;
+        }
+        server.join();
+        // data of some call is written while another file is being written
+        let log = log.lock().unwrap().iter().copied().filter(|x| (0x80..0xa0).contains(x)).collect::<Vec<_>>();
+        let overlapped = (0..4u8).flat_map(|i| (0..4u8).map(move |round| pattern(i, round))).any(|b| {
+            match (log.iter().position(|x| *x == b), log.iter().rposition(|x| *x == b)) {
+                (Some(first), Some(last)) => log[first..last].iter().any(|x| *x != b),
+                _ => false,
+            }
+        });
+        assert!(overlapped);
+        for ino in [inos[4] as u64, inos[5] as u64] {
+            let data = (0..size).map(|x| (x / 7) as u8).collect::<Vec<_>>();
+            shared.write(ino, 1, &data)?;
+            shared.write(ino, 0x8001, &data[0x8000..0x9000])?;
+            assert_eq!(shared.read(ino, 0, size as u32 + 1)?[1..], data);
+            assert_eq!(shared.read(ino, 0x4000, 0x4000)?, data[0x3fff..0x7fff]);
+        }
+        let mut fs = shared.fs.lock().unwrap();
+        assert_eq!(fs.get_dir_entries(EXT2_ROOT_INO)?.len(), 9);
+        assert!(SharedRfs::<LogDriver>::is_compressed(&fs.get_inode(inos[4])?));
+        assert!(SharedRfs::<LogDriver>::is_encrypted(&fs.get_inode(inos[5])?));
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+/// Snapshots of the whole filesystem, kept by block-level copy-on-write.
+///
+/// Each snapshot is a sparse regular file `/.snapshots/<name>` flagged
+/// `EXT4_SNAPFILE_FL`, logical block `b` of it holds the content disk block `b`
+/// had when the snapshot was taken, saved just before `b` is overwritten for the
+/// first time. Blocks missing in one snapshot are found in newer snapshots, or
+/// on disk if no snapshot saved them. `s_snapshot_list` is the directory,
+/// `s_snapshot_inum` the newest snapshot which receives saved blocks and
+/// `s_snapshot_id` the last id given, kept in `i_generation` of snapshot files.
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::{anyhow, Error, Result};
+use disk_driver::DiskDriver;
+use libc::{EEXIST, ENOENT, EPERM};
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// Directory under root holding snapshot files
+pub const RFS_SNAPSHOT_DIR: &str = ".snapshots";
+
+/// Copy-on-write state in memory
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotState {
+    /// Snapshot file receiving saved blocks, 0 if none
+    pub active: usize,
+    /// Blocks in use by any snapshot, same layout as `bitmap_data`
+    pub used: Vec<u8>,
+    /// Blocks already saved in active snapshot
+    pub saved: BTreeSet<usize>,
+    /// Disk blocks read instead when mounted as a snapshot
+    pub view: BTreeMap<usize, usize>,
+    /// Saving one block, new blocks must not need saving
+    busy: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub ino: usize,
+    pub id: u32,
+    pub time: u32,
+    /// Blocks used by snapshot file
+    pub blocks: usize,
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Snapshots from the oldest one
+    pub fn snapshot_list(&mut self) -> Result<Vec<SnapshotInfo>> {
+        let dir = self.super_block.s_snapshot_list as usize;
+        if dir == 0 { return Ok(vec![]); }
+        let mut list = vec![];
+        for entry in self.get_dir_entries(dir)? {
+            let inode = self.get_inode(entry.inode as usize)?;
+            if inode.i_flags & EXT4_SNAPFILE_FL as u32 == 0 { continue; }
+            list.push(SnapshotInfo {
+                name: entry.get_name(),
+                ino: entry.inode as usize,
+                id: inode.i_generation,
+                time: inode.i_ctime,
+                blocks: inode.i_blocks as usize * 512 / self.block_size(),
+            });
+        }
+        list.sort_by_key(|x| x.id);
+        Ok(list)
+    }
+
+    fn snapshot_find(&mut self, name: &str) -> Result<(Vec<SnapshotInfo>, usize)> {
+        let list = self.snapshot_list()?;
+        let pos = list.iter().position(|x| x.name == name)
+            .ok_or_else(|| Error::new(Errno(ENOENT)).context(format!("snapshot {} not found", name)))?;
+        Ok((list, pos))
+    }
+
+    /// Saved blocks of snapshot file, disk block to copy
+    fn snapshot_mapping(&mut self, ino: usize) -> Result<BTreeMap<usize, usize>> {
+        let inode = self.get_inode(ino)?;
+        let end = self.blocks_end(ino, &inode)?;
+        let mut mapping = BTreeMap::new();
+        if end == 0 { return Ok(mapping); }
+        self.visit_blocks_inode(ino, 0, &mut |block, index| {
+            if block != 0 { mapping.insert(index, block); }
+            Ok((index + 1 < end, false))
+        })?;
+        Ok(mapping)
+    }
+
+    /// Where each block of snapshot `pos` is kept, blocks not listed are unchanged on disk
+    fn snapshot_view(&mut self, list: &[SnapshotInfo], pos: usize) -> Result<BTreeMap<usize, usize>> {
+        let mut view = BTreeMap::new();
+        for snapshot in &list[pos..] {
+            for (block, copy) in self.snapshot_mapping(snapshot.ino)? {
+                view.entry(block).or_insert(copy);
+            }
+        }
+        Ok(view)
+    }
+
+    /// Block bitmap as seen by a snapshot view
+    fn snapshot_bitmap(&mut self, view: &BTreeMap<usize, usize>) -> Result<Vec<u8>> {
+        let mut bitmap = vec![];
+        for group in 0..self.groups_count() {
+            let block = self.alloc().group_desc_table[group].bg_block_bitmap as usize;
+            let data = self.get_data_block(*view.get(&block).unwrap_or(&block))?;
+            bitmap.extend_from_slice(&data[..self.group_bitmap_bytes(true)]);
+        }
+        Ok(bitmap)
+    }
+
+    /// Data and index blocks of inode
+    fn snapshot_owned_blocks(&mut self, ino: usize) -> Result<Vec<usize>> {
+        let inode = self.get_inode(ino)?;
+        let mut blocks = self.snapshot_mapping(ino)?.into_values().collect::<Vec<_>>();
+        blocks.extend(self.index_blocks(ino, &inode)?);
+        Ok(blocks)
+    }
+
+    /// Load copy-on-write state, or switch to the snapshot given by `RfsConfig::snapshot`
+    pub fn snapshot_load(&mut self) -> Result<()> {
+        self.snapshot = Default::default();
+        let name = self.config.snapshot.clone();
+        if !name.is_empty() { return self.snapshot_mount(&name); }
+        let active = self.super_block.s_snapshot_inum as usize;
+        if active == 0 { return Ok(()); }
+        let list = self.snapshot_list()?;
+        let mut used = vec![0; self.alloc().bitmap_data.len()];
+        for pos in 0..list.len() {
+            let view = self.snapshot_view(&list, pos)?;
+            for (x, y) in used.iter_mut().zip(self.snapshot_bitmap(&view)?) { *x |= y; }
+        }
+        // snapshot files and journal are never part of snapshots
+        let mut excluded = list.iter().map(|x| x.ino).collect::<Vec<_>>();
+        if self.super_block.s_journal_inum != 0 { excluded.push(self.super_block.s_journal_inum as usize); }
+        for ino in excluded {
+            for block in self.snapshot_owned_blocks(ino)? {
+                let bit = self.block_bit(block);
+                Self::bitmap_unset(&mut used, bit);
+            }
+        }
+        let saved = self.snapshot_mapping(active)?.into_keys().collect();
+        self.snapshot = SnapshotState { active, used, saved, ..Default::default() };
+        debug!("snapshot {} active, {} blocks saved", active, self.snapshot.saved.len());
+        if self.read_only { return Ok(()); }
+        // blocks written while saving blocks or after checksums of bitmaps are set, saved first
+        let (inode_block, _) = self.fetch_inode_block_offset(active)?;
+        let mut blocks = vec![inode_block, self.filesystem_first_block * EXT2_SUPER_BLOCK_OFFSET / self.block_size()];
+        blocks.extend(self.alloc().group_desc_table.iter().flat_map(|x| [x.bg_block_bitmap as usize, x.bg_inode_bitmap as usize]));
+        for first in [self.group_desc_block() - 1].into_iter().chain(self.super_block_backups()) {
+            blocks.extend(first + 1..=first + self.group_desc_blocks());
+        }
+        self.snapshot_save_blocks(&blocks)
+    }
+
+    /// Show filesystem as snapshot `name`, read only
+    pub fn snapshot_mount(&mut self, name: &str) -> Result<()> {
+        let (list, pos) = self.snapshot_find(name)?;
+        let view = self.snapshot_view(&list, pos)?;
+        info!("mount snapshot {}, {} blocks saved", name, view.len());
+        // cached inodes and names are of the filesystem, not the snapshot
+        if !self.read_only { self.inode_cache_flush(None)?; }
+        self.cache_clear();
+        self.snapshot = SnapshotState { view, ..Default::default() };
+        self.read_only = true;
+        self.journal = None;
+        let offset = self.filesystem_first_block * EXT2_SUPER_BLOCK_OFFSET;
+        let data = self.get_data_block(offset / self.block_size())?;
+        let super_block: Ext2SuperBlock = unsafe { deserialize_row(&data[offset % self.block_size()..]) };
+        self.load_fs_meta(&super_block)?;
+        self.root_dir = self.get_inode(EXT2_ROOT_INO)?;
+        self.quota_load()?;
+        self.refcount_load()
+    }
+
+    /// Disk block holding content of block in mounted snapshot
+    pub fn snapshot_block(&self, block: usize) -> usize {
+        *self.snapshot.view.get(&block).unwrap_or(&block)
+    }
+
+    /// Free block not used by any snapshot, so writing it needs no saving.
+    /// Returns None to take any free block, unless saving a block.
+    pub(crate) fn snapshot_search(&self, reserved: usize, limit: usize) -> Result<Option<usize>> {
+        if self.snapshot.used.is_empty() { return Ok(None); }
+        let mut start = reserved;
+        while let Ok(bit) = Self::bitmap_search(&self.alloc().bitmap_data, start, limit) {
+            if !Self::bitmap_get(&self.snapshot.used, bit) { return Ok(Some(bit)); }
+            start = bit;
+        }
+        if self.snapshot.busy { Err(anyhow!("No space left for snapshot")) } else { Ok(None) }
+    }
+
+    /// Save content of block to active snapshot before it is overwritten
+    pub fn snapshot_cow(&mut self, block: usize) -> Result<()> {
+        self.snapshot_save_blocks(&[block])
+    }
+
+    /// Save blocks not saved yet, all of them are marked saved before any block is written
+    pub(crate) fn snapshot_save_blocks(&mut self, blocks: &[usize]) -> Result<()> {
+        if self.snapshot.active == 0 { return Ok(()); }
+        let mut contents = vec![];
+        for &block in blocks {
+            if self.snapshot.saved.contains(&block) || block < self.super_block.s_first_data_block as usize {
+                continue;
+            }
+            let bit = self.block_bit(block);
+            if bit > self.snapshot.used.len() * 8 || !Self::bitmap_get(&self.snapshot.used, bit) { continue; }
+            // content on disk, not staged in journal
+            let mut data = self.create_block_vec();
+            self.seek_block(block)?;
+            self.read_block(&mut data)?;
+            self.snapshot.saved.insert(block);
+            contents.push((block, data));
+        }
+        if contents.is_empty() { return Ok(()); }
+        self.snapshot.busy = true;
+        let r = contents.iter().try_for_each(|(block, data)| self.snapshot_save(*block, data));
+        self.snapshot.busy = false;
+        r
+    }
+
+    fn snapshot_save(&mut self, block: usize, data: &[u8]) -> Result<()> {
+        let ino = self.snapshot.active;
+        debug!("save block {} to snapshot {}", block, ino);
+        // allocating may save other blocks to snapshot file, inode is read after it
+        let copy = self.allocate_block()?;
+        let mut inode = self.get_inode(ino)?;
+        if self.quota_tracked(ino) { self.quota_charge(inode.uid(), inode.gid(), 1, 0)?; }
+        inode.i_blocks += (self.block_size() / 512) as u32;
+        self.set_inode(ino, &inode)?;
+        self.write_data_block(copy, data)?;
+        Self::block_map(&inode).map(self, ino, &[(block, copy)])
+    }
+
+    /// Snapshot files and their directory cannot be changed from outside
+    pub fn snapshot_check_protected(&mut self, ino: usize) -> Result<()> {
+        let ino = Self::shift_ino(ino);
+        if ino == self.super_block.s_snapshot_list as usize
+            || self.get_inode(ino)?.i_flags & EXT4_SNAPFILE_FL as u32 != 0 {
+            return Err(Error::new(Errno(EPERM)).context(format!("inode {} belongs to snapshots", ino)));
+        }
+        Ok(())
+    }
+
+    /// Take a snapshot of current state, returns snapshot file inode
+    pub fn rfs_snapshot_create(&mut self, name: &str) -> Result<usize> {
+        self.check_writable()?;
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(anyhow!("Invalid snapshot name {:?}", name));
+        }
+        let blocks_count = self.super_block.s_blocks_count as usize;
+        if !self.extent_enabled() && blocks_count > self.threshold(2) {
+            return Err(anyhow!("{} blocks cannot be mapped by snapshot file without extents", blocks_count));
+        }
+        if self.super_block.s_snapshot_list == 0 {
+            if self.get_dir_entries(EXT2_ROOT_INO)?.iter().any(|x| x.get_name() == RFS_SNAPSHOT_DIR) {
+                return Err(Error::new(Errno(EEXIST)).context(format!("/{} exists", RFS_SNAPSHOT_DIR)));
+            }
+            let (dir, _) = self.make_node(EXT2_ROOT_INO, RFS_SNAPSHOT_DIR, 0o700, Ext2FileType::Directory, 0, 0)?;
+            self.super_block.s_snapshot_list = dir as u32;
+        }
+        if self.snapshot_list()?.iter().any(|x| x.name == name) {
+            return Err(Error::new(Errno(EEXIST)).context(format!("snapshot {} exists", name)));
+        }
+        let dir = self.super_block.s_snapshot_list as usize;
+        let (ino, mut inode) = self.make_node(dir, name, 0o400, Ext2FileType::RegularFile, 0, 0)?;
+        let size = (blocks_count * self.block_size()) as u64;
+        self.super_block.s_snapshot_id += 1;
+        inode.i_flags |= EXT4_SNAPFILE_FL as u32;
+        inode.i_size = size as u32;
+        inode.i_size_high = (size >> 32) as u32;
+        inode.i_generation = self.super_block.s_snapshot_id;
+        inode.i_ctime = get_time_now();
+        self.set_inode(ino, &inode)?;
+        self.super_block.s_snapshot_inum = ino as u32;
+        // everything on disk now is the content of snapshot
+        self.rfs_dump()?;
+        self.snapshot_load()?;
+        info!("created snapshot {} id {} on inode {}", name, inode.i_generation, ino);
+        Ok(ino)
+    }
+
+    /// Remove a snapshot, blocks still needed by the older snapshot are moved to it
+    pub fn rfs_snapshot_delete(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let (list, pos) = self.snapshot_find(name)?;
+        let ino = list[pos].ino;
+        let dir = self.super_block.s_snapshot_list as usize;
+        self.remove_dir_entry(dir, name)?;
+        if pos > 0 {
+            let older = list[pos - 1].ino;
+            // moving blocks may save more blocks to this snapshot when it is active
+            loop {
+                let view = self.snapshot_view(&list, pos - 1)?;
+                let bitmap = self.snapshot_bitmap(&view)?;
+                let older_mapping = self.snapshot_mapping(older)?;
+                let moves = self.snapshot_mapping(ino)?.into_iter()
+                    .filter(|(block, _)| !older_mapping.contains_key(block)
+                        && Self::bitmap_get(&bitmap, self.block_bit(*block)))
+                    .collect::<Vec<_>>();
+                if moves.is_empty() { break; }
+                debug!("move {} blocks of snapshot {} to {}", moves.len(), ino, older);
+                let sectors = (moves.len() * self.block_size() / 512) as u32;
+                let inode = self.get_inode(ino)?;
+                Self::block_map(&inode).map(self, ino, &moves.iter().map(|x| (x.0, 0)).collect::<Vec<_>>())?;
+                let mut inode = self.get_inode(ino)?;
+                inode.i_blocks -= sectors;
+                self.set_inode(ino, &inode)?;
+                let inode = self.get_inode(older)?;
+                Self::block_map(&inode).map(self, older, &moves)?;
+                let mut inode = self.get_inode(older)?;
+                inode.i_blocks += sectors;
+                self.set_inode(older, &inode)?;
+            }
+        }
+        if self.super_block.s_snapshot_inum as usize == ino {
+            self.super_block.s_snapshot_inum = if pos > 0 { list[pos - 1].ino as u32 } else { 0 };
+        }
+        self.snapshot_load()?;
+        let inode = self.get_inode(ino)?;
+        self.set_inode(ino, &Ext2INode { i_links_count: 0, ..inode })?;
+        self.free_inode(ino)?;
+        info!("deleted snapshot {}", name);
+        self.rfs_dump()
+    }
+
+    /// Bring whole filesystem back to snapshot `name`, newer snapshots are dropped
+    pub fn rfs_snapshot_rollback(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let (list, pos) = self.snapshot_find(name)?;
+        let view = self.snapshot_view(&list, pos)?;
+        info!("roll back to snapshot {}, {} blocks", name, view.len());
+        // copies are read first, written blocks are never copies
+        let mut blocks = vec![];
+        for (&block, &copy) in view.iter() {
+            blocks.push((block, self.get_data_block(copy)?));
+        }
+        self.snapshot = Default::default();
+        // cached inodes and names are newer than the snapshot
+        self.cache_clear();
+        for (block, data) in blocks {
+            self.write_data_block(block, &data)?;
+        }
+        self.get_driver().ddriver_flush()?;
+        self.cache_clear();
+        let super_block = self.read_super_block()?;
+        self.load_fs_meta(&super_block)?;
+        self.root_dir = self.get_inode(EXT2_ROOT_INO)?;
+        self.quota_load()?;
+        self.refcount_load()?;
+        self.snapshot_load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk_driver::memory::MemoryDiskDriver;
+
+    fn read_file(fs: &mut RFS<MemoryDiskDriver>, name: &str) -> Result<Vec<u8>> {
+        let (ino, inode) = fs.rfs_lookup(EXT2_ROOT_INO, name)?;
+        let data = fs.rfs_read(ino as u64, 0, 0x1000)?;
+        Ok(data[..inode.i_size as usize].to_vec())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let (a, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(a as u64, 0, &[1; 3000])?;
+        fs.rfs_dump()?;
+        let free = fs.alloc().free_blocks_count;
+        fs.rfs_snapshot_create("s1")?;
+        fs.rfs_write(a as u64, 0, &[2; 3000])?;
+        let (b, _) = fs.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(b as u64, 0, &[3; 100])?;
+        fs.rfs_snapshot_create("s2")?;
+        fs.rfs_write(a as u64, 1024, &[4; 1024])?;
+        fs.rfs_unlink(EXT2_ROOT_INO, "b")?;
+        let list = fs.snapshot_list()?;
+        assert_eq!(list.iter().map(|x| x.name.clone()).collect::<Vec<_>>(), ["s1", "s2"]);
+        assert!(fs.rfs_write(list[0].ino as u64, 0, &[0; 10]).is_err());
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+
+        let mut view = RFS::new(fs.driver, fs.config);
+        view.rfs_init("mem")?;
+        view.snapshot_mount("s1")?;
+        assert_eq!(read_file(&mut view, "a")?, [1; 3000]);
+        assert!(view.rfs_lookup(EXT2_ROOT_INO, "b").is_err());
+        let mut view = RFS::new(view.driver, view.config);
+        view.rfs_init("mem")?;
+        // names looked up before mounting are not kept
+        assert!(view.rfs_lookup(EXT2_ROOT_INO, "b").is_err());
+        view.snapshot_mount("s2")?;
+        assert_eq!(read_file(&mut view, "a")?, [2; 3000]);
+        assert_eq!(read_file(&mut view, "b")?, [3; 100]);
+
+        // s1 keeps blocks saved by s2
+        let mut fs = RFS::new(view.driver, view.config);
+        fs.rfs_init("mem")?;
+        fs.rfs_snapshot_delete("s2")?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        fs.rfs_snapshot_rollback("s1")?;
+        assert_eq!(read_file(&mut fs, "a")?, [1; 3000]);
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "b").is_err());
+        fs.rfs_snapshot_delete("s1")?;
+        fs.rfs_dump()?;
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        assert!(fs.snapshot_list()?.is_empty());
+        // snapshot directory is left
+        assert_eq!(fs.alloc().free_blocks_count, free - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_rollback_cache() -> Result<()> {
+        let mut fs = crate::rfs_lib::test_fs()?;
+        let (a, _) = fs.make_node(EXT2_ROOT_INO, "a", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        let (b, _) = fs.make_node(EXT2_ROOT_INO, "b", 0o644, Ext2FileType::RegularFile, 0, 0)?;
+        fs.rfs_write(a as u64, 0, &[1; 1000])?;
+        fs.rfs_write(b as u64, 0, &[1; 1000])?;
+        fs.rfs_dump()?;
+        fs.rfs_snapshot_create("s1")?;
+        // cached as not found, and a dirty inode not written yet
+        fs.rfs_unlink(EXT2_ROOT_INO, "a")?;
+        assert!(fs.rfs_lookup(EXT2_ROOT_INO, "a").is_err());
+        fs.rfs_write(b as u64, 0, &[2; 3000])?;
+        assert!(fs.inode_cache.dirty_count() > 0);
+        fs.rfs_snapshot_rollback("s1")?;
+        assert_eq!(read_file(&mut fs, "a")?, [1; 1000]);
+        assert_eq!(read_file(&mut fs, "b")?, [1; 1000]);
+        fs.rfs_snapshot_delete("s1")?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(read_file(&mut fs, "b")?, [1; 1000]);
+        assert!(fs.rfs_fsck(false)?.is_clean());
+        Ok(())
+    }
+}
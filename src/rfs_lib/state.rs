@@ -0,0 +1,134 @@
+/// Mount state of super block, and the `s_errors` policy on detected corruption.
+///
+/// Like ext2 in kernel, `EXT2_VALID_FS` is cleared while mounted and set again
+/// when unmounted cleanly, `EXT2_ERROR_FS` stays until fsck repairs the disk.
+use std::mem::size_of;
+use anyhow::{anyhow, Error, Result};
+use disk_driver::DiskDriver;
+use libc::EIO;
+use log::*;
+use crate::rfs_lib::RFS;
+use crate::rfs_lib::desc::*;
+use crate::rfs_lib::utils::*;
+
+/// Names of `s_errors` used by mke2fs `-e`
+const ERRORS_NAMES: [(usize, &str); 3] = [
+    (EXT2_ERRORS_CONTINUE, "continue"),
+    (EXT2_ERRORS_RO, "remount-ro"),
+    (EXT2_ERRORS_PANIC, "panic"),
+];
+
+pub fn parse_errors_behavior(name: &str) -> Result<u16> {
+    ERRORS_NAMES.iter().find(|x| x.1 == name).map(|x| x.0 as u16)
+        .ok_or_else(|| anyhow!("Invalid errors behavior {}, expected continue, remount-ro or panic", name))
+}
+
+pub fn errors_behavior_name(errors: u16) -> &'static str {
+    ERRORS_NAMES.iter().find(|x| x.0 == errors as usize).map_or("unknown", |x| x.1)
+}
+
+impl<T: DiskDriver> RFS<T> {
+    /// Mark fs dirty and count this mount, called after loading metadata
+    pub fn mount_state_begin(&mut self) -> Result<()> {
+        let sb = &mut self.super_block;
+        if sb.s_state & EXT2_ERROR_FS as u16 != 0 {
+            warn!("mounting fs with errors, running fsck is recommended");
+        } else if sb.s_state & EXT2_VALID_FS as u16 == 0 {
+            warn!("mounting unchecked fs, running fsck is recommended");
+        }
+        let max = sb.s_max_mnt_count as i16;
+        if max > 0 && sb.s_mnt_count as i16 >= max {
+            warn!("maximal mount count reached ({} of {}), running fsck is recommended", sb.s_mnt_count, max);
+        }
+        if self.read_only { return Ok(()); }
+        sb.s_mnt_count = sb.s_mnt_count.wrapping_add(1);
+        sb.s_mtime = get_time_now();
+        sb.s_state &= !(EXT2_VALID_FS as u16);
+        self.rfs_dump()
+    }
+
+    /// Mark fs clean unless errors are detected, called before unmounting
+    pub fn mount_state_end(&mut self) {
+        if self.super_block.s_state & EXT2_ERROR_FS as u16 == 0 {
+            self.super_block.s_state |= EXT2_VALID_FS as u16;
+        }
+    }
+
+    /// Report metadata corruption and follow `s_errors`, returns EIO error for caller
+    pub fn fs_error(&mut self, message: String) -> Error {
+        error!("fs error: {}", message);
+        self.super_block.s_state |= EXT2_ERROR_FS as u16;
+        let errors = self.super_block.s_errors as usize;
+        if errors == EXT2_ERRORS_RO || errors == EXT2_ERRORS_PANIC {
+            if let Err(e) = self.write_error_state() {
+                error!("cannot record error state: {}", e);
+            }
+        }
+        match errors {
+            EXT2_ERRORS_RO if !self.read_only => {
+                warn!("remounting fs read-only");
+                self.read_only = true;
+            }
+            EXT2_ERRORS_PANIC => panic!("fs panic forced by errors behavior: {}", message),
+            _ => {}
+        }
+        Error::new(Errno(EIO)).context(message)
+    }
+
+    /// Set error state in primary super block on disk, bypassing journal
+    fn write_error_state(&mut self) -> Result<()> {
+        if self.read_only { return Ok(()); }
+        let bs = self.block_size();
+        let offset = self.filesystem_first_block * EXT2_SUPER_BLOCK_OFFSET;
+        let journal = self.journal.take();
+        let r = self.get_data_block(offset / bs).and_then(|mut data| {
+            let mut sb: Ext2SuperBlock = unsafe { deserialize_row(&data[offset % bs..]) };
+            sb.s_state |= EXT2_ERROR_FS as u16;
+            sb.update_checksum();
+            data[offset % bs..][..size_of::<Ext2SuperBlock>()].copy_from_slice(unsafe { serialize_row(&sb) });
+            self.write_data_block(offset / bs, &data)
+        });
+        self.journal = journal;
+        r?;
+        self.get_driver().ddriver_flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfs_lib::utils::get_errno;
+    use libc::EROFS;
+
+    #[test]
+    fn test_state() -> Result<()> {
+        let fs = crate::rfs_lib::test_fs()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        let count = fs.super_block.s_mnt_count;
+        assert_eq!(fs.super_block.s_state & EXT2_VALID_FS as u16, 0);
+        fs.mount_state_end();
+        fs.rfs_dump()?;
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_eq!(fs.super_block.s_mnt_count, count + 1);
+
+        fs.super_block.s_errors = parse_errors_behavior("remount-ro")?;
+        let e = fs.get_inode(fs.super_block.s_inodes_count as usize + 1).unwrap_err();
+        assert_eq!(get_errno(&e, 0), EIO);
+        assert!(fs.read_only);
+        let e = fs.transaction(|fs| fs.make_node(EXT2_ROOT_INO, "file", 0o644, Ext2FileType::RegularFile, 0, 0)).unwrap_err();
+        assert_eq!(get_errno(&e, 0), EROFS);
+
+        // error state is kept after unmounting
+        fs.mount_state_end();
+        let mut fs = RFS::new(fs.driver, fs.config);
+        fs.rfs_init("mem")?;
+        assert_ne!(fs.super_block.s_state & EXT2_ERROR_FS as u16, 0);
+        fs.rfs_fsck(true)?;
+        assert_eq!(fs.super_block.s_state & EXT2_ERROR_FS as u16, 0);
+        fs.super_block.s_errors = EXT2_ERRORS_CONTINUE as u16;
+        fs.rfs_dump()?;
+        Ok(())
+    }
+}
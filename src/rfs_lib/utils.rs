@@ -178,8 +178,8 @@ macro_rules! rep {
     ($reply:expr, $n:ident, $r:expr) => {
         let $n;
         let _result = $r;
-        if _result.is_err() {
-            $reply.error(ENOENT);
+        if let Err(e) = &_result {
+            $reply.error(get_errno(e, ENOENT));
             return;
         } else {
             $n = _result.unwrap();
@@ -197,8 +197,8 @@ macro_rules! rep_mut {
     ($reply:expr, $n:ident, $r:expr) => {
         let mut $n;
         let _result = $r;
-        if _result.is_err() {
-            $reply.error(ENOENT);
+        if let Err(e) = &_result {
+            $reply.error(get_errno(e, ENOENT));
             return;
         } else {
             $n = _result.unwrap();
@@ -209,6 +209,26 @@ macro_rules! rep_mut {
     };
 }
 
+/// Error carrying an errno, so that FUSE replies can report the real reason
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub c_int);
+
+impl std::fmt::Display for Errno {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "errno {}", self.0)
+    }
+}
+
+impl std::error::Error for Errno {}
+
+/// Get errno attached to error by `Errno`, or use `default`
+pub fn get_errno(e: &anyhow::Error, default: c_int) -> c_int {
+    match e.downcast_ref::<Errno>() {
+        Some(errno) => errno.0,
+        None => default,
+    }
+}
+
 /// Convert Result<T, E> to Result<T, c_int>
 pub fn ret<E, T>(res: Result<T, E>) -> Result<T, c_int> where E: std::fmt::Debug {
     match res {
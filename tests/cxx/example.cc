@@ -0,0 +1,43 @@
+// Example of the C++ API: format an image, write files and read them back.
+// Build and run by test.sh
+#include <dirent.h>
+#include <cerrno>
+#include <cstdio>
+#include <string>
+#include "rust/cxx.h"
+#include "rfs/src/lib.rs.h"
+
+#define CHECK(x) do { if (!(x)) { fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #x); return 1; } } while (0)
+
+int main(int argc, char **argv) {
+  const char *path = argc > 1 ? argv[1] : "example.img";
+  auto fs = rfs::format(path, 8 << 20, 1024);
+  fs->mkdir("/docs");
+  std::string text = "hello from c++";
+  fs->write("/docs/hello.txt", rust::Slice<const uint8_t>((const uint8_t *) text.data(), text.size()));
+  fs->close();
+
+  fs = rfs::open(path, false);
+  rfs::Stat st = fs->stat("/docs/hello.txt");
+  CHECK(st.file_type == DT_REG && st.size == text.size());
+  CHECK(fs->lookup("/docs/hello.txt") == st.ino);
+  auto data = fs->read("/docs/hello.txt");
+  CHECK(std::string(data.begin(), data.end()) == text);
+  for (const auto &e : fs->readdir("/docs")) {
+    printf("%lu %s\n", (unsigned long) e.ino, std::string(e.name).c_str());
+  }
+
+  try {
+    fs->unlink("/docs");
+    CHECK(false);
+  } catch (const rust::Error &e) {
+    printf("unlink: %s\n", e.what());
+    CHECK(fs->error() == ENOTEMPTY);
+  }
+  fs->unlink("/docs/hello.txt");
+  fs->unlink("/docs");
+  CHECK(fs->readdir("/").size() == 1);  // lost+found
+  fs->close();
+  printf("Test Pass :)\n");
+  return 0;
+}
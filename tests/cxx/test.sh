@@ -0,0 +1,10 @@
+#!/bin/bash
+# Build static library of rfs, then build and run the C++ example against it
+set -e
+cd "$(dirname "$0")/../.."
+cargo build --lib $CARGO_FLAGS
+libs=$(cargo rustc -q $CARGO_FLAGS --lib --crate-type staticlib -- --print native-static-libs 2>&1 | sed -n 's/.*native-static-libs: //p' | tail -1)
+out=$(mktemp -d)
+g++ -std=c++14 -I target/cxxbridge tests/cxx/example.cc target/debug/librfs.a $libs -o "$out/example"
+"$out/example" "$out/example.img"
+rm -rf "$out"